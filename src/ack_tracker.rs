@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::NodeId;
+
+#[derive(Debug)]
+struct Sent<T> {
+    peer: NodeId,
+    payload: T,
+    sent_at: Instant,
+    // The id of the latest send, acks for earlier ones are resolved to it through `chains`.
+    latest: u64,
+}
+
+// What was sent to which peer and is not confirmed yet, by the id of the message it went out
+// with. A payload that is sent again goes out under a new id chained to the earlier ones, so an
+// ack for any of them confirms it, like a reply to a request that was retried. Acks only count
+// from the peer the payload was sent to.
+#[derive(Debug)]
+pub struct AckTracker<T> {
+    // By the id of the first send.
+    sent: HashMap<u64, Sent<T>>,
+    // Every id of an unconfirmed send to the id of its first send.
+    chains: HashMap<u64, u64>,
+}
+
+impl<T> Default for AckTracker<T> {
+    fn default() -> Self {
+        Self {
+            sent: HashMap::new(),
+            chains: HashMap::new(),
+        }
+    }
+}
+
+impl<T> AckTracker<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send(&mut self, peer: impl Into<NodeId>, msg_id: u64, payload: T) {
+        self.chains.insert(msg_id, msg_id);
+        self.sent.insert(
+            msg_id,
+            Sent {
+                peer: peer.into(),
+                payload,
+                sent_at: Instant::now(),
+                latest: msg_id,
+            },
+        );
+    }
+
+    // The payload sent under `previous` went out again under `msg_id`. Returns `false` when it
+    // was confirmed already.
+    pub fn resend(&mut self, previous: u64, msg_id: u64) -> bool {
+        let Some(first) = self.chains.get(&previous).copied() else {
+            return false;
+        };
+        let sent = self
+            .sent
+            .get_mut(&first)
+            .expect("chains only lead to sends");
+        sent.sent_at = Instant::now();
+        sent.latest = msg_id;
+        self.chains.insert(msg_id, first);
+        true
+    }
+
+    // Confirms the payload sent to the peer under the id or one chained to it, and hands it back.
+    // `None` for ids that are unknown, confirmed already or were sent to another peer.
+    pub fn acknowledge(&mut self, peer: &str, msg_id: u64) -> Option<T> {
+        let first = *self.chains.get(&msg_id)?;
+        if *self.sent[&first].peer != *peer {
+            return None;
+        }
+        let sent = self.sent.remove(&first).expect("chains only lead to sends");
+        self.chains.retain(|_, chained| *chained != first);
+        Some(sent.payload)
+    }
+
+    pub fn is_confirmed(&self, msg_id: u64) -> bool {
+        !self.chains.contains_key(&msg_id)
+    }
+
+    pub fn payload(&self, msg_id: u64) -> Option<&T> {
+        let first = self.chains.get(&msg_id)?;
+        Some(&self.sent[first].payload)
+    }
+
+    pub fn unconfirmed<'a>(&'a self, peer: &'a str) -> impl Iterator<Item = &'a T> + 'a {
+        self.sent
+            .values()
+            .filter(move |sent| *sent.peer == *peer)
+            .map(|sent| &sent.payload)
+    }
+
+    // The latest ids of the sends to the peer that were not confirmed within the timeout, to be
+    // sent again.
+    pub fn overdue(&self, peer: &str, timeout: Duration) -> Vec<u64> {
+        let now = Instant::now();
+        let mut overdue: Vec<u64> = self
+            .sent
+            .values()
+            .filter(|sent| *sent.peer == *peer && now.duration_since(sent.sent_at) >= timeout)
+            .map(|sent| sent.latest)
+            .collect();
+        overdue.sort_unstable();
+        overdue
+    }
+
+    // Unconfirmed sends, not counting their resends.
+    pub fn len(&self) -> usize {
+        self.sent.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sent.is_empty()
+    }
+}
@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, bail};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+
+use crate::{
+    ID_GENERATOR, Init, InitOk, MaelstromError, Message, NodeId, Reply, RequestInfo, Response,
+    ResponseInfo, SendError, id_gen, probe,
+};
+
+pub struct AsyncEventInjector<E> {
+    sender: mpsc::UnboundedSender<E>,
+}
+
+impl<E> Clone for AsyncEventInjector<E> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<E> AsyncEventInjector<E> {
+    pub fn send(&mut self, event: E) {
+        self.sender
+            .send(event)
+            .unwrap_or_else(|_| panic!("failed to send event over channel"))
+    }
+
+    pub fn try_send(&mut self, event: E) -> Result<(), SendError<E>> {
+        self.sender.send(event).map_err(|error| SendError(error.0))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+type PendingReplies = Arc<Mutex<HashMap<u64, oneshot::Sender<serde_json::Value>>>>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[derive(Clone)]
+pub struct AsyncSocket {
+    node_id: NodeId,
+    writer: Arc<tokio::sync::Mutex<Writer>>,
+    pending: PendingReplies,
+}
+
+impl AsyncSocket {
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub async fn send<B>(&self, message: Message<B>) -> Result<()>
+    where
+        B: Serialize,
+    {
+        let mut frame = serde_json::to_vec(&message).context("serializing message")?;
+        frame.push(b'\n');
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&frame).await.context("writing message")?;
+        writer.flush().await.context("flushing message")
+    }
+
+    // Only the future returned by this function waits for the reply, which the reader hands it
+    // while other messages keep being served.
+    pub async fn rpc<Req, Res>(&self, dest: impl Into<NodeId>, body: Req) -> Result<Res>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let message_id = ID_GENERATOR.next_id();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("failed to lock pending replies")
+            .insert(message_id, tx);
+
+        self.send(Message::new(self.node_id.clone(), dest, body).with_id(message_id))
+            .await
+            .context("sending rpc request")?;
+
+        let reply = rx.await.context("waiting for rpc reply")?;
+        serde_json::from_value(reply).context("deserializing rpc reply")
+    }
+}
+
+pub trait AsyncNode: Sized + Send + Sync + 'static {
+    type Request: DeserializeOwned + Send + 'static;
+    type Response: Serialize + DeserializeOwned + Send + 'static;
+    type Event: Send + 'static;
+
+    type InitState;
+
+    fn from_init(
+        init: Init,
+        init_state: Self::InitState,
+        event_injector: AsyncEventInjector<Self::Event>,
+    ) -> Self;
+
+    // Every message is handled in a task of its own, so a handler waiting on an rpc does not
+    // hold up the others. The node is shared by them, state that changes goes behind a lock.
+    //
+    // Errors are sent back to the client, the node keeps serving.
+    fn handle_request(
+        &self,
+        request: Self::Request,
+        info: RequestInfo,
+        socket: &AsyncSocket,
+    ) -> impl Future<Output = Result<Reply<Self::Response>, MaelstromError>> + Send;
+
+    fn handle_response(
+        &self,
+        response: Self::Response,
+        info: ResponseInfo,
+        socket: &AsyncSocket,
+    ) -> impl Future<Output = Result<()>> + Send {
+        // Responses to rpcs never reach the node, and all others do not matter by default.
+        let _ = (response, info, socket);
+        async { Ok(()) }
+    }
+
+    fn handle_event(
+        &self,
+        event: Self::Event,
+        socket: &AsyncSocket,
+    ) -> impl Future<Output = Result<()>> + Send {
+        // By default no event handling is enabled.
+        let _ = (event, socket);
+        async { Ok(()) }
+    }
+
+    fn run(init_state: Self::InitState) -> impl Future<Output = Result<()>> {
+        Self::run_with_io(init_state, tokio::io::stdin(), tokio::io::stdout())
+    }
+
+    // Runs until the input ends, an error of a response or event handler stops it early.
+    fn run_with_io(
+        init_state: Self::InitState,
+        input: impl AsyncRead + Send + Unpin + 'static,
+        output: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> impl Future<Output = Result<()>> {
+        async move {
+            let mut lines = BufReader::new(input).lines();
+
+            let line = lines
+                .next_line()
+                .await
+                .context("reading init message")?
+                .context("first message to node should be init")?;
+            let init: Message<Init> = serde_json::from_str(&line).context("parsing init")?;
+            // Lines are counted with their newline.
+            let mut offset = line.len() as u64 + 1;
+
+            let socket = AsyncSocket {
+                node_id: NodeId::new(&init.body.kind.node_id),
+                writer: Arc::new(tokio::sync::Mutex::new(Box::new(output))),
+                pending: PendingReplies::default(),
+            };
+            socket
+                .send(Message::reply(init.dest, init.src, init.body.id, InitOk {}))
+                .await
+                .context("sending init ok")?;
+
+            let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+            let this = Arc::new(Self::from_init(
+                init.body.kind,
+                init_state,
+                AsyncEventInjector { sender: event_tx },
+            ));
+
+            let (message_tx, mut message_rx) = mpsc::unbounded_channel();
+            let pending = socket.pending.clone();
+            let mut reader = tokio::spawn(async move {
+                while let Some(line) = lines.next_line().await.context("reading input")? {
+                    let line_offset = offset;
+                    offset += line.len() as u64 + 1;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let message: Message<serde_json::Value> = match serde_json::from_str(&line) {
+                        Ok(message) => message,
+                        Err(error) => {
+                            eprintln!(
+                                "skipping malformed message at byte {line_offset}: {error}: {line:?}"
+                            );
+                            continue;
+                        }
+                    };
+
+                    // Replies to outstanding rpcs go straight to the waiting future.
+                    let waiter = message
+                        .body
+                        .kind
+                        .get("in_reply_to")
+                        .and_then(id_gen::id_from_value)
+                        .and_then(|id| {
+                            pending
+                                .lock()
+                                .expect("failed to lock pending replies")
+                                .remove(&id)
+                        });
+                    match waiter {
+                        Some(waiter) => {
+                            let _ = waiter.send(message.body.kind);
+                        }
+                        None => {
+                            if message_tx.send(message).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                Ok::<_, anyhow::Error>(())
+            });
+
+            let mut handlers = JoinSet::new();
+            loop {
+                tokio::select! {
+                    result = &mut reader => {
+                        // Handlers still waiting on rpcs are dropped, no reply can reach them.
+                        return result.context("joining reader task")?;
+                    }
+                    Some(message) = message_rx.recv() => {
+                        handlers.spawn(dispatch(this.clone(), socket.clone(), message));
+                    }
+                    Some(event) = event_rx.recv() => {
+                        let (this, socket) = (this.clone(), socket.clone());
+                        handlers.spawn(async move {
+                            this.handle_event(event, &socket)
+                                .await
+                                .context("handling event")
+                        });
+                    }
+                    Some(handled) = handlers.join_next() => {
+                        handled.context("joining handler task")??;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Only responses carry `in_reply_to`, requests that do not parse are answered with an error
+// like the sync runtime does.
+async fn dispatch<N: AsyncNode>(
+    this: Arc<N>,
+    socket: AsyncSocket,
+    message: Message<serde_json::Value>,
+) -> Result<()> {
+    let Message { src, dest, body } = message;
+    if body.kind.get("in_reply_to").is_some() {
+        let response: Response<N::Response> = match serde_json::from_value(body.kind) {
+            Ok(response) => response,
+            Err(error) => {
+                eprintln!("skipping malformed response from {src}: {error}");
+                return Ok(());
+            }
+        };
+        return this
+            .handle_response(
+                response.inner,
+                ResponseInfo {
+                    in_reply_to: response.in_reply_to,
+                },
+                &socket,
+            )
+            .await
+            .context("handling a response");
+    }
+
+    let kind = body
+        .kind
+        .get("type")
+        .and_then(serde_json::Value::as_str)
+        .map(str::to_owned);
+    let result = match serde_json::from_value(body.kind) {
+        Ok(request) => {
+            this.handle_request(request, RequestInfo { src: &src }, &socket)
+                .await
+        }
+        Err(error) => Err(match kind {
+            Some(kind) if probe::knows_type::<N::Request>(&kind) => {
+                MaelstromError::MalformedRequest(format!("malformed {kind} request: {error}"))
+            }
+            Some(kind) => {
+                MaelstromError::NotSupported(format!("request type {kind} is not supported"))
+            }
+            None => MaelstromError::NotSupported(
+                "requests without a type are not supported".to_string(),
+            ),
+        }),
+    };
+    let responses = match result {
+        Ok(Reply::Respond(response)) => vec![response],
+        Ok(Reply::Stream(responses)) => responses,
+        Ok(Reply::Deferred) => bail!("request deferred without a responder"),
+        Ok(Reply::None) => return Ok(()),
+        Err(error) => {
+            eprintln!("request from {src} failed: {error}");
+            return socket
+                .send(Message::reply(dest, src, body.id, error))
+                .await
+                .context("sending error response");
+        }
+    };
+    for response in responses {
+        socket
+            .send(Message::reply(dest.clone(), src.clone(), body.id, response))
+            .await
+            .context("sending response")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::{Value, json};
+    use tokio::io::{DuplexStream, Lines, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Ping,
+        // Answered with what n2 holds under the key.
+        Ask { key: u64 },
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Response {
+        Pong,
+        AskOk { value: u64 },
+    }
+
+    struct Asker;
+
+    impl AsyncNode for Asker {
+        type Request = Request;
+        type Response = Response;
+        type Event = ();
+        type InitState = ();
+
+        fn from_init(_: Init, _: (), _: AsyncEventInjector<()>) -> Self {
+            Self
+        }
+
+        async fn handle_request(
+            &self,
+            request: Request,
+            _info: RequestInfo<'_>,
+            socket: &AsyncSocket,
+        ) -> Result<Reply<Response>, MaelstromError> {
+            match request {
+                Request::Ping => Ok(Response::Pong.into()),
+                Request::Ask { key } => socket
+                    .rpc("n2", json!({ "type": "ask", "key": key }))
+                    .await
+                    .map(Reply::Respond)
+                    .map_err(|error| MaelstromError::Crash(format!("{error:#}"))),
+            }
+        }
+    }
+
+    struct Client {
+        input: WriteHalf<DuplexStream>,
+        output: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    }
+
+    impl Client {
+        async fn send(&mut self, src: &str, body: Value) {
+            let frame = json!({ "src": src, "dest": "n1", "body": body });
+            let mut line = frame.to_string();
+            line.push('\n');
+            self.input.write_all(line.as_bytes()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Value {
+            let line = self
+                .output
+                .next_line()
+                .await
+                .unwrap()
+                .expect("node is running");
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    fn start() -> (Client, tokio::task::JoinHandle<Result<()>>) {
+        let (node, client) = tokio::io::duplex(4096);
+        let (node_input, node_output) = tokio::io::split(node);
+        let (output, input) = tokio::io::split(client);
+        let node = tokio::spawn(Asker::run_with_io((), node_input, node_output));
+        let client = Client {
+            input,
+            output: BufReader::new(output).lines(),
+        };
+        (client, node)
+    }
+
+    async fn init(client: &mut Client) {
+        let init =
+            json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"] });
+        client.send("c1", init).await;
+        assert_eq!(client.recv().await["body"]["type"], "init_ok");
+    }
+
+    #[tokio::test]
+    async fn serves_requests_while_a_handler_waits_on_an_rpc() {
+        let (mut client, node) = start();
+        init(&mut client).await;
+
+        client
+            .send("c1", json!({ "type": "ask", "msg_id": 2, "key": 7 }))
+            .await;
+        let ask = client.recv().await;
+        assert_eq!(ask["dest"], "n2");
+        assert_eq!(ask["body"]["key"], 7);
+
+        client
+            .send("c1", json!({ "type": "ping", "msg_id": 3 }))
+            .await;
+        let pong = client.recv().await;
+        assert_eq!(pong["body"]["type"], "pong");
+        assert_eq!(pong["body"]["in_reply_to"], 3);
+
+        let reply = json!({
+            "type": "ask_ok",
+            "value": 42,
+            "in_reply_to": ask["body"]["msg_id"],
+        });
+        client.send("n2", reply).await;
+        let answer = client.recv().await;
+        assert_eq!(answer["dest"], "c1");
+        assert_eq!(answer["body"]["value"], 42);
+        assert_eq!(answer["body"]["in_reply_to"], 2);
+
+        client.input.shutdown().await.unwrap();
+        node.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn answers_rejected_requests_with_errors() {
+        let (mut client, node) = start();
+        init(&mut client).await;
+
+        client
+            .send("c1", json!({ "type": "write", "msg_id": 2 }))
+            .await;
+        let unsupported = client.recv().await;
+        assert_eq!(unsupported["body"]["type"], "error");
+        assert_eq!(unsupported["body"]["code"], 10);
+        assert_eq!(unsupported["body"]["in_reply_to"], 2);
+
+        client
+            .send("c1", json!({ "type": "ask", "msg_id": 3 }))
+            .await;
+        let malformed = client.recv().await;
+        assert_eq!(malformed["body"]["code"], 12);
+        assert_eq!(malformed["body"]["in_reply_to"], 3);
+
+        // The node kept serving.
+        client
+            .send("c1", json!({ "type": "ping", "msg_id": 4 }))
+            .await;
+        assert_eq!(client.recv().await["body"]["type"], "pong");
+
+        client.input.shutdown().await.unwrap();
+        node.await.unwrap().unwrap();
+    }
+}
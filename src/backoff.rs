@@ -0,0 +1,63 @@
+use std::fmt;
+use std::time::Duration;
+
+use rand::Rng;
+
+// Decides how long to wait before trying something again.
+pub trait Backoff: fmt::Debug + Send + Sync {
+    // The delay before the next attempt, `previous` is the delay before the last attempt and
+    // `None` before the first retry.
+    fn delay(&self, previous: Option<Duration>) -> Duration;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fixed(pub Duration);
+
+impl Backoff for Fixed {
+    fn delay(&self, _previous: Option<Duration>) -> Duration {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Exponential {
+    pub initial: Duration,
+    pub factor: u32,
+    pub max: Duration,
+}
+
+impl Exponential {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            factor: 2,
+            max,
+        }
+    }
+}
+
+impl Backoff for Exponential {
+    fn delay(&self, previous: Option<Duration>) -> Duration {
+        previous
+            .map_or(self.initial, |previous| previous * self.factor)
+            .min(self.max)
+    }
+}
+
+// Picks a random delay between `base` and three times the previous one, which spreads out retries
+// of many nodes that failed at the same time.
+#[derive(Debug, Clone, Copy)]
+pub struct DecorrelatedJitter {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff for DecorrelatedJitter {
+    fn delay(&self, previous: Option<Duration>) -> Duration {
+        let Some(previous) = previous else {
+            return self.base;
+        };
+        let upper = (previous * 3).max(self.base);
+        rand::rng().random_range(self.base..=upper).min(self.max)
+    }
+}
@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::kv::{Contention, KvClient, KvError, LinKv, Watch};
+use crate::{Node, NodeContext};
+
+// Lets nodes wait for each other: every node adds itself to the set stored under the key, and
+// its callback fires once the set holds `parties` nodes. A barrier is passed once, a later phase
+// takes a key of its own.
+#[derive(Debug, Clone)]
+pub struct Barrier<K = LinKv> {
+    client: K,
+    key: String,
+    parties: usize,
+    interval: Duration,
+}
+
+impl<K: KvClient> Barrier<K> {
+    pub fn new(client: K, key: impl Into<String>, parties: usize) -> Self {
+        Self {
+            client,
+            key: key.into(),
+            parties,
+            interval: Duration::from_millis(100),
+        }
+    }
+
+    // How often the nodes that arrived so far are read while waiting.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn parties(&self) -> usize {
+        self.parties
+    }
+
+    // Arrives at the barrier and waits for the others. Timeouts and unavailable stores are
+    // waited out, any other error ends the wait.
+    pub fn wait<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        let node_id = ctx.node_id().to_string();
+        let barrier = self.clone();
+        self.client.update(
+            ctx,
+            &self.key,
+            Contention::default(),
+            move |arrived: Option<BTreeSet<String>>| {
+                let mut arrived = arrived.unwrap_or_default();
+                arrived.insert(node_id.clone());
+                Ok(arrived)
+            },
+            move |node, arrived, ctx| match arrived {
+                Ok(arrived) if arrived.len() >= barrier.parties => callback(node, Ok(()), ctx),
+                Ok(_) => {
+                    barrier.await_others(ctx, callback);
+                    Ok(())
+                }
+                Err(error) => callback(node, Err(error), ctx),
+            },
+        )
+    }
+
+    fn await_others<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) where
+        N: Node,
+        O: Write,
+    {
+        let parties = self.parties;
+        let mut callback = Some(callback);
+        // Filled in before the first read can come back.
+        let watch = Rc::new(RefCell::new(None::<Watch>));
+        let stop = watch.clone();
+        let watching = self.client.watch(
+            ctx,
+            &self.key,
+            self.interval,
+            move |node, arrived: Result<Option<BTreeSet<String>>, KvError>, ctx| {
+                let passed = match arrived {
+                    Ok(Some(arrived)) if arrived.len() >= parties => Ok(()),
+                    Ok(_) | Err(KvError::Timeout | KvError::TemporarilyUnavailable(_)) => {
+                        return Ok(());
+                    }
+                    Err(error) => Err(error),
+                };
+                if let Some(watch) = stop.borrow_mut().take() {
+                    watch.cancel();
+                }
+                match callback.take() {
+                    Some(callback) => callback(node, passed, ctx),
+                    None => Ok(()),
+                }
+            },
+        );
+        *watch.borrow_mut() = Some(watching);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    // Arrives at the barrier once told to and notes when it passed.
+    struct Party {
+        barrier: Barrier<MockKv>,
+        arrive: bool,
+        passed: bool,
+    }
+
+    impl Scenario for Party {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.tick(ctx)
+        }
+
+        fn tick(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            if std::mem::take(&mut self.arrive) {
+                self.barrier
+                    .wait(ctx, |node: &mut Harness<Self>, passed, _| {
+                        passed?;
+                        node.scenario.passed = true;
+                        Ok(())
+                    })?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn passes_once_every_party_arrived() {
+        let kv = MockKv::new();
+        let barrier =
+            Barrier::new(kv.clone(), "phase-1", 3).with_interval(Duration::from_millis(5));
+        let mut cluster = Cluster::new([true, true, false].map(|arrive| Party {
+            barrier: barrier.clone(),
+            arrive,
+            passed: false,
+        }));
+        cluster.run_for(Duration::from_millis(50));
+        assert!((0..3).all(|node| !cluster.scenario(node).passed));
+        assert_eq!(kv.get("phase-1"), Some(serde_json::json!(["n1", "n2"])));
+
+        cluster.scenario_mut(2).arrive = true;
+        cluster.run_until(|cluster| (0..3).all(|node| cluster.scenario(node).passed));
+    }
+}
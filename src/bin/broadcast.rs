@@ -1,75 +1,673 @@
 use std::{
-    collections::{BTreeSet, HashMap, HashSet},
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    hash::{Hash, Hasher},
     io::{Read, Write},
-    thread::JoinHandle,
-    time::Duration,
+    iter,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result, bail};
-use mael::{EventIncjector, ID_GENERATOR, Message, Node, RequestInfo, ResponseInfo, Socket};
+use mael::{
+    AckTracker, Aimd, BloomFilter, ElementSet, EventIncjector, Gossip, GossipState, Gossiper,
+    MaelstromError, Matched, Node, NodeContext, NodeId, Reply, RequestInfo, RumorMonger, Socket,
+    SpanningTree, Topology,
+};
+use rand::seq::IteratorRandom;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-const GOSSIP_INTERVAL: Duration = Duration::from_millis(50);
+// How often the node checks whether the batch window is over.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
 const GOSSIP_NEIGHBOUR_COUNT: usize = 2;
+// Incoming messages are sent on together once per window, a longer window trades latency for
+// fewer messages per operation.
+const DEFAULT_BATCH_WINDOW: Duration = Duration::from_millis(50);
+const DEFAULT_TREE_DEGREE: usize = 4;
+// Added to two windows before unacknowledged messages are sent to a neighbour again, enough for
+// the round trip of a slow network.
+const RESEND_MARGIN: Duration = Duration::from_millis(500);
+// Peers asked for their messages when the node starts, so a node that restarted mid-test does
+// not have to wait for gossip to bring back everything. Any one answer is enough.
+const DEFAULT_SYNC_PEERS: usize = 2;
+// Routing starts without a snapshot when no peer answers in time.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+// How often a random peer is asked for what it got since the last time, which catches up on
+// whatever routing lost.
+const DEFAULT_RESYNC_INTERVAL: Duration = Duration::from_secs(1);
+// How many peers that knew a rumor it is pushed to before it dies.
+const DEFAULT_RUMOR_MISSES: u32 = 4;
+// Of the Bloom filters gossiped when reconciling with them.
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+// Any JSON value Maelstrom broadcasts, compared by its JSON text so that it can go in sets. The
+// keys of objects are sorted, so equal values have the same text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Value", into = "Value")]
+struct Payload {
+    value: Value,
+    text: String,
+}
+
+impl From<Value> for Payload {
+    fn from(value: Value) -> Self {
+        let text = value.to_string();
+        Self { value, text }
+    }
+}
+
+impl From<Payload> for Value {
+    fn from(payload: Payload) -> Self {
+        payload.value
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Eq for Payload {}
+
+impl PartialOrd for Payload {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Payload {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.text.cmp(&other.text)
+    }
+}
+
+impl Hash for Payload {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.text.hash(state);
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde[tag = "type", rename_all = "snake_case"]]
 enum Request {
-    Broadcast {
-        message: u32,
-    },
+    Broadcast { message: Payload },
     Read,
-    Topology {
-        topology: HashMap<String, HashSet<String>>,
-    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    BroadcastOk,
+    ReadOk { messages: BTreeSet<Payload> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerRequest {
     Gossip {
-        messages: BTreeSet<u32>,
+        #[serde(default)]
+        delta: Option<BTreeSet<Payload>>,
+        #[serde(default)]
+        ack: Option<BTreeSet<Payload>>,
+        #[serde(default)]
+        filter: Option<BloomFilter>,
+        #[serde(default)]
+        matched: Option<Matched>,
+    },
+    // Not answered, the batch is acknowledged by its id with the next batch the other way.
+    Batch {
+        messages: BTreeSet<Payload>,
+        acks: BTreeSet<u64>,
+        // How many hops each of the messages took to the sender, in their order. Only sent with a
+        // TTL.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        hops: Vec<u32>,
+    },
+    // The messages added after the version, for peers that read before. Version 0 gets all of
+    // them, like for a node that just started.
+    ReadSince {
+        version: u64,
+    },
+    Rumor {
+        rumors: BTreeSet<Payload>,
     },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 #[allow(clippy::enum_variant_names)]
-enum Response {
-    InitOk,
-    BroadcastOk,
-    ReadOk { messages: BTreeSet<u32> },
-    TopologyOk,
+enum PeerResponse {
     GossipOk,
+    ReadSinceOk {
+        messages: Vec<Payload>,
+        version: u64,
+    },
+    RumorOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        known: Option<BTreeSet<Payload>>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // To random peers, which answer every gossip unless acks are piggybacked.
+    Gossip,
+    // Along a spanning tree of the cluster, which keeps the messages per operation low enough
+    // for the efficiency targets of the challenge.
+    Tree,
+    // Along the topology Maelstrom hands out. It has loops, around which messages go until every
+    // node on them has them, unless a TTL stops them first.
+    Flood,
+    // As rumors to random peers, until enough of them knew a message already. The resync picks
+    // up the messages whose rumors died before reaching every node.
+    Rumor,
 }
 
-enum Event {
-    StartGossip,
+#[derive(Debug, Clone)]
+struct Config {
+    mode: Mode,
+    window: Duration,
+    // Children per node of the tree, deeper trees take longer to reach every node.
+    degree: usize,
+    piggyback_acks: bool,
+    // Gossip Bloom filters of the messages instead of what every peer is missing going by what
+    // it is known to have.
+    bloom_filters: bool,
+    // How many hops a flooded message is forwarded, unlimited when `None`. Nodes further away
+    // than it from where a message was broadcast only get it from a resync.
+    ttl: Option<u32>,
+    // Peers that knew a rumor before it dies, more reach more nodes at the cost of more messages.
+    rumor_misses: u32,
+    // 0 skips the sync.
+    sync_peers: usize,
+    // `None` never asks again after the sync.
+    resync_interval: Option<Duration>,
+}
+
+impl Config {
+    // Every knob is taken from `--<name>=<value>` on the command line, then from its variable
+    // in the environment: `--mode` or `BROADCAST_MODE` for `gossip`, `tree`, `flood` or
+    // `rumor`, `--window-ms` or `BROADCAST_BATCH_WINDOW_MS`, `--degree` or
+    // `BROADCAST_TREE_DEGREE`, `--acks` or `BROADCAST_ACKS` for gossip answered with `reply` or
+    // acked through `piggyback`, `--reconcile` or `BROADCAST_RECONCILE` for gossip of `delta`s
+    // or `bloom` filters, `--ttl` or `BROADCAST_TTL`, `--rumor-misses` or
+    // `BROADCAST_RUMOR_MISSES`, `--sync-peers` or `BROADCAST_SYNC_PEERS`, and `--resync-ms` or
+    // `BROADCAST_RESYNC_MS` where 0 turns resyncing off.
+    fn from_args() -> Result<Self> {
+        let mode = match setting("mode", "BROADCAST_MODE").as_deref() {
+            None | Some("gossip") => Mode::Gossip,
+            Some("tree") => Mode::Tree,
+            Some("flood") => Mode::Flood,
+            Some("rumor") => Mode::Rumor,
+            Some(other) => {
+                bail!("unknown broadcast mode {other:?}, expected gossip, tree, flood or rumor")
+            }
+        };
+        let window = match setting("window-ms", "BROADCAST_BATCH_WINDOW_MS") {
+            Some(millis) => {
+                Duration::from_millis(millis.parse().context("parsing the batch window")?)
+            }
+            None => DEFAULT_BATCH_WINDOW,
+        };
+        let degree = match setting("degree", "BROADCAST_TREE_DEGREE") {
+            Some(degree) => degree.parse().context("parsing the tree degree")?,
+            None => DEFAULT_TREE_DEGREE,
+        };
+        let piggyback_acks = match setting("acks", "BROADCAST_ACKS").as_deref() {
+            None | Some("reply") => false,
+            Some("piggyback") => true,
+            Some(other) => bail!("unknown kind of acks {other:?}, expected reply or piggyback"),
+        };
+        let bloom_filters = match setting("reconcile", "BROADCAST_RECONCILE").as_deref() {
+            None | Some("delta") => false,
+            Some("bloom") => true,
+            Some(other) => bail!("unknown reconciliation {other:?}, expected delta or bloom"),
+        };
+        let ttl = setting("ttl", "BROADCAST_TTL")
+            .map(|ttl| ttl.parse().context("parsing the ttl"))
+            .transpose()?;
+        match ttl {
+            // A tree or gossip has no loops for it to cut short.
+            Some(_) if mode != Mode::Flood => bail!("a ttl only applies to flood mode"),
+            Some(0) => bail!("a ttl of 0 forwards no message at all"),
+            _ => {}
+        }
+        let rumor_misses = match setting("rumor-misses", "BROADCAST_RUMOR_MISSES") {
+            Some(misses) => misses.parse().context("parsing the rumor misses")?,
+            None => DEFAULT_RUMOR_MISSES,
+        };
+        let sync_peers = match setting("sync-peers", "BROADCAST_SYNC_PEERS") {
+            Some(peers) => peers.parse().context("parsing the sync peers")?,
+            None => DEFAULT_SYNC_PEERS,
+        };
+        let resync_interval = match setting("resync-ms", "BROADCAST_RESYNC_MS") {
+            Some(millis) => match millis.parse().context("parsing the resync interval")? {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            },
+            None => Some(DEFAULT_RESYNC_INTERVAL),
+        };
+        Ok(Self {
+            mode,
+            window,
+            degree,
+            piggyback_acks,
+            bloom_filters,
+            ttl,
+            rumor_misses,
+            sync_peers,
+            resync_interval,
+        })
+    }
+}
+
+fn setting(flag: &str, variable: &str) -> Option<String> {
+    let prefix = format!("--{flag}=");
+    std::env::args()
+        .find_map(|arg| arg.strip_prefix(&prefix).map(str::to_owned))
+        .or_else(|| std::env::var(variable).ok())
+}
+
+// The version is the number of messages this node has, which only grows, so whoever read up to
+// a version only needs the messages added after it.
+#[derive(Default)]
+struct Messages {
+    set: BTreeSet<Payload>,
+    // In the order they arrived in.
+    log: Vec<Payload>,
+}
+
+impl Messages {
+    fn insert(&mut self, message: Payload) -> bool {
+        if !self.set.insert(message.clone()) {
+            return false;
+        }
+        self.log.push(message);
+        true
+    }
+
+    fn version(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    // Everything for versions this node has not reached, like those of a node that restarted.
+    fn since(&self, version: u64) -> &[Payload] {
+        match usize::try_from(version) {
+            Ok(version) if version <= self.log.len() => &self.log[version..],
+            _ => &self.log,
+        }
+    }
+}
+
+impl ElementSet for Messages {
+    type Element = Payload;
+
+    fn elements(&self) -> impl Iterator<Item = &Payload> {
+        self.set.iter()
+    }
+
+    fn select(&self, mut keep: impl FnMut(&Payload) -> bool) -> Option<BTreeSet<Payload>> {
+        let selected: BTreeSet<Payload> = self
+            .set
+            .iter()
+            .filter(|message| keep(message))
+            .cloned()
+            .collect();
+        (!selected.is_empty()).then_some(selected)
+    }
+}
+
+impl GossipState for Messages {
+    type Digest = BTreeSet<Payload>;
+    type Delta = BTreeSet<Payload>;
+
+    fn digest(&self) -> Self::Digest {
+        self.set.clone()
+    }
+
+    fn diff(&self, digest: &Self::Digest) -> Option<Self::Delta> {
+        let missing: BTreeSet<Payload> = self.set.difference(digest).cloned().collect();
+        (!missing.is_empty()).then_some(missing)
+    }
+
+    fn merge(&mut self, delta: Self::Delta) {
+        for message in delta {
+            self.insert(message);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Link {
+    // Not sent to the neighbour yet.
+    outgoing: BTreeSet<Payload>,
+    // Ids of the batches that arrived from the neighbour, acknowledged with the next batch to it.
+    to_ack: BTreeSet<u64>,
+}
+
+// Sends every message once over every link, the edges of the tree or the topology, batches that
+// are not acknowledged in time are sent again.
+struct Flood {
+    node_id: NodeId,
+    // Whether the links follow the topology instead of the tree.
+    topology: bool,
+    links: HashMap<NodeId, Link>,
+    unconfirmed: AckTracker<BTreeSet<Payload>>,
+    resend_after: Duration,
+    ttl: Option<u32>,
+    // How many hops the messages took to this node, only kept with a TTL.
+    hops: HashMap<Payload, u32>,
+    sent: u64,
+}
+
+impl Flood {
+    fn new(node_id: &str, nodes: &[NodeId], config: &Config) -> Self {
+        // The topology comes in later on.
+        let links = match config.mode {
+            Mode::Tree => SpanningTree::balanced(nodes, config.degree)
+                .map(|tree| {
+                    tree.neighbours(node_id)
+                        .map(|neighbour| (neighbour.clone(), Link::default()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            _ => HashMap::new(),
+        };
+        Self {
+            node_id: NodeId::new(node_id),
+            topology: config.mode == Mode::Flood,
+            links,
+            unconfirmed: AckTracker::new(),
+            resend_after: config.window * 2 + RESEND_MARGIN,
+            ttl: config.ttl,
+            hops: HashMap::new(),
+            sent: 0,
+        }
+    }
+
+    fn link_topology(&mut self, topology: &Topology) {
+        if !self.topology {
+            return;
+        }
+        self.links = topology
+            .neighbours(&self.node_id)
+            .map(|neighbour| (neighbour.clone(), Link::default()))
+            .collect();
+    }
+
+    // Queues messages that are new to this node, with the hops they took to it, for every
+    // neighbour but the one they came from. Messages that used up the TTL are not forwarded.
+    fn spread(&mut self, messages: impl IntoIterator<Item = (Payload, u32)>, from: Option<&str>) {
+        let mut forwarded = BTreeSet::new();
+        for (message, hops) in messages {
+            let Some(ttl) = self.ttl else {
+                forwarded.insert(message);
+                continue;
+            };
+            self.hops.insert(message.clone(), hops);
+            if hops < ttl {
+                forwarded.insert(message);
+            }
+        }
+        for (neighbour, link) in &mut self.links {
+            if Some(neighbour.as_ref()) != from {
+                link.outgoing.extend(forwarded.iter().cloned());
+            }
+        }
+    }
+
+    // Batches without messages only carry acks, they are not acknowledged themselves.
+    fn acknowledge(&mut self, from: &str, batch: Option<u64>, acks: &BTreeSet<u64>) {
+        let Some(link) = self.links.get_mut(from) else {
+            return;
+        };
+        link.to_ack.extend(batch);
+        for msg_id in acks {
+            self.unconfirmed.acknowledge(from, *msg_id);
+        }
+    }
+
+    fn flush(&mut self, ctx: &mut NodeContext<BroadcastNode, impl Read, impl Write>) -> Result<()> {
+        let hops = self.ttl.map(|_| &self.hops);
+        for (neighbour, link) in &mut self.links {
+            let mut acks = std::mem::take(&mut link.to_ack);
+            for previous in self.unconfirmed.overdue(neighbour, self.resend_after) {
+                let messages = self
+                    .unconfirmed
+                    .payload(previous)
+                    .cloned()
+                    .unwrap_or_default();
+                let batch = batch(messages, std::mem::take(&mut acks), hops);
+                let msg_id = send_batch(ctx, neighbour, batch)?;
+                self.unconfirmed.resend(previous, msg_id);
+                self.sent += 1;
+            }
+            let messages = std::mem::take(&mut link.outgoing);
+            if messages.is_empty() && acks.is_empty() {
+                continue;
+            }
+            self.sent += 1;
+            if messages.is_empty() {
+                ctx.send(neighbour.clone(), batch(messages, acks, hops))
+                    .context("sending acks to neighbour")?;
+                continue;
+            }
+            let msg_id = send_batch(ctx, neighbour, batch(messages.clone(), acks, hops))?;
+            self.unconfirmed.send(neighbour.clone(), msg_id, messages);
+        }
+        Ok(())
+    }
+}
+
+fn batch(
+    messages: BTreeSet<Payload>,
+    acks: BTreeSet<u64>,
+    hops: Option<&HashMap<Payload, u32>>,
+) -> PeerRequest {
+    let hops = match hops {
+        Some(hops) => messages
+            .iter()
+            .map(|message| hops.get(message).copied().unwrap_or_default())
+            .collect(),
+        None => Vec::new(),
+    };
+    PeerRequest::Batch {
+        messages,
+        acks,
+        hops,
+    }
+}
+
+fn send_batch(
+    ctx: &mut NodeContext<BroadcastNode, impl Read, impl Write>,
+    neighbour: &NodeId,
+    batch: PeerRequest,
+) -> Result<u64> {
+    let message = ctx.message(neighbour.clone(), batch);
+    ctx.socket()
+        .send_with_id(message)
+        .context("sending batch to neighbour")
+}
+
+enum Sync {
+    // The peers to ask on the first tick, the node cannot send before.
+    Due(Vec<NodeId>),
+    Waiting,
+    Done,
+}
+
+enum Routing {
+    Gossip(Gossiper<Messages>),
+    Flood(Flood),
+    Rumor(RumorMonger<Messages>),
 }
 
 struct BroadcastNode {
-    node_id: String,
-    messages: BTreeSet<u32>,
-    neighbours: HashSet<String>,
-    neighbour_known: HashMap<String, BTreeSet<u32>>,
-    sent_to_neighbour: HashMap<u32, (String, BTreeSet<u32>)>,
-    _gossip_thread: GossipThread,
+    messages: Messages,
+    routing: Routing,
+    window: Duration,
+    next_batch: Instant,
+    // Client requests and gossip requests of peers answered, for the messages per operation.
+    operations: u64,
+    gossips_answered: u64,
+    sync: Sync,
+    // Sync requests sent and answered.
+    syncs: u64,
+    // The versions of the peers read up to, only what they got after is asked for again.
+    versions: HashMap<NodeId, u64>,
+    resync_interval: Option<Duration>,
+    next_resync: Option<Instant>,
+}
+
+impl BroadcastNode {
+    // Only this node's share, summed over all nodes it matches what Maelstrom reports.
+    fn messages_sent(&self) -> u64 {
+        (match &self.routing {
+            Routing::Gossip(gossiper) => gossiper.sent() + self.gossips_answered,
+            Routing::Flood(flood) => flood.sent,
+            Routing::Rumor(monger) => monger.sent() + self.gossips_answered,
+        }) + self.syncs
+    }
+
+    // Routing waits for the first answer, the others are merged when they arrive. Peers that
+    // answer with an error, like those in another mode, or not at all are given up on after the
+    // timeout.
+    fn request_sync(
+        &mut self,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+        peers: Vec<NodeId>,
+    ) -> Result<()> {
+        for peer in peers {
+            self.read_since(ctx, peer)?;
+        }
+        self.sync = Sync::Waiting;
+        ctx.after(SYNC_TIMEOUT, |this: &mut Self, ctx| {
+            if matches!(this.sync, Sync::Waiting) {
+                eprintln!("{} starts without a snapshot", ctx.node_id());
+                this.sync = Sync::Done;
+            }
+            Ok(())
+        });
+        Ok(())
+    }
+
+    // Asks the peer for the messages it got since the version read last time, which ends the
+    // sync once it answers.
+    fn read_since(
+        &mut self,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+        peer: NodeId,
+    ) -> Result<()> {
+        let version = self.versions.get(&peer).copied().unwrap_or(0);
+        let from = peer.clone();
+        let sent = ctx.raw_rpc(
+            peer,
+            PeerRequest::ReadSince { version },
+            move |this: &mut Self, reply, ctx| {
+                match serde_json::from_value(reply) {
+                    Ok(PeerResponse::ReadSinceOk { messages, version }) => {
+                        this.merge_snapshot(messages);
+                        this.versions.insert(from, version);
+                        this.sync = Sync::Done;
+                    }
+                    _ => eprintln!("{} got no messages from {from}", ctx.node_id()),
+                }
+                Ok(())
+            },
+        );
+        let message_id = sent.context("reading messages of a peer")?;
+        self.syncs += 1;
+        ctx.after(SYNC_TIMEOUT, move |_, ctx| {
+            ctx.abandon_rpc(message_id);
+            Ok(())
+        });
+        Ok(())
+    }
+
+    fn merge_snapshot(&mut self, messages: Vec<Payload>) {
+        match &mut self.routing {
+            // Spread like messages broadcast to this node, the links reach nobody else that
+            // missed them.
+            Routing::Flood(flood) => {
+                let new: Vec<(Payload, u32)> = messages
+                    .into_iter()
+                    .filter(|message| self.messages.insert(message.clone()))
+                    .map(|message| (message, 0))
+                    .collect();
+                flood.spread(new, None);
+            }
+            Routing::Gossip(_) => self.messages.merge(messages.into_iter().collect()),
+            // Nodes the rumors missed are likely to have missed them too.
+            Routing::Rumor(monger) => {
+                for message in messages {
+                    if self.messages.insert(message.clone()) {
+                        monger.spread(message);
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl Node for BroadcastNode {
     type Request = Request;
     type Response = Response;
-    type Event = Event;
+    type PeerRequest = PeerRequest;
+    type PeerResponse = PeerResponse;
+    type InboundResponse = PeerResponse;
+    type Event = ();
+
+    type InitState = Config;
 
-    type InitState = ();
+    const TICK_INTERVAL: Option<Duration> = Some(TICK_INTERVAL);
 
     fn from_init(
         init: mael::Init,
-        _init_state: Self::InitState,
-        event_injector: EventIncjector<Self::Request, Self::Response, Self::Event>,
+        config: Self::InitState,
+        _event_injector: EventIncjector<Self>,
     ) -> Self {
+        let peers = init.node_ids.iter().filter(|id| **id != init.node_id);
+        let sync = match peers.choose_multiple(&mut rand::rng(), config.sync_peers) {
+            peers if peers.is_empty() => Sync::Done,
+            peers => Sync::Due(peers.into_iter().map(|id| NodeId::new(id)).collect()),
+        };
+        let routing = match config.mode {
+            Mode::Gossip => {
+                let mut gossiper = Gossiper::new(GOSSIP_NEIGHBOUR_COUNT, config.window)
+                    .with_rate_control(Aimd::default());
+                if config.piggyback_acks {
+                    gossiper = gossiper.with_piggybacked_acks();
+                }
+                if config.bloom_filters {
+                    gossiper = gossiper.with_bloom_filters(FALSE_POSITIVE_RATE);
+                }
+                Routing::Gossip(gossiper)
+            }
+            Mode::Tree | Mode::Flood => {
+                let nodes: Vec<NodeId> = init.node_ids.iter().map(|id| NodeId::new(id)).collect();
+                Routing::Flood(Flood::new(&init.node_id, &nodes, &config))
+            }
+            Mode::Rumor => Routing::Rumor(RumorMonger::new(
+                GOSSIP_NEIGHBOUR_COUNT,
+                config.rumor_misses,
+            )),
+        };
         Self {
-            node_id: init.node_id,
-            messages: BTreeSet::new(),
-            neighbours: init.node_ids,
-            neighbour_known: HashMap::new(),
-            sent_to_neighbour: HashMap::new(),
-            _gossip_thread: GossipThread::new(event_injector),
+            messages: Messages::default(),
+            routing,
+            window: config.window,
+            next_batch: Instant::now() + config.window,
+            operations: 0,
+            gossips_answered: 0,
+            sync,
+            syncs: 0,
+            versions: HashMap::new(),
+            resync_interval: config.resync_interval,
+            next_resync: config
+                .resync_interval
+                .map(|interval| Instant::now() + interval),
         }
     }
 
@@ -77,127 +675,476 @@ impl Node for BroadcastNode {
         &mut self,
         request: Self::Request,
         _info: RequestInfo,
-        _socket: &mut Socket<impl Read, impl Write>,
-    ) -> Result<Self::Response> {
+        _ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError> {
+        self.operations += 1;
         Ok(match request {
             Request::Broadcast { message } => {
-                self.messages.insert(message);
+                if self.messages.insert(message.clone()) {
+                    match &mut self.routing {
+                        Routing::Flood(flood) => flood.spread([(message, 0)], None),
+                        Routing::Rumor(monger) => monger.spread(message),
+                        Routing::Gossip(_) => {}
+                    }
+                }
                 Response::BroadcastOk
             }
             Request::Read => Response::ReadOk {
-                messages: self.messages.clone(),
+                messages: self.messages.set.clone(),
             },
-            Request::Topology { .. } => {
-                // self.neighbours = topology.remove(&self.node_id).unwrap_or_default();
-                Response::TopologyOk
-            }
-            Request::Gossip { messages } => {
-                self.messages.extend(messages);
-                Response::GossipOk
-            }
-        })
+        }
+        .into())
     }
 
-    fn handle_response(
+    fn handle_peer_request(
         &mut self,
-        response: Self::Response,
-        info: ResponseInfo,
-        _socket: &mut Socket<impl Read, impl Write>,
-    ) -> Result<()> {
-        if let Response::GossipOk = response {
-            let Some(ref in_reply_to) = info.in_reply_to else {
-                bail!("gossip ok received without in-reply-to field");
-            };
-            let Some((neighbour, messages)) = self.sent_to_neighbour.remove(in_reply_to) else {
-                bail!("gossip ok received to a message that is not known to be sent");
-            };
-            self.neighbour_known
-                .entry(neighbour)
-                .or_default()
-                .extend(messages);
+        request: Self::PeerRequest,
+        info: RequestInfo,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::PeerResponse>, MaelstromError> {
+        match (request, &mut self.routing) {
+            (
+                PeerRequest::Gossip {
+                    delta,
+                    ack,
+                    filter,
+                    matched,
+                },
+                Routing::Gossip(gossiper),
+            ) => {
+                let gossip = Gossip {
+                    delta,
+                    ack,
+                    filter,
+                    matched,
+                };
+                if !gossiper.receive(&mut self.messages, info.src, gossip) {
+                    return Ok(Reply::None);
+                }
+                self.gossips_answered += 1;
+                Ok(PeerResponse::GossipOk.into())
+            }
+            (
+                PeerRequest::Batch {
+                    messages,
+                    acks,
+                    hops,
+                },
+                Routing::Flood(flood),
+            ) => {
+                let batch = ctx.msg_id().filter(|_| !messages.is_empty());
+                flood.acknowledge(info.src, batch, &acks);
+                // One more hop than they took to the sender, senders without a TTL leave them out.
+                let hops = hops.into_iter().map(|hops| hops + 1).chain(iter::repeat(1));
+                let new: Vec<(Payload, u32)> = messages
+                    .iter()
+                    .cloned()
+                    .zip(hops)
+                    .filter(|(message, _)| self.messages.insert(message.clone()))
+                    .collect();
+                flood.spread(new, Some(info.src));
+                Ok(Reply::None)
+            }
+            (PeerRequest::Rumor { rumors }, Routing::Rumor(monger)) => {
+                self.gossips_answered += 1;
+                let known = monger.receive(&mut self.messages, rumors);
+                Ok(PeerResponse::RumorOk { known }.into())
+            }
+            (PeerRequest::ReadSince { version }, _) => {
+                self.syncs += 1;
+                Ok(PeerResponse::ReadSinceOk {
+                    messages: self.messages.since(version).to_vec(),
+                    version: self.messages.version(),
+                }
+                .into())
+            }
+            _ => Err(MaelstromError::NotSupported(format!(
+                "{} runs in another broadcast mode",
+                info.src
+            ))),
         }
-        Ok(())
     }
 
-    fn handle_event(
-        &mut self,
-        event: Self::Event,
-        socket: &mut Socket<impl Read, impl Write>,
-    ) -> Result<()> {
-        match event {
-            Event::StartGossip => {
-                // 1. Decide the neighbours to send to.
-                //    - Everyone?
-                //    - Random?
-                //    - Topology?
-                // 2. What data to send.
-                //    - Keep list of neighbour-known values?
-                //    - Random?
-
-                use rand::seq::IteratorRandom;
-
-                for neighbour in self
-                    .neighbours
-                    .iter()
-                    .choose_multiple(&mut rand::rng(), GOSSIP_NEIGHBOUR_COUNT)
-                {
-                    let messages: BTreeSet<u32> = self
-                        .messages
-                        .difference(self.neighbour_known.entry(neighbour.clone()).or_default())
-                        .copied()
-                        .collect();
-
-                    if messages.is_empty() {
-                        continue;
-                    }
+    fn on_topology(&mut self, topology: Topology) {
+        if let Routing::Flood(flood) = &mut self.routing {
+            flood.link_topology(&topology);
+        }
+    }
 
-                    let message_id = ID_GENERATOR.next_id();
-                    socket
-                        .send(
-                            Message::new(
-                                self.node_id.clone(),
-                                neighbour.clone(),
-                                Request::Gossip {
-                                    messages: messages.clone(),
-                                },
-                            )
-                            .with_id(message_id),
-                        )
-                        .context("gossiping messages to neightbour")?;
-                    self.sent_to_neighbour
-                        .entry(message_id)
-                        .or_insert_with(|| (neighbour.clone(), messages));
-                }
+    fn on_tick(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        if let Sync::Due(peers) = &mut self.sync {
+            let peers = std::mem::take(peers);
+            self.request_sync(ctx, peers)?;
+        }
+        if matches!(self.sync, Sync::Waiting) {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if let (Some(next), Some(interval)) = (self.next_resync, self.resync_interval)
+            && now >= next
+        {
+            self.next_resync = Some(now + interval);
+            if let Some(peer) = ctx.peers().choose(&mut rand::rng()).cloned() {
+                self.read_since(ctx, peer)?;
             }
         }
+        if now < self.next_batch {
+            return Ok(());
+        }
+        self.next_batch = now + self.window;
+        match &mut self.routing {
+            Routing::Gossip(gossiper) => gossiper.tick(ctx, &self.messages),
+            Routing::Flood(flood) => flood.flush(ctx),
+            Routing::Rumor(monger) => monger.tick(ctx, &self.messages),
+        }
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        eprintln!(
+            "{} shutting down with {} messages and {} unacknowledged gossips",
+            ctx.node_id(),
+            self.messages.set.len(),
+            ctx.pending_rpcs()
+        );
+        let sent = self.messages_sent();
+        eprintln!(
+            "{} sent {sent} messages to peers for {} operations with a {:?} window, {:.2} per operation",
+            ctx.node_id(),
+            self.operations,
+            self.window,
+            sent as f64 / self.operations.max(1) as f64
+        );
         Ok(())
     }
 }
 
-struct GossipThread {
-    _jh: JoinHandle<Result<()>>,
+fn main() -> Result<()> {
+    let config = Config::from_args()?;
+
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let socket = Socket::with_writer_thread(stdin, stdout);
+
+    BroadcastNode::run(config, socket)
 }
 
-impl GossipThread {
-    fn new<Req, Res>(mut event_injector: EventIncjector<Req, Res, Event>) -> Self
-    where
-        EventIncjector<Req, Res, Event>: Send + 'static,
-    {
-        let _jh = std::thread::spawn(move || {
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::thread;
+
+    use mael::{Loopback, LoopbackTransport, Transport};
+    use serde_json::json;
+
+    use super::*;
+
+    const NODES: usize = 5;
+
+    fn config(mode: Mode) -> Config {
+        Config {
+            mode,
+            window: Duration::from_millis(10),
+            degree: 2,
+            piggyback_acks: false,
+            bloom_filters: false,
+            ttl: None,
+            rumor_misses: DEFAULT_RUMOR_MISSES,
+            sync_peers: 0,
+            resync_interval: None,
+        }
+    }
+
+    // Nodes on a loopback network and a client that talks to them.
+    struct Cluster {
+        network: Loopback,
+        client: LoopbackTransport,
+        ids: Vec<String>,
+        nodes: Vec<thread::JoinHandle<Result<()>>>,
+        msg_id: u64,
+    }
+
+    impl Cluster {
+        // Hands every node the topology when there is one.
+        fn start(config: Config, topology: Option<Value>) -> Self {
+            let network = Loopback::new();
+            let client = network.transport("c1");
+            let ids: Vec<String> = (1..=NODES).map(|node| format!("n{node}")).collect();
+            let nodes = ids
+                .iter()
+                .map(|id| {
+                    let transport = network.transport(id.clone());
+                    let config = config.clone();
+                    thread::spawn(move || {
+                        BroadcastNode::run(config, Socket::from_transport(transport))
+                    })
+                })
+                .collect();
+            let mut cluster = Self {
+                network,
+                client,
+                ids,
+                nodes,
+                msg_id: 0,
+            };
+
+            // Every node is sent its init before any of them can gossip, which a node must not
+            // receive before.
+            let ids = cluster.ids.clone();
+            let mut inits: BTreeSet<u64> = ids
+                .iter()
+                .map(|id| {
+                    cluster.send(
+                        id,
+                        json!({ "type": "init", "node_id": id, "node_ids": ids }),
+                    )
+                })
+                .collect();
+            // The nodes answer in any order.
+            while !inits.is_empty() {
+                let frame = cluster
+                    .client
+                    .recv()
+                    .unwrap()
+                    .expect("the client is connected");
+                let reply: Value = serde_json::from_str(&frame).unwrap();
+                inits.remove(&reply["body"]["in_reply_to"].as_u64().unwrap());
+            }
+            if let Some(topology) = topology {
+                for id in &ids {
+                    cluster.request(id, json!({ "type": "topology", "topology": topology }));
+                }
+            }
+            cluster
+        }
+
+        fn send(&mut self, dest: &str, mut body: Value) -> u64 {
+            self.msg_id += 1;
+            body["msg_id"] = json!(self.msg_id);
+            let frame = json!({ "src": "c1", "dest": dest, "body": body });
+            self.client.send(&frame.to_string()).unwrap();
+            self.msg_id
+        }
+
+        fn request(&mut self, dest: &str, body: Value) -> Value {
+            let msg_id = self.send(dest, body);
             loop {
-                event_injector.send(Event::StartGossip);
-                std::thread::sleep(GOSSIP_INTERVAL);
+                let frame = self
+                    .client
+                    .recv()
+                    .unwrap()
+                    .expect("the client is connected");
+                let reply: Value = serde_json::from_str(&frame).unwrap();
+                if reply["body"]["in_reply_to"] == msg_id {
+                    return reply["body"].clone();
+                }
             }
-        });
+        }
+
+        fn read(&mut self, id: &str) -> BTreeSet<String> {
+            let read = self.request(id, json!({ "type": "read" }));
+            read["messages"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(Value::to_string)
+                .collect()
+        }
 
-        Self { _jh }
+        fn stop(self) {
+            for id in &self.ids {
+                self.network.disconnect(id);
+            }
+            for node in self.nodes {
+                node.join().unwrap().unwrap();
+            }
+        }
     }
-}
 
-fn main() -> Result<()> {
-    let stdin = std::io::stdin();
-    let stdout = std::io::stdout();
-    let socket = Socket::new(stdin, stdout);
+    // Broadcasts a message to every node, which has to read all of them in the end.
+    fn converges(config: Config, topology: Option<Value>) {
+        let mut cluster = Cluster::start(config, topology);
+        let ids = cluster.ids.clone();
+        for (message, id) in ids.iter().enumerate() {
+            cluster.request(id, json!({ "type": "broadcast", "message": message }));
+        }
+
+        let expected: BTreeSet<String> = (0..NODES).map(|message| message.to_string()).collect();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        for id in &ids {
+            loop {
+                let messages = cluster.read(id);
+                if messages == expected {
+                    break;
+                }
+                assert!(Instant::now() < deadline, "{id} only read {messages:?}");
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+        cluster.stop();
+    }
+
+    #[test]
+    fn gossip_converges() {
+        converges(config(Mode::Gossip), None);
+    }
+
+    #[test]
+    fn gossip_with_piggybacked_acks_converges() {
+        converges(
+            Config {
+                piggyback_acks: true,
+                ..config(Mode::Gossip)
+            },
+            None,
+        );
+    }
 
-    BroadcastNode::run((), socket)
+    #[test]
+    fn gossip_with_bloom_filters_converges() {
+        converges(
+            Config {
+                bloom_filters: true,
+                ..config(Mode::Gossip)
+            },
+            None,
+        );
+    }
+
+    #[test]
+    fn tree_converges() {
+        converges(config(Mode::Tree), None);
+    }
+
+    // Every node next to the one before and after it.
+    fn ring() -> Value {
+        let neighbours = |node: usize| {
+            [(node + NODES - 1) % NODES, (node + 1) % NODES].map(|node| format!("n{}", node + 1))
+        };
+        (0..NODES)
+            .map(|node| (format!("n{}", node + 1), json!(neighbours(node))))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    #[test]
+    fn flood_converges() {
+        converges(config(Mode::Flood), Some(ring()));
+    }
+
+    // Rumors live long enough to reach every node without a resync to catch up on the ones that
+    // died early.
+    #[test]
+    fn rumor_converges() {
+        converges(
+            Config {
+                rumor_misses: 12,
+                ..config(Mode::Rumor)
+            },
+            None,
+        );
+    }
+
+    #[test]
+    fn flood_stops_after_the_ttl() {
+        let mut cluster = Cluster::start(
+            Config {
+                ttl: Some(1),
+                ..config(Mode::Flood)
+            },
+            Some(ring()),
+        );
+        cluster.request("n1", json!({ "type": "broadcast", "message": 7 }));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        for neighbour in ["n2", "n5"] {
+            while cluster.read(neighbour).is_empty() {
+                assert!(
+                    Instant::now() < deadline,
+                    "{neighbour} never got the message"
+                );
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+        // Plenty of windows for the neighbours to forward it, had they.
+        thread::sleep(Duration::from_millis(200));
+        for further in ["n3", "n4"] {
+            assert!(
+                cluster.read(further).is_empty(),
+                "{further} got the message"
+            );
+        }
+        cluster.stop();
+    }
+
+    // The peer is played by the test, it hands out two messages on the sync and a third one on
+    // the resync after.
+    #[test]
+    fn resync_reads_only_what_the_peer_got_since() {
+        let network = Loopback::new();
+        let client = network.transport("c1");
+        let peer = network.transport("n2");
+        let config = Config {
+            sync_peers: 1,
+            resync_interval: Some(Duration::from_millis(20)),
+            ..config(Mode::Gossip)
+        };
+        let node = {
+            let transport = network.transport("n1");
+            thread::spawn(move || BroadcastNode::run(config, Socket::from_transport(transport)))
+        };
+        let receive = |transport: &dyn Transport| -> Value {
+            let frame = transport
+                .recv()
+                .unwrap()
+                .expect("the transport is connected");
+            serde_json::from_str(&frame).unwrap()
+        };
+        let request = |msg_id: u64, mut body: Value| {
+            body["msg_id"] = json!(msg_id);
+            let frame = json!({ "src": "c1", "dest": "n1", "body": body });
+            client.send(&frame.to_string()).unwrap();
+            receive(&client)["body"].clone()
+        };
+        // Skips the gossip of the node, which the peer leaves unanswered.
+        let read_since = || loop {
+            let frame = receive(&peer);
+            if frame["body"]["type"] == "read_since" {
+                return frame["body"].clone();
+            }
+        };
+        let answer = |request: &Value, messages: Value, version: u64| {
+            let frame = json!({
+                "src": "n2",
+                "dest": "n1",
+                "body": {
+                    "type": "read_since_ok",
+                    "in_reply_to": request["msg_id"],
+                    "messages": messages,
+                    "version": version,
+                },
+            });
+            peer.send(&frame.to_string()).unwrap();
+        };
+
+        request(
+            1,
+            json!({ "type": "init", "node_id": "n1", "node_ids": ["n1", "n2"] }),
+        );
+        let sync = read_since();
+        assert_eq!(sync["version"], 0);
+        answer(&sync, json!([1, 2]), 2);
+        let resync = read_since();
+        assert_eq!(resync["version"], 2);
+        answer(&resync, json!([3]), 3);
+        assert_eq!(read_since()["version"], 3);
+
+        let read = request(2, json!({ "type": "read" }));
+        assert_eq!(read["messages"], json!([1, 2, 3]));
+        // And the other way around.
+        let since = request(3, json!({ "type": "read_since", "version": 1 }));
+        assert_eq!(since["messages"], json!([2, 3]));
+        assert_eq!(since["version"], 3);
+
+        network.disconnect("n1");
+        node.join().unwrap().unwrap();
+    }
 }
@@ -0,0 +1,250 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use mael::{
+    CausalOrder, EventIncjector, Init, MaelstromError, Node, NodeContext, NodeId, Reply,
+    RequestInfo, Socket, Stamped,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde[tag = "type", rename_all = "snake_case"]]
+enum Request {
+    Broadcast { message: u32 },
+    Read,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    BroadcastOk,
+    // In the order they were delivered in, which is causal.
+    ReadOk { messages: Vec<u32> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerRequest {
+    Deliver { message: Stamped<u32> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerResponse {
+    DeliverOk,
+}
+
+// Every node sends its messages to all others itself, reliably, and delivers what it receives in
+// causal order: a message another node broadcast after reading a third one's is held back
+// until that one arrived too, so no read ever shows an effect without its cause.
+struct CausalBroadcastNode {
+    order: CausalOrder<u32>,
+    delivered: Vec<u32>,
+}
+
+impl Node for CausalBroadcastNode {
+    type Request = Request;
+    type Response = Response;
+    type PeerRequest = PeerRequest;
+    type PeerResponse = PeerResponse;
+    type InboundResponse = PeerResponse;
+    type Event = ();
+
+    type InitState = ();
+
+    fn from_init(
+        init: Init,
+        _init_state: Self::InitState,
+        _event_injector: EventIncjector<Self>,
+    ) -> Self {
+        Self {
+            order: CausalOrder::new(NodeId::new(&init.node_id)),
+            delivered: Vec::new(),
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        request: Self::Request,
+        _: RequestInfo,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError> {
+        Ok(match request {
+            Request::Broadcast { message } => {
+                let stamped = self.order.stamp(message);
+                self.delivered.push(message);
+                let peers: Vec<NodeId> = ctx.peers().cloned().collect();
+                for peer in peers {
+                    ctx.send_reliable(
+                        peer,
+                        PeerRequest::Deliver {
+                            message: stamped.clone(),
+                        },
+                    )
+                    .context("sending broadcast to peer")?;
+                }
+                Response::BroadcastOk
+            }
+            Request::Read => Response::ReadOk {
+                messages: self.delivered.clone(),
+            },
+        }
+        .into())
+    }
+
+    fn handle_peer_request(
+        &mut self,
+        request: Self::PeerRequest,
+        _: RequestInfo,
+        _ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::PeerResponse>, MaelstromError> {
+        let PeerRequest::Deliver { message } = request;
+        self.delivered.extend(self.order.receive(message));
+        Ok(PeerResponse::DeliverOk.into())
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        eprintln!(
+            "{} shutting down with {} delivered and {} buffered messages at {:?}",
+            ctx.node_id(),
+            self.delivered.len(),
+            self.order.buffered().count(),
+            self.order.delivered()
+        );
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let socket = Socket::new(stdin, stdout);
+
+    CausalBroadcastNode::run((), socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mael::{Loopback, LoopbackTransport, Transport};
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    // Loses the first message `n1` delivers, so whatever depends on it arrives first.
+    struct LosesFirstDelivery {
+        inner: LoopbackTransport,
+        lost: AtomicBool,
+    }
+
+    impl Transport for LosesFirstDelivery {
+        fn send(&self, frame: &str) -> Result<()> {
+            self.inner.send(frame)
+        }
+
+        fn recv(&self) -> Result<Option<String>> {
+            loop {
+                let Some(frame) = self.inner.recv()? else {
+                    return Ok(None);
+                };
+                let message: Value = serde_json::from_str(&frame)?;
+                let delivery = message["src"] == "n1" && message["body"]["type"] == "deliver";
+                if !delivery || self.lost.swap(true, Ordering::Relaxed) {
+                    return Ok(Some(frame));
+                }
+            }
+        }
+    }
+
+    struct Client {
+        transport: LoopbackTransport,
+        msg_id: u64,
+    }
+
+    impl Client {
+        fn request(&mut self, dest: &str, mut body: Value) -> Value {
+            self.msg_id += 1;
+            body["msg_id"] = json!(self.msg_id);
+            let frame = json!({ "src": "c1", "dest": dest, "body": body });
+            self.transport.send(&frame.to_string()).unwrap();
+            loop {
+                let frame = self
+                    .transport
+                    .recv()
+                    .unwrap()
+                    .expect("the client is connected");
+                let reply: Value = serde_json::from_str(&frame).unwrap();
+                if reply["body"]["in_reply_to"] == self.msg_id {
+                    return reply["body"].clone();
+                }
+            }
+        }
+
+        fn read(&mut self, dest: &str) -> Vec<u32> {
+            let read = self.request(dest, json!({ "type": "read" }));
+            serde_json::from_value(read["messages"].clone()).unwrap()
+        }
+
+        // Reads until the node delivered the messages, checking every read on the way.
+        fn await_messages(&mut self, dest: &str, messages: &[u32], check: impl Fn(&[u32])) {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                let read = self.read(dest);
+                check(&read);
+                if read == messages {
+                    return;
+                }
+                assert!(Instant::now() < deadline, "{dest} only read {read:?}");
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
+    #[test]
+    fn holds_back_messages_until_their_causes_arrive() {
+        let network = Loopback::new();
+        let ids = ["n1", "n2", "n3"];
+        let nodes: Vec<_> = ids
+            .iter()
+            .map(|&id| {
+                let socket = if id == "n3" {
+                    Socket::from_transport(LosesFirstDelivery {
+                        inner: network.transport(id),
+                        lost: AtomicBool::new(false),
+                    })
+                } else {
+                    Socket::from_transport(network.transport(id))
+                };
+                thread::spawn(move || CausalBroadcastNode::run((), socket))
+            })
+            .collect();
+        let mut client = Client {
+            transport: network.transport("c1"),
+            msg_id: 0,
+        };
+        for id in ids {
+            let init = json!({ "type": "init", "node_id": id, "node_ids": ids });
+            assert_eq!(client.request(id, init)["type"], "init_ok");
+        }
+
+        client.request("n1", json!({ "type": "broadcast", "message": 1 }));
+        client.await_messages("n2", &[1], |_| {});
+        // Sent after n2 delivered 1, so it depends on it.
+        client.request("n2", json!({ "type": "broadcast", "message": 2 }));
+        client.await_messages("n3", &[1, 2], |read| {
+            assert!(read != [2], "n3 delivered 2 without 1");
+        });
+        client.await_messages("n1", &[1, 2], |_| {});
+
+        for id in ids {
+            network.disconnect(id);
+        }
+        for node in nodes {
+            node.join().unwrap().unwrap();
+        }
+    }
+}
@@ -1,103 +1,104 @@
-use std::{
-    collections::HashSet,
-    io::{Read, Write},
-};
+use std::io::{Read, Write};
 
-use anyhow::{Context, Result};
-use mael::{Node, RequestInfo, SeqKv, Socket};
+use anyhow::Result;
+use mael::{
+    Contention, EventIncjector, Init, KvClient, MaelstromError, Never, Node, NodeContext, Reply,
+    RequestInfo, SeqKv, Socket,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde[tag = "type", rename_all = "snake_case"]]
 enum Request {
-    Init {
-        node_id: String,
-        node_ids: HashSet<String>,
-    },
-    Add {
-        delta: u32,
-    },
+    Add { delta: u32 },
     Read,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 #[allow(clippy::enum_variant_names)]
 enum Response {
-    InitOk,
     AddOk,
     ReadOk { value: u32 },
 }
 
-#[derive(Default)]
 struct CountingNode {
-    id: String,
+    kv: SeqKv,
 }
 
 impl Node for CountingNode {
     type Request = Request;
-
     type Response = Response;
+    type PeerRequest = Never;
+    type PeerResponse = Never;
+    type InboundResponse = Never;
+    type Event = ();
+
+    type InitState = ();
+
+    fn from_init(
+        _init: Init,
+        _init_state: Self::InitState,
+        _event_injector: EventIncjector<Self>,
+    ) -> Self {
+        Self { kv: SeqKv::new() }
+    }
 
     fn handle_request(
         &mut self,
         request: Self::Request,
         _: RequestInfo,
-        socket: &mut Socket<impl Read, impl Write>,
-    ) -> Result<Self::Response> {
-        Ok(match request {
-            Request::Init { node_id, .. } => {
-                self.id = node_id;
-                Response::InitOk
-            }
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError> {
+        let responder = ctx.defer()?;
+        match request {
+            // Errors of the store are answered with their own code. Plain reads of seq-kv may miss
+            // adds that were acknowledged already.
             Request::Read => {
-                let value = SeqKv
-                    .read(self.id.clone(), "counter".to_string(), socket)
-                    .context("reading counter from key-value store")?
-                    .unwrap_or_else(|| "0".to_string())
-                    .parse()
-                    .context("parsing value into u32")?;
-                Response::ReadOk { value }
+                self.kv
+                    .fresh_read(ctx, "counter", move |_, value, ctx| match value {
+                        Ok(value) => responder.respond(
+                            ctx,
+                            Response::ReadOk {
+                                value: value.unwrap_or(0),
+                            },
+                        ),
+                        Err(error) => responder.respond(ctx, MaelstromError::from(error)),
+                    })?
             }
-            Request::Add { delta } => {
-                loop {
-                    use mael::seq_kv::CasResponse;
+            Request::Add { delta } => self.kv.fetch_add(
+                ctx,
+                "counter",
+                delta,
+                Contention::default(),
+                true,
+                move |_, added, ctx| match added {
+                    Ok(_) => responder.respond(ctx, Response::AddOk),
+                    Err(error) => responder.respond(ctx, MaelstromError::from(error)),
+                },
+            )?,
+        }
+        Ok(Reply::Deferred)
+    }
 
-                    let value = SeqKv
-                        .read(self.id.clone(), "counter".to_string(), socket)
-                        .context("reading counter from key-value store")?
-                        .unwrap_or_else(|| "0".to_string());
-                    let result = SeqKv
-                        .compare_and_set(
-                            self.id.clone(),
-                            "counter".to_string(),
-                            value.clone(),
-                            format!(
-                                "{}",
-                                value.parse::<u32>().context("parsing value as u32")? + delta
-                            ),
-                            socket,
-                        )
-                        .context("setting a new counter in the key-value store")?;
-                    match result {
-                        CasResponse::Ok => break,
-                        CasResponse::Retry => {
-                            // The cas operation failed as the value was not the same anymore as
-                            // the expected value. Retry the operation.
-                            continue;
-                        }
-                    }
-                }
-                Response::AddOk
-            }
-        })
+    fn on_shutdown(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        let stats = self.kv.service().stats().snapshot();
+        let cas = stats.operation("cas");
+        eprintln!(
+            "{} shutting down after {} compare-and-sets, {:.0}% of them conflicts, {:?} each",
+            ctx.node_id(),
+            cas.calls,
+            cas.conflict_rate(&Default::default()) * 100.0,
+            cas.latency(&Default::default()),
+        );
+        Ok(())
     }
 }
 
 fn main() -> Result<()> {
-    let stdin = std::io::stdin().lock();
-    let stdout = std::io::stdout().lock();
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
     let socket = Socket::new(stdin, stdout);
 
-    CountingNode::default().run(socket)
+    CountingNode::run((), socket)
 }
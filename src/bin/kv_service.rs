@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use mael::{
+    EventIncjector, Init, MaelstromError, Never, Node, NodeContext, Reply, RequestInfo, Socket,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// What nodes send to the key-value services of Maelstrom. Keys can be any JSON value.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde[tag = "type", rename_all = "snake_case"]]
+enum Request {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    ReadOk { value: Value },
+    WriteOk,
+    CasOk,
+}
+
+// Stands in for seq-kv, lin-kv and lww-kv, whichever id it is started with. Every operation
+// happens in the order it arrives, which is as strong as any of them promises.
+#[derive(Default)]
+struct KvServiceNode {
+    // By the key as JSON, since JSON values cannot be hashed.
+    values: HashMap<String, Value>,
+}
+
+impl Node for KvServiceNode {
+    type Request = Request;
+    type Response = Response;
+    type PeerRequest = Never;
+    type PeerResponse = Never;
+    type InboundResponse = Never;
+    type Event = ();
+
+    type InitState = ();
+
+    fn from_init(
+        _init: Init,
+        _init_state: Self::InitState,
+        _event_injector: EventIncjector<Self>,
+    ) -> Self {
+        Self::default()
+    }
+
+    fn handle_request(
+        &mut self,
+        request: Self::Request,
+        _: RequestInfo,
+        _: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError> {
+        Ok(match request {
+            Request::Read { key } => match self.values.get(&key.to_string()) {
+                Some(value) => Response::ReadOk {
+                    value: value.clone(),
+                },
+                None => return Err(MaelstromError::KeyDoesNotExist("key does not exist".into())),
+            },
+            Request::Write { key, value } => {
+                self.values.insert(key.to_string(), value);
+                Response::WriteOk
+            }
+            Request::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                match self.values.get(&key.to_string()) {
+                    Some(current) if *current != from => {
+                        return Err(MaelstromError::PreconditionFailed(format!(
+                            "expected {from}, but had {current}"
+                        )));
+                    }
+                    Some(_) => {}
+                    None if create_if_not_exists => {}
+                    None => {
+                        return Err(MaelstromError::KeyDoesNotExist("key does not exist".into()));
+                    }
+                }
+                self.values.insert(key.to_string(), to);
+                Response::CasOk
+            }
+        }
+        .into())
+    }
+}
+
+fn main() -> Result<()> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let socket = Socket::new(stdin, stdout);
+
+    KvServiceNode::run((), socket)
+}
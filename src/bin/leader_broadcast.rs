@@ -0,0 +1,499 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use mael::{
+    EventIncjector, Init, KvClient, LeaderElector, Leadership, Lease, LinKv, MaelstromError, Node,
+    NodeContext, NodeId, Reply, RequestInfo, Socket,
+};
+use serde::{Deserialize, Serialize};
+
+// How often the node checks whether the batch window is over.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+// New messages go to the leader and the leader's order goes out once per window.
+const BATCH_WINDOW: Duration = Duration::from_millis(50);
+const LEASE_DURATION: Duration = Duration::from_secs(1);
+// Forwards and ordered batches that are not reported back in time are sent again.
+const RESEND_AFTER: Duration = Duration::from_millis(600);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde[tag = "type", rename_all = "snake_case"]]
+enum Request {
+    Broadcast { message: u32 },
+    Read,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    BroadcastOk,
+    ReadOk { messages: BTreeSet<u32> },
+}
+
+// Neither is answered, followers report how much of the order they have with their next
+// forward instead.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerRequest {
+    // From a follower to the leader: messages that are not in the order yet, and how many
+    // entries of the order of the epoch the follower has.
+    Forward {
+        messages: BTreeSet<u32>,
+        epoch: u64,
+        have: usize,
+    },
+    // From the leader to a follower: the entries of the order from `first` on.
+    Ordered {
+        epoch: u64,
+        first: usize,
+        messages: Vec<u32>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerResponse {}
+
+// The order the leader gave the messages. The epoch is the token of the leader's lease, which
+// grows with every new leader, so followers drop the order of an earlier one.
+#[derive(Debug, Default)]
+struct Log {
+    epoch: u64,
+    entries: Vec<u32>,
+    contains: BTreeSet<u32>,
+}
+
+impl Log {
+    fn append(&mut self, message: u32) -> bool {
+        if !self.contains.insert(message) {
+            return false;
+        }
+        self.entries.push(message);
+        true
+    }
+
+    // Starts the order of a new epoch, the entries of the old one are handed back.
+    fn restart(&mut self, epoch: u64) -> Vec<u32> {
+        self.epoch = epoch;
+        self.contains.clear();
+        std::mem::take(&mut self.entries)
+    }
+}
+
+// What the leader knows about a follower.
+#[derive(Debug)]
+struct Follower {
+    // Entries the follower reported to have.
+    have: usize,
+    // Entries sent to the follower.
+    sent: usize,
+    sent_at: Instant,
+}
+
+// Every node forwards the messages it gets to the leader, which puts them in order and sends
+// the new part of the order to everyone once per window. That is about two messages per node
+// and window however many broadcasts there are, where gossip sends some per message. The leader
+// is elected with a lease on lin-kv, until one is known the messages stay with the node.
+struct LeaderBroadcastNode<K = LinKv> {
+    elector: LeaderElector<K>,
+    log: Log,
+    // Messages that are not in the order of the current epoch yet.
+    pending: BTreeSet<u32>,
+    // Pending messages sent to the leader, the others go with the next forward.
+    forwarded: BTreeSet<u32>,
+    forwarded_at: Instant,
+    // The entries this node last told the leader it has, a change is reported even without
+    // messages to forward.
+    reported: Option<(u64, usize)>,
+    followers: HashMap<NodeId, Follower>,
+    next_batch: Instant,
+    operations: u64,
+    sent: u64,
+}
+
+impl<K: KvClient> Node for LeaderBroadcastNode<K> {
+    type Request = Request;
+    type Response = Response;
+    type PeerRequest = PeerRequest;
+    type PeerResponse = PeerResponse;
+    type InboundResponse = PeerResponse;
+    type Event = Leadership;
+
+    // The store the leader is elected with.
+    type InitState = K;
+
+    const TICK_INTERVAL: Option<Duration> = Some(TICK_INTERVAL);
+
+    fn from_init(
+        _init: Init,
+        store: Self::InitState,
+        _event_injector: EventIncjector<Self>,
+    ) -> Self {
+        Self {
+            elector: LeaderElector::new(Lease::new(store, "broadcast-leader", LEASE_DURATION)),
+            log: Log::default(),
+            pending: BTreeSet::new(),
+            forwarded: BTreeSet::new(),
+            forwarded_at: Instant::now(),
+            reported: None,
+            followers: HashMap::new(),
+            next_batch: Instant::now() + BATCH_WINDOW,
+            operations: 0,
+            sent: 0,
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        request: Self::Request,
+        _: RequestInfo,
+        _ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError> {
+        self.operations += 1;
+        Ok(match request {
+            Request::Broadcast { message } => {
+                if !self.log.contains.contains(&message) {
+                    self.pending.insert(message);
+                }
+                Response::BroadcastOk
+            }
+            Request::Read => Response::ReadOk {
+                messages: self.log.contains.union(&self.pending).copied().collect(),
+            },
+        }
+        .into())
+    }
+
+    fn handle_peer_request(
+        &mut self,
+        request: Self::PeerRequest,
+        info: RequestInfo,
+        _ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::PeerResponse>, MaelstromError> {
+        match request {
+            PeerRequest::Forward {
+                messages,
+                epoch,
+                have,
+            } => {
+                // Messages sent to an old leader stay pending with the follower until they are
+                // in the order, so they reach the new one.
+                if !self.elector.is_leader() {
+                    return Ok(Reply::None);
+                }
+                self.pending.extend(
+                    messages
+                        .into_iter()
+                        .filter(|message| !self.log.contains.contains(message)),
+                );
+                let have = if epoch == self.log.epoch { have } else { 0 };
+                self.follower(info.src).have = have;
+            }
+            PeerRequest::Ordered {
+                epoch,
+                first,
+                messages,
+            } => {
+                if epoch < self.log.epoch {
+                    return Ok(Reply::None);
+                }
+                if epoch > self.log.epoch {
+                    let earlier = self.log.restart(epoch);
+                    self.pending.extend(earlier);
+                }
+                // Entries after a gap are sent again from what this node reports, which it does
+                // for every batch so the leader stops sending it again.
+                self.reported = None;
+                let have = self.log.entries.len();
+                if first <= have {
+                    for message in messages.into_iter().skip(have - first) {
+                        self.log.append(message);
+                        self.pending.remove(&message);
+                        self.forwarded.remove(&message);
+                    }
+                }
+            }
+        }
+        Ok(Reply::None)
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Self::Event,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<()> {
+        match event {
+            Leadership::BecameLeader { token } => {
+                eprintln!("{} orders the broadcasts with token {token}", ctx.node_id());
+                let earlier = self.log.restart(token);
+                self.pending.extend(earlier);
+                self.followers.clear();
+            }
+            Leadership::LostLeadership { leader } => {
+                eprintln!("{} lost the lead to {leader:?}", ctx.node_id())
+            }
+        }
+        Ok(())
+    }
+
+    fn on_tick(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        self.elector.start(ctx);
+        let now = Instant::now();
+        if now < self.next_batch {
+            return Ok(());
+        }
+        self.next_batch = now + BATCH_WINDOW;
+        if self.elector.is_leader() {
+            self.disseminate(ctx)
+        } else {
+            self.forward(ctx)
+        }
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        eprintln!(
+            "{} shutting down with {} ordered and {} pending messages",
+            ctx.node_id(),
+            self.log.entries.len(),
+            self.pending.len()
+        );
+        eprintln!(
+            "{} sent {} messages to peers for {} operations, {:.2} per operation",
+            ctx.node_id(),
+            self.sent,
+            self.operations,
+            self.sent as f64 / self.operations.max(1) as f64
+        );
+        Ok(())
+    }
+}
+
+impl<K: KvClient> LeaderBroadcastNode<K> {
+    fn follower(&mut self, node: &str) -> &mut Follower {
+        self.followers
+            .entry(NodeId::new(node))
+            .or_insert_with(|| Follower {
+                have: 0,
+                sent: 0,
+                sent_at: Instant::now(),
+            })
+    }
+
+    // Orders the pending messages and sends every follower what it does not have.
+    fn disseminate(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        for message in std::mem::take(&mut self.pending) {
+            self.log.append(message);
+        }
+        self.forwarded.clear();
+        let len = self.log.entries.len();
+        let peers: Vec<NodeId> = ctx.peers().cloned().collect();
+        for peer in peers {
+            let follower = self.follower(&peer);
+            let first = if follower.sent < len {
+                follower.sent
+            } else if follower.have < len && follower.sent_at.elapsed() >= RESEND_AFTER {
+                follower.have
+            } else {
+                continue;
+            };
+            follower.sent = len;
+            follower.sent_at = Instant::now();
+            ctx.send(
+                peer,
+                PeerRequest::Ordered {
+                    epoch: self.log.epoch,
+                    first,
+                    messages: self.log.entries[first..].to_vec(),
+                },
+            )
+            .context("sending ordered messages")?;
+            self.sent += 1;
+        }
+        Ok(())
+    }
+
+    // Sends the leader the pending messages it was not sent yet, or all of them once it did not
+    // order them in time, along with how much of the order this node has.
+    fn forward(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        let Some(leader) = self.elector.leader() else {
+            return Ok(());
+        };
+        if leader.as_str() == ctx.node_id() {
+            return Ok(());
+        }
+        if self.forwarded_at.elapsed() >= RESEND_AFTER {
+            self.forwarded.clear();
+        }
+        let messages: BTreeSet<u32> = self.pending.difference(&self.forwarded).copied().collect();
+        let have = (self.log.epoch, self.log.entries.len());
+        if messages.is_empty() && self.reported == Some(have) {
+            return Ok(());
+        }
+        if !messages.is_empty() {
+            self.forwarded.extend(&messages);
+            self.forwarded_at = Instant::now();
+        }
+        self.reported = Some(have);
+        ctx.send(
+            leader,
+            PeerRequest::Forward {
+                messages,
+                epoch: have.0,
+                have: have.1,
+            },
+        )
+        .context("forwarding messages to the leader")?;
+        self.sent += 1;
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let socket = Socket::with_writer_thread(stdin, stdout);
+
+    LeaderBroadcastNode::run(LinKv::new(), socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::{Arc, mpsc};
+    use std::thread;
+
+    use mael::runtime::Runtime;
+    use mael::{Loopback, LoopbackTransport, MockKv, RunConfig, Transport};
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    const NODES: usize = 5;
+
+    type Runtimes = Vec<Runtime<LeaderBroadcastNode<MockKv>, io::Empty, io::Sink>>;
+
+    // Nodes sharing one store, which run in turns on the test thread since the store cannot
+    // leave it, and a client that talks to them.
+    struct Cluster {
+        network: Loopback,
+        client: Arc<LoopbackTransport>,
+        replies: mpsc::Receiver<Value>,
+        received: HashMap<u64, Value>,
+        nodes: Runtimes,
+        msg_id: u64,
+    }
+
+    impl Cluster {
+        fn start(kv: &MockKv) -> Self {
+            let network = Loopback::new();
+            let client = Arc::new(network.transport("c1"));
+            let (sender, replies) = mpsc::channel();
+            {
+                let client = client.clone();
+                thread::spawn(move || {
+                    while let Ok(Some(frame)) = client.recv() {
+                        let reply: Value = serde_json::from_str(&frame).unwrap();
+                        if sender.send(reply).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            let mut cluster = Self {
+                client,
+                replies,
+                received: HashMap::new(),
+                nodes: Vec::new(),
+                msg_id: 0,
+                network,
+            };
+            let ids: Vec<String> = (1..=NODES).map(|node| format!("n{node}")).collect();
+            for id in &ids {
+                let socket = Socket::from_transport(cluster.network.transport(id.clone()));
+                cluster.send(
+                    id,
+                    json!({ "type": "init", "node_id": id, "node_ids": ids }),
+                );
+                let node = Runtime::new(kv.clone(), socket, RunConfig::default());
+                cluster.nodes.push(node.unwrap());
+            }
+            cluster
+        }
+
+        fn send(&mut self, dest: &str, mut body: Value) -> u64 {
+            self.msg_id += 1;
+            body["msg_id"] = json!(self.msg_id);
+            let frame = json!({ "src": "c1", "dest": dest, "body": body });
+            self.client.send(&frame.to_string()).unwrap();
+            self.msg_id
+        }
+
+        // Lets every node run for a millisecond at a time until the condition holds.
+        fn run_until(&mut self, mut done: impl FnMut(&mut Self) -> bool) {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !done(self) {
+                assert!(Instant::now() < deadline, "the cluster did not get there");
+                for node in &mut self.nodes {
+                    node.run_for(Duration::from_millis(1)).unwrap();
+                }
+                for reply in self.replies.try_iter() {
+                    let in_reply_to = reply["body"]["in_reply_to"].as_u64().unwrap();
+                    self.received.insert(in_reply_to, reply["body"].clone());
+                }
+            }
+        }
+
+        // Sends every request to the node by its index and returns the replies in order.
+        fn requests(&mut self, requests: impl IntoIterator<Item = (usize, Value)>) -> Vec<Value> {
+            let msg_ids: Vec<u64> = requests
+                .into_iter()
+                .map(|(node, body)| self.send(&format!("n{}", node + 1), body))
+                .collect();
+            self.run_until(|cluster| {
+                msg_ids
+                    .iter()
+                    .all(|msg_id| cluster.received.contains_key(msg_id))
+            });
+            msg_ids
+                .iter()
+                .map(|msg_id| self.received.remove(msg_id).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn every_node_ends_up_with_the_order_of_the_leader() {
+        let kv = MockKv::new().with_latency(Duration::from_millis(1));
+        let mut cluster = Cluster::start(&kv);
+        let broadcasts = (0..20).map(|message| {
+            let broadcast = json!({ "type": "broadcast", "message": message });
+            (message % NODES, broadcast)
+        });
+        let replies = cluster.requests(broadcasts);
+        assert!(replies.iter().all(|reply| reply["type"] == "broadcast_ok"));
+
+        cluster.run_until(|cluster| {
+            let first = &cluster.nodes[0].node().log;
+            first.entries.len() == 20
+                && cluster
+                    .nodes
+                    .iter()
+                    .all(|node| node.node().log.entries == first.entries)
+        });
+        let leaders = cluster
+            .nodes
+            .iter()
+            .filter(|node| node.node().elector.is_leader())
+            .count();
+        assert_eq!(leaders, 1);
+        let all: BTreeSet<u32> = (0..20).collect();
+        let reads = cluster.requests((0..NODES).map(|node| (node, json!({ "type": "read" }))));
+        for read in reads {
+            let messages: BTreeSet<u32> = serde_json::from_value(read["messages"].clone()).unwrap();
+            assert_eq!(messages, all);
+        }
+    }
+}
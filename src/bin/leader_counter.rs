@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use mael::{
+    Contention, EventIncjector, Init, KvClient, LeaderElector, Leadership, Lease, LinKv,
+    MaelstromError, Node, NodeContext, NodeId, Reply, RequestInfo, Responder, SeqKv, Socket,
+};
+use serde::{Deserialize, Serialize};
+
+// How often the leader writes the deltas it gathered to the store.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+const LEASE_DURATION: Duration = Duration::from_secs(1);
+// A leader that does not answer a forwarded add in time leaves the add undecided.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde[tag = "type", rename_all = "snake_case"]]
+enum Request {
+    Add { delta: u32 },
+    Read,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    AddOk,
+    ReadOk { value: u32 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerRequest {
+    Forward { delta: u32 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerResponse {
+    ForwardOk,
+    // The add was not taken, so the follower can make it itself.
+    NotLeader,
+    // The leader failed to write the add, an error reply would not reach the rpc.
+    ForwardFailed { code: u32, text: String },
+}
+
+// The deltas the leader gathered since the last flush, and who waits for them.
+#[derive(Default)]
+struct Batch {
+    delta: u32,
+    clients: Vec<Responder>,
+    peers: Vec<Responder>,
+}
+
+// Like the grow-only counter, but only the leader writes to the store: followers forward their
+// adds and the leader writes them in one compare-and-set per flush, so nodes hardly ever
+// conflict. Without a leader every node adds to the store itself.
+struct LeaderCountingNode<L = LinKv, S = SeqKv> {
+    elector: LeaderElector<L>,
+    // Where the counter is kept.
+    store: S,
+    batch: Batch,
+    flushing: bool,
+    // Adds forwarded to the leader by the id they were sent with, until it answers.
+    forwarded: HashMap<u64, (u32, Responder)>,
+    next_forward: u64,
+}
+
+impl<L: KvClient, S: KvClient> Node for LeaderCountingNode<L, S> {
+    type Request = Request;
+    type Response = Response;
+    type PeerRequest = PeerRequest;
+    type PeerResponse = PeerResponse;
+    type InboundResponse = PeerResponse;
+    type Event = Leadership;
+
+    // The stores of the lease and of the counter.
+    type InitState = (L, S);
+
+    const TICK_INTERVAL: Option<Duration> = Some(FLUSH_INTERVAL);
+
+    fn from_init(
+        _init: Init,
+        (lease, store): Self::InitState,
+        _event_injector: EventIncjector<Self>,
+    ) -> Self {
+        Self {
+            elector: LeaderElector::new(Lease::new(lease, "counter-leader", LEASE_DURATION)),
+            store,
+            batch: Batch::default(),
+            flushing: false,
+            forwarded: HashMap::new(),
+            next_forward: 0,
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        request: Self::Request,
+        _: RequestInfo,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError> {
+        let responder = ctx.defer()?;
+        match request {
+            Request::Read => {
+                self.store
+                    .fresh_read(ctx, "counter", move |_, value, ctx| match value {
+                        Ok(value) => responder.respond(
+                            ctx,
+                            Response::ReadOk {
+                                value: value.unwrap_or(0),
+                            },
+                        ),
+                        Err(error) => responder.respond(ctx, MaelstromError::from(error)),
+                    })?
+            }
+            Request::Add { delta } if self.elector.is_leader() => {
+                self.batch.delta += delta;
+                self.batch.clients.push(responder);
+            }
+            Request::Add { delta } => match self.elector.leader() {
+                Some(leader) if leader.as_str() != ctx.node_id() => {
+                    self.forward(ctx, leader, delta, responder)?
+                }
+                _ => add(&self.store, ctx, delta, responder)?,
+            },
+        }
+        Ok(Reply::Deferred)
+    }
+
+    fn handle_peer_request(
+        &mut self,
+        request: Self::PeerRequest,
+        _: RequestInfo,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::PeerResponse>, MaelstromError> {
+        let PeerRequest::Forward { delta } = request;
+        if !self.elector.is_leader() {
+            return Ok(PeerResponse::NotLeader.into());
+        }
+        self.batch.delta += delta;
+        self.batch.peers.push(ctx.defer()?);
+        Ok(Reply::Deferred)
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Self::Event,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<()> {
+        match event {
+            Leadership::BecameLeader { token } => {
+                eprintln!("{} leads the counter with token {token}", ctx.node_id())
+            }
+            Leadership::LostLeadership { leader } => {
+                eprintln!("{} lost the lead to {leader:?}", ctx.node_id())
+            }
+        }
+        Ok(())
+    }
+
+    fn on_tick(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        self.elector.start(ctx);
+        // Acknowledged adds have to make it to the store even after the lead is lost.
+        if self.flushing || self.batch.clients.is_empty() && self.batch.peers.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        self.flushing = true;
+        self.store
+            .fetch_add(
+                ctx,
+                "counter",
+                batch.delta,
+                Contention::default(),
+                true,
+                move |this: &mut Self, added, ctx| {
+                    this.flushing = false;
+                    let added = added.map_err(MaelstromError::from);
+                    for client in batch.clients {
+                        match &added {
+                            Ok(_) => client.respond(ctx, Response::AddOk)?,
+                            Err(error) => client.respond(ctx, error)?,
+                        }
+                    }
+                    for peer in batch.peers {
+                        match &added {
+                            Ok(_) => peer.respond(ctx, PeerResponse::ForwardOk)?,
+                            Err(error) => peer.respond(
+                                ctx,
+                                PeerResponse::ForwardFailed {
+                                    code: error.code(),
+                                    text: error.text().to_string(),
+                                },
+                            )?,
+                        }
+                    }
+                    Ok(())
+                },
+            )
+            .context("flushing the counter")?;
+        Ok(())
+    }
+}
+
+impl<L: KvClient, S: KvClient> LeaderCountingNode<L, S> {
+    fn forward(
+        &mut self,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+        leader: NodeId,
+        delta: u32,
+        responder: Responder,
+    ) -> Result<()> {
+        let forward = self.next_forward;
+        self.next_forward += 1;
+        self.forwarded.insert(forward, (delta, responder));
+        let store = self.store.clone();
+        ctx.rpc(
+            leader,
+            PeerRequest::Forward { delta },
+            move |this: &mut Self, response, ctx| {
+                let Some((delta, responder)) = this.forwarded.remove(&forward) else {
+                    return Ok(());
+                };
+                match response {
+                    PeerResponse::ForwardOk => responder.respond(ctx, Response::AddOk),
+                    PeerResponse::NotLeader => add(&store, ctx, delta, responder),
+                    PeerResponse::ForwardFailed { code, text } => {
+                        responder.respond(ctx, MaelstromError::from_code(code, text))
+                    }
+                }
+            },
+        )
+        .context("forwarding add to the leader")?;
+        ctx.after(FORWARD_TIMEOUT, move |this: &mut Self, ctx| {
+            match this.forwarded.remove(&forward) {
+                Some((_, responder)) => responder.respond(
+                    ctx,
+                    MaelstromError::Timeout("the leader did not answer in time".into()),
+                ),
+                None => Ok(()),
+            }
+        });
+        Ok(())
+    }
+}
+
+// Adds to the store directly, when there is no leader to forward to.
+fn add<N: Node>(
+    store: &impl KvClient,
+    ctx: &mut NodeContext<N, impl Read, impl Write>,
+    delta: u32,
+    responder: Responder,
+) -> Result<()> {
+    store.fetch_add(
+        ctx,
+        "counter",
+        delta,
+        Contention::default(),
+        true,
+        move |_, added, ctx| match added {
+            Ok(_) => responder.respond(ctx, Response::AddOk),
+            Err(error) => responder.respond(ctx, MaelstromError::from(error)),
+        },
+    )?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let socket = Socket::new(stdin, stdout);
+
+    LeaderCountingNode::run((LinKv::new(), SeqKv::new()), socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::{Arc, mpsc};
+    use std::thread;
+    use std::time::Instant;
+
+    use mael::runtime::Runtime;
+    use mael::{Loopback, LoopbackTransport, MockKv, RunConfig, Transport};
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    const NODES: usize = 3;
+
+    type Runtimes = Vec<Runtime<LeaderCountingNode<MockKv, MockKv>, io::Empty, io::Sink>>;
+
+    // Nodes sharing one store, which run in turns on the test thread since the store cannot
+    // leave it, and a client that talks to them.
+    struct Cluster {
+        network: Loopback,
+        client: Arc<LoopbackTransport>,
+        replies: mpsc::Receiver<Value>,
+        received: HashMap<u64, Value>,
+        nodes: Runtimes,
+        msg_id: u64,
+    }
+
+    impl Cluster {
+        fn start(lease: &MockKv, counter: &MockKv) -> Self {
+            let network = Loopback::new();
+            let client = Arc::new(network.transport("c1"));
+            let (sender, replies) = mpsc::channel();
+            {
+                let client = client.clone();
+                thread::spawn(move || {
+                    while let Ok(Some(frame)) = client.recv() {
+                        let reply: Value = serde_json::from_str(&frame).unwrap();
+                        if sender.send(reply).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            let mut cluster = Self {
+                client,
+                replies,
+                received: HashMap::new(),
+                nodes: Vec::new(),
+                msg_id: 0,
+                network,
+            };
+            let ids: Vec<String> = (1..=NODES).map(|node| format!("n{node}")).collect();
+            for id in &ids {
+                let socket = Socket::from_transport(cluster.network.transport(id.clone()));
+                cluster.send(
+                    id,
+                    json!({ "type": "init", "node_id": id, "node_ids": ids }),
+                );
+                let node = Runtime::new(
+                    (lease.clone(), counter.clone()),
+                    socket,
+                    RunConfig::default(),
+                );
+                cluster.nodes.push(node.unwrap());
+            }
+            cluster
+        }
+
+        fn send(&mut self, dest: &str, mut body: Value) -> u64 {
+            self.msg_id += 1;
+            body["msg_id"] = json!(self.msg_id);
+            let frame = json!({ "src": "c1", "dest": dest, "body": body });
+            self.client.send(&frame.to_string()).unwrap();
+            self.msg_id
+        }
+
+        // Lets every node run for a millisecond at a time until the condition holds.
+        fn run_until(&mut self, mut done: impl FnMut(&mut Self) -> bool) {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !done(self) {
+                assert!(Instant::now() < deadline, "the cluster did not get there");
+                for node in &mut self.nodes {
+                    node.run_for(Duration::from_millis(1)).unwrap();
+                }
+                for reply in self.replies.try_iter() {
+                    let in_reply_to = reply["body"]["in_reply_to"].as_u64().unwrap();
+                    self.received.insert(in_reply_to, reply["body"].clone());
+                }
+            }
+        }
+
+        // Sends every request to the node by its index and returns the replies in order.
+        fn requests(&mut self, requests: impl IntoIterator<Item = (usize, Value)>) -> Vec<Value> {
+            let msg_ids: Vec<u64> = requests
+                .into_iter()
+                .map(|(node, body)| self.send(&format!("n{}", node + 1), body))
+                .collect();
+            self.run_until(|cluster| {
+                msg_ids
+                    .iter()
+                    .all(|msg_id| cluster.received.contains_key(msg_id))
+            });
+            msg_ids
+                .iter()
+                .map(|msg_id| self.received.remove(msg_id).unwrap())
+                .collect()
+        }
+
+        // The index of the node all others agree leads, once there is one.
+        fn leader(&self) -> Option<usize> {
+            let leader = self
+                .nodes
+                .iter()
+                .position(|node| node.node().elector.is_leader())?;
+            let id = format!("n{}", leader + 1);
+            self.nodes
+                .iter()
+                .all(|node| {
+                    node.node()
+                        .elector
+                        .leader()
+                        .is_some_and(|known| known.as_str() == id)
+                })
+                .then_some(leader)
+        }
+    }
+
+    fn adds(deltas: std::ops::RangeInclusive<u64>) -> Vec<(usize, Value)> {
+        deltas
+            .map(|delta| {
+                let node = delta as usize % NODES;
+                (node, json!({ "type": "add", "delta": delta }))
+            })
+            .collect()
+    }
+
+    // Reads the counter on every node once all adds were acknowledged.
+    fn counts_every_add(cluster: &mut Cluster, kv: &MockKv) {
+        let replies = cluster.requests(adds(1..=10));
+        assert!(replies.iter().all(|reply| reply["type"] == "add_ok"));
+        let reads = cluster.requests((0..NODES).map(|node| (node, json!({ "type": "read" }))));
+        assert!(reads.iter().all(|read| read["value"] == 55));
+        assert_eq!(kv.get("counter"), Some(json!(55)));
+    }
+
+    #[test]
+    fn nodes_add_to_the_store_themselves_without_a_leader() {
+        let lease = MockKv::new().with_error_rate(1.0);
+        let kv = MockKv::new().with_latency(Duration::from_millis(1));
+        let mut cluster = Cluster::start(&lease, &kv);
+        counts_every_add(&mut cluster, &kv);
+        assert_eq!(cluster.leader(), None);
+    }
+
+    #[test]
+    fn followers_forward_adds_to_the_leader() {
+        let kv = MockKv::new().with_latency(Duration::from_millis(1));
+        let mut cluster = Cluster::start(&kv, &kv);
+        cluster.run_until(|cluster| cluster.leader().is_some());
+        let leader = cluster.leader();
+        counts_every_add(&mut cluster, &kv);
+        assert_eq!(cluster.leader(), leader);
+        // The leader answered every add that was forwarded to it.
+        assert!(
+            cluster
+                .nodes
+                .iter()
+                .all(|node| node.node().forwarded.is_empty())
+        );
+    }
+}
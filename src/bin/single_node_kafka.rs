@@ -1,10 +1,12 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
     io::{Read, Write},
 };
 
 use anyhow::Result;
-use mael::{Node, RequestInfo, Socket};
+use mael::{
+    EventIncjector, Init, MaelstromError, Never, Node, NodeContext, Reply, RequestInfo, Socket,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Default)]
@@ -16,10 +18,6 @@ struct Log {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde[tag = "type", rename_all = "snake_case"]]
 enum Request {
-    Init {
-        node_id: String,
-        node_ids: HashSet<String>,
-    },
     Send {
         #[serde(rename = "key")]
         log: String,
@@ -38,11 +36,10 @@ enum Request {
     },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 #[allow(clippy::enum_variant_names)]
 enum Response {
-    InitOk,
     SendOk {
         offset: usize,
     },
@@ -63,17 +60,29 @@ struct KafkaNode {
 
 impl Node for KafkaNode {
     type Request = Request;
-
     type Response = Response;
+    type PeerRequest = Never;
+    type PeerResponse = Never;
+    type InboundResponse = Never;
+    type Event = ();
+
+    type InitState = ();
+
+    fn from_init(
+        _init: Init,
+        _init_state: Self::InitState,
+        _event_injector: EventIncjector<Self>,
+    ) -> Self {
+        Self::default()
+    }
 
     fn handle_request(
         &mut self,
         request: Self::Request,
         _: RequestInfo,
-        _: &mut Socket<impl Read, impl Write>,
-    ) -> Result<Self::Response> {
+        _: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError> {
         Ok(match request {
-            Request::Init { .. } => Response::InitOk,
             Request::Send { log, message } => {
                 let log = self.logs.entry(log).or_default();
                 log.messages.push(message);
@@ -111,14 +120,15 @@ impl Node for KafkaNode {
                     .map(|log| (log.clone(), self.logs.entry(log).or_default().commit_offset))
                     .collect(),
             },
-        })
+        }
+        .into())
     }
 }
 
 fn main() -> Result<()> {
-    let stdin = std::io::stdin().lock();
-    let stdout = std::io::stdout().lock();
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
     let socket = Socket::new(stdin, stdout);
 
-    KafkaNode::default().run(socket)
+    KafkaNode::run((), socket)
 }
@@ -1,27 +1,21 @@
-use std::{
-    collections::HashSet,
-    io::{Read, Write},
-};
+use std::io::{Read, Write};
 
 use anyhow::Result;
-use mael::{Node, RequestInfo, Socket};
+use mael::{
+    EventIncjector, Init, MaelstromError, Never, Node, NodeContext, Reply, RequestInfo, Socket,
+};
 use serde::{Deserialize, Serialize};
 use ulid::Ulid;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde[tag = "type", rename_all = "snake_case"]]
 enum Request {
-    Init {
-        node_id: String,
-        node_ids: HashSet<String>,
-    },
     Generate,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum Response {
-    InitOk,
     GenerateOk { id: Ulid },
 }
 
@@ -29,26 +23,39 @@ struct UniqueIdNode;
 
 impl Node for UniqueIdNode {
     type Request = Request;
-
     type Response = Response;
+    type PeerRequest = Never;
+    type PeerResponse = Never;
+    type InboundResponse = Never;
+    type Event = ();
+
+    type InitState = ();
+
+    fn from_init(
+        _init: Init,
+        _init_state: Self::InitState,
+        _event_injector: EventIncjector<Self>,
+    ) -> Self {
+        Self
+    }
 
     fn handle_request(
         &mut self,
         request: Self::Request,
         _: RequestInfo,
-        _: &mut Socket<impl Read, impl Write>,
-    ) -> Result<Self::Response> {
+        _: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError> {
         Ok(match request {
-            Request::Init { .. } => Response::InitOk,
             Request::Generate => Response::GenerateOk { id: Ulid::new() },
-        })
+        }
+        .into())
     }
 }
 
 fn main() -> Result<()> {
-    let stdin = std::io::stdin().lock();
-    let stdout = std::io::stdout().lock();
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
     let socket = Socket::new(stdin, stdout);
 
-    UniqueIdNode.run(socket)
+    UniqueIdNode::run((), socket)
 }
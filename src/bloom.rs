@@ -0,0 +1,106 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+// A set that can only tell that an element is certainly not in it, at a fraction of the size of
+// the elements. Hashes do not change between processes, so a filter built on one node can be
+// checked on another.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    // Words of 32 bits, which every JSON parser on the way reads back exactly.
+    bits: Vec<u32>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    // Sized so that `false_positive_rate` of the elements that are not in it seem to be, once
+    // it holds `expected` elements.
+    pub fn new(expected: usize, false_positive_rate: f64) -> Self {
+        let expected = expected.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let bits = (-expected * rate.ln() / std::f64::consts::LN_2.powi(2)).ceil();
+        let hashes = (bits / expected * std::f64::consts::LN_2).round().max(1.0);
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(32).max(1)],
+            hashes: hashes as u32,
+        }
+    }
+
+    pub fn insert(&mut self, element: &impl Hash) {
+        for bit in bits_of(element, self.hashes, self.bits.len()) {
+            self.bits[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+
+    // `false` only for elements that were never inserted.
+    pub fn contains(&self, element: &impl Hash) -> bool {
+        !self.bits.is_empty()
+            && bits_of(element, self.hashes, self.bits.len())
+                .all(|bit| self.bits[bit / 32] & (1 << (bit % 32)) != 0)
+    }
+}
+
+impl<T: Hash> Extend<T> for BloomFilter {
+    fn extend<E: IntoIterator<Item = T>>(&mut self, elements: E) {
+        for element in elements {
+            self.insert(&element);
+        }
+    }
+}
+
+// Tells sets apart without sending them, equal sets have equal checksums no matter the order.
+pub(crate) fn checksum<'a, T: Hash + 'a>(elements: impl IntoIterator<Item = &'a T>) -> u64 {
+    elements
+        .into_iter()
+        .fold(0, |checksum, element| checksum ^ hash(element, 2))
+}
+
+fn hash(element: &impl Hash, seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    element.hash(&mut hasher);
+    hasher.finish()
+}
+
+// The bits of an element in a filter of `words` words, from two hashes combined like Kirsch and
+// Mitzenmacher do.
+fn bits_of(element: &impl Hash, hashes: u32, words: usize) -> impl Iterator<Item = usize> {
+    let len = (words as u64 * 32).max(1);
+    let first = hash(element, 0);
+    let second = hash(element, 1) | 1;
+    (0..u64::from(hashes)).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_every_inserted_element_and_few_others() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        filter.extend(0..1000u64);
+        assert!((0..1000u64).all(|element| filter.contains(&element)));
+        let false_positives = (1000..11_000u64)
+            .filter(|element| filter.contains(element))
+            .count();
+        // About a hundred are expected.
+        assert!(false_positives < 200, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn filters_built_apart_agree() {
+        let mut first = BloomFilter::new(10, 0.01);
+        first.extend(["a", "b", "c"]);
+        let mut second = BloomFilter::new(10, 0.01);
+        second.extend(["c", "a", "b"]);
+        assert_eq!(first, second);
+        let sent: BloomFilter =
+            serde_json::from_str(&serde_json::to_string(&first).unwrap()).unwrap();
+        assert_eq!(sent, first);
+
+        assert_eq!(checksum(&[1, 2, 3]), checksum(&[3, 1, 2]));
+        assert_ne!(checksum(&[1, 2, 3]), checksum(&[1, 2]));
+        assert!(!BloomFilter::new(0, 0.01).contains(&"a"));
+    }
+}
@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::ops::Add;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::kv::{Contention, KvClient, KvError, SeqKv};
+use crate::{Node, NodeContext};
+
+#[derive(Debug, Default)]
+struct Buffer<T> {
+    delta: T,
+    // Adds in `delta`, a flush is due once there are `threshold` of them.
+    adds: usize,
+    // Whether a flush is set to run once the interval is over.
+    scheduled: bool,
+}
+
+// Gathers the deltas of a counter locally and adds them to the store in one go, every
+// `interval` or once `threshold` adds are waiting, whichever comes first. Reads of the store
+// miss up to an interval's worth of adds, unless the buffer is flushed right before them with
+// `force_flush`.
+//
+// Clones share the buffer.
+#[derive(Debug, Clone)]
+pub struct BufferedCounter<T, K = SeqKv> {
+    client: K,
+    key: String,
+    interval: Duration,
+    threshold: usize,
+    buffer: Rc<RefCell<Buffer<T>>>,
+}
+
+impl<T, K> BufferedCounter<T, K>
+where
+    T: Add<Output = T> + Default + Copy + Serialize + DeserializeOwned + 'static,
+    K: KvClient,
+{
+    pub fn new(client: K, key: impl Into<String>) -> Self {
+        Self {
+            client,
+            key: key.into(),
+            interval: Duration::from_millis(100),
+            threshold: usize::MAX,
+            buffer: Rc::default(),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    // How many adds may wait before they are flushed right away.
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold.max(1);
+        self
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // The deltas that did not make it to the store yet, not counting flushes under way.
+    pub fn pending(&self) -> T {
+        self.buffer.borrow().delta
+    }
+
+    pub fn add<N, I, O>(&self, ctx: &mut NodeContext<N, I, O>, delta: T) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.delta = buffer.delta + delta;
+        buffer.adds += 1;
+        let full = buffer.adds >= self.threshold;
+        drop(buffer);
+        if full {
+            return self.flush(ctx, |_, _, _| Ok(()));
+        }
+        self.schedule(ctx);
+        Ok(())
+    }
+
+    // Flushes the buffer now instead of at the end of the interval, the callback runs once the
+    // deltas up to here are in the store. Flushes that are already under way may still land
+    // after it.
+    pub fn force_flush<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        self.flush(ctx, callback)
+    }
+
+    // Flushes the buffer and reads the counter after, so the read sees the adds of this node.
+    pub fn read<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(&mut N, Result<T, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        let counter = self.clone();
+        self.flush(ctx, move |node, flushed, ctx| {
+            if let Err(error) = flushed {
+                return callback(node, Err(error), ctx);
+            }
+            let sent = counter
+                .client
+                .read(ctx, &counter.key, move |node, value, ctx| {
+                    callback(node, value.map(Option::unwrap_or_default), ctx)
+                });
+            Ok(sent?)
+        })
+    }
+
+    fn schedule<N, I, O>(&self, ctx: &mut NodeContext<N, I, O>)
+    where
+        N: Node,
+        O: Write,
+    {
+        let mut buffer = self.buffer.borrow_mut();
+        if buffer.scheduled {
+            return;
+        }
+        buffer.scheduled = true;
+        let counter = self.clone();
+        ctx.after(self.interval, move |_, ctx| {
+            counter.buffer.borrow_mut().scheduled = false;
+            // The deltas are back in the buffer, for the next interval.
+            if counter.flush(ctx, |_, _, _| Ok(())).is_err() {
+                counter.schedule(ctx);
+            }
+            Ok(())
+        });
+    }
+
+    fn flush<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        let (delta, adds) = {
+            let mut buffer = self.buffer.borrow_mut();
+            (
+                std::mem::take(&mut buffer.delta),
+                std::mem::take(&mut buffer.adds),
+            )
+        };
+        if adds == 0 {
+            ctx.after(Duration::ZERO, move |node, ctx| callback(node, Ok(()), ctx));
+            return Ok(());
+        }
+        let counter = self.clone();
+        let sent = self.client.fetch_add(
+            ctx,
+            &self.key,
+            delta,
+            Contention::default(),
+            true,
+            move |node, added, ctx| {
+                let added = added.map(|_| ());
+                if let Err(error) = &added
+                    && error.is_definite()
+                {
+                    counter.restore(delta, adds);
+                    counter.schedule(ctx);
+                }
+                callback(node, added, ctx)
+            },
+        );
+        if sent.is_err() {
+            self.restore(delta, adds);
+        }
+        sent
+    }
+
+    // Puts the deltas of a failed flush back for the next one. Deltas of a flush that may have
+    // gone through are dropped instead, counting them twice is no better than losing them.
+    fn restore(&self, delta: T, adds: usize) {
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.delta = buffer.delta + delta;
+        buffer.adds += adds;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::Instant;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    // Adds what it is told to and reads the counter when asked.
+    struct Adder {
+        counter: BufferedCounter<u64, MockKv>,
+        adds: Vec<u64>,
+        read: bool,
+        reads: Vec<u64>,
+    }
+
+    impl Scenario for Adder {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.tick(ctx)
+        }
+
+        fn tick(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            for delta in self.adds.drain(..) {
+                self.counter.add(ctx, delta)?;
+            }
+            if std::mem::take(&mut self.read) {
+                self.counter
+                    .read(ctx, |node: &mut Harness<Self>, value, _| {
+                        node.scenario.reads.push(value?);
+                        Ok(())
+                    })?;
+            }
+            Ok(())
+        }
+    }
+
+    fn adder(counter: BufferedCounter<u64, MockKv>, adds: &[u64]) -> Cluster<Adder> {
+        Cluster::new([Adder {
+            counter,
+            adds: adds.to_vec(),
+            read: false,
+            reads: Vec::new(),
+        }])
+    }
+
+    #[test]
+    fn flushes_every_interval_and_before_reads() {
+        let kv = MockKv::new();
+        let interval = Duration::from_millis(50);
+        let counter = BufferedCounter::new(kv.clone(), "counter").with_interval(interval);
+        let started = Instant::now();
+        let mut cluster = adder(counter, &[1, 2, 3]);
+        cluster.run_until(|cluster| cluster.scenario(0).counter.pending() == 6);
+        cluster.run_until(|_| kv.get("counter") == Some(json!(6)));
+        assert!(started.elapsed() >= interval);
+        assert_eq!(cluster.scenario(0).counter.pending(), 0);
+
+        let adder = cluster.scenario_mut(0);
+        adder.adds.push(4);
+        adder.read = true;
+        cluster.run_until(|cluster| !cluster.scenario(0).reads.is_empty());
+        assert_eq!(cluster.scenario(0).reads, [10]);
+    }
+
+    #[test]
+    fn flushes_once_the_threshold_is_reached() {
+        let kv = MockKv::new();
+        let counter = BufferedCounter::new(kv.clone(), "counter")
+            .with_interval(Duration::from_secs(60))
+            .with_threshold(3);
+        let mut cluster = adder(counter, &[1, 2, 3, 4]);
+        cluster.run_until(|_| kv.get("counter") == Some(json!(6)));
+        assert_eq!(cluster.scenario(0).counter.pending(), 4);
+    }
+
+    #[test]
+    fn keeps_the_deltas_of_failed_flushes() {
+        let kv = MockKv::new().with_error_rate(1.0);
+        let interval = Duration::from_millis(5);
+        let counter = BufferedCounter::new(kv.clone(), "counter").with_interval(interval);
+        let mut cluster = adder(counter, &[5]);
+        cluster.run_for(interval * 10);
+        assert_eq!(cluster.scenario(0).counter.pending(), 5);
+        assert_eq!(kv.get("counter"), None);
+    }
+}
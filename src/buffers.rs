@@ -0,0 +1,32 @@
+use std::sync::{Arc, Mutex};
+
+// How many buffers are kept around, more are only needed while many threads send at once.
+const POOLED: usize = 8;
+// Buffers that grew beyond this are dropped instead of keeping the memory around.
+const MAX_CAPACITY: usize = 64 * 1024;
+
+// Buffers that frames are serialized into, handed back once they are written so sending does not
+// allocate for every message.
+#[derive(Clone, Default)]
+pub(crate) struct Buffers(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl Buffers {
+    pub(crate) fn take(&self) -> Vec<u8> {
+        self.0
+            .lock()
+            .expect("failed to lock buffers")
+            .pop()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > MAX_CAPACITY {
+            return;
+        }
+        buffer.clear();
+        let mut buffers = self.0.lock().expect("failed to lock buffers");
+        if buffers.len() < POOLED {
+            buffers.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::kv::{CasResponse, KvClient, KvError, parse};
+use crate::service::ServiceClient;
+use crate::{Node, NodeContext};
+
+#[derive(Debug, Default)]
+struct Cache {
+    // `None` for keys that are known not to exist.
+    entries: HashMap<String, (Option<Value>, Instant)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Cache {
+    fn fresh(&mut self, key: &str, ttl: Duration) -> Option<Option<Value>> {
+        match self.entries.get(key) {
+            Some((value, at)) if at.elapsed() < ttl => {
+                self.hits += 1;
+                Some(value.clone())
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: String, value: Option<Value>) {
+        self.entries.insert(key, (value, Instant::now()));
+    }
+}
+
+// Serves reads from what this node read or wrote less than `ttl` ago, everything else goes to the
+// store. Writes and compare-and-sets go through to the store and update the cache once they
+// succeed, a lost compare-and-set drops the key since someone else wrote it.
+//
+// Clones share the cache, so a node keeps one client around instead of making one per request.
+// Writes of other nodes only show once an entry expires or is invalidated.
+#[derive(Debug, Clone)]
+pub struct CachedKv<K> {
+    inner: K,
+    ttl: Duration,
+    cache: Rc<RefCell<Cache>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / reads as f64
+    }
+}
+
+impl<K: KvClient> CachedKv<K> {
+    pub fn new(inner: K, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Rc::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &K {
+        &self.inner
+    }
+
+    // The next read of the key goes to the store.
+    pub fn invalidate(&self, key: &str) {
+        self.cache.borrow_mut().entries.remove(key);
+    }
+
+    pub fn clear(&self) {
+        self.cache.borrow_mut().entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let cache = self.cache.borrow();
+        CacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            entries: cache.entries.len(),
+        }
+    }
+}
+
+impl<K: KvClient> KvClient for CachedKv<K> {
+    fn service(&self) -> &ServiceClient {
+        self.inner.service()
+    }
+
+    fn read<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: DeserializeOwned,
+        N: Node,
+        O: Write,
+    {
+        let cached = self.cache.borrow_mut().fresh(key, self.ttl);
+        if let Some(value) = cached {
+            // Still answered on the main loop, like a reply of the store.
+            ctx.after(Duration::ZERO, move |node, ctx| {
+                callback(node, parse(value), ctx)
+            });
+            return Ok(());
+        }
+        let cache = self.cache.clone();
+        let cached = key.to_string();
+        self.inner.read(
+            ctx,
+            key,
+            move |node, value: Result<Option<Value>, KvError>, ctx| {
+                let value = value.inspect(|value| {
+                    cache.borrow_mut().insert(cached, value.clone());
+                });
+                callback(node, value.and_then(parse), ctx)
+            },
+        )
+    }
+
+    fn write<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        value: &T,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let value = serde_json::to_value(value).context("serializing value")?;
+        let cache = self.cache.clone();
+        let cached = key.to_string();
+        let written = value.clone();
+        self.inner
+            .write(ctx, key, &value, move |node, result, ctx| {
+                match &result {
+                    Ok(()) => cache.borrow_mut().insert(cached, Some(written)),
+                    // It may or may not have been written.
+                    Err(_) => {
+                        cache.borrow_mut().entries.remove(&cached);
+                    }
+                }
+                callback(node, result, ctx)
+            })
+    }
+
+    fn compare_and_set<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+        callback: impl FnOnce(
+            &mut N,
+            Result<CasResponse, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let from = serde_json::to_value(from).context("serializing value")?;
+        let to = serde_json::to_value(to).context("serializing value")?;
+        let cache = self.cache.clone();
+        let cached = key.to_string();
+        let written = to.clone();
+        self.inner.compare_and_set(
+            ctx,
+            key,
+            &from,
+            &to,
+            create_if_not_exists,
+            move |node, result, ctx| {
+                match &result {
+                    Ok(CasResponse::Ok) => cache.borrow_mut().insert(cached, Some(written)),
+                    Ok(CasResponse::Retry) | Err(_) => {
+                        cache.borrow_mut().entries.remove(&cached);
+                    }
+                }
+                callback(node, result, ctx)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::Read;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    enum Op {
+        Read,
+        Write(u64),
+        // Another node writes to the store.
+        Behind(u64),
+        Invalidate,
+        Wait(Duration),
+    }
+
+    // Goes through the operations on one key one at a time, keeping what the reads returned.
+    struct Caching {
+        store: MockKv,
+        kv: CachedKv<MockKv>,
+        ops: VecDeque<Op>,
+        busy: bool,
+        waiting: Option<Instant>,
+        reads: Vec<Option<u64>>,
+    }
+
+    impl Scenario for Caching {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.tick(ctx)
+        }
+
+        fn tick(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            if self.busy || self.waiting.is_some_and(|until| Instant::now() < until) {
+                return Ok(());
+            }
+            let Some(op) = self.ops.pop_front() else {
+                return Ok(());
+            };
+            match op {
+                Op::Read => {
+                    self.busy = true;
+                    self.kv
+                        .read(ctx, "k", |node: &mut Harness<Self>, read, _| {
+                            node.scenario.busy = false;
+                            node.scenario.reads.push(read?);
+                            Ok(())
+                        })?;
+                }
+                Op::Write(value) => {
+                    self.busy = true;
+                    self.kv
+                        .write(ctx, "k", &value, |node: &mut Harness<Self>, written, _| {
+                            node.scenario.busy = false;
+                            Ok(written?)
+                        })?;
+                }
+                Op::Behind(value) => self.store.insert("k", json!(value)),
+                Op::Invalidate => self.kv.invalidate("k"),
+                Op::Wait(duration) => self.waiting = Some(Instant::now() + duration),
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn serves_fresh_reads_and_own_writes_from_the_cache() {
+        let ttl = Duration::from_millis(50);
+        let store = MockKv::new();
+        store.insert("k", json!(1));
+        let ops = [
+            Op::Read,
+            Op::Behind(2),
+            Op::Read,
+            Op::Write(3),
+            Op::Read,
+            Op::Behind(4),
+            Op::Invalidate,
+            Op::Read,
+            Op::Behind(5),
+            Op::Wait(ttl),
+            Op::Read,
+        ];
+        let mut cluster = Cluster::new([Caching {
+            store: store.clone(),
+            kv: CachedKv::new(store.clone(), ttl),
+            ops: ops.into(),
+            busy: false,
+            waiting: None,
+            reads: Vec::new(),
+        }]);
+        cluster.run_until(|cluster| {
+            let caching = cluster.scenario(0);
+            caching.ops.is_empty() && !caching.busy
+        });
+
+        let caching = cluster.scenario(0);
+        // The write of another node only shows once the entry is gone.
+        assert_eq!(caching.reads, [Some(1), Some(1), Some(3), Some(4), Some(5)]);
+        assert_eq!(store.get("k"), Some(json!(5)));
+        let stats = caching.kv.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 3, 1));
+        assert_eq!(stats.hit_rate(), 0.4);
+    }
+}
@@ -0,0 +1,195 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+
+use crate::transport::Transport;
+
+// Writes every frame that passes the wrapped transport to a file, one per line behind the
+// microseconds since the capture started and `<` for received or `>` for sent frames.
+pub struct Capture<T> {
+    inner: T,
+    file: Mutex<File>,
+    start: Instant,
+}
+
+impl<T: Transport> Capture<T> {
+    pub fn new(inner: T, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("creating capture file {}", path.display()))?;
+        Ok(Self {
+            inner,
+            file: Mutex::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&self, direction: char, frame: &str) -> Result<()> {
+        let line = format!("{} {direction} {frame}\n", self.start.elapsed().as_micros());
+        // Written at once, the capture holds everything up to a crash.
+        self.file
+            .lock()
+            .expect("failed to lock capture file")
+            .write_all(line.as_bytes())
+            .context("writing capture file")
+    }
+}
+
+impl<T: Transport> Transport for Capture<T> {
+    fn send(&self, frame: &str) -> Result<()> {
+        self.record('>', frame)?;
+        self.inner.send(frame)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn recv(&self) -> Result<Option<String>> {
+        let frame = self.inner.recv()?;
+        if let Some(frame) = &frame {
+            self.record('<', frame)?;
+        }
+        Ok(frame)
+    }
+}
+
+// Hands the received frames of a capture to a node again, what the node sends goes to stderr so
+// it can be compared with the sent frames of the capture.
+pub struct ReplayTransport {
+    frames: Mutex<Replay>,
+    paced: bool,
+}
+
+struct Replay {
+    lines: std::io::Lines<BufReader<File>>,
+    start: Option<Instant>,
+}
+
+impl ReplayTransport {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("opening capture file {}", path.display()))?;
+        Ok(Self {
+            frames: Mutex::new(Replay {
+                lines: BufReader::new(file).lines(),
+                start: None,
+            }),
+            paced: false,
+        })
+    }
+
+    // Waits until frames are due as recorded instead of handing them over at once, for nodes
+    // whose behaviour depends on timers.
+    pub fn paced(mut self) -> Self {
+        self.paced = true;
+        self
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, frame: &str) -> Result<()> {
+        eprintln!("> {frame}");
+        Ok(())
+    }
+
+    fn recv(&self) -> Result<Option<String>> {
+        let mut replay = self.frames.lock().expect("failed to lock replay");
+        let start = *replay.start.get_or_insert_with(Instant::now);
+        for line in replay.lines.by_ref() {
+            let line = line.context("reading capture file")?;
+            let (at, direction, frame) = parse_line(&line)?;
+            if direction != '<' {
+                continue;
+            }
+            if self.paced {
+                std::thread::sleep((start + at).saturating_duration_since(Instant::now()));
+            }
+            return Ok(Some(frame.to_string()));
+        }
+        Ok(None)
+    }
+}
+
+fn parse_line(line: &str) -> Result<(Duration, char, &str)> {
+    let mut parts = line.splitn(3, ' ');
+    let (Some(at), Some(direction), Some(frame)) = (parts.next(), parts.next(), parts.next())
+    else {
+        bail!("capture line {line:?} is incomplete");
+    };
+    let at = at
+        .parse()
+        .map(Duration::from_micros)
+        .with_context(|| format!("parsing timestamp of capture line {line:?}"))?;
+    let direction = match direction {
+        "<" => '<',
+        ">" => '>',
+        _ => bail!("unknown direction {direction:?} in capture line {line:?}"),
+    };
+    Ok((at, direction, frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::Loopback;
+
+    #[test]
+    fn replays_the_received_frames_of_a_capture() {
+        let path = std::env::temp_dir().join(format!("mael-{}-capture.log", std::process::id()));
+        let network = Loopback::new();
+        let client = network.transport("c1");
+        let captured = Capture::new(network.transport("n1"), &path).unwrap();
+        let frames: Vec<String> = (1..=3)
+            .map(|msg_id| {
+                let body = json!({ "type": "echo", "msg_id": msg_id });
+                json!({ "src": "c1", "dest": "n1", "body": body }).to_string()
+            })
+            .collect();
+        let answer = json!({ "src": "n1", "dest": "c1", "body": { "type": "echo_ok" } });
+
+        client.send(&frames[0]).unwrap();
+        assert_eq!(captured.recv().unwrap().as_ref(), Some(&frames[0]));
+        captured.send(&answer.to_string()).unwrap();
+        for frame in &frames[1..] {
+            client.send(frame).unwrap();
+            captured.recv().unwrap();
+        }
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let directions: Vec<char> = lines
+            .lines()
+            .map(|line| parse_line(line).unwrap().1)
+            .collect();
+        assert_eq!(directions, ['<', '>', '<', '<']);
+
+        // Only what the node received is replayed.
+        let replay = ReplayTransport::open(&path).unwrap();
+        let replayed: Vec<String> = iter::from_fn(|| replay.recv().unwrap()).collect();
+        assert_eq!(replayed, frames);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn paced_replay_waits_until_frames_are_due() {
+        let path = std::env::temp_dir().join(format!("mael-{}-paced.log", std::process::id()));
+        std::fs::write(&path, "0 < first\n50000 > sent\n80000 < second\n").unwrap();
+        let replay = ReplayTransport::open(&path).unwrap().paced();
+
+        assert_eq!(replay.recv().unwrap().as_deref(), Some("first"));
+        let start = Instant::now();
+        assert_eq!(replay.recv().unwrap().as_deref(), Some("second"));
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert_eq!(replay.recv().unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::NodeId;
+
+// How many messages of every node happened before, nodes without an entry sent none.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<NodeId, u64>);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    // Counts another message of the node and returns its count.
+    pub fn increment(&mut self, node: &str) -> u64 {
+        let count = self.0.entry(NodeId::new(node)).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    // The larger count of every node.
+    pub fn merge(&mut self, other: &Self) {
+        for (node, count) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_insert(0);
+            *entry = (*entry).max(*count);
+        }
+    }
+
+    // `Less` when this clock happened before the other, `None` when they are concurrent.
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        let nodes = self.0.keys().chain(other.0.keys());
+        let mut ordering = Ordering::Equal;
+        for node in nodes {
+            match (ordering, self.get(node).cmp(&other.get(node))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, next) => ordering = next,
+                (current, next) if current != next => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&NodeId, u64)> {
+        self.0.iter().map(|(node, count)| (node, *count))
+    }
+}
+
+// A message with the clock of its sender when it was sent, its own count included.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamped<T> {
+    pub sender: NodeId,
+    pub clock: VectorClock,
+    pub payload: T,
+}
+
+// Delivers messages in causal order: a message is only handed on once everything its sender had
+// delivered before sending it was delivered here too. Messages that arrive early wait in a
+// buffer, and messages that were delivered already are dropped, so they can be sent again or
+// arrive along several paths.
+#[derive(Debug)]
+pub struct CausalOrder<T> {
+    node: NodeId,
+    delivered: VectorClock,
+    // By sender and count.
+    buffered: BTreeMap<NodeId, BTreeMap<u64, Stamped<T>>>,
+}
+
+impl<T> CausalOrder<T> {
+    pub fn new(node: impl Into<NodeId>) -> Self {
+        Self {
+            node: node.into(),
+            delivered: VectorClock::new(),
+            buffered: BTreeMap::new(),
+        }
+    }
+
+    // Stamps a message of this node to send to the others. It counts as delivered here right
+    // away, so later messages depend on it.
+    pub fn stamp(&mut self, payload: T) -> Stamped<T> {
+        self.delivered.increment(&self.node);
+        Stamped {
+            sender: self.node.clone(),
+            clock: self.delivered.clone(),
+            payload,
+        }
+    }
+
+    // The payloads that can be delivered now that the message arrived, in causal order. Empty
+    // when the message waits for others or was delivered already.
+    pub fn receive(&mut self, message: Stamped<T>) -> Vec<T> {
+        let count = message.clock.get(&message.sender);
+        if count <= self.delivered.get(&message.sender) {
+            return Vec::new();
+        }
+        self.buffered
+            .entry(message.sender.clone())
+            .or_default()
+            .insert(count, message);
+        let mut delivered = Vec::new();
+        // Every delivery can make other buffered messages deliverable, but only the next message
+        // of every sender.
+        while let Some(sender) = self.buffered.iter().find_map(|(sender, messages)| {
+            let next = messages.get(&(self.delivered.get(sender) + 1))?;
+            self.deliverable(next).then(|| sender.clone())
+        }) {
+            let count = self.delivered.increment(&sender);
+            let messages = self.buffered.get_mut(&sender).expect("found in the buffer");
+            let message = messages.remove(&count).expect("found in the buffer");
+            if messages.is_empty() {
+                self.buffered.remove(&sender);
+            }
+            delivered.push(message.payload);
+        }
+        delivered
+    }
+
+    // Everything delivered here, as a clock.
+    pub fn delivered(&self) -> &VectorClock {
+        &self.delivered
+    }
+
+    // The messages waiting for others to be delivered first.
+    pub fn buffered(&self) -> impl Iterator<Item = &Stamped<T>> {
+        self.buffered.values().flat_map(BTreeMap::values)
+    }
+
+    // The next message of its sender, and nothing from others that was not delivered here yet.
+    fn deliverable(&self, message: &Stamped<T>) -> bool {
+        message.clock.iter().all(|(node, count)| {
+            if *node == message.sender {
+                count == self.delivered.get(node) + 1
+            } else {
+                count <= self.delivered.get(node)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(counts: &[(&str, u64)]) -> VectorClock {
+        let mut clock = VectorClock::new();
+        for (node, count) in counts {
+            for _ in 0..*count {
+                clock.increment(node);
+            }
+        }
+        clock
+    }
+
+    #[test]
+    fn compares_clocks() {
+        let a = clock(&[("n1", 1)]);
+        let b = clock(&[("n1", 1), ("n2", 1)]);
+        let c = clock(&[("n1", 2)]);
+        assert_eq!(a.compare(&a), Some(Ordering::Equal));
+        assert_eq!(a.compare(&b), Some(Ordering::Less));
+        assert_eq!(b.compare(&a), Some(Ordering::Greater));
+        assert_eq!(b.compare(&c), None);
+        assert_eq!(c.compare(&b), None);
+        let mut merged = b.clone();
+        merged.merge(&c);
+        assert_eq!(merged, clock(&[("n1", 2), ("n2", 1)]));
+    }
+
+    #[test]
+    fn delivers_in_causal_order() {
+        let mut n1 = CausalOrder::new("n1");
+        let mut n2 = CausalOrder::new("n2");
+        let mut n3 = CausalOrder::new("n3");
+        let first = n1.stamp("first");
+        let second = n1.stamp("second");
+        assert_eq!(n2.receive(first.clone()), ["first"]);
+        // Sent after n2 delivered the first message of n1.
+        let answer = n2.stamp("answer");
+
+        assert!(n3.receive(answer.clone()).is_empty());
+        assert!(n3.receive(second.clone()).is_empty());
+        assert_eq!(n3.buffered().count(), 2);
+        assert_eq!(n3.receive(first.clone()), ["first", "second", "answer"]);
+        assert_eq!(n3.buffered().count(), 0);
+        assert_eq!(n3.delivered(), &clock(&[("n1", 2), ("n2", 1)]));
+
+        // Duplicates are dropped, delivered or still buffered.
+        assert!(n3.receive(first).is_empty());
+        assert!(n3.receive(answer).is_empty());
+        let third = n1.stamp("third");
+        let fourth = n1.stamp("fourth");
+        assert!(n3.receive(fourth.clone()).is_empty());
+        assert!(n3.receive(fourth).is_empty());
+        assert_eq!(n3.buffered().count(), 1);
+        assert_eq!(n3.receive(third), ["third", "fourth"]);
+    }
+
+    #[test]
+    fn delivers_concurrent_messages_as_they_arrive() {
+        let mut n1 = CausalOrder::new("n1");
+        let mut n2 = CausalOrder::new("n2");
+        let mut n3 = CausalOrder::new("n3");
+        let from_n1 = n1.stamp(1);
+        let from_n2 = n2.stamp(2);
+        assert_eq!(from_n1.clock.compare(&from_n2.clock), None);
+        assert_eq!(n3.receive(from_n2), [2]);
+        assert_eq!(n3.receive(from_n1), [1]);
+    }
+}
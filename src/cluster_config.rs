@@ -0,0 +1,208 @@
+use std::io::Write;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::kv::{Contention, KvClient, KvError, LinKv, Watch};
+use crate::{Node, NodeContext};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    // Starts at 1 and grows with every change.
+    pub version: u64,
+    pub config: T,
+}
+
+// Settings shared by the whole cluster, like a fanout or a batch size, kept as one value in the
+// store so every node can change them while the cluster runs.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig<T, K = LinKv> {
+    client: K,
+    key: String,
+    config: PhantomData<fn() -> T>,
+}
+
+impl<T, K> ClusterConfig<T, K>
+where
+    T: Serialize + DeserializeOwned + Clone + 'static,
+    K: KvClient,
+{
+    pub fn new(client: K, key: impl Into<String>) -> Self {
+        Self {
+            client,
+            key: key.into(),
+            config: PhantomData,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // `None` until the config was set for the first time.
+    pub fn get<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<Versioned<T>>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        self.client.read(ctx, &self.key, callback)
+    }
+
+    // Replaces the config whatever it was, the callback gets the version that was written.
+    pub fn set<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        config: T,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Versioned<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        self.update(ctx, move |_| Ok(config.clone()), callback)
+    }
+
+    // Changes the config based on the current one, starting over when another node changed it
+    // in the meantime.
+    pub fn update<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        mut update: impl FnMut(Option<T>) -> Result<T> + 'static,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Versioned<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        self.client.update(
+            ctx,
+            &self.key,
+            Contention::default(),
+            move |current: Option<Versioned<T>>| {
+                let version = current.as_ref().map_or(1, |current| current.version + 1);
+                let config = update(current.map(|current| current.config))?;
+                Ok(Versioned { version, config })
+            },
+            callback,
+        )
+    }
+
+    // Hands every new version of the config to the callback, reading it every `interval`.
+    pub fn watch<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        interval: Duration,
+        callback: impl FnMut(
+            &mut N,
+            Result<Option<Versioned<T>>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Watch
+    where
+        N: Node,
+        O: Write,
+    {
+        self.client.watch(ctx, &self.key, interval, callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    // Raises the fanout of the cluster by one and watches every version of the config.
+    struct Member {
+        config: ClusterConfig<u64, MockKv>,
+        updated: Option<Versioned<u64>>,
+        seen: Vec<Versioned<u64>>,
+    }
+
+    impl Scenario for Member {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.config.watch(
+                ctx,
+                Duration::from_millis(5),
+                |node: &mut Harness<Self>, config, _| {
+                    node.scenario.seen.extend(config?);
+                    Ok(())
+                },
+            );
+            self.config.update(
+                ctx,
+                |fanout| Ok(fanout.unwrap_or_default() + 1),
+                |node: &mut Harness<Self>, updated, _| {
+                    node.scenario.updated = Some(updated?);
+                    Ok(())
+                },
+            )?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_node_sees_the_versions_of_concurrent_updates() {
+        let kv = MockKv::new().with_latency(Duration::from_millis(1));
+        let mut cluster = Cluster::new((0..3).map(|_| Member {
+            config: ClusterConfig::new(kv.clone(), "config"),
+            updated: None,
+            seen: Vec::new(),
+        }));
+        let last = Versioned {
+            version: 3,
+            config: 3,
+        };
+        cluster.run_until(|cluster| {
+            (0..3).all(|node| cluster.scenario(node).seen.last() == Some(&last))
+        });
+
+        // Every update went through once, on top of the one before.
+        let mut updated: Vec<_> = (0..3)
+            .map(|node| cluster.scenario(node).updated.clone().unwrap())
+            .collect();
+        updated.sort_by_key(|updated| updated.version);
+        assert!(
+            updated
+                .iter()
+                .zip(1..)
+                .all(|(updated, version)| updated.version == version && updated.config == version)
+        );
+        for node in 0..3 {
+            let seen = &cluster.scenario(node).seen;
+            assert!(
+                seen.windows(2)
+                    .all(|seen| seen[0].version < seen[1].version)
+            );
+        }
+    }
+}
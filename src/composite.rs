@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::kv::{Contention, KvClient, KvError, LinKv};
+use crate::{Node, NodeContext};
+
+// The entries kept under a composite key, as one JSON object.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Entries(BTreeMap<String, Value>);
+
+impl Entries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, entry: &str) -> Result<Option<T>, KvError> {
+        let Some(value) = self.0.get(entry) else {
+            return Ok(None);
+        };
+        let value = serde_json::from_value(value.clone())
+            .with_context(|| format!("parsing entry {entry}"))?;
+        Ok(Some(value))
+    }
+
+    pub fn set<T: Serialize>(&mut self, entry: impl Into<String>, value: &T) -> Result<()> {
+        let value = serde_json::to_value(value).context("serializing entry")?;
+        self.0.insert(entry.into(), value);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, entry: &str) -> bool {
+        self.0.remove(entry).is_some()
+    }
+
+    pub fn contains(&self, entry: &str) -> bool {
+        self.0.contains_key(entry)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// Several entries packed into the value of one key, since the stores of Maelstrom cannot change
+// more than one key at once. Every change is a compare-and-set of the whole value, so entries that
+// belong together, like the committed offsets of kafka, change together or not at all.
+#[derive(Debug, Clone)]
+pub struct CompositeKey<K = LinKv> {
+    client: K,
+    key: String,
+}
+
+impl<K: KvClient> CompositeKey<K> {
+    pub fn new(client: K, key: impl Into<String>) -> Self {
+        Self {
+            client,
+            key: key.into(),
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // Empty until the first change.
+    pub fn read<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(&mut N, Result<Entries, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        self.client.read(ctx, &self.key, move |node, entries, ctx| {
+            callback(node, entries.map(Option::unwrap_or_default), ctx)
+        })
+    }
+
+    pub fn get<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        entry: impl Into<String>,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: DeserializeOwned,
+        N: Node,
+        O: Write,
+    {
+        let entry = entry.into();
+        self.read(ctx, move |node, entries, ctx| {
+            callback(node, entries.and_then(|entries| entries.get(&entry)), ctx)
+        })
+    }
+
+    // Changes the entries based on the current ones, starting over when another node changed
+    // them in the meantime. The callback gets the entries that were written.
+    pub fn update<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        mut update: impl FnMut(&mut Entries) -> Result<()> + 'static,
+        callback: impl FnOnce(&mut N, Result<Entries, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        self.client.update(
+            ctx,
+            &self.key,
+            Contention::default(),
+            move |entries: Option<Entries>| {
+                let mut entries = entries.unwrap_or_default();
+                update(&mut entries)?;
+                Ok(entries)
+            },
+            callback,
+        )
+    }
+
+    // Sets all entries in one go, leaving the others as they are.
+    pub fn set_many<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        entries: impl IntoIterator<Item = (impl Into<String>, T)>,
+        callback: impl FnOnce(&mut N, Result<Entries, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let mut values = Entries::new();
+        for (entry, value) in entries {
+            values.set(entry, &value)?;
+        }
+        self.update(
+            ctx,
+            move |entries| {
+                entries.0.extend(values.0.clone());
+                Ok(())
+            },
+            callback,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    // Commits the offset of a log of its own and one all nodes share, then reads them back once
+    // told to.
+    struct Committer {
+        offsets: CompositeKey<MockKv>,
+        offset: u64,
+        committed: bool,
+        read: bool,
+        reads: BTreeMap<&'static str, Option<u64>>,
+    }
+
+    impl Scenario for Committer {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            let own = format!("{}-log", ctx.node_id());
+            let offsets = [(own, self.offset), ("shared-log".into(), self.offset)];
+            self.offsets
+                .set_many(ctx, offsets, |node: &mut Harness<Self>, committed, _| {
+                    committed?;
+                    node.scenario.committed = true;
+                    Ok(())
+                })?;
+            Ok(())
+        }
+
+        fn tick(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            if !std::mem::take(&mut self.read) {
+                return Ok(());
+            }
+            for log in ["n1-log", "n2-log", "n3-log", "missing-log"] {
+                self.offsets
+                    .get(ctx, log, move |node: &mut Harness<Self>, offset, _| {
+                        node.scenario.reads.insert(log, offset?);
+                        Ok(())
+                    })?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn concurrent_changes_keep_every_entry() {
+        let kv = MockKv::new().with_latency(Duration::from_millis(1));
+        let mut cluster = Cluster::new([10, 20, 30].map(|offset| Committer {
+            offsets: CompositeKey::new(kv.clone(), "offsets"),
+            offset,
+            committed: false,
+            read: false,
+            reads: BTreeMap::new(),
+        }));
+        cluster.run_until(|cluster| (0..3).all(|node| cluster.scenario(node).committed));
+
+        cluster.scenario_mut(0).read = true;
+        cluster.run_until(|cluster| cluster.scenario(0).reads.len() == 4);
+        let reads = BTreeMap::from([
+            ("n1-log", Some(10)),
+            ("n2-log", Some(20)),
+            ("n3-log", Some(30)),
+            ("missing-log", None),
+        ]);
+        assert_eq!(cluster.scenario(0).reads, reads);
+        let entries: Entries = serde_json::from_value(kv.get("offsets").unwrap()).unwrap();
+        assert_eq!(entries.len(), 4);
+        // The shared entry holds whichever change went through last.
+        let shared: u64 = entries.get("shared-log").unwrap().unwrap();
+        assert!([10, 20, 30].contains(&shared));
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backoff::{Backoff, Exponential};
+
+// What happens to incoming messages when the queue in front of the node is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    // Stop reading from the socket until there is room again.
+    #[default]
+    Block,
+    // Throw away the oldest queued message to make room for the new one.
+    DropOldest,
+    // Answer requests with a `temporarily_unavailable` error instead of queueing them.
+    Reject,
+}
+
+// How often the reader is started again after reading from the socket failed, before the node is
+// told through `on_reader_error`.
+#[derive(Debug, Clone)]
+pub struct ReaderSupervision {
+    pub max_restarts: u32,
+    pub backoff: Arc<dyn Backoff>,
+}
+
+impl Default for ReaderSupervision {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            backoff: Arc::new(Exponential::new(
+                Duration::from_millis(10),
+                Duration::from_secs(1),
+            )),
+        }
+    }
+}
+
+// What happens when a background thread of the runtime panics, the panic itself is always logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    // Stop the node as if its input ended.
+    #[default]
+    Shutdown,
+    Abort,
+}
+
+// When gossip holds back because the node's own traffic is not getting out: rounds go to a single
+// peer once batches wait for the writer thread at `max_queue_depth` or sent requests wait for an
+// answer at `max_unacked`, and are skipped at twice either limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GossipThrottle {
+    pub max_queue_depth: usize,
+    pub max_unacked: usize,
+}
+
+impl Default for GossipThrottle {
+    fn default() -> Self {
+        Self {
+            max_queue_depth: 64,
+            max_unacked: 256,
+        }
+    }
+}
+
+impl GossipThrottle {
+    // How many peers a round of `fanout` goes to.
+    pub(crate) fn fanout(&self, fanout: usize, queue_depth: usize, unacked: usize) -> usize {
+        if queue_depth >= self.max_queue_depth.saturating_mul(2)
+            || unacked >= self.max_unacked.saturating_mul(2)
+        {
+            0
+        } else if queue_depth >= self.max_queue_depth || unacked >= self.max_unacked {
+            fanout.min(1)
+        } else {
+            fanout
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    // Maximum amount of queued incoming messages, unbounded when `None`. Events and control
+    // messages never count towards it.
+    pub channel_capacity: Option<usize>,
+    pub backpressure: Backpressure,
+    // How long `NodeContext::send_reliable` waits before sending a request again.
+    pub retry_backoff: Arc<dyn Backoff>,
+    // Requests of `NodeContext::send_reliable` per destination that wait for a reply at once, the
+    // others are held back until there is room. Unbounded when `None`.
+    pub max_in_flight: Option<usize>,
+    pub reader_supervision: ReaderSupervision,
+    pub panic_policy: PanicPolicy,
+    pub gossip_throttle: GossipThrottle,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            channel_capacity: None,
+            backpressure: Backpressure::default(),
+            retry_backoff: Arc::new(Exponential::new(
+                Duration::from_millis(100),
+                Duration::from_secs(1),
+            )),
+            max_in_flight: None,
+            reader_supervision: ReaderSupervision::default(),
+            panic_policy: PanicPolicy::default(),
+            gossip_throttle: GossipThrottle::default(),
+        }
+    }
+}
+
+impl RunConfig {
+    pub fn with_channel_capacity(mut self, capacity: usize, backpressure: Backpressure) -> Self {
+        self.channel_capacity = Some(capacity);
+        self.backpressure = backpressure;
+        self
+    }
+
+    pub fn with_retry_backoff(mut self, backoff: impl Backoff + 'static) -> Self {
+        self.retry_backoff = Arc::new(backoff);
+        self
+    }
+
+    pub fn with_max_in_flight(mut self, limit: usize) -> Self {
+        self.max_in_flight = Some(limit);
+        self
+    }
+
+    pub fn with_reader_restarts(
+        mut self,
+        max_restarts: u32,
+        backoff: impl Backoff + 'static,
+    ) -> Self {
+        self.reader_supervision = ReaderSupervision {
+            max_restarts,
+            backoff: Arc::new(backoff),
+        };
+        self
+    }
+
+    pub fn with_panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    pub fn with_gossip_throttle(mut self, max_queue_depth: usize, max_unacked: usize) -> Self {
+        self.gossip_throttle = GossipThrottle {
+            max_queue_depth,
+            max_unacked,
+        };
+        self
+    }
+}
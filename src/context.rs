@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::GossipThrottle;
+use crate::outbox::Outbox;
+use crate::reliable::ReliableSender;
+use crate::reply::Responders;
+use crate::rpc::PendingRpcs;
+use crate::{ID_GENERATOR, MaelstromError, Message, Node, NodeId, Reply, ResponseInfo, Socket};
+
+pub struct NodeContext<'a, N: Node, I, O> {
+    pub(crate) node_id: &'a NodeId,
+    pub(crate) node_ids: &'a HashSet<NodeId>,
+    pub(crate) msg_id: Option<u64>,
+    pub(crate) src: Option<&'a NodeId>,
+    pub(crate) deferred: bool,
+    pub(crate) socket: &'a mut Socket<I, O>,
+    pub(crate) rpcs: &'a mut PendingRpcs<N, I, O>,
+    pub(crate) responders: &'a mut Responders,
+    pub(crate) reliable: &'a mut ReliableSender,
+    pub(crate) outbox: &'a mut Outbox,
+    pub(crate) gossip_throttle: GossipThrottle,
+}
+
+impl<'a, N: Node, I, O> NodeContext<'a, N, I, O> {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        node_id: &'a NodeId,
+        node_ids: &'a HashSet<NodeId>,
+        socket: &'a mut Socket<I, O>,
+        rpcs: &'a mut PendingRpcs<N, I, O>,
+        responders: &'a mut Responders,
+        reliable: &'a mut ReliableSender,
+        outbox: &'a mut Outbox,
+        gossip_throttle: GossipThrottle,
+    ) -> Self {
+        Self {
+            node_id,
+            node_ids,
+            msg_id: None,
+            src: None,
+            deferred: false,
+            socket,
+            rpcs,
+            responders,
+            reliable,
+            outbox,
+            gossip_throttle,
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        self.node_id
+    }
+
+    pub fn node_ids(&self) -> &HashSet<NodeId> {
+        self.node_ids
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &NodeId> {
+        self.node_ids.iter().filter(|id| *id != self.node_id)
+    }
+
+    // The id of the message being handled, events do not have one.
+    pub fn msg_id(&self) -> Option<u64> {
+        self.msg_id
+    }
+
+    pub fn message<B>(&self, dest: impl Into<NodeId>, body: B) -> Message<B> {
+        Message::new(self.node_id.clone(), dest, body)
+    }
+
+    pub fn socket(&mut self) -> &mut Socket<I, O> {
+        self.socket
+    }
+
+    pub fn pending_rpcs(&self) -> usize {
+        self.rpcs.len()
+    }
+
+    // Gives up on the reply to an rpc or raw rpc, a late one is handled like a response that
+    // belongs to no rpc. Returns whether the rpc was still waiting.
+    pub fn abandon_rpc(&mut self, message_id: u64) -> bool {
+        let typed = self.rpcs.take(message_id).is_some();
+        let raw = self.rpcs.take_raw(message_id).is_some();
+        typed || raw
+    }
+
+    pub fn dispatch_rpc(
+        &mut self,
+        node: &mut N,
+        response: N::InboundResponse,
+        info: ResponseInfo,
+    ) -> Result<()> {
+        // Responses that do not belong to an rpc are ignored.
+        let Some(callback) = info.in_reply_to.and_then(|id| self.rpcs.take(id)) else {
+            return Ok(());
+        };
+        callback(node, response, self)
+    }
+}
+
+impl<N, I, O> NodeContext<'_, N, I, O>
+where
+    N: Node,
+    O: Write,
+{
+    pub fn send<B>(&mut self, dest: impl Into<NodeId>, body: B) -> Result<()>
+    where
+        B: Serialize,
+    {
+        let message = self.message(dest, body);
+        self.socket.send(&message)
+    }
+
+    // How many peers a gossip round of `fanout` goes to, fewer while the node's own traffic is
+    // backed up, see `GossipThrottle`.
+    pub(crate) fn gossip_fanout(&self, fanout: usize) -> usize {
+        let queue_depth = self.socket.writer_stats().snapshot().queue_depth;
+        let unacked = self.reliable.len() + self.rpcs.len();
+        self.gossip_throttle.fanout(fanout, queue_depth, unacked)
+    }
+
+    // Sends the outcome of a handled request back to where it came from.
+    pub(crate) fn answer<R>(&mut self, result: Result<Reply<R>, MaelstromError>) -> Result<()>
+    where
+        R: Serialize,
+    {
+        let src = self.src.expect("only requests can be answered").clone();
+        let responses = match result {
+            Ok(Reply::Respond(response)) => vec![response],
+            Ok(Reply::Stream(responses)) => responses,
+            Ok(Reply::Deferred) if self.deferred => return Ok(()),
+            Ok(Reply::Deferred) => bail!("request deferred without a responder"),
+            Ok(Reply::None) => return Ok(()),
+            Err(error) => {
+                // The request failed on its own, the node keeps serving.
+                eprintln!("request from {src} failed: {error}");
+                return self
+                    .socket
+                    .send(&Message::reply(
+                        self.node_id.clone(),
+                        src,
+                        self.msg_id,
+                        error,
+                    ))
+                    .context("sending error response");
+            }
+        };
+        let (node_id, msg_id) = (self.node_id, self.msg_id);
+        self.socket
+            .send_many(
+                responses
+                    .into_iter()
+                    .map(|response| Message::reply(node_id.clone(), src.clone(), msg_id, response)),
+            )
+            .context("sending response")
+    }
+
+    pub fn rpc<B>(
+        &mut self,
+        dest: impl Into<NodeId>,
+        body: B,
+        callback: impl FnOnce(&mut N, N::InboundResponse, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<u64>
+    where
+        B: Serialize,
+    {
+        let message = self.message(dest, body);
+        let message_id = self
+            .socket
+            .send_with_id(message)
+            .context("sending rpc request")?;
+        self.rpcs.insert(message_id, Box::new(callback));
+        Ok(message_id)
+    }
+
+    // Like `rpc`, but the reply is handed over as JSON instead of being parsed as one of the
+    // responses of the node and it never reaches `handle_response`. Meant for services like the
+    // key-value stores.
+    pub fn raw_rpc<B>(
+        &mut self,
+        dest: impl Into<NodeId>,
+        body: B,
+        callback: impl FnOnce(&mut N, Value, &mut NodeContext<N, I, O>) -> Result<()> + 'static,
+    ) -> Result<u64>
+    where
+        B: Serialize,
+    {
+        let message_id = ID_GENERATOR.next_id();
+        self.rpcs.insert_raw(message_id, Box::new(callback));
+        let message = self.message(dest, body).with_id(message_id);
+        if let Err(error) = self.socket.send(&message) {
+            self.rpcs.take_raw(message_id);
+            return Err(error.context("sending rpc request"));
+        }
+        Ok(message_id)
+    }
+
+    // Runs the callback on the main loop once the delay passed, like an event that carries its own
+    // handler.
+    pub fn after(
+        &mut self,
+        delay: Duration,
+        callback: impl FnOnce(&mut N, &mut NodeContext<N, I, O>) -> Result<()> + 'static,
+    ) {
+        self.rpcs
+            .schedule(Instant::now() + delay, Box::new(callback));
+    }
+
+    // Hands the request being handled to `owner` and relays its answer back to the sender of the
+    // request, the handler returns `Reply::Deferred` afterwards.
+    pub fn forward<B>(&mut self, owner: impl Into<NodeId>, request: B) -> Result<u64>
+    where
+        B: Serialize,
+        N::InboundResponse: Serialize,
+    {
+        let responder = self.defer().context("forwarding request")?;
+        self.rpc(owner, request, move |_, response, ctx| {
+            responder.respond(ctx, response)
+        })
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::Message;
+
+// How many warnings of one kind are printed per window, the rest are only counted.
+const WARNINGS_PER_WINDOW: u32 = 10;
+const WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Warning {
+    SendFailed,
+    DecodeFailed,
+    Dropped,
+    GossipThrottled,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::SendFailed => "send failed",
+            Self::DecodeFailed => "decode failed",
+            Self::Dropped => "dropped",
+            Self::GossipThrottled => "gossip throttled",
+        })
+    }
+}
+
+struct Window {
+    start: Instant,
+    printed: u32,
+    suppressed: u64,
+}
+
+static WINDOWS: LazyLock<Mutex<HashMap<Warning, Window>>> = LazyLock::new(Default::default);
+
+// Prints the warning to stderr unless too many of its kind were printed recently. A flood of
+// failures, like every gossip to a partitioned node, would otherwise drown out everything else.
+pub(crate) fn warn(warning: Warning, message: fmt::Arguments<'_>) {
+    let now = Instant::now();
+    let mut windows = WINDOWS.lock().expect("failed to lock warning windows");
+    let window = windows.entry(warning).or_insert(Window {
+        start: now,
+        printed: 0,
+        suppressed: 0,
+    });
+    if now.duration_since(window.start) >= WINDOW {
+        if window.suppressed > 0 {
+            eprintln!(
+                "warning: {warning}: suppressed {} similar warnings",
+                window.suppressed
+            );
+        }
+        *window = Window {
+            start: now,
+            printed: 0,
+            suppressed: 0,
+        };
+    }
+    if window.printed < WARNINGS_PER_WINDOW {
+        window.printed += 1;
+        eprintln!("warning: {warning}: {message}");
+    } else {
+        window.suppressed += 1;
+    }
+}
+
+// The message a warning is about, printed as `src=.. dest=.. type=.. msg_id=..` leaving out what
+// is not known.
+pub(crate) struct Subject<'a> {
+    pub(crate) src: &'a str,
+    pub(crate) dest: &'a str,
+    pub(crate) kind: Option<String>,
+    pub(crate) id: Option<u64>,
+}
+
+impl<'a> Subject<'a> {
+    // The type is only known once the body is serialized, which is fine on the way to a warning.
+    pub(crate) fn of<R: Serialize>(message: &'a Message<R>) -> Self {
+        let kind = serde_json::to_value(&message.body.kind)
+            .ok()
+            .and_then(|body| Some(body.get("type")?.as_str()?.to_string()));
+        Self {
+            src: message.src(),
+            dest: message.dest(),
+            kind,
+            id: message.id(),
+        }
+    }
+}
+
+impl fmt::Display for Subject<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "src={} dest={}", self.src, self.dest)?;
+        if let Some(kind) = &self.kind {
+            write!(f, " type={kind}")?;
+        }
+        if let Some(id) = self.id {
+            write!(f, " msg_id={id}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::io::BufRead;
+
+use anyhow::{Context, Result};
+
+// How frames are laid out on the wire of the TCP and unix transports. Messages stay JSON within the
+// node, other encodings convert them when they are sent and received.
+pub trait Encoding: fmt::Debug + Send + Sync {
+    fn write_frame(&self, frame: &str, output: &mut Vec<u8>) -> Result<()>;
+
+    // `None` once the stream ended.
+    fn read_frame(&self, input: &mut dyn BufRead) -> Result<Option<String>>;
+}
+
+// Newline delimited JSON, the same as Maelstrom speaks over stdio.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Encoding for Json {
+    fn write_frame(&self, frame: &str, output: &mut Vec<u8>) -> Result<()> {
+        output.extend_from_slice(frame.as_bytes());
+        output.push(b'\n');
+        Ok(())
+    }
+
+    fn read_frame(&self, input: &mut dyn BufRead) -> Result<Option<String>> {
+        let mut line = String::new();
+        if input.read_line(&mut line).context("reading frame")? == 0 {
+            return Ok(None);
+        }
+        line.truncate(line.trim_end().len());
+        Ok(Some(line))
+    }
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Encoding for MessagePack {
+    fn write_frame(&self, frame: &str, output: &mut Vec<u8>) -> Result<()> {
+        let value: serde_json::Value = serde_json::from_str(frame).context("parsing frame")?;
+        let encoded = rmp_serde::to_vec_named(&value).context("encoding frame as msgpack")?;
+        write_prefixed(&encoded, output)
+    }
+
+    fn read_frame(&self, input: &mut dyn BufRead) -> Result<Option<String>> {
+        let Some(encoded) = read_prefixed(input)? else {
+            return Ok(None);
+        };
+        let value: serde_json::Value =
+            rmp_serde::from_slice(&encoded).context("decoding msgpack frame")?;
+        serde_json::to_string(&value)
+            .map(Some)
+            .context("serializing frame")
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Encoding for Cbor {
+    fn write_frame(&self, frame: &str, output: &mut Vec<u8>) -> Result<()> {
+        let value: serde_json::Value = serde_json::from_str(frame).context("parsing frame")?;
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&value, &mut encoded).context("encoding frame as cbor")?;
+        write_prefixed(&encoded, output)
+    }
+
+    fn read_frame(&self, input: &mut dyn BufRead) -> Result<Option<String>> {
+        let Some(encoded) = read_prefixed(input)? else {
+            return Ok(None);
+        };
+        let value: serde_json::Value =
+            ciborium::from_reader(encoded.as_slice()).context("decoding cbor frame")?;
+        serde_json::to_string(&value)
+            .map(Some)
+            .context("serializing frame")
+    }
+}
+
+// Binary frames are preceded by their length as a big endian u32.
+#[cfg_attr(not(any(feature = "msgpack", feature = "cbor")), allow(dead_code))]
+fn write_prefixed(encoded: &[u8], output: &mut Vec<u8>) -> Result<()> {
+    let len = u32::try_from(encoded.len()).context("frame is too large")?;
+    output.extend_from_slice(&len.to_be_bytes());
+    output.extend_from_slice(encoded);
+    Ok(())
+}
+
+#[cfg_attr(not(any(feature = "msgpack", feature = "cbor")), allow(dead_code))]
+fn read_prefixed(input: &mut dyn BufRead) -> Result<Option<Vec<u8>>> {
+    // The stream may only end in between frames.
+    if input.fill_buf().context("reading frame")?.is_empty() {
+        return Ok(None);
+    }
+    let mut len = [0; 4];
+    input.read_exact(&mut len).context("reading frame length")?;
+    let mut encoded = vec![0; u32::from_be_bytes(len) as usize];
+    input.read_exact(&mut encoded).context("reading frame")?;
+    Ok(Some(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    // Writes the frames one after the other and reads them back until the stream ends.
+    fn round_trip(encoding: &dyn Encoding) {
+        let frames = [
+            json!({ "src": "c1", "dest": "n1", "body": { "type": "echo", "msg_id": 1 } }),
+            json!({ "src": "n1", "dest": "c1", "body": { "text": "two\nlines", "n": [1.5, null] } }),
+        ];
+        let mut stream = Vec::new();
+        for frame in &frames {
+            encoding
+                .write_frame(&frame.to_string(), &mut stream)
+                .unwrap();
+        }
+
+        let mut input = stream.as_slice();
+        let mut read = Vec::new();
+        while let Some(frame) = encoding.read_frame(&mut input).unwrap() {
+            read.push(serde_json::from_str::<Value>(&frame).unwrap());
+        }
+        assert_eq!(read, frames);
+    }
+
+    #[test]
+    fn json_round_trips_frames() {
+        round_trip(&Json);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn message_pack_round_trips_frames() {
+        round_trip(&MessagePack);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trips_frames() {
+        round_trip(&Cbor);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn binary_frames_may_not_end_early() {
+        let mut stream = Vec::new();
+        Cbor.write_frame(r#"{"a":1}"#, &mut stream).unwrap();
+        stream.pop();
+        assert!(Cbor.read_frame(&mut stream.as_slice()).is_err());
+    }
+}
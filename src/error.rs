@@ -0,0 +1,131 @@
+use std::any::Any;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// The standard error codes from the Maelstrom protocol, codes not listed here end up as
+// `Custom`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "ErrorBody", into = "ErrorBody")]
+pub enum MaelstromError {
+    Timeout(String),
+    NodeNotFound(String),
+    NotSupported(String),
+    TemporarilyUnavailable(String),
+    MalformedRequest(String),
+    Crash(String),
+    Abort(String),
+    KeyDoesNotExist(String),
+    KeyAlreadyExists(String),
+    PreconditionFailed(String),
+    TxnConflict(String),
+    Custom { code: u32, text: String },
+}
+
+impl MaelstromError {
+    pub fn from_code(code: u32, text: String) -> Self {
+        match code {
+            0 => Self::Timeout(text),
+            1 => Self::NodeNotFound(text),
+            10 => Self::NotSupported(text),
+            11 => Self::TemporarilyUnavailable(text),
+            12 => Self::MalformedRequest(text),
+            13 => Self::Crash(text),
+            14 => Self::Abort(text),
+            20 => Self::KeyDoesNotExist(text),
+            21 => Self::KeyAlreadyExists(text),
+            22 => Self::PreconditionFailed(text),
+            30 => Self::TxnConflict(text),
+            code => Self::Custom { code, text },
+        }
+    }
+
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::Timeout(_) => 0,
+            Self::NodeNotFound(_) => 1,
+            Self::NotSupported(_) => 10,
+            Self::TemporarilyUnavailable(_) => 11,
+            Self::MalformedRequest(_) => 12,
+            Self::Crash(_) => 13,
+            Self::Abort(_) => 14,
+            Self::KeyDoesNotExist(_) => 20,
+            Self::KeyAlreadyExists(_) => 21,
+            Self::PreconditionFailed(_) => 22,
+            Self::TxnConflict(_) => 30,
+            Self::Custom { code, .. } => *code,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        match self {
+            Self::Timeout(text)
+            | Self::NodeNotFound(text)
+            | Self::NotSupported(text)
+            | Self::TemporarilyUnavailable(text)
+            | Self::MalformedRequest(text)
+            | Self::Crash(text)
+            | Self::Abort(text)
+            | Self::KeyDoesNotExist(text)
+            | Self::KeyAlreadyExists(text)
+            | Self::PreconditionFailed(text)
+            | Self::TxnConflict(text)
+            | Self::Custom { text, .. } => text,
+        }
+    }
+
+    pub(crate) fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic payload".to_string());
+        Self::Crash(format!("handler panicked: {message}"))
+    }
+
+    // Whether the operation is known to have had no effect, as opposed to maybe having happened.
+    pub fn is_definite(&self) -> bool {
+        !matches!(
+            self,
+            Self::Timeout(_) | Self::Crash(_) | Self::Custom { .. }
+        )
+    }
+}
+
+impl fmt::Display for MaelstromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {}: {}", self.code(), self.text())
+    }
+}
+
+impl std::error::Error for MaelstromError {}
+
+// Handlers can keep using `anyhow` and `?`, anything unexpected is reported as a crash.
+impl From<anyhow::Error> for MaelstromError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::Crash(format!("{error:#}"))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename = "error")]
+struct ErrorBody {
+    code: u32,
+    #[serde(default)]
+    text: String,
+}
+
+impl From<ErrorBody> for MaelstromError {
+    fn from(body: ErrorBody) -> Self {
+        Self::from_code(body.code, body.text)
+    }
+}
+
+impl From<MaelstromError> for ErrorBody {
+    fn from(error: MaelstromError) -> Self {
+        Self {
+            code: error.code(),
+            text: error.text().to_string(),
+        }
+    }
+}
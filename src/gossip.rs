@@ -0,0 +1,863 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::backoff::{Backoff, Exponential};
+use crate::bloom::{self, BloomFilter};
+use crate::diagnostics::{self, Warning};
+use crate::{Node, NodeContext, NodeId};
+
+// How long a `gossip_ok` is waited for before the gossip is given up on.
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// State that nodes spread by sending each other the parts the other one is missing.
+pub trait GossipState: Default {
+    // What a node has, for working out what it lacks.
+    type Digest;
+    type Delta: Clone + Serialize + DeserializeOwned + 'static;
+
+    fn digest(&self) -> Self::Digest;
+
+    // What the node with the digest lacks, `None` when it has everything.
+    fn diff(&self, digest: &Self::Digest) -> Option<Self::Delta>;
+
+    fn merge(&mut self, delta: Self::Delta);
+}
+
+// State made of elements, which `Gossiper::with_bloom_filters` reconciles by sending peers a
+// Bloom filter of the elements instead of keeping track of what every peer has.
+pub trait ElementSet: GossipState {
+    type Element: Hash;
+
+    fn elements(&self) -> impl Iterator<Item = &Self::Element>;
+
+    // The elements `keep` picks as a delta, `None` when it picks none.
+    fn select(&self, keep: impl FnMut(&Self::Element) -> bool) -> Option<Self::Delta>;
+}
+
+// The body of a `gossip` request. Peers that piggyback acks send `ack` with what they merged
+// from the receiver since their last gossip to it, and leave out `delta` when they only ack.
+//
+// Peers that reconcile with Bloom filters send `filter` with what they have. It is answered by
+// the next gossip back, with what the filter lacks under `delta` and what it matched under
+// `matched`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "gossip")]
+pub struct Gossip<D> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta: Option<D>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack: Option<D>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<BloomFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched: Option<Matched>,
+}
+
+// The elements of a peer that a Bloom filter seemed to have. The node that sent the filter has
+// all of them only if as many of its own elements pass `filter`, with the same checksum.
+// Otherwise the filter hid some of them by mistake.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Matched {
+    pub filter: BloomFilter,
+    pub count: usize,
+    pub checksum: u64,
+}
+
+// The false positive rate of `Matched::filter` relative to the one of the gossip, every false
+// positive there leads to a full sync.
+const MATCHED_RATE_FACTOR: f64 = 0.01;
+
+// Additive increase, multiplicative decrease of how many peers may owe an answer to gossip at
+// once, like the congestion window of TCP. Every answer grows the window by `increase` divided by
+// the window, so by about `increase` per round trip, and gossip that is not answered in time
+// shrinks it by `decrease`, at most once per timeout. The window starts out at `max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aimd {
+    pub min: f64,
+    pub max: f64,
+    pub increase: f64,
+    pub decrease: f64,
+}
+
+impl Default for Aimd {
+    fn default() -> Self {
+        Self {
+            min: 1.0,
+            max: 8.0,
+            increase: 1.0,
+            decrease: 0.5,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Peers<S> {
+    // What every peer is known to have, from gossip it acknowledged or sent itself.
+    known: HashMap<NodeId, S>,
+    // When unresponsive peers are gossiped to again, and the delay that led up to it.
+    backing_off: HashMap<NodeId, (Instant, Duration)>,
+    // What peers gossiped that was not acknowledged yet, with piggybacked acks only.
+    owed: HashMap<NodeId, S>,
+    sent: u64,
+    throttled: u64,
+    // Peers that owe an answer to gossip, since when. Only kept with rate control.
+    awaiting: HashMap<NodeId, Instant>,
+    window: f64,
+    last_decrease: Option<Instant>,
+    // Filters peers sent since the last round, answered in the next one. With Bloom filters only.
+    requested: HashMap<NodeId, BloomFilter>,
+    // Peers whose answer showed that the filter hid some of their elements, asked for all of
+    // them in the next round.
+    full_sync: HashSet<NodeId>,
+}
+
+impl<S> Peers<S> {
+    fn answered(&mut self, peer: &NodeId, aimd: Option<&Aimd>) {
+        self.backing_off.remove(peer);
+        if let Some(aimd) = aimd
+            && self.awaiting.remove(peer).is_some()
+        {
+            self.window = (self.window + aimd.increase / self.window).min(aimd.max);
+        }
+    }
+}
+
+// Sends a few random peers what they are missing of the state on every tick. Peers answer a
+// `gossip` request with `gossip_ok` once they merged it. Peers that do not are gossiped to less
+// and less often.
+//
+// With `with_piggybacked_acks` nobody answers, the ack rides on the next gossip to the peer
+// instead. Peers that are owed one are gossiped to in the next round, on their own if there is
+// nothing else to send them.
+//
+// With `with_bloom_filters` the peers are sent a Bloom filter of the state instead, and send
+// back what it lacks. Filters are sent every round, so lost ones need no answer. Acks, backoff
+// and rate control do not apply.
+//
+// Rounds go to fewer peers or none while the node's own traffic is backed up, see
+// `GossipThrottle`. With `with_rate_control` gossip also slows down while peers stop answering,
+// as in a partition, and speeds up again gradually once they answer.
+//
+// Clones share what is known about the peers.
+#[derive(Debug, Clone)]
+pub struct Gossiper<S: GossipState> {
+    fanout: usize,
+    backoff: Arc<dyn Backoff>,
+    piggyback: bool,
+    aimd: Option<Aimd>,
+    filters: Option<Filters<S>>,
+    peers: Rc<RefCell<Peers<S>>>,
+}
+
+// Reconciling with Bloom filters, which needs the state to be an `ElementSet`. Kept as functions
+// so that only `with_bloom_filters` needs to know that.
+struct Filters<S: GossipState> {
+    false_positive_rate: f64,
+    filter: fn(&S, f64) -> BloomFilter,
+    answer: fn(&S, &BloomFilter, f64) -> Answer<S::Delta>,
+    has_matched: fn(&S, &Matched) -> bool,
+}
+
+// What a filter lacks and what it matched.
+type Answer<D> = (Option<D>, Matched);
+
+impl<S: GossipState> Clone for Filters<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: GossipState> Copy for Filters<S> {}
+
+impl<S: GossipState> fmt::Debug for Filters<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Filters")
+            .field("false_positive_rate", &self.false_positive_rate)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: GossipState + 'static> Gossiper<S> {
+    // Gossips to `fanout` peers per tick, backing off from `interval` up to a second for peers
+    // that do not answer.
+    pub fn new(fanout: usize, interval: Duration) -> Self {
+        Self {
+            fanout,
+            backoff: Arc::new(Exponential {
+                initial: interval,
+                factor: 2,
+                max: Duration::from_secs(1),
+            }),
+            piggyback: false,
+            aimd: None,
+            filters: None,
+            peers: Rc::new(RefCell::new(Peers {
+                known: HashMap::new(),
+                backing_off: HashMap::new(),
+                owed: HashMap::new(),
+                sent: 0,
+                throttled: 0,
+                awaiting: HashMap::new(),
+                window: f64::INFINITY,
+                last_decrease: None,
+                requested: HashMap::new(),
+                full_sync: HashSet::new(),
+            })),
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: impl Backoff + 'static) -> Self {
+        self.backoff = Arc::new(backoff);
+        self
+    }
+
+    // Every node of the cluster has to gossip the same way.
+    pub fn with_piggybacked_acks(mut self) -> Self {
+        self.piggyback = true;
+        self
+    }
+
+    pub fn with_rate_control(mut self, aimd: Aimd) -> Self {
+        self.peers.borrow_mut().window = aimd.max;
+        self.aimd = Some(aimd);
+        self
+    }
+
+    pub fn fanout(&self) -> usize {
+        self.fanout
+    }
+
+    // How many peers may owe an answer at once, unbounded without rate control.
+    pub fn window(&self) -> f64 {
+        self.peers.borrow().window
+    }
+
+    // How many gossip messages were sent so far, for working out the messages per operation.
+    pub fn sent(&self) -> u64 {
+        self.peers.borrow().sent
+    }
+
+    // How many rounds went to fewer peers than the fanout, or none, because of `GossipThrottle`.
+    pub fn throttled(&self) -> u64 {
+        self.peers.borrow().throttled
+    }
+
+    // Merges gossip from a peer into the state, the peer has it already so it is not sent back.
+    // Returns whether the gossip is to be answered with `gossip_ok`.
+    pub fn receive(&self, state: &mut S, from: &str, gossip: Gossip<S::Delta>) -> bool {
+        let mut peers = self.peers.borrow_mut();
+        let from = NodeId::new(from);
+        if let Some(filters) = &self.filters {
+            if let Some(filter) = gossip.filter {
+                peers.requested.insert(from.clone(), filter);
+            }
+            // Checked before merging, the delta holds what the filter did not match.
+            if let Some(matched) = gossip.matched
+                && !(filters.has_matched)(state, &matched)
+            {
+                peers.full_sync.insert(from);
+            }
+            if let Some(delta) = gossip.delta {
+                state.merge(delta);
+            }
+            return false;
+        }
+        if let Some(ack) = gossip.ack {
+            peers.answered(&from, self.aimd.as_ref());
+            peers.known.entry(from.clone()).or_default().merge(ack);
+        }
+        let Some(delta) = gossip.delta else {
+            return false;
+        };
+        peers
+            .known
+            .entry(from.clone())
+            .or_default()
+            .merge(delta.clone());
+        if self.piggyback {
+            peers.owed.entry(from).or_default().merge(delta.clone());
+        }
+        state.merge(delta);
+        !self.piggyback
+    }
+
+    // Gossips to the peers picked for this round, meant to be called from `on_tick`.
+    pub fn tick<N, I, O>(&self, ctx: &mut NodeContext<N, I, O>, state: &S) -> Result<()>
+    where
+        N: Node,
+        O: Write,
+    {
+        let fanout = ctx.gossip_fanout(self.fanout);
+        if fanout < self.fanout {
+            self.peers.borrow_mut().throttled += 1;
+            diagnostics::warn(
+                Warning::GossipThrottled,
+                format_args!(
+                    "gossiping to {fanout} instead of {} peers while traffic is backed up",
+                    self.fanout
+                ),
+            );
+            if fanout == 0 {
+                return Ok(());
+            }
+        }
+        let mut picked: Vec<NodeId> = ctx
+            .peers()
+            .cloned()
+            .choose_multiple(&mut rand::rng(), fanout);
+        if let Some(filters) = &self.filters {
+            return self.reconcile(ctx, state, filters, picked);
+        }
+        for peer in self.peers.borrow().owed.keys() {
+            if !picked.contains(peer) {
+                picked.push(peer.clone());
+            }
+        }
+        let now = Instant::now();
+        let mut budget = usize::MAX;
+        if let Some(aimd) = &self.aimd {
+            budget = self.control_rate(aimd, now);
+        }
+        for peer in picked {
+            let mut peers = self.peers.borrow_mut();
+            let ack = peers
+                .owed
+                .remove(&peer)
+                .and_then(|owed| owed.diff(&S::default().digest()));
+            let delta = match peers.backing_off.get(&peer) {
+                Some((retry_at, _)) if now < *retry_at => None,
+                // Peers that owe an answer already do not take up more of the window.
+                _ if budget == 0 && !peers.awaiting.contains_key(&peer) => None,
+                _ => state.diff(&peers.known.entry(peer.clone()).or_default().digest()),
+            };
+            if delta.is_some() && self.aimd.is_some() && !peers.awaiting.contains_key(&peer) {
+                peers.awaiting.insert(peer.clone(), now);
+                budget -= 1;
+            }
+            if delta.is_none() && ack.is_none() {
+                continue;
+            }
+            peers.sent += 1;
+            if delta.is_some() {
+                // Cleared again once the peer acknowledges the gossip.
+                let previous = peers.backing_off.get(&peer).map(|(_, delay)| *delay);
+                let delay = self.backoff.delay(previous);
+                peers.backing_off.insert(peer.clone(), (now + delay, delay));
+            }
+            drop(peers);
+
+            let gossip = Gossip {
+                delta,
+                ack,
+                filter: None,
+                matched: None,
+            };
+            if self.piggyback {
+                ctx.send(peer, gossip).context("gossiping to peer")?;
+                continue;
+            }
+            let shared = self.peers.clone();
+            let gossiped = gossip.delta.clone();
+            let to = peer.clone();
+            let aimd = self.aimd;
+            let sent = ctx.raw_rpc(peer, gossip, move |_, reply, _| {
+                if reply.get("type").and_then(Value::as_str) != Some("gossip_ok") {
+                    return Ok(());
+                }
+                let mut peers = shared.borrow_mut();
+                peers.answered(&to, aimd.as_ref());
+                if let Some(gossiped) = gossiped {
+                    peers.known.entry(to).or_default().merge(gossiped);
+                }
+                Ok(())
+            });
+            let message_id = sent.context("gossiping to peer")?;
+            // Unanswered gossip counts as backed up traffic, but not forever.
+            ctx.after(GOSSIP_TIMEOUT, move |_, ctx| {
+                ctx.rpcs.take_raw(message_id);
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    // Sends the picked peers a filter of the state, and answers the filters peers sent since the
+    // last round.
+    fn reconcile<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        state: &S,
+        filters: &Filters<S>,
+        picked: Vec<NodeId>,
+    ) -> Result<()>
+    where
+        N: Node,
+        O: Write,
+    {
+        let rate = filters.false_positive_rate;
+        let own = (filters.filter)(state, rate);
+        let mut peers = self.peers.borrow_mut();
+        let requested = std::mem::take(&mut peers.requested);
+        let full_sync = std::mem::take(&mut peers.full_sync);
+        let mut to = picked.clone();
+        for peer in requested.keys().chain(&full_sync) {
+            if !to.contains(peer) {
+                to.push(peer.clone());
+            }
+        }
+        peers.sent += to.len() as u64;
+        drop(peers);
+
+        for peer in to {
+            let filter = if full_sync.contains(&peer) {
+                // Nothing passes an empty filter, so the peer answers with everything.
+                Some(BloomFilter::new(0, rate))
+            } else {
+                picked.contains(&peer).then(|| own.clone())
+            };
+            let (delta, matched) = match requested.get(&peer) {
+                Some(filter) => {
+                    let (delta, matched) = (filters.answer)(state, filter, rate);
+                    (delta, Some(matched))
+                }
+                None => (None, None),
+            };
+            let gossip = Gossip {
+                delta,
+                ack: None,
+                filter,
+                matched,
+            };
+            ctx.send(peer, gossip).context("reconciling with peer")?;
+        }
+        Ok(())
+    }
+
+    // Gives up on the answers that are overdue, shrinking the window, and returns how many more
+    // peers may owe one.
+    fn control_rate(&self, aimd: &Aimd, now: Instant) -> usize {
+        let mut peers = self.peers.borrow_mut();
+        let before = peers.awaiting.len();
+        peers
+            .awaiting
+            .retain(|_, since| now.duration_since(*since) < GOSSIP_TIMEOUT);
+        if peers.awaiting.len() < before
+            && peers
+                .last_decrease
+                .is_none_or(|at| now.duration_since(at) >= GOSSIP_TIMEOUT)
+        {
+            peers.window = (peers.window * aimd.decrease).max(aimd.min);
+            peers.last_decrease = Some(now);
+        }
+        (peers.window as usize).saturating_sub(peers.awaiting.len())
+    }
+}
+
+impl<S: ElementSet + 'static> Gossiper<S> {
+    // Every node of the cluster has to gossip the same way. Elements a filter seems to have by
+    // mistake are not sent back, the node that sent it asks for everything once it notices.
+    pub fn with_bloom_filters(mut self, false_positive_rate: f64) -> Self {
+        self.filters = Some(Filters {
+            false_positive_rate,
+            filter: filter_of::<S>,
+            answer: answer_filter::<S>,
+            has_matched: has_matched::<S>,
+        });
+        self
+    }
+}
+
+fn filter_of<S: ElementSet>(state: &S, false_positive_rate: f64) -> BloomFilter {
+    let elements: Vec<_> = state.elements().collect();
+    filter_of_elements(&elements, false_positive_rate)
+}
+
+// What the node that sent the filter lacks, and what it seems to have.
+fn answer_filter<S: ElementSet>(
+    state: &S,
+    filter: &BloomFilter,
+    false_positive_rate: f64,
+) -> Answer<S::Delta> {
+    let delta = state.select(|element| !filter.contains(element));
+    let matched: Vec<_> = state
+        .elements()
+        .filter(|element| filter.contains(element))
+        .collect();
+    let matched = Matched {
+        filter: filter_of_elements(&matched, false_positive_rate * MATCHED_RATE_FACTOR),
+        count: matched.len(),
+        checksum: bloom::checksum(matched.iter().copied()),
+    };
+    (delta, matched)
+}
+
+fn filter_of_elements<T: Hash>(elements: &[&T], false_positive_rate: f64) -> BloomFilter {
+    let mut filter = BloomFilter::new(elements.len(), false_positive_rate);
+    filter.extend(elements);
+    filter
+}
+
+fn has_matched<S: ElementSet>(state: &S, matched: &Matched) -> bool {
+    let passed: Vec<_> = state
+        .elements()
+        .filter(|element| matched.filter.contains(element))
+        .collect();
+    passed.len() == matched.count && bloom::checksum(passed.iter().copied()) == matched.checksum
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "rumor")]
+struct Rumor<D> {
+    rumors: D,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename = "rumor_ok")]
+struct RumorOk<D> {
+    known: Option<D>,
+}
+
+struct Rumors<S: ElementSet> {
+    // Rumors still being spread, with how many peers they were pushed to that knew them already.
+    hot: HashMap<S::Element, u32>,
+    sent: u64,
+}
+
+// Spreads new elements as rumors instead of syncing whole states: every tick the rumors that
+// are still hot are pushed to a few random peers, and a rumor dies once `max_misses` peers
+// already knew it. Traffic stops with the last rumor, at the cost of elements that may miss a
+// node when every rumor dies early.
+//
+// Whether an element is known is up to the state, the monger only keeps the hot ones. Peers
+// answer a `rumor` request, which carries the hot elements as a delta under `rumors`, with
+// `rumor_ok` and the ones they knew already under `known`, as handed out by `receive`.
+//
+// Clones share the rumors.
+pub struct RumorMonger<S: ElementSet> {
+    fanout: usize,
+    max_misses: u32,
+    rumors: Rc<RefCell<Rumors<S>>>,
+}
+
+impl<S: ElementSet> Clone for RumorMonger<S> {
+    fn clone(&self) -> Self {
+        Self {
+            fanout: self.fanout,
+            max_misses: self.max_misses,
+            rumors: self.rumors.clone(),
+        }
+    }
+}
+
+impl<S: ElementSet> fmt::Debug for RumorMonger<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RumorMonger")
+            .field("fanout", &self.fanout)
+            .field("max_misses", &self.max_misses)
+            .field("hot", &self.rumors.borrow().hot.len())
+            .finish()
+    }
+}
+
+impl<S> RumorMonger<S>
+where
+    S: ElementSet + 'static,
+    S::Element: Eq + Clone,
+{
+    pub fn new(fanout: usize, max_misses: u32) -> Self {
+        Self {
+            fanout,
+            max_misses: max_misses.max(1),
+            rumors: Rc::new(RefCell::new(Rumors {
+                hot: HashMap::new(),
+                sent: 0,
+            })),
+        }
+    }
+
+    // Starts a rumor of an element that is new to the state, like one a client added.
+    pub fn spread(&self, element: S::Element) {
+        self.rumors.borrow_mut().hot.entry(element).or_insert(0);
+    }
+
+    // Merges the rumors of a peer into the state and starts rumors of the ones that were new.
+    // Returns the ones that were known already, for the reply.
+    pub fn receive(&self, state: &mut S, rumors: S::Delta) -> Option<S::Delta> {
+        let received = elements_of::<S>(rumors.clone());
+        let (known, new) = {
+            let had: HashSet<&S::Element> = state.elements().collect();
+            let known = received.select(|element| had.contains(element));
+            let new: Vec<S::Element> = received
+                .elements()
+                .filter(|element| !had.contains(element))
+                .cloned()
+                .collect();
+            (known, new)
+        };
+        state.merge(rumors);
+        for element in new {
+            self.spread(element);
+        }
+        known
+    }
+
+    // How many rumors are still being spread.
+    pub fn hot(&self) -> usize {
+        self.rumors.borrow().hot.len()
+    }
+
+    // Rumor requests sent to peers.
+    pub fn sent(&self) -> u64 {
+        self.rumors.borrow().sent
+    }
+
+    // Pushes the hot rumors to the peers picked for this round, meant to be called from
+    // `on_tick`.
+    pub fn tick<N, I, O>(&self, ctx: &mut NodeContext<N, I, O>, state: &S) -> Result<()>
+    where
+        N: Node,
+        O: Write,
+    {
+        let Some(rumors) = ({
+            let shared = self.rumors.borrow();
+            state.select(|element| shared.hot.contains_key(element))
+        }) else {
+            return Ok(());
+        };
+        let picked: Vec<NodeId> = ctx
+            .peers()
+            .cloned()
+            .choose_multiple(&mut rand::rng(), self.fanout);
+        for peer in picked {
+            let shared = self.rumors.clone();
+            let max_misses = self.max_misses;
+            let rumor = Rumor {
+                rumors: rumors.clone(),
+            };
+            let sent = ctx.raw_rpc(peer, rumor, move |_, reply, _| {
+                // Peers that did not take the rumors do not count against them.
+                let Ok(RumorOk { known }) = serde_json::from_value::<RumorOk<S::Delta>>(reply)
+                else {
+                    return Ok(());
+                };
+                let Some(known) = known else {
+                    return Ok(());
+                };
+                let mut rumors = shared.borrow_mut();
+                for element in elements_of::<S>(known).elements() {
+                    if let Some(misses) = rumors.hot.get_mut(element) {
+                        *misses += 1;
+                        if *misses >= max_misses {
+                            rumors.hot.remove(element);
+                        }
+                    }
+                }
+                Ok(())
+            });
+            let message_id = sent.context("spreading rumors to peer")?;
+            self.rumors.borrow_mut().sent += 1;
+            // Lost rumors are pushed again while hot, their answers are not waited for forever.
+            ctx.after(GOSSIP_TIMEOUT, move |_, ctx| {
+                ctx.rpcs.take_raw(message_id);
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+}
+
+// The elements of a delta, which only a state can tell apart.
+fn elements_of<S: ElementSet>(delta: S::Delta) -> S {
+    let mut state = S::default();
+    state.merge(delta);
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::io::Read;
+    use std::thread;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::Client;
+    use crate::{
+        EventIncjector, Init, Loopback, MaelstromError, Never, Reply, RequestInfo, Socket,
+    };
+
+    #[derive(Debug, Default)]
+    struct Numbers(BTreeSet<u64>);
+
+    impl GossipState for Numbers {
+        type Digest = BTreeSet<u64>;
+        type Delta = BTreeSet<u64>;
+
+        fn digest(&self) -> BTreeSet<u64> {
+            self.0.clone()
+        }
+
+        fn diff(&self, digest: &BTreeSet<u64>) -> Option<BTreeSet<u64>> {
+            let missing: BTreeSet<u64> = self.0.difference(digest).copied().collect();
+            (!missing.is_empty()).then_some(missing)
+        }
+
+        fn merge(&mut self, delta: BTreeSet<u64>) {
+            self.0.extend(delta);
+        }
+    }
+
+    impl ElementSet for Numbers {
+        type Element = u64;
+
+        fn elements(&self) -> impl Iterator<Item = &u64> {
+            self.0.iter()
+        }
+
+        fn select(&self, mut keep: impl FnMut(&u64) -> bool) -> Option<BTreeSet<u64>> {
+            let selected: BTreeSet<u64> = self.0.iter().copied().filter(|n| keep(n)).collect();
+            (!selected.is_empty()).then_some(selected)
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Read,
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Response {
+        ReadOk { numbers: BTreeSet<u64> },
+    }
+
+    // Reconciles the numbers it starts with with its peers through Bloom filters.
+    struct Member {
+        gossiper: Gossiper<Numbers>,
+        numbers: Numbers,
+    }
+
+    impl Node for Member {
+        type Request = Request;
+        type Response = Response;
+        type PeerRequest = Gossip<BTreeSet<u64>>;
+        type PeerResponse = Never;
+        type InboundResponse = Never;
+        type Event = ();
+
+        // The numbers of the node and the false positive rate of the filters.
+        type InitState = (BTreeSet<u64>, f64);
+
+        const TICK_INTERVAL: Option<Duration> = Some(Duration::from_millis(5));
+
+        fn from_init(
+            _: Init,
+            (numbers, false_positive_rate): (BTreeSet<u64>, f64),
+            _: EventIncjector<Self>,
+        ) -> Self {
+            Self {
+                gossiper: Gossiper::new(2, Duration::from_millis(5))
+                    .with_bloom_filters(false_positive_rate),
+                numbers: Numbers(numbers),
+            }
+        }
+
+        fn handle_request(
+            &mut self,
+            request: Request,
+            _: RequestInfo,
+            _: &mut NodeContext<Self, impl Read, impl Write>,
+        ) -> Result<Reply<Response>, MaelstromError> {
+            let Request::Read = request;
+            let numbers = self.numbers.0.clone();
+            Ok(Response::ReadOk { numbers }.into())
+        }
+
+        fn handle_peer_request(
+            &mut self,
+            gossip: Gossip<BTreeSet<u64>>,
+            info: RequestInfo,
+            _: &mut NodeContext<Self, impl Read, impl Write>,
+        ) -> Result<Reply<Never>, MaelstromError> {
+            let answer = self.gossiper.receive(&mut self.numbers, info.src, gossip);
+            assert!(!answer, "gossip with Bloom filters is never answered");
+            Ok(Reply::None)
+        }
+
+        fn on_tick(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+            self.gossiper.tick(ctx, &self.numbers)
+        }
+    }
+
+    // Every node starts with numbers of its own and has to end up with all of them.
+    fn reconciles(false_positive_rate: f64) {
+        const NODES: u64 = 4;
+        const PER_NODE: u64 = 200;
+        let network = Loopback::new();
+        let ids: Vec<String> = (1..=NODES).map(|node| format!("n{node}")).collect();
+        let nodes: Vec<_> = ids
+            .iter()
+            .zip(0..)
+            .map(|(id, node)| {
+                let socket = Socket::from_transport(network.transport(id.clone()));
+                let numbers = (node * PER_NODE..(node + 1) * PER_NODE).collect();
+                thread::spawn(move || Member::run((numbers, false_positive_rate), socket))
+            })
+            .collect();
+        let mut client = Client::new(&network, "c1");
+        let node_ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        for id in &ids {
+            client.init(id, &node_ids);
+        }
+
+        let all: BTreeSet<u64> = (0..NODES * PER_NODE).collect();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        for id in &ids {
+            loop {
+                let read = client.request(id, json!({ "type": "read" }));
+                let numbers: BTreeSet<u64> =
+                    serde_json::from_value(read["numbers"].clone()).unwrap();
+                if numbers == all {
+                    break;
+                }
+                assert!(
+                    Instant::now() < deadline,
+                    "{id} only has {} numbers",
+                    numbers.len()
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        for id in &ids {
+            network.disconnect(id);
+        }
+        for node in nodes {
+            node.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn bloom_filters_reconcile_every_element() {
+        reconciles(0.01);
+    }
+
+    // Half of the missing elements pass every filter by mistake, which only a full sync makes up
+    // for.
+    #[test]
+    fn bloom_filters_fall_back_to_full_syncs() {
+        reconciles(0.5);
+    }
+}
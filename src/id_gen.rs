@@ -1,17 +1,50 @@
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicU64;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde_json::Value;
 
 pub static ID_GENERATOR: IdGen = IdGen::new();
 
 #[derive(Default)]
-pub struct IdGen(AtomicU32);
+pub struct IdGen(AtomicU64);
 
 impl IdGen {
     const fn new() -> Self {
-        Self(AtomicU32::new(0))
+        Self(AtomicU64::new(0))
     }
 
-    pub fn next_id(&self) -> u32 {
+    pub fn next_id(&self) -> u64 {
         use std::sync::atomic::Ordering;
         self.0.fetch_add(1, Ordering::AcqRel)
     }
 }
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Id {
+    Number(u64),
+    String(String),
+}
+
+// Some clients send message ids as strings, they are accepted as long as they hold a number.
+pub(crate) fn deserialize_id<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<Id>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Id::Number(id)) => Ok(Some(id)),
+        Some(Id::String(id)) => id
+            .parse()
+            .map(Some)
+            .map_err(|_| de::Error::custom(format!("message id {id:?} is not a number"))),
+    }
+}
+
+pub(crate) fn id_from_value(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(id) => id.as_u64(),
+        Value::String(id) => id.parse().ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,1284 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::ops::Add;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::backoff::DecorrelatedJitter;
+pub use crate::service::RetryPolicy;
+use crate::service::{ServiceClient, ServiceError};
+use crate::{Backoff, ID_GENERATOR, MaelstromError, Node, NodeContext};
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request<'a, T> {
+    Read {
+        key: &'a str,
+    },
+    Write {
+        key: &'a str,
+        value: &'a T,
+    },
+    Cas {
+        key: &'a str,
+        from: &'a T,
+        to: &'a T,
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum Response {
+    ReadOk { value: Value },
+    WriteOk,
+    CasOk,
+}
+
+// A reply of the service that does not belong to the request.
+fn unexpected() -> KvError {
+    KvError::Client(anyhow!("incorrect response received"))
+}
+
+// Why an operation on a key-value store failed. Keys that do not exist and compare-and-sets that
+// lose are regular outcomes of `read` and `compare_and_set` instead.
+#[derive(Debug)]
+pub enum KvError {
+    // No reply arrived in time, even after retrying where that is safe. The operation may still
+    // have happened.
+    Timeout,
+    KeyDoesNotExist(String),
+    KeyAlreadyExists(String),
+    PreconditionFailed(String),
+    TxnConflict(String),
+    TemporarilyUnavailable(String),
+    // Any other error the service answered with.
+    Service(MaelstromError),
+    // The request could not be sent, or the reply or a value made no sense.
+    Client(anyhow::Error),
+}
+
+impl KvError {
+    // Whether the operation is known to have had no effect.
+    pub fn is_definite(&self) -> bool {
+        match self {
+            Self::Timeout | Self::Client(_) => false,
+            Self::Service(error) => error.is_definite(),
+            _ => true,
+        }
+    }
+}
+
+impl From<MaelstromError> for KvError {
+    fn from(error: MaelstromError) -> Self {
+        match error {
+            MaelstromError::Timeout(_) => Self::Timeout,
+            MaelstromError::KeyDoesNotExist(text) => Self::KeyDoesNotExist(text),
+            MaelstromError::KeyAlreadyExists(text) => Self::KeyAlreadyExists(text),
+            MaelstromError::PreconditionFailed(text) => Self::PreconditionFailed(text),
+            MaelstromError::TxnConflict(text) => Self::TxnConflict(text),
+            MaelstromError::TemporarilyUnavailable(text) => Self::TemporarilyUnavailable(text),
+            error => Self::Service(error),
+        }
+    }
+}
+
+impl From<ServiceError> for KvError {
+    fn from(error: ServiceError) -> Self {
+        match error {
+            ServiceError::Timeout => Self::Timeout,
+            ServiceError::Service(error) => error.into(),
+            ServiceError::Client(error) => Self::Client(error),
+        }
+    }
+}
+
+// An `update` closure can fail with a `KvError` of its own, which is handed on as it is.
+impl From<anyhow::Error> for KvError {
+    fn from(error: anyhow::Error) -> Self {
+        error.downcast().unwrap_or_else(Self::Client)
+    }
+}
+
+// Handlers that fail because of the store answer with the matching error code.
+impl From<KvError> for MaelstromError {
+    fn from(error: KvError) -> Self {
+        match error {
+            KvError::Timeout => Self::Timeout("timed out waiting for the key-value store".into()),
+            KvError::KeyDoesNotExist(text) => Self::KeyDoesNotExist(text),
+            KvError::KeyAlreadyExists(text) => Self::KeyAlreadyExists(text),
+            KvError::PreconditionFailed(text) => Self::PreconditionFailed(text),
+            KvError::TxnConflict(text) => Self::TxnConflict(text),
+            KvError::TemporarilyUnavailable(text) => Self::TemporarilyUnavailable(text),
+            KvError::Service(error) => error,
+            KvError::Client(error) => error.into(),
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timed out waiting for the key-value store"),
+            Self::KeyDoesNotExist(text) => write!(f, "key does not exist: {text}"),
+            Self::KeyAlreadyExists(text) => write!(f, "key already exists: {text}"),
+            Self::PreconditionFailed(text) => write!(f, "precondition failed: {text}"),
+            Self::TxnConflict(text) => write!(f, "transaction conflict: {text}"),
+            Self::TemporarilyUnavailable(text) => write!(f, "temporarily unavailable: {text}"),
+            Self::Service(error) => write!(f, "key-value store failed: {error}"),
+            Self::Client(error) => write!(f, "{error:#}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+// What each key of `KvClient::read_many` came to.
+pub type Reads<T> = HashMap<String, Result<Option<T>, KvError>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CasResponse {
+    Ok,
+    Retry,
+}
+
+// How `update` and `fetch_add` wait after losing a compare-and-set. Nodes that lost against the
+// same write would try again all at once with a fixed delay, so the default one is random.
+#[derive(Debug, Clone)]
+pub struct Contention {
+    // Lost compare-and-sets after which the update fails with `KvError::PreconditionFailed`,
+    // retried until one goes through when `None`.
+    pub max_retries: Option<u32>,
+    pub backoff: Arc<dyn Backoff>,
+}
+
+impl Default for Contention {
+    fn default() -> Self {
+        Self {
+            max_retries: None,
+            backoff: Arc::new(DecorrelatedJitter {
+                base: Duration::from_millis(5),
+                max: Duration::from_millis(100),
+            }),
+        }
+    }
+}
+
+// The key-value services of Maelstrom, which all speak the same protocol and only differ in
+// their consistency guarantees. Values are anything that serializes to JSON.
+//
+// Requests are rpcs of the node, their replies are handed to the callback on the main loop while
+// the node keeps handling other messages. Handlers answer through a `Responder` from the callback.
+pub trait KvClient: Clone + 'static {
+    // Sends the requests to the service and takes care of retrying them.
+    fn service(&self) -> &ServiceClient;
+
+    // `None` when the key does not exist.
+    fn read<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: DeserializeOwned,
+        N: Node,
+        O: Write,
+    {
+        let read = Request::Read::<()> { key };
+        request(self, ctx, read, move |node, response, ctx| {
+            let value = match response {
+                Ok(Response::ReadOk { value }) => serde_json::from_value(value)
+                    .map(Some)
+                    .context("parsing value")
+                    .map_err(KvError::Client),
+                Ok(_) => Err(unexpected()),
+                Err(KvError::KeyDoesNotExist(_)) => Ok(None),
+                Err(error) => Err(error),
+            };
+            callback(node, value, ctx)
+        })
+    }
+
+    // Reads all keys at once instead of one round trip after the other. The callback gets every
+    // key with what its read came to, once the last one is in.
+    fn read_many<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        keys: impl IntoIterator<Item = impl Into<String>>,
+        callback: impl FnOnce(&mut N, Reads<T>, &mut NodeContext<N, I, O>) -> Result<()> + 'static,
+    ) where
+        T: DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+    {
+        let keys: HashSet<String> = keys.into_iter().map(Into::into).collect();
+        let gathered = Rc::new(RefCell::new(Gathered {
+            results: HashMap::new(),
+            remaining: keys.len(),
+            callback: Some(callback),
+        }));
+        for key in keys {
+            let reading = gathered.clone();
+            let read = key.clone();
+            let sent = self.read(ctx, &key, move |node, value, ctx| {
+                let done = reading.borrow_mut().insert(read, value);
+                match done {
+                    Some((callback, results)) => callback(node, results, ctx),
+                    None => Ok(()),
+                }
+            });
+            if let Err(error) = sent {
+                let done = gathered.borrow_mut().insert(key, Err(error));
+                if let Some((callback, results)) = done {
+                    ctx.after(Duration::ZERO, move |node, ctx| {
+                        callback(node, results, ctx)
+                    });
+                }
+            }
+        }
+        // Nothing to read.
+        let done = gathered.borrow_mut().take();
+        if let Some((callback, results)) = done {
+            ctx.after(Duration::ZERO, move |node, ctx| {
+                callback(node, results, ctx)
+            });
+        }
+    }
+
+    // Reads the key no older than this call, which seq-kv does not promise for plain reads: first
+    // writes a value nobody wrote before to a key of this node, the read then has to come after
+    // that write.
+    fn fresh_read<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+    {
+        let nonce = format!("fresh-read/{}", ctx.node_id());
+        let client = self.clone();
+        let key = key.to_string();
+        self.write(
+            ctx,
+            &nonce,
+            &ID_GENERATOR.next_id(),
+            move |node, written, ctx| match written {
+                Ok(()) => Ok(client.read(ctx, &key, callback)?),
+                Err(error) => callback(node, Err(error), ctx),
+            },
+        )
+    }
+
+    fn write<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        value: &T,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let write = Request::Write { key, value };
+        request(self, ctx, write, move |node, response, ctx| {
+            let written = response.and_then(|response| match response {
+                Response::WriteOk => Ok(()),
+                _ => Err(unexpected()),
+            });
+            callback(node, written, ctx)
+        })
+    }
+
+    // Sets the key to `to` if it holds `from`. With `create_if_not_exists` a key that does not
+    // exist is created with `to` whatever `from` is, without it that fails with
+    // `key_does_not_exist`.
+    //
+    // Unlike the other requests it is never sent again, a second one could lose against the
+    // first one going through and take that for a concurrent write. It fails with
+    // `KvError::Timeout` instead, which leaves open whether the key was set.
+    fn compare_and_set<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+        callback: impl FnOnce(
+            &mut N,
+            Result<CasResponse, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let cas = Request::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        };
+        let sent = self
+            .service()
+            .call_once(ctx, &cas, move |node, response, ctx| {
+                let result = match response.map_err(KvError::from) {
+                    Ok(Response::CasOk) => Ok(CasResponse::Ok),
+                    Ok(_) => Err(unexpected()),
+                    Err(KvError::PreconditionFailed(_)) => Ok(CasResponse::Retry),
+                    Err(error) => Err(error),
+                };
+                callback(node, result, ctx)
+            });
+        Ok(sent?)
+    }
+
+    // Keeps applying `update` to the latest value until the compare-and-set goes through, waiting
+    // between attempts that lost against a concurrent write. The callback gets the value that was
+    // written. A compare-and-set without a reply is not tried again, the update fails with
+    // `KvError::Timeout` since `update` may already have been applied.
+    fn update<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        contention: Contention,
+        update: impl FnMut(Option<T>) -> Result<T> + 'static,
+        callback: impl FnOnce(&mut N, Result<T, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize + DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+    {
+        let attempt = Update {
+            client: self.clone(),
+            key: key.to_string(),
+            contention,
+            retries: 0,
+            delay: None,
+            update: Box::new(update),
+        };
+        attempt.run(ctx, callback)
+    }
+
+    // Adds `delta` to the number stored under the key, the callback gets the number from before
+    // like `AtomicU64::fetch_add` does. A key that does not exist holds zero with
+    // `create_if_not_exists` and fails with `KvError::KeyDoesNotExist` without it.
+    fn fetch_add<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        delta: T,
+        contention: Contention,
+        create_if_not_exists: bool,
+        callback: impl FnOnce(&mut N, Result<T, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Add<Output = T> + Default + Copy + Serialize + DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+    {
+        // Whatever the last attempt read, the one that went through is the last.
+        let previous = Rc::new(Cell::new(T::default()));
+        let read = previous.clone();
+        let missing = key.to_string();
+        self.update(
+            ctx,
+            key,
+            contention,
+            move |current: Option<T>| {
+                let current = match current {
+                    Some(current) => current,
+                    None if create_if_not_exists => T::default(),
+                    None => return Err(KvError::KeyDoesNotExist(missing.clone()).into()),
+                };
+                read.set(current);
+                Ok(current + delta)
+            },
+            move |node, added, ctx| callback(node, added.map(|_| previous.get()), ctx),
+        )
+    }
+
+    // Reads the key every `interval` and hands the value to the callback whenever it changed,
+    // starting with the first read. Failed reads are handed on every time, polling goes on
+    // until the watch is cancelled.
+    fn watch<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        interval: Duration,
+        callback: impl FnMut(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Watch
+    where
+        T: DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+    {
+        let watch = Watch::default();
+        let watcher = Rc::new(RefCell::new(Watcher {
+            client: self.clone(),
+            key: key.to_string(),
+            interval,
+            last: None,
+            callback,
+            cancelled: watch.clone(),
+        }));
+        poll(watcher, ctx);
+        watch
+    }
+}
+
+// One round of `KvClient::update`, the next one is scheduled when the compare-and-set loses.
+struct Update<K, T> {
+    client: K,
+    key: String,
+    contention: Contention,
+    // Compare-and-sets lost so far.
+    retries: u32,
+    delay: Option<Duration>,
+    update: Box<dyn FnMut(Option<T>) -> Result<T>>,
+}
+
+impl<K, T> Update<K, T>
+where
+    K: KvClient,
+    T: Serialize + DeserializeOwned + 'static,
+{
+    fn run<N, I, O, F>(self, ctx: &mut NodeContext<N, I, O>, callback: F) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+        F: FnOnce(&mut N, Result<T, KvError>, &mut NodeContext<N, I, O>) -> Result<()> + 'static,
+    {
+        let client = self.client.clone();
+        let key = self.key.clone();
+        // Compared against exactly what was read, whatever `T` makes of it.
+        client.read(
+            ctx,
+            &key,
+            move |node, current: Result<Option<Value>, KvError>, ctx| {
+                self.attempt(node, ctx, current, callback)
+            },
+        )
+    }
+
+    // Sets what `update` makes of the current value, or starts over once another write got there
+    // first.
+    fn attempt<N, I, O, F>(
+        mut self,
+        node: &mut N,
+        ctx: &mut NodeContext<N, I, O>,
+        current: Result<Option<Value>, KvError>,
+        callback: F,
+    ) -> Result<()>
+    where
+        N: Node,
+        O: Write,
+        F: FnOnce(&mut N, Result<T, KvError>, &mut NodeContext<N, I, O>) -> Result<()> + 'static,
+    {
+        let prepared = current.and_then(|current| {
+            let old = current
+                .clone()
+                .map(serde_json::from_value)
+                .transpose()
+                .context("parsing current value")?;
+            let new = (self.update)(old)?;
+            let to = serde_json::to_value(&new).context("serializing new value")?;
+            Ok((current, to, new))
+        });
+        let (current, to, new) = match prepared {
+            Ok(prepared) => prepared,
+            Err(error) => return callback(node, Err(error), ctx),
+        };
+        let client = self.client.clone();
+        let key = self.key.clone();
+        // Only a key that was not there has to be created.
+        let create = current.is_none();
+        let from = current.unwrap_or(Value::Null);
+        let set =
+            client.compare_and_set(ctx, &key, &from, &to, create, move |node, result, ctx| {
+                match result {
+                    Ok(CasResponse::Ok) => callback(node, Ok(new), ctx),
+                    Ok(CasResponse::Retry)
+                        if self
+                            .contention
+                            .max_retries
+                            .is_some_and(|max_retries| self.retries >= max_retries) =>
+                    {
+                        let error = KvError::PreconditionFailed(format!(
+                            "gave up on {} after {} lost compare-and-sets",
+                            self.key, self.retries
+                        ));
+                        callback(node, Err(error), ctx)
+                    }
+                    Ok(CasResponse::Retry) => {
+                        self.retries += 1;
+                        let wait = self.contention.backoff.delay(self.delay);
+                        self.delay = Some(wait);
+                        ctx.after(wait, move |_, ctx| Ok(self.run(ctx, callback)?));
+                        Ok(())
+                    }
+                    Err(error) => callback(node, Err(error), ctx),
+                }
+            });
+        Ok(set?)
+    }
+}
+
+// The reads of `KvClient::read_many` that came in so far.
+struct Gathered<T, F> {
+    results: Reads<T>,
+    remaining: usize,
+    callback: Option<F>,
+}
+
+impl<T, F> Gathered<T, F> {
+    // Hands out the callback with all results once the last read is in.
+    fn insert(&mut self, key: String, value: Result<Option<T>, KvError>) -> Option<(F, Reads<T>)> {
+        self.results.insert(key, value);
+        self.remaining -= 1;
+        self.take()
+    }
+
+    fn take(&mut self) -> Option<(F, Reads<T>)> {
+        if self.remaining > 0 {
+            return None;
+        }
+        let callback = self.callback.take()?;
+        Some((callback, std::mem::take(&mut self.results)))
+    }
+}
+
+// Stops the polling of `KvClient::watch`, clones stop the same watch.
+#[derive(Debug, Clone, Default)]
+pub struct Watch(Rc<Cell<bool>>);
+
+impl Watch {
+    pub fn cancel(&self) {
+        self.0.set(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.get()
+    }
+}
+
+struct Watcher<K, F> {
+    client: K,
+    key: String,
+    interval: Duration,
+    // What the last successful read returned, `None` before the first.
+    last: Option<Option<Value>>,
+    callback: F,
+    cancelled: Watch,
+}
+
+fn poll<K, T, F, N, I, O>(watcher: Rc<RefCell<Watcher<K, F>>>, ctx: &mut NodeContext<N, I, O>)
+where
+    K: KvClient,
+    T: DeserializeOwned + 'static,
+    F: FnMut(&mut N, Result<Option<T>, KvError>, &mut NodeContext<N, I, O>) -> Result<()> + 'static,
+    N: Node,
+    O: Write,
+{
+    let (client, key) = {
+        let watcher = watcher.borrow();
+        if watcher.cancelled.is_cancelled() {
+            return;
+        }
+        (watcher.client.clone(), watcher.key.clone())
+    };
+    let polled = watcher.clone();
+    let sent = client.read(
+        ctx,
+        &key,
+        move |node, value: Result<Option<Value>, KvError>, ctx| {
+            let changed = {
+                let mut watcher = polled.borrow_mut();
+                match value {
+                    Ok(value) if watcher.last.as_ref() == Some(&value) => None,
+                    Ok(value) => {
+                        watcher.last = Some(value.clone());
+                        Some(parse(value))
+                    }
+                    Err(error) => Some(Err(error)),
+                }
+            };
+            watch_next(polled.clone(), ctx);
+            match changed {
+                Some(value) => notify(&polled, node, value, ctx),
+                None => Ok(()),
+            }
+        },
+    );
+    if let Err(error) = sent {
+        ctx.after(Duration::ZERO, move |node, ctx| {
+            watch_next(watcher.clone(), ctx);
+            notify(&watcher, node, Err(error), ctx)
+        });
+    }
+}
+
+fn watch_next<K, T, F, N, I, O>(watcher: Rc<RefCell<Watcher<K, F>>>, ctx: &mut NodeContext<N, I, O>)
+where
+    K: KvClient,
+    T: DeserializeOwned + 'static,
+    F: FnMut(&mut N, Result<Option<T>, KvError>, &mut NodeContext<N, I, O>) -> Result<()> + 'static,
+    N: Node,
+    O: Write,
+{
+    let interval = watcher.borrow().interval;
+    ctx.after(interval, move |_, ctx| {
+        poll(watcher, ctx);
+        Ok(())
+    });
+}
+
+fn notify<K, T, F, N, I, O>(
+    watcher: &RefCell<Watcher<K, F>>,
+    node: &mut N,
+    value: Result<Option<T>, KvError>,
+    ctx: &mut NodeContext<N, I, O>,
+) -> Result<()>
+where
+    N: Node,
+    F: FnMut(&mut N, Result<Option<T>, KvError>, &mut NodeContext<N, I, O>) -> Result<()>,
+{
+    let mut watcher = watcher.borrow_mut();
+    if watcher.cancelled.is_cancelled() {
+        return Ok(());
+    }
+    (watcher.callback)(node, value, ctx)
+}
+
+pub(crate) fn parse<T: DeserializeOwned>(value: Option<Value>) -> Result<Option<T>, KvError> {
+    value
+        .map(serde_json::from_value)
+        .transpose()
+        .context("parsing value")
+        .map_err(KvError::Client)
+}
+
+fn request<K, T, N, I, O>(
+    client: &K,
+    ctx: &mut NodeContext<N, I, O>,
+    request: Request<T>,
+    callback: impl FnOnce(&mut N, Result<Response, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+    + 'static,
+) -> Result<(), KvError>
+where
+    K: KvClient,
+    T: Serialize,
+    N: Node,
+    O: Write,
+{
+    let sent = client
+        .service()
+        .call(ctx, &request, move |node, response, ctx| {
+            callback(node, response.map_err(KvError::from), ctx)
+        });
+    Ok(sent?)
+}
+
+// Sequentially consistent, reads may return stale values.
+#[derive(Debug, Clone)]
+pub struct SeqKv {
+    service: ServiceClient,
+}
+
+impl Default for SeqKv {
+    fn default() -> Self {
+        Self {
+            service: ServiceClient::new("seq-kv"),
+        }
+    }
+}
+
+impl SeqKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retry(retry: RetryPolicy) -> Self {
+        Self {
+            service: ServiceClient::new("seq-kv").with_retry(retry),
+        }
+    }
+}
+
+impl KvClient for SeqKv {
+    fn service(&self) -> &ServiceClient {
+        &self.service
+    }
+}
+
+// Linearizable.
+#[derive(Debug, Clone)]
+pub struct LinKv {
+    service: ServiceClient,
+}
+
+impl Default for LinKv {
+    fn default() -> Self {
+        Self {
+            service: ServiceClient::new("lin-kv"),
+        }
+    }
+}
+
+impl LinKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retry(retry: RetryPolicy) -> Self {
+        Self {
+            service: ServiceClient::new("lin-kv").with_retry(retry),
+        }
+    }
+}
+
+impl KvClient for LinKv {
+    fn service(&self) -> &ServiceClient {
+        &self.service
+    }
+}
+
+// Last write wins, reads may return stale values and concurrent writes are lost.
+#[derive(Debug, Clone)]
+pub struct LwwKv {
+    service: ServiceClient,
+}
+
+impl Default for LwwKv {
+    fn default() -> Self {
+        Self {
+            service: ServiceClient::new("lww-kv"),
+        }
+    }
+}
+
+impl LwwKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retry(retry: RetryPolicy) -> Self {
+        Self {
+            service: ServiceClient::new("lww-kv").with_retry(retry),
+        }
+    }
+}
+
+impl KvClient for LwwKv {
+    fn service(&self) -> &ServiceClient {
+        &self.service
+    }
+}
+
+// Keeps the keys of the client under a prefix of their own, so parts of a node like logs and
+// their offsets cannot step on each other's keys.
+#[derive(Debug, Clone)]
+pub struct Namespace<K> {
+    inner: K,
+    prefix: String,
+}
+
+impl<K: KvClient> Namespace<K> {
+    pub fn new(inner: K, prefix: impl Into<String>) -> Self {
+        Self {
+            inner,
+            prefix: prefix.into(),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn inner(&self) -> &K {
+        &self.inner
+    }
+
+    // The key the inner client is asked for.
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+}
+
+impl<K: KvClient> KvClient for Namespace<K> {
+    fn service(&self) -> &ServiceClient {
+        self.inner.service()
+    }
+
+    fn read<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: DeserializeOwned,
+        N: Node,
+        O: Write,
+    {
+        self.inner.read(ctx, &self.key(key), callback)
+    }
+
+    fn write<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        value: &T,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        self.inner.write(ctx, &self.key(key), value, callback)
+    }
+
+    fn compare_and_set<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+        callback: impl FnOnce(
+            &mut N,
+            Result<CasResponse, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let key = self.key(key);
+        self.inner
+            .compare_and_set(ctx, &key, from, to, create_if_not_exists, callback)
+    }
+}
+
+// A value split into one key per node, like `counter/n1` and `counter/n2`. Each node only writes
+// its own shard, so nodes never race each other's compare-and-sets, and reads merge all of them.
+#[derive(Debug, Clone)]
+pub struct ShardedKey {
+    name: String,
+}
+
+impl ShardedKey {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn shard(&self, node: &str) -> String {
+        format!("{}/{node}", self.name)
+    }
+
+    // The shard this node writes.
+    pub fn own<N: Node, I, O>(&self, ctx: &NodeContext<N, I, O>) -> String {
+        self.shard(ctx.node_id())
+    }
+
+    // The shards of every node in the cluster.
+    pub fn shards<N: Node, I, O>(&self, ctx: &NodeContext<N, I, O>) -> Vec<String> {
+        let mut shards: Vec<_> = ctx.node_ids().iter().map(|node| self.shard(node)).collect();
+        shards.sort();
+        shards
+    }
+
+    // Folds the shards of all nodes into one value, shards that were never written are left out.
+    // The callback gets the first error instead if any shard could not be read.
+    pub fn read_merged<K, T, A, N, I, O>(
+        &self,
+        client: &K,
+        ctx: &mut NodeContext<N, I, O>,
+        init: A,
+        mut merge: impl FnMut(A, T) -> A + 'static,
+        callback: impl FnOnce(&mut N, Result<A, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) where
+        K: KvClient,
+        T: DeserializeOwned + 'static,
+        A: 'static,
+        N: Node,
+        O: Write,
+    {
+        let shards = self.shards(ctx);
+        client.read_many(ctx, shards, move |node, reads: Reads<T>, ctx| {
+            let mut reads: Vec<_> = reads.into_iter().collect();
+            reads.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let merged = reads
+                .into_iter()
+                .try_fold(init, |merged, (_, read)| match read? {
+                    Some(value) => Ok(merge(merged, value)),
+                    None => Ok(merged),
+                });
+            callback(node, merged, ctx)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::Instant;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    // Reads the keys at once and keeps what came back.
+    struct ReadMany {
+        kv: MockKv,
+        keys: Vec<&'static str>,
+        reads: Option<Reads<u64>>,
+    }
+
+    impl Scenario for ReadMany {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.kv.read_many(
+                ctx,
+                self.keys.clone(),
+                |node: &mut Harness<Self>, reads, _| {
+                    node.scenario.reads = Some(reads);
+                    Ok(())
+                },
+            );
+            Ok(())
+        }
+    }
+
+    fn read_many(kv: MockKv, keys: &[&'static str]) -> Reads<u64> {
+        let mut cluster = Cluster::new([ReadMany {
+            kv,
+            keys: keys.to_vec(),
+            reads: None,
+        }]);
+        cluster.run_until(|cluster| cluster.scenario(0).reads.is_some());
+        cluster.scenario_mut(0).reads.take().unwrap()
+    }
+
+    #[test]
+    fn read_many_reads_all_keys_at_once() {
+        let latency = Duration::from_millis(50);
+        let kv = MockKv::new().with_latency(latency);
+        kv.insert("a", json!(1));
+        kv.insert("b", json!(2));
+        let started = Instant::now();
+        let reads = read_many(kv, &["a", "b", "missing"]);
+        // One after the other would take three times the latency.
+        assert!(started.elapsed() < latency * 2);
+        assert_eq!(reads.len(), 3);
+        assert_eq!(reads["a"].as_ref().unwrap(), &Some(1));
+        assert_eq!(reads["b"].as_ref().unwrap(), &Some(2));
+        assert_eq!(reads["missing"].as_ref().unwrap(), &None);
+    }
+
+    #[test]
+    fn read_many_hands_on_failed_reads_and_no_reads() {
+        let reads = read_many(MockKv::new().with_error_rate(1.0), &["a", "b"]);
+        assert_eq!(reads.len(), 2);
+        assert!(
+            reads
+                .values()
+                .all(|read| matches!(read, Err(KvError::TemporarilyUnavailable(_))))
+        );
+        assert!(read_many(MockKv::new(), &[]).is_empty());
+    }
+
+    // Writes the same key through two namespaces over one store, then reads it through the
+    // first.
+    struct Namespaces {
+        logs: Namespace<MockKv>,
+        offsets: Namespace<MockKv>,
+        read: Option<Result<Option<u64>, KvError>>,
+    }
+
+    impl Scenario for Namespaces {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            let offsets = self.offsets.clone();
+            let logs = self.logs.clone();
+            self.logs.write(ctx, "k1", &1, move |_, written, ctx| {
+                written?;
+                Ok(offsets.write(ctx, "k1", &2, move |_, written, ctx| {
+                    written?;
+                    Ok(logs.read(ctx, "k1", |node: &mut Harness<Self>, read, _| {
+                        node.scenario.read = Some(read);
+                        Ok(())
+                    })?)
+                })?)
+            })?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn namespaces_keep_their_keys_apart() {
+        let kv = MockKv::new();
+        let mut cluster = Cluster::new([Namespaces {
+            logs: Namespace::new(kv.clone(), "logs/"),
+            offsets: Namespace::new(kv.clone(), "offsets/"),
+            read: None,
+        }]);
+        cluster.run_until(|cluster| cluster.scenario(0).read.is_some());
+        let read = cluster.scenario_mut(0).read.take().unwrap();
+        assert_eq!(read.unwrap(), Some(1));
+        assert_eq!(kv.get("logs/k1"), Some(json!(1)));
+        assert_eq!(kv.get("offsets/k1"), Some(json!(2)));
+        assert_eq!(kv.len(), 2);
+    }
+
+    // Writes its shard of a sum, if it has a value, and keeps reading the whole sum.
+    struct Shard {
+        kv: MockKv,
+        sum: ShardedKey,
+        value: Option<u64>,
+        shards: Vec<String>,
+        reading: bool,
+        merged: Option<u64>,
+    }
+
+    impl Scenario for Shard {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.shards = self.sum.shards(ctx);
+            if let Some(value) = self.value {
+                self.kv
+                    .write(ctx, &self.sum.own(ctx), &value, |_, written, _| {
+                        Ok(written?)
+                    })?;
+            }
+            Ok(())
+        }
+
+        fn tick(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            if self.reading {
+                return Ok(());
+            }
+            self.reading = true;
+            self.sum.read_merged(
+                &self.kv,
+                ctx,
+                0,
+                |sum, value: u64| sum + value,
+                |node: &mut Harness<Self>, merged, _| {
+                    node.scenario.reading = false;
+                    node.scenario.merged = Some(merged?);
+                    Ok(())
+                },
+            );
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sharded_keys_merge_the_shards_of_all_nodes() {
+        let kv = MockKv::new();
+        let sum = ShardedKey::new("sum");
+        let mut cluster = Cluster::new([Some(1), Some(2), None].map(|value| Shard {
+            kv: kv.clone(),
+            sum: sum.clone(),
+            value,
+            shards: Vec::new(),
+            reading: false,
+            merged: None,
+        }));
+        cluster.run_until(|cluster| (0..3).all(|node| cluster.scenario(node).merged == Some(3)));
+        assert_eq!(cluster.scenario(0).shards, ["sum/n1", "sum/n2", "sum/n3"]);
+        assert_eq!(kv.get("sum/n2"), Some(json!(2)));
+        // The node without a value never wrote its shard.
+        assert_eq!(kv.len(), 2);
+    }
+
+    // Watches a key and keeps every value it was told about.
+    struct Watching {
+        kv: MockKv,
+        watch: Option<Watch>,
+        seen: Vec<Option<u64>>,
+    }
+
+    impl Scenario for Watching {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            let watch = self.kv.watch(
+                ctx,
+                "k",
+                Duration::from_millis(5),
+                |node: &mut Harness<Self>, value, _| {
+                    node.scenario.seen.push(value?);
+                    Ok(())
+                },
+            );
+            self.watch = Some(watch);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn watches_hand_on_changes_until_cancelled() {
+        let kv = MockKv::new();
+        let mut cluster = Cluster::new([Watching {
+            kv: kv.clone(),
+            watch: None,
+            seen: Vec::new(),
+        }]);
+        cluster.run_until(|cluster| cluster.scenario(0).seen.len() == 1);
+        kv.insert("k", json!(1));
+        cluster.run_until(|cluster| cluster.scenario(0).seen.len() == 2);
+        // Writing the same value again is no change.
+        kv.insert("k", json!(1));
+        cluster.run_for(Duration::from_millis(30));
+        assert_eq!(cluster.scenario(0).seen, [None, Some(1)]);
+
+        cluster.scenario(0).watch.as_ref().unwrap().cancel();
+        kv.insert("k", json!(2));
+        cluster.run_for(Duration::from_millis(30));
+        assert_eq!(cluster.scenario(0).seen, [None, Some(1)]);
+    }
+
+    type FreshReads = Vec<Result<Option<u64>, KvError>>;
+
+    // Reads the key fresh twice in a row, keeping the nonce written before each read.
+    struct FreshRead {
+        kv: MockKv,
+        reads: FreshReads,
+        nonces: Vec<Option<Value>>,
+    }
+
+    impl FreshRead {
+        fn read(&self, ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>) -> Result<()> {
+            self.kv
+                .fresh_read(ctx, "k", |node: &mut Harness<Self>, read, ctx| {
+                    let fresh = &mut node.scenario;
+                    fresh.reads.push(read);
+                    fresh.nonces.push(fresh.kv.get("fresh-read/n1"));
+                    if fresh.reads.len() < 2 {
+                        fresh.read(ctx)?;
+                    }
+                    Ok(())
+                })?;
+            Ok(())
+        }
+    }
+
+    impl Scenario for FreshRead {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.read(ctx)
+        }
+    }
+
+    fn fresh_read(kv: MockKv) -> (FreshReads, Vec<Option<Value>>) {
+        let mut cluster = Cluster::new([FreshRead {
+            kv,
+            reads: Vec::new(),
+            nonces: Vec::new(),
+        }]);
+        cluster.run_until(|cluster| cluster.scenario(0).reads.len() == 2);
+        let fresh = cluster.scenario_mut(0);
+        (
+            std::mem::take(&mut fresh.reads),
+            std::mem::take(&mut fresh.nonces),
+        )
+    }
+
+    #[test]
+    fn fresh_reads_write_a_new_nonce_before_reading() {
+        let kv = MockKv::new();
+        kv.insert("k", json!(7));
+        let (reads, nonces) = fresh_read(kv);
+        assert!(reads.iter().all(|read| read.as_ref().unwrap() == &Some(7)));
+        assert!(nonces.iter().all(Option::is_some));
+        assert_ne!(nonces[0], nonces[1]);
+    }
+
+    #[test]
+    fn fresh_reads_fail_when_the_nonce_is_not_written() {
+        let (reads, nonces) = fresh_read(MockKv::new().with_error_rate(1.0));
+        assert!(
+            reads
+                .iter()
+                .all(|read| matches!(read, Err(KvError::TemporarilyUnavailable(_))))
+        );
+        assert_eq!(nonces, [None, None]);
+    }
+}
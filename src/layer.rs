@@ -0,0 +1,444 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::{MaelstromError, Message, Node, NodeId, Reply, RequestInfo, id_gen};
+
+// A message as it is read from or written to the socket, before it is given a protocol type.
+pub type RawMessage = Message<Value>;
+
+pub enum Inbound {
+    // Hands the message to the next layer, or to the node after the innermost layer.
+    Pass(RawMessage),
+    // Swallows the message, nothing else gets to see it.
+    Drop,
+    // Swallows the message and answers it right away with the responses in order, they pass
+    // through the outbound side of the layers around this one.
+    Respond(Vec<RawMessage>),
+}
+
+// Behavior that is shared by every node, installed on the socket with `Socket::with_layer`.
+// Incoming messages pass through the outermost layer first, outgoing messages through the
+// innermost layer first. Layers see frames only, `Middleware` sees what the handlers do with
+// them.
+pub trait Layer: Send {
+    fn inbound(&mut self, message: RawMessage) -> Result<Inbound> {
+        Ok(Inbound::Pass(message))
+    }
+
+    // Returning `None` keeps the message from being sent.
+    fn outbound(&mut self, message: RawMessage) -> Result<Option<RawMessage>> {
+        Ok(Some(message))
+    }
+}
+
+// Hands a request on to the next middleware, or to the handler after the innermost one.
+pub type Next<'a, Req, Res> = &'a mut dyn FnMut(Req) -> Result<Reply<Res>, MaelstromError>;
+
+// Behavior around the request handlers of a node, installed on the runtime with
+// `Runtime::with_middleware`. It gets the requests as the node's types along with where they
+// came from, and what the handler made of them, errors included. A middleware that does not
+// call `next` answers the request itself. Requests pass through the outermost middleware
+// first, which is the one installed last.
+pub trait Middleware<N: Node> {
+    fn handle_request(
+        &mut self,
+        request: N::Request,
+        info: &RequestInfo,
+        next: Next<'_, N::Request, N::Response>,
+    ) -> Result<Reply<N::Response>, MaelstromError> {
+        let _ = info;
+        next(request)
+    }
+
+    fn handle_peer_request(
+        &mut self,
+        request: N::PeerRequest,
+        info: &RequestInfo,
+        next: Next<'_, N::PeerRequest, N::PeerResponse>,
+    ) -> Result<Reply<N::PeerResponse>, MaelstromError> {
+        let _ = info;
+        next(request)
+    }
+}
+
+// Runs the request through the given middleware from the outermost one inwards.
+pub(crate) fn handle_request<N: Node>(
+    middleware: &mut [Box<dyn Middleware<N>>],
+    request: N::Request,
+    info: &RequestInfo,
+    handler: Next<'_, N::Request, N::Response>,
+) -> Result<Reply<N::Response>, MaelstromError> {
+    match middleware.split_last_mut() {
+        None => handler(request),
+        Some((outer, inner)) => outer.handle_request(request, info, &mut |request| {
+            handle_request(inner, request, info, handler)
+        }),
+    }
+}
+
+pub(crate) fn handle_peer_request<N: Node>(
+    middleware: &mut [Box<dyn Middleware<N>>],
+    request: N::PeerRequest,
+    info: &RequestInfo,
+    handler: Next<'_, N::PeerRequest, N::PeerResponse>,
+) -> Result<Reply<N::PeerResponse>, MaelstromError> {
+    match middleware.split_last_mut() {
+        None => handler(request),
+        Some((outer, inner)) => outer.handle_peer_request(request, info, &mut |request| {
+            handle_peer_request(inner, request, info, handler)
+        }),
+    }
+}
+
+// Prints every message that passes through to stderr. As middleware it prints the requests
+// the node handles and the errors they failed with.
+#[derive(Debug, Default)]
+pub struct Logging;
+
+impl Logging {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Layer for Logging {
+    fn inbound(&mut self, message: RawMessage) -> Result<Inbound> {
+        let json = serde_json::to_string(&message).context("serializing inbound message")?;
+        eprintln!("<- {json}");
+        Ok(Inbound::Pass(message))
+    }
+
+    fn outbound(&mut self, message: RawMessage) -> Result<Option<RawMessage>> {
+        let json = serde_json::to_string(&message).context("serializing outbound message")?;
+        eprintln!("-> {json}");
+        Ok(Some(message))
+    }
+}
+
+impl<N> Middleware<N> for Logging
+where
+    N: Node,
+    N::Request: fmt::Debug,
+    N::PeerRequest: fmt::Debug,
+{
+    fn handle_request(
+        &mut self,
+        request: N::Request,
+        info: &RequestInfo,
+        next: Next<'_, N::Request, N::Response>,
+    ) -> Result<Reply<N::Response>, MaelstromError> {
+        log_handled(info, request, next)
+    }
+
+    fn handle_peer_request(
+        &mut self,
+        request: N::PeerRequest,
+        info: &RequestInfo,
+        next: Next<'_, N::PeerRequest, N::PeerResponse>,
+    ) -> Result<Reply<N::PeerResponse>, MaelstromError> {
+        log_handled(info, request, next)
+    }
+}
+
+fn log_handled<Req: fmt::Debug, Res>(
+    info: &RequestInfo,
+    request: Req,
+    next: Next<'_, Req, Res>,
+) -> Result<Reply<Res>, MaelstromError> {
+    eprintln!("handling {request:?} from {}", info.src);
+    let result = next(request);
+    if let Err(error) = &result {
+        eprintln!("handling a request from {} failed: {error}", info.src);
+    }
+    result
+}
+
+type RequestKey = (NodeId, u64);
+
+struct Seen {
+    // Every frame sent in reply, like the parts of a streamed or chunked reply. Empty while the
+    // request is still being handled.
+    replies: Vec<RawMessage>,
+}
+
+// Handles every request at most once, a retried request gets the replies of the first attempt.
+// Requests are remembered for `ttl`, and only the `capacity` most recent ones are kept.
+pub struct Dedup {
+    capacity: usize,
+    ttl: Duration,
+    seen: HashMap<RequestKey, Seen>,
+    order: VecDeque<(Instant, RequestKey)>,
+}
+
+impl Dedup {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn evict(&mut self, now: Instant) {
+        while let Some((at, key)) = self.order.front() {
+            if self.order.len() <= self.capacity && now.duration_since(*at) < self.ttl {
+                break;
+            }
+            self.seen.remove(key);
+            self.order.pop_front();
+        }
+    }
+}
+
+impl Layer for Dedup {
+    fn inbound(&mut self, message: RawMessage) -> Result<Inbound> {
+        // Responses are left alone, only requests are answered.
+        let Some(msg_id) = message.id() else {
+            return Ok(Inbound::Pass(message));
+        };
+        if message.body().get("in_reply_to").is_some() {
+            return Ok(Inbound::Pass(message));
+        }
+
+        let now = Instant::now();
+        self.evict(now);
+        let key = (message.src().clone(), msg_id);
+        match self.seen.get(&key) {
+            // The first attempt is answered once the node gets to it.
+            Some(Seen { replies }) if replies.is_empty() => Ok(Inbound::Drop),
+            Some(Seen { replies }) => Ok(Inbound::Respond(replies.clone())),
+            None => {
+                self.seen.insert(
+                    key.clone(),
+                    Seen {
+                        replies: Vec::new(),
+                    },
+                );
+                self.order.push_back((now, key));
+                Ok(Inbound::Pass(message))
+            }
+        }
+    }
+
+    fn outbound(&mut self, message: RawMessage) -> Result<Option<RawMessage>> {
+        let in_reply_to = message
+            .body()
+            .get("in_reply_to")
+            .and_then(id_gen::id_from_value);
+        if let Some(in_reply_to) = in_reply_to
+            && let Some(seen) = self.seen.get_mut(&(message.dest().clone(), in_reply_to))
+        {
+            seen.replies.push(message.clone());
+        }
+        Ok(Some(message))
+    }
+}
+
+// Runs the message through the given layers from the innermost one outwards.
+pub(crate) fn outbound(
+    layers: &mut [Box<dyn Layer>],
+    message: RawMessage,
+) -> Result<Option<RawMessage>> {
+    let mut message = message;
+    for layer in layers {
+        match layer.outbound(message)? {
+            Some(passed) => message = passed,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(message))
+}
+
+pub(crate) fn into_raw<T: serde::Serialize>(message: &Message<T>) -> Result<RawMessage> {
+    serde_json::to_value(message)
+        .and_then(serde_json::from_value)
+        .context("converting message to raw message")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::Client;
+    use crate::{EventIncjector, Init, Loopback, Never, NodeContext, RunConfig, Runtime, Socket};
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Read,
+        Write { value: u64 },
+        Fail,
+        // Answered with two parts.
+        Stream,
+        Streamed,
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Response {
+        ReadOk { value: u64 },
+        WriteOk,
+        Part { index: u64 },
+        StreamedOk { count: u64 },
+    }
+
+    struct Register {
+        value: u64,
+        streamed: u64,
+    }
+
+    impl Node for Register {
+        type Request = Request;
+        type Response = Response;
+        type PeerRequest = Never;
+        type PeerResponse = Never;
+        type InboundResponse = Never;
+        type Event = ();
+
+        type InitState = ();
+
+        fn from_init(_: Init, _: (), _: EventIncjector<Self>) -> Self {
+            Self {
+                value: 0,
+                streamed: 0,
+            }
+        }
+
+        fn handle_request(
+            &mut self,
+            request: Request,
+            _: RequestInfo,
+            _: &mut NodeContext<Self, impl Read, impl Write>,
+        ) -> Result<Reply<Response>, MaelstromError> {
+            match request {
+                Request::Read => Ok(Response::ReadOk { value: self.value }.into()),
+                Request::Write { value } => {
+                    self.value = value;
+                    Ok(Response::WriteOk.into())
+                }
+                Request::Fail => Err(MaelstromError::Crash("failed on purpose".to_string())),
+                Request::Stream => {
+                    self.streamed += 1;
+                    let parts = (0..2).map(|index| Response::Part { index });
+                    Ok(Reply::Stream(parts.collect()))
+                }
+                Request::Streamed => Ok(Response::StreamedOk {
+                    count: self.streamed,
+                }
+                .into()),
+            }
+        }
+    }
+
+    // Answers writes itself, they never reach the node.
+    struct ReadOnly;
+
+    impl Middleware<Register> for ReadOnly {
+        fn handle_request(
+            &mut self,
+            request: Request,
+            _: &RequestInfo,
+            next: Next<'_, Request, Response>,
+        ) -> Result<Reply<Response>, MaelstromError> {
+            match request {
+                Request::Write { .. } => Err(MaelstromError::NotSupported("read only".to_string())),
+                request => next(request),
+            }
+        }
+    }
+
+    // Where a request came from and the code of the error it failed with.
+    type Outcome = (String, Option<u32>);
+
+    struct Outcomes(Arc<Mutex<Vec<Outcome>>>);
+
+    impl Middleware<Register> for Outcomes {
+        fn handle_request(
+            &mut self,
+            request: Request,
+            info: &RequestInfo,
+            next: Next<'_, Request, Response>,
+        ) -> Result<Reply<Response>, MaelstromError> {
+            let result = next(request);
+            let code = result.as_ref().err().map(MaelstromError::code);
+            self.0.lock().unwrap().push((info.src.to_owned(), code));
+            result
+        }
+    }
+
+    #[test]
+    fn middleware_answers_requests_and_sees_their_outcome() {
+        let network = Loopback::new();
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let node = {
+            let socket = Socket::from_transport(network.transport("n1"));
+            let outcomes = Outcomes(outcomes.clone());
+            thread::spawn(move || {
+                Runtime::<Register, _, _>::new((), socket, RunConfig::default())?
+                    .with_middleware(ReadOnly)
+                    .with_middleware(outcomes)
+                    .run()
+            })
+        };
+        let mut client = Client::new(&network, "c1");
+        client.init("n1", &["n1"]);
+
+        let write = client.request("n1", json!({ "type": "write", "value": 5 }));
+        assert_eq!(write["code"], 10);
+        // The write never reached the node.
+        assert_eq!(client.request("n1", json!({ "type": "read" }))["value"], 0);
+        assert_eq!(client.request("n1", json!({ "type": "fail" }))["code"], 13);
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            [
+                ("c1".to_owned(), Some(10)),
+                ("c1".to_owned(), None),
+                ("c1".to_owned(), Some(13)),
+            ]
+        );
+
+        network.disconnect("n1");
+        node.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn dedup_replays_every_reply_without_handling_again() {
+        let network = Loopback::new();
+        let node = {
+            let socket = Socket::from_transport(network.transport("n1"))
+                .with_layer(Dedup::new(16, Duration::from_secs(60)));
+            thread::spawn(move || Register::run((), socket))
+        };
+        let mut client = Client::new(&network, "c1");
+        client.init("n1", &["n1"]);
+
+        let stream = || {
+            client.send_raw("n1", json!({ "type": "stream", "msg_id": 100 }));
+            [client.recv(), client.recv()]
+        };
+        let first = stream();
+        assert_eq!(
+            first.clone().map(|reply| reply["body"]["index"].clone()),
+            [0, 1]
+        );
+        assert_eq!(stream(), first);
+        assert_eq!(
+            client.request("n1", json!({ "type": "streamed" }))["count"],
+            1
+        );
+
+        network.disconnect("n1");
+        node.join().unwrap().unwrap();
+    }
+}
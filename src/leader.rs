@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use crate::kv::{KvClient, KvError, LinKv};
+use crate::lease::{Acquisition, Lease};
+use crate::{Node, NodeContext, NodeId};
+
+// Handed to `Node::handle_event` whenever this node starts or stops being the leader.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Leadership {
+    BecameLeader { token: u64 },
+    // `leader` is the node that took over, if it is known.
+    LostLeadership { leader: Option<NodeId> },
+}
+
+#[derive(Debug, Default)]
+struct Campaign {
+    running: bool,
+    leading: bool,
+    leader: Option<NodeId>,
+}
+
+// Keeps trying to take a lease and renews it while this node holds it, the holder of the lease
+// is the leader. Renewals happen three times per lease duration so a slow reply does not cost
+// the lease.
+//
+// Clones share the campaign.
+#[derive(Debug, Clone)]
+pub struct LeaderElector<K = LinKv> {
+    lease: Lease<K>,
+    campaign: Rc<RefCell<Campaign>>,
+}
+
+impl<K: KvClient> LeaderElector<K> {
+    pub fn new(lease: Lease<K>) -> Self {
+        Self {
+            lease,
+            campaign: Rc::default(),
+        }
+    }
+
+    pub fn lease(&self) -> &Lease<K> {
+        &self.lease
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.campaign.borrow().leading && self.lease.is_held()
+    }
+
+    // The leader as far as this node has seen, to forward requests to.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.campaign.borrow().leader.clone()
+    }
+
+    // Starts campaigning, from any handler or the first tick. Calling it again does nothing.
+    pub fn start<N, I, O>(&self, ctx: &mut NodeContext<N, I, O>)
+    where
+        N: Node,
+        N::Event: From<Leadership>,
+        I: Read,
+        O: Write,
+    {
+        let mut campaign = self.campaign.borrow_mut();
+        if campaign.running {
+            return;
+        }
+        campaign.running = true;
+        drop(campaign);
+        self.campaign(ctx);
+    }
+
+    // Stops campaigning and gives up the lease if this node holds it. No event is handed to the
+    // node for leadership given up this way.
+    pub fn resign<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        {
+            let mut campaign = self.campaign.borrow_mut();
+            campaign.running = false;
+            if campaign.leading {
+                campaign.leading = false;
+                campaign.leader = None;
+            }
+        }
+        self.lease.release(ctx, callback)
+    }
+
+    fn campaign<N, I, O>(&self, ctx: &mut NodeContext<N, I, O>)
+    where
+        N: Node,
+        N::Event: From<Leadership>,
+        I: Read,
+        O: Write,
+    {
+        if !self.campaign.borrow().running {
+            return;
+        }
+        let elector = self.clone();
+        let sent = self.lease.acquire(ctx, move |node, acquired, ctx| {
+            let event = elector.observe(ctx.node_id(), acquired);
+            elector.next(ctx);
+            match event {
+                Some(event) => node.handle_event(event.into(), ctx),
+                None => Ok(()),
+            }
+        });
+        // Tried again with the next renewal.
+        if sent.is_err() {
+            self.next(ctx);
+        }
+    }
+
+    fn next<N, I, O>(&self, ctx: &mut NodeContext<N, I, O>)
+    where
+        N: Node,
+        N::Event: From<Leadership>,
+        I: Read,
+        O: Write,
+    {
+        let elector = self.clone();
+        ctx.after(self.lease.duration() / 3, move |_, ctx| {
+            elector.campaign(ctx);
+            Ok(())
+        });
+    }
+
+    // Whether the node has to be told about a change of leadership.
+    fn observe(&self, node_id: &str, acquired: Result<Acquisition, KvError>) -> Option<Leadership> {
+        let mut campaign = self.campaign.borrow_mut();
+        if !campaign.running {
+            return None;
+        }
+        match acquired {
+            Ok(Acquisition::Acquired { token }) => {
+                campaign.leader = Some(NodeId::new(node_id));
+                let became = !campaign.leading;
+                campaign.leading = true;
+                became.then_some(Leadership::BecameLeader { token })
+            }
+            Ok(Acquisition::HeldBy { holder, .. }) => {
+                let leader = NodeId::new(&holder);
+                campaign.leader = Some(leader.clone());
+                let lost = campaign.leading;
+                campaign.leading = false;
+                lost.then_some(Leadership::LostLeadership {
+                    leader: Some(leader),
+                })
+            }
+            // Still the leader as long as the lease did not run out.
+            Err(_) if campaign.leading && !self.lease.is_held() => {
+                campaign.leading = false;
+                campaign.leader = None;
+                Some(Leadership::LostLeadership { leader: None })
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    // Campaigns from the start and resigns when told to, keeping the events it was handed.
+    struct Candidate {
+        elector: LeaderElector<MockKv>,
+        resign: bool,
+        events: Vec<Leadership>,
+    }
+
+    impl Scenario for Candidate {
+        type Event = Leadership;
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.elector.start(ctx);
+            Ok(())
+        }
+
+        fn tick(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            if std::mem::take(&mut self.resign) {
+                self.elector
+                    .resign(ctx, |_: &mut Harness<Self>, resigned, _| Ok(resigned?))?;
+            }
+            Ok(())
+        }
+
+        fn event(
+            &mut self,
+            event: Leadership,
+            _: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.events.push(event);
+            Ok(())
+        }
+    }
+
+    // The one leader all the nodes agree on, if there is one.
+    fn leader(cluster: &Cluster<Candidate>, nodes: &[usize]) -> Option<usize> {
+        let leaders: Vec<_> = nodes
+            .iter()
+            .copied()
+            .filter(|&node| cluster.scenario(node).elector.is_leader())
+            .collect();
+        let [leader] = leaders[..] else {
+            return None;
+        };
+        let id = NodeId::new(&format!("n{}", leader + 1));
+        nodes
+            .iter()
+            .all(|&node| cluster.scenario(node).elector.leader() == Some(id.clone()))
+            .then_some(leader)
+    }
+
+    #[test]
+    fn elects_one_leader_and_another_once_it_resigns() {
+        let kv = MockKv::new();
+        let mut cluster = Cluster::new((0..3).map(|_| Candidate {
+            elector: LeaderElector::new(Lease::new(
+                kv.clone(),
+                "leader",
+                Duration::from_millis(60),
+            )),
+            resign: false,
+            events: Vec::new(),
+        }));
+        let all = [0, 1, 2];
+        cluster.run_until(|cluster| leader(cluster, &all).is_some());
+        let first = leader(&cluster, &all).unwrap();
+        assert_eq!(
+            cluster.scenario(first).events,
+            [Leadership::BecameLeader { token: 1 }]
+        );
+
+        cluster.scenario_mut(first).resign = true;
+        let rest: Vec<usize> = all.into_iter().filter(|&node| node != first).collect();
+        cluster.run_until(|cluster| leader(cluster, &rest).is_some());
+        let second = leader(&cluster, &rest).unwrap();
+        assert_eq!(
+            cluster.scenario(second).events,
+            [Leadership::BecameLeader { token: 2 }]
+        );
+        // Resigning hands the node no event.
+        assert_eq!(cluster.scenario(first).events.len(), 1);
+        assert!(!cluster.scenario(first).elector.is_leader());
+    }
+}
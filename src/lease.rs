@@ -0,0 +1,369 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::kv::{CasResponse, KvClient, KvError, LinKv};
+use crate::{Node, NodeContext};
+
+// What is stored under the key of a lease. Expiry is wall clock time in milliseconds, which is
+// shared by all nodes as long as they run on one machine like under Maelstrom.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    holder: String,
+    expires: u64,
+    token: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Acquisition {
+    // The token only grows with every new holder, so whoever guards writes with it can turn
+    // away a holder whose lease ran out without it noticing.
+    Acquired { token: u64 },
+    HeldBy { holder: String, token: u64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    token: u64,
+    // Counted from before the lease was requested, so it runs out here no later than in the
+    // store.
+    until: Instant,
+    expires: u64,
+}
+
+// A lock on a key of the store that is only held for a while, so a holder that crashed does
+// not keep it forever. Holders renew it by acquiring it again before it runs out.
+//
+// Clones share whether the lease is held.
+#[derive(Debug, Clone)]
+pub struct Lease<K = LinKv> {
+    client: K,
+    key: String,
+    duration: Duration,
+    held: Rc<RefCell<Option<Held>>>,
+}
+
+impl<K: KvClient> Lease<K> {
+    pub fn new(client: K, key: impl Into<String>, duration: Duration) -> Self {
+        Self {
+            client,
+            key: key.into(),
+            duration,
+            held: Rc::default(),
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.token().is_some()
+    }
+
+    // The fencing token while the lease is held.
+    pub fn token(&self) -> Option<u64> {
+        self.held
+            .borrow()
+            .filter(|held| Instant::now() < held.until)
+            .map(|held| held.token)
+    }
+
+    // Takes the lease unless another node holds it, or extends it when this node does.
+    pub fn acquire<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Acquisition, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        let lease = self.clone();
+        let holder = ctx.node_id().to_string();
+        let requested = Instant::now();
+        self.client.read(
+            ctx,
+            &self.key,
+            move |node, current: Result<Option<Value>, KvError>, ctx| {
+                let current = current.and_then(|current| {
+                    let record = current
+                        .clone()
+                        .map(serde_json::from_value::<Record>)
+                        .transpose()
+                        .context("parsing lease")?;
+                    Ok((current, record))
+                });
+                let (current, record) = match current {
+                    Ok(current) => current,
+                    Err(error) => return callback(node, Err(error), ctx),
+                };
+                let now = unix_millis();
+                let token = match &record {
+                    Some(record) if record.expires > now && record.holder != holder => {
+                        lease.held.borrow_mut().take();
+                        let held_by = Acquisition::HeldBy {
+                            holder: record.holder.clone(),
+                            token: record.token,
+                        };
+                        return callback(node, Ok(held_by), ctx);
+                    }
+                    Some(record) if record.expires > now => record.token,
+                    Some(record) => record.token + 1,
+                    None => 1,
+                };
+                let expires = now + lease.duration.as_millis() as u64;
+                let to = Record {
+                    holder,
+                    expires,
+                    token,
+                };
+                lease.set(ctx, current, to, requested, callback)
+            },
+        )
+    }
+
+    // Lets the lease run out right away. The token stays, the next holder gets a larger one.
+    pub fn release<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        N: Node,
+        O: Write,
+    {
+        let Some(held) = self.held.borrow_mut().take() else {
+            ctx.after(Duration::ZERO, move |node, ctx| callback(node, Ok(()), ctx));
+            return Ok(());
+        };
+        let holder = ctx.node_id().to_string();
+        let from = Record {
+            holder: holder.clone(),
+            expires: held.expires,
+            token: held.token,
+        };
+        let to = Record {
+            holder,
+            expires: 0,
+            token: held.token,
+        };
+        // Losing means somebody else has the lease by now, which is as good as released.
+        self.client.compare_and_set(
+            ctx,
+            &self.key,
+            &from,
+            &to,
+            false,
+            move |node, result, ctx| {
+                let released = match result {
+                    Ok(_) | Err(KvError::KeyDoesNotExist(_)) => Ok(()),
+                    Err(error) => Err(error),
+                };
+                callback(node, released, ctx)
+            },
+        )
+    }
+
+    fn set<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        current: Option<Value>,
+        to: Record,
+        requested: Instant,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Acquisition, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<()>
+    where
+        N: Node,
+        O: Write,
+    {
+        let create = current.is_none();
+        let from = current.unwrap_or(Value::Null);
+        let to_value = serde_json::to_value(&to).context("serializing lease")?;
+        let lease = self.clone();
+        let set = self.client.compare_and_set(
+            ctx,
+            &self.key,
+            &from,
+            &to_value,
+            create,
+            move |node, result, ctx| match result {
+                Ok(CasResponse::Ok) => {
+                    *lease.held.borrow_mut() = Some(Held {
+                        token: to.token,
+                        until: requested + lease.duration,
+                        expires: to.expires,
+                    });
+                    callback(node, Ok(Acquisition::Acquired { token: to.token }), ctx)
+                }
+                // Somebody else got to the lease first, the next read tells who.
+                Ok(CasResponse::Retry) => Ok(lease.acquire(ctx, callback)?),
+                Err(error) => callback(node, Err(error), ctx),
+            },
+        );
+        Ok(set?)
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::Read;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    enum Op {
+        Acquire,
+        Release,
+    }
+
+    // Goes through the operations on the lease one at a time, keeping what the acquisitions came
+    // to.
+    struct Holder {
+        lease: Lease<MockKv>,
+        ops: VecDeque<Op>,
+        busy: bool,
+        acquired: Vec<Acquisition>,
+    }
+
+    impl Scenario for Holder {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.tick(ctx)
+        }
+
+        fn tick(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            if self.busy {
+                return Ok(());
+            }
+            let Some(op) = self.ops.pop_front() else {
+                return Ok(());
+            };
+            self.busy = true;
+            match op {
+                Op::Acquire => {
+                    self.lease
+                        .acquire(ctx, |node: &mut Harness<Self>, acquired, _| {
+                            node.scenario.busy = false;
+                            node.scenario.acquired.push(acquired?);
+                            Ok(())
+                        })?
+                }
+                Op::Release => {
+                    self.lease
+                        .release(ctx, |node: &mut Harness<Self>, released, _| {
+                            node.scenario.busy = false;
+                            Ok(released?)
+                        })?
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn holders(duration: Duration) -> Cluster<Holder> {
+        let kv = MockKv::new();
+        Cluster::new((0..2).map(|_| Holder {
+            lease: Lease::new(kv.clone(), "lease", duration),
+            ops: VecDeque::new(),
+            busy: false,
+            acquired: Vec::new(),
+        }))
+    }
+
+    // Runs the operation on the node and returns what it acquired, if anything.
+    fn run(cluster: &mut Cluster<Holder>, node: usize, op: Op) -> Option<Acquisition> {
+        let acquired = cluster.scenario(node).acquired.len();
+        cluster.scenario_mut(node).ops.push_back(op);
+        cluster.run_until(|cluster| {
+            let holder = cluster.scenario(node);
+            holder.ops.is_empty() && !holder.busy
+        });
+        cluster.scenario(node).acquired.get(acquired).cloned()
+    }
+
+    #[test]
+    fn one_node_holds_the_lease_until_it_releases_it() {
+        let mut cluster = holders(Duration::from_secs(5));
+        assert_eq!(
+            run(&mut cluster, 0, Op::Acquire),
+            Some(Acquisition::Acquired { token: 1 })
+        );
+        let held_by = Acquisition::HeldBy {
+            holder: "n1".into(),
+            token: 1,
+        };
+        assert_eq!(run(&mut cluster, 1, Op::Acquire), Some(held_by));
+        assert!(!cluster.scenario(1).lease.is_held());
+        // Renewing keeps the token.
+        assert_eq!(
+            run(&mut cluster, 0, Op::Acquire),
+            Some(Acquisition::Acquired { token: 1 })
+        );
+
+        run(&mut cluster, 0, Op::Release);
+        assert!(!cluster.scenario(0).lease.is_held());
+        assert_eq!(
+            run(&mut cluster, 1, Op::Acquire),
+            Some(Acquisition::Acquired { token: 2 })
+        );
+        assert_eq!(cluster.scenario(1).lease.token(), Some(2));
+    }
+
+    #[test]
+    fn leases_that_are_not_renewed_run_out() {
+        let duration = Duration::from_millis(50);
+        let mut cluster = holders(duration);
+        run(&mut cluster, 0, Op::Acquire);
+        assert_eq!(cluster.scenario(0).lease.token(), Some(1));
+
+        cluster.run_for(duration);
+        assert!(!cluster.scenario(0).lease.is_held());
+        assert_eq!(
+            run(&mut cluster, 1, Op::Acquire),
+            Some(Acquisition::Acquired { token: 2 })
+        );
+        // The old holder learns who took over.
+        let held_by = Acquisition::HeldBy {
+            holder: "n2".into(),
+            token: 2,
+        };
+        assert_eq!(run(&mut cluster, 0, Op::Acquire), Some(held_by));
+    }
+}
@@ -1,28 +1,130 @@
+use std::borrow::Borrow;
 use std::collections::HashSet;
-use std::io::{Read, Write};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+#[cfg(not(feature = "simd-json"))]
+use serde_json::value::RawValue;
 
+pub use self::ack_tracker::AckTracker;
+#[cfg(feature = "tokio")]
+pub use self::async_node::{AsyncEventInjector, AsyncNode, AsyncSocket};
+pub use self::backoff::Backoff;
+pub use self::barrier::Barrier;
+pub use self::bloom::BloomFilter;
+pub use self::buffered_counter::BufferedCounter;
+pub use self::cached_kv::CachedKv;
+pub use self::capture::{Capture, ReplayTransport};
+pub use self::causal::{CausalOrder, Stamped, VectorClock};
+pub use self::cluster_config::{ClusterConfig, Versioned};
+pub use self::composite::{CompositeKey, Entries};
+pub use self::config::{Backpressure, GossipThrottle, PanicPolicy, ReaderSupervision, RunConfig};
+pub use self::context::NodeContext;
+#[cfg(feature = "cbor")]
+pub use self::encoding::Cbor;
+#[cfg(feature = "msgpack")]
+pub use self::encoding::MessagePack;
+pub use self::encoding::{Encoding, Json};
+pub use self::error::MaelstromError;
+pub use self::gossip::{Aimd, ElementSet, Gossip, GossipState, Gossiper, Matched, RumorMonger};
 pub use self::id_gen::ID_GENERATOR;
-pub use self::seq_kv::SeqKv;
+pub use self::kv::{
+    Contention, KvClient, KvError, LinKv, LwwKv, Namespace, SeqKv, ShardedKey, Watch,
+};
+pub use self::layer::{Dedup, Inbound, Layer, Logging, Middleware, Next, RawMessage};
+pub use self::leader::{LeaderElector, Leadership};
+pub use self::lease::{Acquisition, Lease};
+pub use self::merkle::MerkleTree;
+pub use self::mock_kv::MockKv;
+pub use self::node_id::NodeId;
+pub use self::outbox::Outbox;
+pub use self::pool::{PooledNode, WorkerContext};
+pub use self::reliable::ReliableSender;
+pub use self::reply::{Reply, Responder};
+pub use self::runtime::{QueueDepths, Runtime};
+pub use self::service::{LinTso, RetryPolicy, ServiceClient, ServiceError};
+pub use self::session_kv::SessionKv;
+pub use self::sink::MessageSink;
+pub use self::stats::{
+    OperationSnapshot, ServiceSnapshot, ServiceStats, WriterSnapshot, WriterStats,
+};
+pub use self::topology::{SpanningTree, Topology};
+pub use self::transport::{
+    Loopback, LoopbackTransport, StdioTransport, TcpTransport, Transport, UnixTransport,
+};
 
+use self::buffers::Buffers;
+use self::diagnostics::{Subject, Warning};
+use self::queue::Queue;
+use self::timer::Timer;
+use self::transport::SideChannels;
+use self::writer::Writer;
+
+pub mod ack_tracker;
+#[cfg(feature = "tokio")]
+pub mod async_node;
+pub mod backoff;
+pub mod barrier;
+pub mod bloom;
+pub mod buffered_counter;
+mod buffers;
+pub mod cached_kv;
+pub mod capture;
+pub mod causal;
+pub mod cluster_config;
+pub mod composite;
+pub mod config;
+pub mod context;
+mod diagnostics;
+pub mod encoding;
+pub mod error;
+pub mod gossip;
 pub mod id_gen;
-pub mod seq_kv;
+pub mod kv;
+pub mod layer;
+pub mod leader;
+pub mod lease;
+pub mod merkle;
+pub mod mock_kv;
+pub mod node_id;
+pub mod outbox;
+pub mod pool;
+mod probe;
+mod queue;
+pub mod reliable;
+pub mod reply;
+pub mod rpc;
+pub mod runtime;
+pub mod service;
+pub mod session_kv;
+pub mod sink;
+pub mod stats;
+#[cfg(test)]
+mod testing;
+mod thread;
+mod timer;
+pub mod topology;
+pub mod transport;
+mod wheel;
+mod writer;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message<T> {
-    src: String,
-    dest: String,
+    src: NodeId,
+    dest: NodeId,
     body: MessageBody<T>,
 }
 
 impl<T> Message<T> {
-    pub fn new(src: String, dest: String, body: T) -> Self {
+    pub fn new(src: impl Into<NodeId>, dest: impl Into<NodeId>, body: T) -> Self {
         Self {
-            src,
-            dest,
+            src: src.into(),
+            dest: dest.into(),
             body: MessageBody {
                 id: None,
                 kind: body,
@@ -30,32 +132,104 @@ impl<T> Message<T> {
         }
     }
 
-    pub fn with_id(mut self, id: u32) -> Self {
+    pub fn with_id(mut self, id: u64) -> Self {
         self.body.id = Some(id);
         self
     }
+
+    pub fn src(&self) -> &NodeId {
+        &self.src
+    }
+
+    pub fn dest(&self) -> &NodeId {
+        &self.dest
+    }
+
+    pub fn id(&self) -> Option<u64> {
+        self.body.id
+    }
+
+    pub fn body(&self) -> &T {
+        &self.body.kind
+    }
+
+    pub fn body_mut(&mut self) -> &mut T {
+        &mut self.body.kind
+    }
+
+    // Returns the source, destination, id and body.
+    pub fn into_parts(self) -> (NodeId, NodeId, Option<u64>, T) {
+        (self.src, self.dest, self.body.id, self.body.kind)
+    }
+
+    // Answers this message, the reply goes back to where it came from.
+    pub fn reply_to<R>(incoming: &Message<T>, body: R) -> Message<Response<R>> {
+        Message::reply(
+            incoming.dest.clone(),
+            incoming.src.clone(),
+            incoming.body.id,
+            body,
+        )
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl<R> Message<Response<R>> {
+    pub(crate) fn reply(
+        src: impl Into<NodeId>,
+        dest: impl Into<NodeId>,
+        in_reply_to: Option<u64>,
+        inner: R,
+    ) -> Self {
+        Self {
+            src: src.into(),
+            dest: dest.into(),
+            body: MessageBody {
+                id: in_reply_to,
+                kind: Response { in_reply_to, inner },
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct MessageBody<T> {
-    #[serde(rename = "msg_id")]
-    id: Option<u32>,
+    #[serde(
+        rename = "msg_id",
+        default,
+        deserialize_with = "id_gen::deserialize_id"
+    )]
+    id: Option<u64>,
     #[serde(flatten)]
     kind: T,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Response<R> {
-    in_reply_to: Option<u32>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response<R> {
+    #[serde(default, deserialize_with = "id_gen::deserialize_id")]
+    in_reply_to: Option<u64>,
     #[serde(flatten)]
     inner: R,
 }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
+impl<R> Response<R> {
+    pub fn in_reply_to(&self) -> Option<u64> {
+        self.in_reply_to
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
 enum RequestResponse<Req, Res> {
     Request(Req),
     Response(Response<Res>),
+    // A reply to a raw rpc, see `NodeContext::raw_rpc`.
+    Raw(Response<Value>),
 }
 
 pub struct RequestInfo<'a> {
@@ -63,14 +237,72 @@ pub struct RequestInfo<'a> {
 }
 
 pub struct ResponseInfo {
-    pub in_reply_to: Option<u32>,
+    pub in_reply_to: Option<u64>,
 }
 
-enum Incoming<Req, Res, E> {
-    Message(Message<RequestResponse<Req, Res>>),
-    Event(E),
+enum Protocol<C, P> {
+    Topology(TopologyMessage),
+    Client(C),
+    Peer(P),
 }
 
+// Picks the protocol by the type of the request before parsing the rest, so the error of a known
+// request with bad fields is not lost to trying the other protocols.
+impl<'de, C, P> Deserialize<'de> for Protocol<C, P>
+where
+    C: DeserializeOwned,
+    P: DeserializeOwned,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let body = Value::deserialize(deserializer)?;
+        let kind = body
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| D::Error::missing_field("type"))?;
+        // Checked first, so nodes that still declare the message do not take it over.
+        if kind == "topology" {
+            serde_json::from_value(body)
+                .map(Self::Topology)
+                .map_err(D::Error::custom)
+        } else if probe::knows_type::<C>(kind) {
+            serde_json::from_value(body)
+                .map(Self::Client)
+                .map_err(D::Error::custom)
+        } else if probe::knows_type::<P>(kind) {
+            serde_json::from_value(body)
+                .map(Self::Peer)
+                .map_err(D::Error::custom)
+        } else {
+            Err(D::Error::unknown_variant(kind, &[]))
+        }
+    }
+}
+
+type IncomingMessage<N> = Message<
+    RequestResponse<
+        Protocol<<N as Node>::Request, <N as Node>::PeerRequest>,
+        <N as Node>::InboundResponse,
+    >,
+>;
+
+enum Incoming<N: Node> {
+    Message(IncomingMessage<N>),
+    Event(N::Event),
+    // The socket reached the end of its input.
+    Closed,
+    // The reader thread stopped, no more messages will arrive.
+    ReaderFailed(anyhow::Error),
+    Signal,
+    // A background thread panicked and the node is shut down.
+    Panicked(&'static str),
+}
+
+// Used as the peer protocol of nodes that do not talk to each other.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Never {}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename = "init")]
 pub struct Init {
@@ -82,157 +314,307 @@ pub struct Init {
 #[serde(tag = "type", rename = "init_ok")]
 struct InitOk {}
 
-pub struct EventIncjector<Req, Res, E> {
-    sender: mpsc::Sender<Incoming<Req, Res, E>>,
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename = "topology")]
+struct TopologyMessage {
+    topology: Topology,
 }
 
-impl<Req, Res, E> Clone for EventIncjector<Req, Res, E> {
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "topology_ok")]
+struct TopologyOk {}
+
+pub struct EventIncjector<N: Node> {
+    queue: Queue<Incoming<N>>,
+    timer: Timer<N::Event>,
+}
+
+impl<N: Node> Clone for EventIncjector<N> {
     fn clone(&self) -> Self {
         Self {
-            sender: self.sender.clone(),
+            queue: self.queue.clone(),
+            timer: self.timer.clone(),
         }
     }
 }
 
-impl<Req, Res, E> EventIncjector<Req, Res, E> {
-    pub fn send(&mut self, event: E) {
-        self.sender
-            .send(Incoming::Event(event))
-            .expect("failed to send event over channel")
+impl<N: Node> EventIncjector<N> {
+    pub fn send(&mut self, event: N::Event) {
+        if self.try_send(event).is_err() {
+            panic!("failed to send event, node has shut down")
+        }
+    }
+
+    // Hands the event back once the node has shut down.
+    pub fn try_send(&mut self, event: N::Event) -> Result<(), SendError<N::Event>> {
+        self.queue
+            .push(Incoming::Event(event))
+            .map_err(|incoming| match incoming {
+                Incoming::Event(event) => SendError(event),
+                _ => unreachable!("only the event is handed back"),
+            })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queue.is_closed()
+    }
+
+    pub fn send_after(&mut self, delay: Duration, event: N::Event) {
+        self.timer.after(delay, event)
+    }
+
+    pub fn send_interval(
+        &mut self,
+        period: Duration,
+        make: impl Fn() -> N::Event + Send + 'static,
+    ) {
+        self.timer.interval(period, make)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<E>(pub E);
+
+impl<E> fmt::Display for SendError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending event to a node that has shut down")
     }
 }
 
+impl<E: fmt::Debug> std::error::Error for SendError<E> {}
+
 pub trait Node: Sized {
     type Request: DeserializeOwned + Send + 'static;
-    type Response: serde::Serialize + DeserializeOwned + Send + 'static;
+    type Response: serde::Serialize;
+    type PeerRequest: DeserializeOwned + Send + 'static;
+    type PeerResponse: serde::Serialize;
+    type InboundResponse: DeserializeOwned + Send + 'static;
     type Event: Send + 'static;
 
     type InitState;
 
+    // How often `on_tick` is called by the run loop, ticks are disabled by default.
+    const TICK_INTERVAL: Option<Duration> = None;
+
     fn from_init(
         init: Init,
         init_state: Self::InitState,
-        event_injector: EventIncjector<Self::Request, Self::Response, Self::Event>,
+        event_injector: EventIncjector<Self>,
     ) -> Self;
 
     fn handle_request(
         &mut self,
         request: Self::Request,
         info: RequestInfo,
-        socket: &mut Socket<impl Read, impl Write>,
-    ) -> Result<Self::Response>;
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::Response>, MaelstromError>;
+
+    fn handle_peer_request(
+        &mut self,
+        request: Self::PeerRequest,
+        info: RequestInfo,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Self::PeerResponse>, MaelstromError> {
+        let _ = (request, ctx);
+        Err(MaelstromError::NotSupported(format!(
+            "peer requests from {} are not supported",
+            info.src
+        )))
+    }
 
     fn handle_response(
         &mut self,
-        response: Self::Response,
+        response: Self::InboundResponse,
         info: ResponseInfo,
-        socket: &mut Socket<impl Read, impl Write>,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
     ) -> Result<()> {
-        // By default receiving a response of any kind, does not matter.
-        let _ = (response, info, socket);
-        Ok(())
+        // By default responses are only routed to the rpc that is waiting for them.
+        ctx.dispatch_rpc(self, response, info)
     }
 
     fn handle_event(
         &mut self,
         event: Self::Event,
-        socket: &mut Socket<impl Read, impl Write>,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
     ) -> Result<()> {
         // By default no event handling is enabled.
-        let _ = (event, socket);
+        let _ = (event, ctx);
+        Ok(())
+    }
+
+    fn on_tick(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        let _ = ctx;
+        Ok(())
+    }
+
+    // Called with the topology Maelstrom sends, which is answered already. Ignored by default.
+    fn on_topology(&mut self, topology: Topology) {
+        let _ = topology;
+    }
+
+    // Called when the socket cannot be read anymore. By default the node stops with the error,
+    // returning `Ok` keeps the node running on events and ticks only.
+    fn on_reader_error(
+        &mut self,
+        error: anyhow::Error,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<()> {
+        let _ = ctx;
+        Err(error)
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        let _ = ctx;
         Ok(())
     }
 
-    fn run<I, O>(init_state: Self::InitState, mut socket: Socket<I, O>) -> Result<()>
+    fn run<I, O>(init_state: Self::InitState, socket: Socket<I, O>) -> Result<()>
     where
+        Self: 'static,
         I: Read,
         O: Write,
         Socket<I, O>: Send + 'static,
     {
-        let (tx, rx) = mpsc::channel();
-
-        let init = socket
-            .receive::<Init>()
-            .expect("first message to node should be init");
-        socket
-            .send(Message {
-                src: init.dest,
-                dest: init.src,
-                body: MessageBody {
-                    id: init.body.id,
-                    kind: Response {
-                        in_reply_to: init.body.id,
-                        inner: InitOk {},
-                    },
-                },
-            })
-            .context("sending init ok")?;
-        let mut this = Self::from_init(
-            init.body.kind,
-            init_state,
-            EventIncjector { sender: tx.clone() },
-        );
+        Self::run_with_config(init_state, socket, RunConfig::default())
+    }
 
-        {
-            let socket_tx = tx.clone();
-            let mut socket = socket.clone();
-            std::thread::spawn(move || -> Result<()> {
-                loop {
-                    let message = socket
-                        .receive::<RequestResponse<Self::Request, Self::Response>>()
-                        .context("receiving message from socket")?;
-                    socket_tx
-                        .send(Incoming::Message(message))
-                        .expect("failed to send incoming message from socket over channel");
-                }
-            })
-        };
+    fn run_with_config<I, O>(
+        init_state: Self::InitState,
+        socket: Socket<I, O>,
+        config: RunConfig,
+    ) -> Result<()>
+    where
+        Self: 'static,
+        I: Read,
+        O: Write,
+        Socket<I, O>: Send + 'static,
+    {
+        Runtime::<Self, I, O>::new(init_state, socket, config)?.run()
+    }
 
-        loop {
-            let incoming = rx.recv().expect("failed to receive message over channel");
-            match incoming {
-                Incoming::Message(message) => match message.body.kind {
-                    RequestResponse::Request(req) => {
-                        let response = this
-                            .handle_request(req, RequestInfo { src: &message.src }, &mut socket)
-                            .context("handling a request")?;
-
-                        let response_message = Message {
-                            src: message.dest,
-                            dest: message.src,
-                            body: MessageBody {
-                                id: message.body.id,
-                                kind: Response {
-                                    in_reply_to: message.body.id,
-                                    inner: response,
-                                },
-                            },
-                        };
-
-                        socket.send(response_message).context("sending response")?;
-                    }
-                    RequestResponse::Response(res) => {
-                        this.handle_response(
-                            res.inner,
-                            ResponseInfo {
-                                in_reply_to: res.in_reply_to,
-                            },
-                            &mut socket,
-                        )
-                        .context("handling a response")?;
-                    }
-                },
-                Incoming::Event(event) => this
-                    .handle_event(event, &mut socket)
-                    .context("handling event")?,
+    // Runs the node until the duration elapsed or it stopped on its own.
+    fn run_for<I, O>(
+        init_state: Self::InitState,
+        socket: Socket<I, O>,
+        duration: Duration,
+    ) -> Result<()>
+    where
+        Self: 'static,
+        I: Read,
+        O: Write,
+        Socket<I, O>: Send + 'static,
+    {
+        let mut runtime = Runtime::<Self, I, O>::new(init_state, socket, RunConfig::default())?;
+        runtime.run_for(duration)?;
+        runtime.shutdown()
+    }
+
+    // Runs the node until the condition holds for it or it stopped on its own.
+    fn run_until<I, O>(
+        init_state: Self::InitState,
+        socket: Socket<I, O>,
+        done: impl FnMut(&Self) -> bool,
+    ) -> Result<()>
+    where
+        Self: 'static,
+        I: Read,
+        O: Write,
+        Socket<I, O>: Send + 'static,
+    {
+        let mut runtime = Runtime::<Self, I, O>::new(init_state, socket, RunConfig::default())?;
+        runtime.run_until(done)?;
+        runtime.shutdown()
+    }
+}
+
+type Layers = Arc<Mutex<Vec<Box<dyn Layer>>>>;
+
+enum Output<O> {
+    // Written and flushed by whoever sends.
+    Locked(Arc<Mutex<O>>),
+    Thread(Writer),
+    Transport(Arc<dyn Transport>),
+}
+
+impl<O> Clone for Output<O> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Locked(stdout) => Self::Locked(stdout.clone()),
+            Self::Thread(writer) => Self::Thread(writer.clone()),
+            Self::Transport(transport) => Self::Transport(transport.clone()),
+        }
+    }
+}
+
+enum Source<I> {
+    Reader(BufReader<I>),
+    Transport(Arc<dyn Transport>),
+    // Frames of several sources read on threads of their own, see `Socket::with_side_channel`.
+    Merged {
+        frames: mpsc::Receiver<Result<Option<String>>>,
+        sender: mpsc::SyncSender<Result<Option<String>>>,
+    },
+}
+
+impl<I: Read> Source<I> {
+    // Replaces the line with the next one and returns how many bytes were read, zero at the end.
+    fn read_line(&mut self, line: &mut String) -> Result<usize> {
+        let frame = match self {
+            Self::Reader(reader) => {
+                line.clear();
+                return reader.read_line(line).context("reading message from stdin");
             }
+            Self::Transport(transport) => transport.recv().context("receiving frame")?,
+            Self::Merged { frames, .. } => frames.recv().unwrap_or(Ok(None))?,
+        };
+        let Some(frame) = frame else {
+            return Ok(0);
+        };
+        *line = frame;
+        // Counted as if the frame was on a line of its own.
+        Ok(line.len() + 1)
+    }
+}
+
+// Stdin and how many bytes of it have been read.
+struct Input<I> {
+    source: Source<I>,
+    offset: u64,
+}
+
+impl<I: Read> Input<I> {
+    fn new(stdin: I) -> Self {
+        Self {
+            source: Source::Reader(BufReader::new(stdin)),
+            offset: 0,
         }
     }
 }
 
+// A line of stdin and the byte offset it starts at.
+pub(crate) struct Line {
+    offset: u64,
+    text: String,
+}
+
 pub struct Socket<I, O> {
-    stdin: Arc<Mutex<I>>,
-    stdout: Arc<Mutex<O>>,
+    stdin: Arc<Mutex<Input<I>>>,
+    stdout: Output<O>,
+    layers: Layers,
+    sides: SideChannels,
+    max_frame_size: Option<usize>,
+    buffers: Buffers,
+    stats: WriterStats,
+}
+
+// A socket whose stream types are boxed, for code that stores or passes sockets around without
+// being generic over them.
+pub type BoxedSocket = Socket<Box<dyn Read + Send>, Box<dyn Write + Send>>;
+
+impl BoxedSocket {
+    pub fn boxed(stdin: impl Read + Send + 'static, stdout: impl Write + Send + 'static) -> Self {
+        Self::new(Box::new(stdin), Box::new(stdout))
+    }
 }
 
 impl<I, O> Clone for Socket<I, O> {
@@ -240,49 +622,565 @@ impl<I, O> Clone for Socket<I, O> {
         Self {
             stdin: self.stdin.clone(),
             stdout: self.stdout.clone(),
+            layers: self.layers.clone(),
+            sides: self.sides.clone(),
+            max_frame_size: self.max_frame_size,
+            buffers: self.buffers.clone(),
+            stats: self.stats.clone(),
         }
     }
 }
 
 impl<I, O> Socket<I, O> {
-    pub fn new(stdin: I, stdout: O) -> Self {
+    pub fn new(stdin: I, stdout: O) -> Self
+    where
+        I: Read,
+    {
+        Self {
+            stdin: Arc::new(Mutex::new(Input::new(stdin))),
+            stdout: Output::Locked(Arc::new(Mutex::new(stdout))),
+            layers: Layers::default(),
+            sides: SideChannels::default(),
+            max_frame_size: None,
+            buffers: Buffers::default(),
+            stats: WriterStats::default(),
+        }
+    }
+
+    // Hands stdout to a thread of its own, sending only queues the message. Messages sent while
+    // the thread is busy are written at once, which saves a flush for every message when sending
+    // a lot. `flush` waits until everything is written.
+    pub fn with_writer_thread(stdin: I, stdout: O) -> Self
+    where
+        I: Read,
+        O: Write + Send + 'static,
+    {
+        let buffers = Buffers::default();
+        let stats = WriterStats::default();
         Self {
-            stdin: Arc::new(Mutex::new(stdin)),
-            stdout: Arc::new(Mutex::new(stdout)),
+            stdin: Arc::new(Mutex::new(Input::new(stdin))),
+            stdout: Output::Thread(Writer::spawn(stdout, buffers.clone(), stats.clone())),
+            layers: Layers::default(),
+            sides: SideChannels::default(),
+            max_frame_size: None,
+            buffers,
+            stats,
         }
     }
+
+    // Wraps the layers that were added before, so the last added layer is the outermost one.
+    pub fn with_layer(self, layer: impl Layer + 'static) -> Self {
+        self.lock_layers().push(Box::new(layer));
+        self
+    }
+
+    // Sending a message that serializes to more bytes fails instead of handing it to the output.
+    pub fn with_max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = Some(bytes);
+        self
+    }
+
+    fn lock_layers(&self) -> std::sync::MutexGuard<'_, Vec<Box<dyn Layer>>> {
+        self.layers.lock().expect("failed to lock layers")
+    }
 }
 
 impl<I, O> Socket<I, O>
 where
     I: Read,
+    O: Write,
 {
     pub fn receive<R>(&mut self) -> Result<Message<R>>
     where
         R: DeserializeOwned,
     {
+        self.try_receive()?
+            .context("waiting for message from stdin")
+    }
+
+    // Returns `None` once stdin is closed.
+    pub fn try_receive<R>(&mut self) -> Result<Option<Message<R>>>
+    where
+        R: DeserializeOwned,
+    {
+        let Some(frame) = self.next_frame()? else {
+            return Ok(None);
+        };
+        serde_json::from_str(&frame.text)
+            .map(Some)
+            .with_context(|| format!("parsing message {:?} at byte {}", frame.text, frame.offset))
+    }
+
+    // Like `try_receive`, but only parses the body once it is known whether it is a request or a
+    // response.
+    pub(crate) fn try_receive_dispatched<Req, Res>(
+        &mut self,
+    ) -> Result<Option<Message<RequestResponse<Req, Res>>>>
+    where
+        Req: DeserializeOwned,
+        Res: DeserializeOwned,
+    {
+        loop {
+            let Some(frame) = self.next_frame()? else {
+                return Ok(None);
+            };
+            if let Some(message) = parse_frame(frame, &|_| false)? {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    // The next message that made it through the layers.
+    pub(crate) fn next_frame(&mut self) -> Result<Option<Line>> {
+        if self.lock_layers().is_empty() {
+            return self.read_line();
+        }
+        // Messages swallowed by a layer are never seen by the caller.
+        loop {
+            let Some(line) = self.read_line()? else {
+                return Ok(None);
+            };
+            let message: RawMessage = match serde_json::from_str(&line.text) {
+                Ok(message) => message,
+                Err(error) => {
+                    let origin = Origin {
+                        offset: line.offset,
+                        text: Some(&line.text),
+                    };
+                    skip_malformed(&origin, &error);
+                    continue;
+                }
+            };
+            let mut layers = self.lock_layers();
+            let mut message = Some(message);
+            for index in (0..layers.len()).rev() {
+                let inbound = message.take().expect("only passed messages reach a layer");
+                match layers[index]
+                    .inbound(inbound)
+                    .context("handling inbound message")?
+                {
+                    Inbound::Pass(passed) => message = Some(passed),
+                    Inbound::Drop => break,
+                    Inbound::Respond(responses) => {
+                        for response in responses {
+                            if let Some(response) =
+                                layer::outbound(&mut layers[index + 1..], response)
+                                    .context("handling outbound message")?
+                            {
+                                self.write(&response)?;
+                            }
+                        }
+                        break;
+                    }
+                }
+            }
+            drop(layers);
+            if let Some(message) = message {
+                let text =
+                    serde_json::to_string(&message).context("serializing inbound message")?;
+                return Ok(Some(Line {
+                    offset: line.offset,
+                    text,
+                }));
+            }
+        }
+    }
+
+    // Every message is on a line of its own, empty lines are skipped.
+    fn read_line(&mut self) -> Result<Option<Line>> {
         let mut stdin = self.stdin.lock().expect("failed to lock stdin");
-        serde_json::Deserializer::from_reader(&mut *stdin)
-            .into_iter::<Message<R>>()
-            .next()
-            .context("waiting for message from stdin")?
-            .context("reading message from stdin")
+        let mut line = String::new();
+        loop {
+            let offset = stdin.offset;
+            let read = stdin.source.read_line(&mut line)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            stdin.offset += read as u64;
+            if !line.trim().is_empty() {
+                line.truncate(line.trim_end().len());
+                return Ok(Some(Line { offset, text: line }));
+            }
+        }
     }
 }
 
+// Parses a frame like `Socket::try_receive_dispatched`, `None` for lines that are not a message at
+// all. Those are skipped, there is nobody to answer them. Replies the `raw` function picks by their
+// `in_reply_to` are left as JSON. Only requests the node cannot parse are an error, they are
+// answered.
+pub(crate) fn parse_frame<Req, Res>(
+    frame: Line,
+    raw: &dyn Fn(u64) -> bool,
+) -> Result<Option<Message<RequestResponse<Req, Res>>>, RejectedRequest>
+where
+    Req: DeserializeOwned,
+    Res: DeserializeOwned,
+{
+    let Line { offset, text } = frame;
+    #[cfg(not(feature = "simd-json"))]
+    let (parsed, origin) = (
+        parse_envelope(&text),
+        Origin {
+            offset,
+            text: Some(&text),
+        },
+    );
+    // The text is gone once simd-json parsed it in place.
+    #[cfg(feature = "simd-json")]
+    let mut text = text.into_bytes();
+    #[cfg(feature = "simd-json")]
+    let (parsed, origin) = (parse_envelope(&mut text), Origin { offset, text: None });
+    let (envelope, correlation) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            skip_malformed(&origin, &error);
+            return Ok(None);
+        }
+    };
+    parse_dispatched(&origin, envelope, correlation, raw)
+}
+
+// Where a frame was read, to point it out in warnings.
+struct Origin<'a> {
+    offset: u64,
+    text: Option<&'a str>,
+}
+
+impl fmt::Display for Origin<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at byte {}", self.offset)?;
+        match self.text {
+            Some(text) => write!(f, " {text:?}"),
+            None => Ok(()),
+        }
+    }
+}
+
+fn skip_malformed(origin: &Origin, error: &dyn fmt::Display) {
+    diagnostics::warn(
+        Warning::DecodeFailed,
+        format_args!("skipping malformed message {origin}: {error:#}"),
+    );
+}
+
+// Bodies make up most of what is received, with the `simd-json` feature the frame is parsed by
+// simd-json instead of serde_json. It is parsed once, and the body is taken from the tree it was
+// parsed into.
+#[cfg(not(feature = "simd-json"))]
+type Body<'a> = &'a RawValue;
+
+#[cfg(feature = "simd-json")]
+type Body<'a> = simd_json::BorrowedValue<'a>;
+
+#[derive(Deserialize)]
+struct Envelope<B> {
+    src: NodeId,
+    dest: NodeId,
+    body: B,
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn parse_envelope(text: &str) -> Result<(Envelope<Body<'_>>, Correlation)> {
+    let envelope: Envelope<Body> = serde_json::from_str(text)?;
+    let correlation = serde_json::from_str(envelope.body.get())?;
+    Ok((envelope, correlation))
+}
+
+#[cfg(feature = "simd-json")]
+fn parse_envelope(text: &mut [u8]) -> Result<(Envelope<Body<'_>>, Correlation)> {
+    let envelope: Envelope<Body> = simd_json::serde::from_slice(text)?;
+    let correlation = simd_json::serde::from_refborrowed_value(&envelope.body)?;
+    Ok((envelope, correlation))
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn parse_body<T: DeserializeOwned>(body: Body) -> Result<T> {
+    Ok(serde_json::from_str(body.get())?)
+}
+
+#[cfg(feature = "simd-json")]
+fn parse_body<T: DeserializeOwned>(body: Body) -> Result<T> {
+    Ok(simd_json::serde::from_borrowed_value(body)?)
+}
+
+#[derive(Deserialize)]
+struct Correlation {
+    #[serde(default, deserialize_with = "id_gen::deserialize_id")]
+    msg_id: Option<u64>,
+    #[serde(default, deserialize_with = "id_gen::deserialize_id")]
+    in_reply_to: Option<u64>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+// A request that does not fit the request types of the node. It is malformed if the node knows
+// its type, and not supported otherwise.
+#[derive(Debug)]
+pub(crate) struct RejectedRequest {
+    src: NodeId,
+    dest: NodeId,
+    id: Option<u64>,
+    kind: Option<String>,
+    malformed: bool,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for RejectedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} request {} from {}: {:#}",
+            if self.malformed {
+                "malformed"
+            } else {
+                "unsupported"
+            },
+            self.kind.as_deref().unwrap_or("without type"),
+            self.src,
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for RejectedRequest {}
+
+// Requests and responses are told apart by `in_reply_to`, so the body is parsed as one of them
+// only.
+fn parse_dispatched<Req, Res>(
+    origin: &Origin,
+    envelope: Envelope<Body>,
+    correlation: Correlation,
+    raw: &dyn Fn(u64) -> bool,
+) -> Result<Option<Message<RequestResponse<Req, Res>>>, RejectedRequest>
+where
+    Req: DeserializeOwned,
+    Res: DeserializeOwned,
+{
+    let body = envelope.body;
+    let body = match correlation.in_reply_to {
+        Some(in_reply_to) if raw(in_reply_to) => {
+            let Some(body) = parse_reply::<Value>(origin, body) else {
+                return Ok(None);
+            };
+            MessageBody {
+                id: body.id,
+                kind: RequestResponse::Raw(body.kind),
+            }
+        }
+        Some(_) => {
+            let Some(body) = parse_reply::<Res>(origin, body) else {
+                return Ok(None);
+            };
+            MessageBody {
+                id: body.id,
+                kind: RequestResponse::Response(body.kind),
+            }
+        }
+        None => {
+            let body: MessageBody<Req> = match parse_body(body) {
+                Ok(body) => body,
+                Err(error) => {
+                    let malformed = correlation
+                        .kind
+                        .as_deref()
+                        .is_some_and(probe::knows_type::<Req>);
+                    return Err(RejectedRequest {
+                        src: envelope.src,
+                        dest: envelope.dest,
+                        id: correlation.msg_id,
+                        kind: correlation.kind,
+                        malformed,
+                        error,
+                    });
+                }
+            };
+            MessageBody {
+                id: body.id,
+                kind: RequestResponse::Request(body.kind),
+            }
+        }
+    };
+    Ok(Some(Message {
+        src: envelope.src,
+        dest: envelope.dest,
+        body,
+    }))
+}
+
+// A late reply or an error the node does not expect must not stop it, such replies are dropped.
+fn parse_reply<Res: DeserializeOwned>(
+    origin: &Origin,
+    body: Body,
+) -> Option<MessageBody<Response<Res>>> {
+    parse_body(body)
+        .inspect_err(|error| {
+            diagnostics::warn(
+                Warning::DecodeFailed,
+                format_args!("dropping reply that does not parse {origin}: {error:#}"),
+            );
+        })
+        .ok()
+}
+
 impl<I, O> Socket<I, O>
 where
     O: Write,
 {
-    pub fn send<R>(&mut self, message: Message<R>) -> Result<()>
+    pub fn send<R>(&mut self, message: &Message<R>) -> Result<()>
     where
         R: serde::Serialize,
     {
-        let mut stdout = self.stdout.lock().expect("failed to lock stdout");
-        serde_json::to_writer(&mut *stdout, &message).context("writing message to stdout")?;
-        stdout.write_all(b"\n").context("writing newline")?;
-        stdout.flush().context("flushing stdout")?;
-        Ok(())
+        self.send_many([message])
+    }
+
+    // Writes all messages before flushing stdout once.
+    pub fn send_many<R, M>(&mut self, messages: impl IntoIterator<Item = M>) -> Result<()>
+    where
+        R: serde::Serialize,
+        M: Borrow<Message<R>>,
+    {
+        // The layers stay locked while writing, so messages leave in the order they passed them.
+        let mut layers = self.lock_layers();
+        let mut routed = self.sides.route(self.max_frame_size);
+        let mut frames = self.buffers.take();
+        for message in messages {
+            let message = message.borrow();
+            if layers.is_empty() {
+                routed.frame(&mut frames, message)?;
+                continue;
+            }
+            let message = layer::into_raw(message)?;
+            if let Some(message) =
+                layer::outbound(&mut layers, message).context("handling outbound message")?
+            {
+                routed.frame(&mut frames, &message)?;
+            }
+        }
+        routed.flush()?;
+        self.write_frames(frames)
+    }
+
+    // Answers a request of a type the node knows with a `malformed_request` error carrying what
+    // did not parse, and any other request with a `not_supported` error.
+    pub(crate) fn answer_rejected(&mut self, rejected: &RejectedRequest) -> Result<()> {
+        diagnostics::warn(
+            Warning::DecodeFailed,
+            format_args!(
+                "{} request {}: {:#}",
+                if rejected.malformed {
+                    "malformed"
+                } else {
+                    "unsupported"
+                },
+                Subject {
+                    src: &rejected.src,
+                    dest: &rejected.dest,
+                    kind: rejected.kind.clone(),
+                    id: rejected.id,
+                },
+                rejected.error
+            ),
+        );
+        let error = match &rejected.kind {
+            Some(kind) if rejected.malformed => MaelstromError::MalformedRequest(format!(
+                "malformed {kind} request: {:#}",
+                rejected.error
+            )),
+            Some(kind) => {
+                MaelstromError::NotSupported(format!("request type {kind} is not supported"))
+            }
+            None => MaelstromError::NotSupported(
+                "requests without a type are not supported".to_string(),
+            ),
+        };
+        self.send(&Message::reply(
+            rejected.dest.clone(),
+            rejected.src.clone(),
+            rejected.id,
+            error,
+        ))
+        .context("answering rejected request")
+    }
+
+    // Gives the message the next id of `ID_GENERATOR`, which is returned to correlate replies.
+    pub fn send_with_id<R>(&mut self, message: Message<R>) -> Result<u64>
+    where
+        R: serde::Serialize,
+    {
+        let message_id = ID_GENERATOR.next_id();
+        self.send(&message.with_id(message_id))?;
+        Ok(message_id)
+    }
+
+    fn write<R>(&self, message: &Message<R>) -> Result<()>
+    where
+        R: serde::Serialize,
+    {
+        let mut routed = self.sides.route(self.max_frame_size);
+        let mut frames = self.buffers.take();
+        routed.frame(&mut frames, message)?;
+        routed.flush()?;
+        self.write_frames(frames)
+    }
+
+    // Hands the buffer back once the frames are written, the writer thread does so itself.
+    fn write_frames(&self, frames: Vec<u8>) -> Result<()> {
+        if frames.is_empty() {
+            self.buffers.give(frames);
+            return Ok(());
+        }
+        let started = Instant::now();
+        let written = match &self.stdout {
+            Output::Locked(stdout) => {
+                let mut stdout = stdout.lock().expect("failed to lock stdout");
+                stdout
+                    .write_all(&frames)
+                    .context("writing message to stdout")
+                    .and_then(|()| stdout.flush().context("flushing stdout"))
+            }
+            Output::Thread(writer) => {
+                let frames_len = frames.len();
+                return writer.write(frames).inspect_err(|error| {
+                    diagnostics::warn(
+                        Warning::SendFailed,
+                        format_args!("writing {frames_len} bytes of frames: {error:#}"),
+                    );
+                });
+            }
+            Output::Transport(transport) => std::str::from_utf8(&frames)
+                .context("serialized frames are not UTF-8")
+                .and_then(|frames| {
+                    for frame in frames.lines() {
+                        transport.send(frame).context("sending frame")?;
+                    }
+                    transport.flush().context("flushing transport")
+                }),
+        };
+        self.stats.record(&frames, started.elapsed());
+        if let Err(error) = &written {
+            diagnostics::warn(
+                Warning::SendFailed,
+                format_args!("writing {} bytes of frames: {error:#}", frames.len()),
+            );
+        }
+        self.buffers.give(frames);
+        written
+    }
+
+    pub fn writer_stats(&self) -> &WriterStats {
+        &self.stats
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match &self.stdout {
+            Output::Locked(stdout) => {
+                let mut stdout = stdout.lock().expect("failed to lock stdout");
+                stdout.flush().context("flushing stdout")
+            }
+            Output::Thread(writer) => writer.flush(),
+            Output::Transport(transport) => transport.flush().context("flushing transport"),
+        }
     }
 }
 
@@ -296,7 +1194,16 @@ where
         Req: serde::Serialize,
         Res: for<'de> serde::Deserialize<'de>,
     {
-        self.send(message).context("sending message")?;
+        self.send(&message).context("sending message")?;
         Ok(self.receive::<Response<Res>>()?.body.kind.inner)
     }
 }
+
+pub(crate) fn write_frame<R>(frames: &mut Vec<u8>, message: &Message<R>) -> Result<()>
+where
+    R: serde::Serialize,
+{
+    serde_json::to_writer(&mut *frames, message).context("serializing message")?;
+    frames.push(b'\n');
+    Ok(())
+}
@@ -0,0 +1,232 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+// A part of the buckets of a tree: the root is level 0, the buckets themselves are the level as
+// deep as the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Range {
+    pub level: u32,
+    pub index: usize,
+}
+
+impl Range {
+    pub const ROOT: Self = Self { level: 0, index: 0 };
+
+    pub fn children(&self) -> [Self; 2] {
+        let level = self.level + 1;
+        [
+            Self {
+                level,
+                index: self.index * 2,
+            },
+            Self {
+                level,
+                index: self.index * 2 + 1,
+            },
+        ]
+    }
+
+    // The buckets of a tree of the depth that the range covers.
+    pub fn buckets(&self, depth: u32) -> std::ops::Range<usize> {
+        let shift = depth.saturating_sub(self.level);
+        self.index << shift..(self.index + 1) << shift
+    }
+}
+
+// Sums up keyed state, like the logs of kafka or the keys of a replica, as a tree of hashes.
+// Keys go to one of `2^depth` buckets by their hash, and every node of the tree hashes the
+// entries below it. Two nodes find where their states differ by comparing the hashes of ever
+// smaller ranges, one level per exchange, and then only send the entries of the buckets that
+// differ:
+//
+// 1. One side sends `summarize` of the ranges in question, starting with `Range::ROOT`.
+// 2. The other side answers with the ranges that are `diverging` from its own tree.
+// 3. The children of those are in question next, until the ranges are single buckets.
+//
+// Hashes do not change between processes, so trees built on different nodes can be compared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    depth: u32,
+    // Level by level from the root, so a range is at `2^level - 1 + index`.
+    hashes: Vec<u64>,
+}
+
+impl MerkleTree {
+    // Between 1 and 20 levels below the root, a tree keeps `2^(depth + 1) - 1` hashes.
+    pub fn new(depth: u32) -> Self {
+        let depth = depth.clamp(1, 20);
+        let mut tree = Self {
+            depth,
+            hashes: vec![0; (1 << (depth + 1)) - 1],
+        };
+        tree.rehash_all();
+        tree
+    }
+
+    pub fn from_entries<'a, K, V>(
+        depth: u32,
+        entries: impl IntoIterator<Item = (&'a K, &'a V)>,
+    ) -> Self
+    where
+        K: Hash + 'a,
+        V: Hash + 'a,
+    {
+        let mut tree = Self::new(depth);
+        for (key, value) in entries {
+            let bucket = tree.bucket(key);
+            tree.hashes[position(Range {
+                level: tree.depth,
+                index: bucket,
+            })] ^= hash(&(key, value));
+        }
+        tree.rehash_all();
+        tree
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn root(&self) -> u64 {
+        self.hashes[0]
+    }
+
+    pub fn bucket(&self, key: &impl Hash) -> usize {
+        (hash(key) >> (64 - self.depth)) as usize
+    }
+
+    // An entry that replaces another has to be removed with its old value first.
+    pub fn insert(&mut self, key: &impl Hash, value: &impl Hash) {
+        self.toggle(key, value);
+    }
+
+    pub fn remove(&mut self, key: &impl Hash, value: &impl Hash) {
+        self.toggle(key, value);
+    }
+
+    pub fn hash(&self, range: Range) -> Option<u64> {
+        (range.level <= self.depth && range.index < 1 << range.level)
+            .then(|| self.hashes[position(range)])
+    }
+
+    // The hashes of the ranges, for the other side to compare against. Ranges beyond the tree
+    // are left out.
+    pub fn summarize(&self, ranges: &[Range]) -> Vec<(Range, u64)> {
+        ranges
+            .iter()
+            .filter_map(|range| Some((*range, self.hash(*range)?)))
+            .collect()
+    }
+
+    // The ranges of the summary that hold something else in this tree.
+    pub fn diverging(&self, summary: &[(Range, u64)]) -> Vec<Range> {
+        summary
+            .iter()
+            .filter(|(range, hash)| self.hash(*range) != Some(*hash))
+            .map(|(range, _)| *range)
+            .collect()
+    }
+
+    // Entries and removals are both added to their bucket by XOR, which undoes itself.
+    fn toggle(&mut self, key: &impl Hash, value: &impl Hash) {
+        let mut range = Range {
+            level: self.depth,
+            index: self.bucket(key),
+        };
+        self.hashes[position(range)] ^= hash(&(key, value));
+        while range.level > 0 {
+            range = Range {
+                level: range.level - 1,
+                index: range.index / 2,
+            };
+            self.rehash(range);
+        }
+    }
+
+    // Everything above the buckets, from the bottom up.
+    fn rehash_all(&mut self) {
+        for level in (0..self.depth).rev() {
+            for index in 0..1 << level {
+                self.rehash(Range { level, index });
+            }
+        }
+    }
+
+    fn rehash(&mut self, range: Range) {
+        let [left, right] = range.children().map(|child| self.hashes[position(child)]);
+        self.hashes[position(range)] = hash(&(left, right));
+    }
+}
+
+fn position(range: Range) -> usize {
+    (1 << range.level) - 1 + range.index
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use super::*;
+
+    // Narrows the ranges in question down one level per exchange, the way two nodes would, and
+    // returns the buckets that differ with how many exchanges it took.
+    fn reconcile(ours: &MerkleTree, theirs: &MerkleTree) -> (BTreeSet<usize>, u32) {
+        let mut in_question = vec![Range::ROOT];
+        let mut exchanges = 0;
+        loop {
+            exchanges += 1;
+            let diverging = theirs.diverging(&ours.summarize(&in_question));
+            if diverging.is_empty() || diverging[0].level == ours.depth() {
+                let buckets = diverging.iter().map(|range| range.index).collect();
+                return (buckets, exchanges);
+            }
+            in_question = diverging.iter().flat_map(Range::children).collect();
+        }
+    }
+
+    #[test]
+    fn finds_the_buckets_of_diverging_entries() {
+        let ours: BTreeMap<u64, u64> = (0..1000).map(|key| (key, key * 2)).collect();
+        let mut theirs = ours.clone();
+        theirs.insert(17, 0);
+        theirs.remove(&500);
+        theirs.insert(5000, 1);
+
+        let depth = 8;
+        let ours = MerkleTree::from_entries(depth, &ours);
+        let theirs = MerkleTree::from_entries(depth, &theirs);
+        let (buckets, exchanges) = reconcile(&ours, &theirs);
+        let expected = [17, 500, 5000].map(|key: u64| ours.bucket(&key)).into();
+        assert_eq!(buckets, expected);
+        assert_eq!(exchanges, depth + 1);
+
+        assert_eq!(reconcile(&ours, &ours), (BTreeSet::new(), 1));
+    }
+
+    #[test]
+    fn changes_in_place_match_building_anew() {
+        let entries: BTreeMap<u64, &str> = BTreeMap::from([(1, "a"), (2, "b"), (3, "c")]);
+        let mut tree = MerkleTree::from_entries(4, &entries);
+        let before = tree.clone();
+
+        // Replacing a value takes removing the old one first.
+        tree.remove(&2u64, &"b");
+        tree.insert(&2u64, &"z");
+        let mut changed = entries.clone();
+        changed.insert(2, "z");
+        assert_eq!(tree, MerkleTree::from_entries(4, &changed));
+        assert_ne!(tree.root(), before.root());
+
+        tree.remove(&2u64, &"z");
+        tree.insert(&2u64, &"b");
+        assert_eq!(tree, before);
+    }
+}
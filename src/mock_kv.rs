@@ -0,0 +1,390 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::kv::{CasResponse, KvClient, KvError, parse};
+use crate::service::ServiceClient;
+use crate::{Node, NodeContext};
+
+// A key-value store in memory, for trying out nodes without Maelstrom. It behaves like lin-kv:
+// every operation happens the moment its reply is due, `latency` after the request.
+//
+// Clones share the store.
+#[derive(Debug, Clone)]
+pub struct MockKv {
+    store: Rc<RefCell<HashMap<String, Value>>>,
+    latency: Duration,
+    // How often an operation fails with `temporarily_unavailable` instead, between 0 and 1.
+    error_rate: f64,
+    service: ServiceClient,
+}
+
+impl Default for MockKv {
+    fn default() -> Self {
+        Self {
+            store: Rc::default(),
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            service: ServiceClient::new("mock-kv"),
+        }
+    }
+}
+
+impl MockKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_error_rate(mut self, error_rate: f64) -> Self {
+        self.error_rate = error_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.store.borrow().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: impl Into<String>, value: Value) {
+        self.store.borrow_mut().insert(key.into(), value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.borrow().is_empty()
+    }
+
+    // Runs the operation on the store once the reply is due, unless it is one that fails.
+    fn reply<R, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        operation: impl FnOnce(&mut HashMap<String, Value>) -> Result<R, KvError> + 'static,
+        callback: impl FnOnce(&mut N, Result<R, KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) where
+        N: Node,
+        O: Write,
+    {
+        let store = self.store.clone();
+        let fails = self.error_rate > 0.0 && rand::rng().random_bool(self.error_rate);
+        ctx.after(self.latency, move |node, ctx| {
+            let result = if fails {
+                Err(KvError::TemporarilyUnavailable("injected failure".into()))
+            } else {
+                operation(&mut store.borrow_mut())
+            };
+            callback(node, result, ctx)
+        });
+    }
+}
+
+impl KvClient for MockKv {
+    fn service(&self) -> &ServiceClient {
+        &self.service
+    }
+
+    fn read<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: DeserializeOwned,
+        N: Node,
+        O: Write,
+    {
+        let key = key.to_string();
+        self.reply(
+            ctx,
+            move |store| Ok(store.get(&key).cloned()),
+            move |node, value, ctx| callback(node, value.and_then(parse), ctx),
+        );
+        Ok(())
+    }
+
+    fn write<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        value: &T,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let key = key.to_string();
+        let value = serde_json::to_value(value).context("serializing value")?;
+        self.reply(
+            ctx,
+            move |store| {
+                store.insert(key, value);
+                Ok(())
+            },
+            callback,
+        );
+        Ok(())
+    }
+
+    fn compare_and_set<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+        callback: impl FnOnce(
+            &mut N,
+            Result<CasResponse, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let key = key.to_string();
+        let from = serde_json::to_value(from).context("serializing value")?;
+        let to = serde_json::to_value(to).context("serializing value")?;
+        self.reply(
+            ctx,
+            move |store| match store.get(&key) {
+                Some(current) if *current != from => Ok(CasResponse::Retry),
+                Some(_) => {
+                    store.insert(key, to);
+                    Ok(CasResponse::Ok)
+                }
+                None if create_if_not_exists => {
+                    store.insert(key, to);
+                    Ok(CasResponse::Ok)
+                }
+                None => Err(KvError::KeyDoesNotExist(format!(
+                    "key {key} does not exist"
+                ))),
+            },
+            callback,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::Read;
+    use std::thread;
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::kv::Contention;
+    use crate::{
+        EventIncjector, Init, Loopback, MaelstromError, Never, Reply, RequestInfo, Socket,
+        Transport,
+    };
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Add,
+        Append { element: u64 },
+        Read,
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    #[allow(clippy::enum_variant_names)]
+    enum Response {
+        AddOk {
+            previous: u64,
+        },
+        AppendOk,
+        ReadOk {
+            counter: Option<Value>,
+            list: Option<Value>,
+        },
+    }
+
+    // Adds to a counter with `fetch_add` and to a list with `update`.
+    struct Store {
+        kv: MockKv,
+    }
+
+    impl Node for Store {
+        type Request = Request;
+        type Response = Response;
+        type PeerRequest = Never;
+        type PeerResponse = Never;
+        type InboundResponse = Never;
+        type Event = ();
+
+        // The latency and error rate of the store.
+        type InitState = (Duration, f64);
+
+        fn from_init(
+            _: Init,
+            (latency, error_rate): (Duration, f64),
+            _: EventIncjector<Self>,
+        ) -> Self {
+            Self {
+                kv: MockKv::new()
+                    .with_latency(latency)
+                    .with_error_rate(error_rate),
+            }
+        }
+
+        fn handle_request(
+            &mut self,
+            request: Request,
+            _: RequestInfo,
+            ctx: &mut NodeContext<Self, impl Read, impl Write>,
+        ) -> Result<Reply<Response>, MaelstromError> {
+            let responder = ctx.defer()?;
+            match request {
+                Request::Add => self.kv.fetch_add(
+                    ctx,
+                    "counter",
+                    1,
+                    Contention::default(),
+                    true,
+                    move |_, previous, ctx| match previous {
+                        Ok(previous) => responder.respond(ctx, Response::AddOk { previous }),
+                        Err(error) => responder.respond(ctx, MaelstromError::from(error)),
+                    },
+                )?,
+                Request::Append { element } => self.kv.update(
+                    ctx,
+                    "list",
+                    Contention::default(),
+                    move |list: Option<Vec<u64>>| {
+                        let mut list = list.unwrap_or_default();
+                        list.push(element);
+                        Ok(list)
+                    },
+                    move |_, appended, ctx| match appended {
+                        Ok(_) => responder.respond(ctx, Response::AppendOk),
+                        Err(error) => responder.respond(ctx, MaelstromError::from(error)),
+                    },
+                )?,
+                Request::Read => responder.respond(
+                    ctx,
+                    Response::ReadOk {
+                        counter: self.kv.get("counter"),
+                        list: self.kv.get("list"),
+                    },
+                )?,
+            }
+            Ok(Reply::Deferred)
+        }
+    }
+
+    // Sends all requests at once so their compare-and-sets conflict, and returns the replies by
+    // the index of their request.
+    fn run(error_rate: f64, requests: &[Value]) -> (BTreeMap<usize, Value>, Value) {
+        let network = Loopback::new();
+        let node = {
+            let socket = Socket::from_transport(network.transport("n1"));
+            let init_state = (Duration::from_millis(1), error_rate);
+            thread::spawn(move || Store::run(init_state, socket))
+        };
+        let client = network.transport("c1");
+        let send = |msg_id: usize, body: &Value| {
+            let mut body = body.clone();
+            body["msg_id"] = json!(msg_id);
+            let frame = json!({ "src": "c1", "dest": "n1", "body": body });
+            client.send(&frame.to_string()).unwrap();
+        };
+        let receive = |replies: &mut BTreeMap<usize, Value>| {
+            let frame = client.recv().unwrap().expect("the client is connected");
+            let mut reply: Value = serde_json::from_str(&frame).unwrap();
+            let in_reply_to = reply["body"]["in_reply_to"].as_u64().unwrap() as usize;
+            replies.insert(in_reply_to, reply["body"].take());
+        };
+
+        let mut replies = BTreeMap::new();
+        send(
+            requests.len(),
+            &json!({ "type": "init", "node_id": "n1", "node_ids": ["n1"] }),
+        );
+        for (msg_id, request) in requests.iter().enumerate() {
+            send(msg_id, request);
+        }
+        while replies.len() <= requests.len() {
+            receive(&mut replies);
+        }
+        replies.remove(&requests.len());
+        send(requests.len() + 1, &json!({ "type": "read" }));
+        let mut read = BTreeMap::new();
+        receive(&mut read);
+
+        network.disconnect("n1");
+        node.join().unwrap().unwrap();
+        (replies, read.remove(&(requests.len() + 1)).unwrap())
+    }
+
+    #[test]
+    fn fetch_add_counts_every_acknowledged_add_once() {
+        let (replies, read) = run(0.3, &vec![json!({ "type": "add" }); 50]);
+        let previous: Vec<u64> = replies
+            .values()
+            .filter(|reply| reply["type"] == "add_ok")
+            .map(|reply| reply["previous"].as_u64().unwrap())
+            .collect();
+        assert!(
+            replies
+                .values()
+                .filter(|reply| reply["type"] != "add_ok")
+                .all(|reply| reply["code"] == 11)
+        );
+        assert!(!previous.is_empty() && previous.len() < replies.len());
+        // Every add saw the one before it.
+        let added = previous.len() as u64;
+        assert_eq!(
+            previous.into_iter().collect::<BTreeSet<_>>(),
+            (0..added).collect()
+        );
+        assert_eq!(read["counter"], added);
+    }
+
+    #[test]
+    fn update_applies_every_acknowledged_update_once() {
+        let requests: Vec<Value> = (0..50)
+            .map(|element| json!({ "type": "append", "element": element }))
+            .collect();
+        let (replies, read) = run(0.3, &requests);
+        let appended: BTreeSet<u64> = replies
+            .iter()
+            .filter(|(_, reply)| reply["type"] == "append_ok")
+            .map(|(element, _)| *element as u64)
+            .collect();
+        assert!(!appended.is_empty() && appended.len() < replies.len());
+        let list: Vec<u64> = serde_json::from_value(read["list"].clone()).unwrap();
+        assert_eq!(list.len(), appended.len());
+        assert_eq!(list.into_iter().collect::<BTreeSet<_>>(), appended);
+    }
+}
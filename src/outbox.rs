@@ -0,0 +1,57 @@
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{ID_GENERATOR, Message, Node, NodeContext, NodeId, Socket};
+
+// Messages queued by a handler, they are given an id and sent once the handler returns.
+#[derive(Default)]
+pub struct Outbox {
+    messages: Vec<(NodeId, Value)>,
+}
+
+impl Outbox {
+    pub fn push<B>(&mut self, dest: impl Into<NodeId>, body: B) -> Result<()>
+    where
+        B: Serialize,
+    {
+        let body = serde_json::to_value(body).context("serializing outbox message")?;
+        self.messages.push((dest.into(), body));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub(crate) fn flush<I, O>(&mut self, node_id: &NodeId, socket: &mut Socket<I, O>) -> Result<()>
+    where
+        O: Write,
+    {
+        if self.messages.is_empty() {
+            return Ok(());
+        }
+        let messages: Vec<_> = self
+            .messages
+            .drain(..)
+            .map(|(dest, body)| {
+                Message::new(node_id.clone(), dest, body).with_id(ID_GENERATOR.next_id())
+            })
+            .collect();
+        socket
+            .send_many(messages)
+            .context("sending outbox messages")
+    }
+}
+
+impl<N: Node, I, O> NodeContext<'_, N, I, O> {
+    pub fn outbox(&mut self) -> &mut Outbox {
+        self.outbox
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use serde::de::{DeserializeOwned, IgnoredAny};
+
+use crate::{
+    Init, InitOk, MaelstromError, Message, MessageBody, NodeId, RejectedRequest, RequestInfo,
+    RequestResponse, Socket,
+};
+
+pub struct WorkerContext<'a, I, O> {
+    node_id: &'a NodeId,
+    node_ids: &'a HashSet<NodeId>,
+    worker: usize,
+    socket: Socket<I, O>,
+}
+
+impl<I, O> WorkerContext<'_, I, O> {
+    pub fn node_id(&self) -> &str {
+        self.node_id
+    }
+
+    pub fn node_ids(&self) -> &HashSet<NodeId> {
+        self.node_ids
+    }
+
+    // The index of the worker handling the request, requests from one source always end up on
+    // the same worker.
+    pub fn worker(&self) -> usize {
+        self.worker
+    }
+}
+
+impl<I, O> WorkerContext<'_, I, O>
+where
+    O: Write,
+{
+    pub fn send<B>(&mut self, dest: impl Into<NodeId>, body: B) -> Result<()>
+    where
+        B: Serialize,
+    {
+        self.socket
+            .send(&Message::new(self.node_id.clone(), dest, body))
+    }
+}
+
+// A node whose requests are handled by a pool of worker threads. Handlers only get shared access,
+// so the node picks how its state is synchronized, e.g. a `Mutex`, a `RwLock` or atomics.
+// Requests from the same source are handled in the order they arrived, responses to messages sent
+// by the node are ignored.
+pub trait PooledNode: Sized + Sync {
+    type Request: DeserializeOwned + Send;
+    type Response: Serialize;
+
+    type InitState;
+
+    fn from_init(init: Init, init_state: Self::InitState) -> Self;
+
+    fn handle_request(
+        &self,
+        request: Self::Request,
+        info: RequestInfo,
+        ctx: &mut WorkerContext<impl Read, impl Write>,
+    ) -> Result<Self::Response, MaelstromError>;
+
+    fn on_shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn run<I, O>(
+        init_state: Self::InitState,
+        mut socket: Socket<I, O>,
+        workers: usize,
+    ) -> Result<()>
+    where
+        I: Read,
+        O: Write,
+        Socket<I, O>: Send,
+    {
+        if workers == 0 {
+            bail!("a pooled node needs at least one worker");
+        }
+
+        let init = socket
+            .receive::<Init>()
+            .expect("first message to node should be init");
+        socket
+            .send(&Message::reply(
+                init.dest,
+                init.src,
+                init.body.id,
+                InitOk {},
+            ))
+            .context("sending init ok")?;
+        let node_id = NodeId::from(&init.body.kind.node_id);
+        let node_ids: HashSet<NodeId> = init.body.kind.node_ids.iter().map(NodeId::from).collect();
+        let this = Self::from_init(init.body.kind, init_state);
+        let (node_id, node_ids, this) = (&node_id, &node_ids, &this);
+
+        std::thread::scope(|scope| {
+            let (senders, handles): (Vec<_>, Vec<_>) = (0..workers)
+                .map(|worker| {
+                    let (sender, receiver) = mpsc::channel::<Message<Self::Request>>();
+                    let mut ctx = WorkerContext {
+                        node_id,
+                        node_ids,
+                        worker,
+                        socket: socket.clone(),
+                    };
+                    let handle = std::thread::Builder::new()
+                        .name(format!("mael-worker-{worker}"))
+                        .spawn_scoped(scope, move || -> Result<()> {
+                            for message in receiver {
+                                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                                    this.handle_request(
+                                        message.body.kind,
+                                        RequestInfo { src: &message.src },
+                                        &mut ctx,
+                                    )
+                                }))
+                                .unwrap_or_else(|panic| Err(MaelstromError::from_panic(panic)));
+                                let sent = match result {
+                                    Ok(response) => ctx.socket.send(&Message::reply(
+                                        node_id.clone(),
+                                        message.src,
+                                        message.body.id,
+                                        response,
+                                    )),
+                                    Err(error) => {
+                                        eprintln!("request from {} failed: {error}", message.src);
+                                        ctx.socket.send(&Message::reply(
+                                            node_id.clone(),
+                                            message.src,
+                                            message.body.id,
+                                            error,
+                                        ))
+                                    }
+                                };
+                                sent.context("sending response")?;
+                            }
+                            Ok(())
+                        })
+                        .expect("failed to spawn worker thread");
+                    (sender, handle)
+                })
+                .unzip();
+
+            let read = loop {
+                let message = match socket.try_receive_dispatched::<Self::Request, IgnoredAny>() {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break Ok(()),
+                    Err(error) => match error.downcast_ref::<RejectedRequest>() {
+                        Some(rejected) => match socket.answer_rejected(rejected) {
+                            Ok(()) => continue,
+                            Err(error) => break Err(error),
+                        },
+                        None => break Err(error.context("receiving message from socket")),
+                    },
+                };
+                let RequestResponse::Request(request) = message.body.kind else {
+                    continue;
+                };
+                let mut hasher = DefaultHasher::new();
+                message.src.hash(&mut hasher);
+                let worker = (hasher.finish() % workers as u64) as usize;
+                let message = Message {
+                    src: message.src,
+                    dest: message.dest,
+                    body: MessageBody {
+                        id: message.body.id,
+                        kind: request,
+                    },
+                };
+                // A worker only hangs up after failing, its error is reported when joining.
+                if senders[worker].send(message).is_err() {
+                    break Ok(());
+                }
+            };
+
+            // Lets the workers finish the requests that are still queued.
+            drop(senders);
+            for handle in handles {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+                    .context("handling requests")?;
+            }
+            read
+        })?;
+
+        this.on_shutdown().context("shutting down")
+    }
+}
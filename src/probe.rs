@@ -0,0 +1,70 @@
+use std::fmt;
+use std::iter;
+
+use serde::de::value::{MapDeserializer, StrDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+// Whether `T` has a variant for messages of this type. `T` is deserialized from a body holding
+// nothing but the type, any error other than an unknown variant means the type itself is known.
+pub(crate) fn knows_type<T: DeserializeOwned>(kind: &str) -> bool {
+    !matches!(
+        T::deserialize(TypeOnly(kind)),
+        Err(ProbeError::UnknownVariant)
+    )
+}
+
+#[derive(Debug)]
+enum ProbeError {
+    UnknownVariant,
+    Other,
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::UnknownVariant => "unknown variant",
+            Self::Other => "probing failed",
+        })
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl de::Error for ProbeError {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        Self::Other
+    }
+
+    fn unknown_variant(_: &str, _: &'static [&'static str]) -> Self {
+        Self::UnknownVariant
+    }
+}
+
+// Looks like `{"type": kind}` to internally tagged types and like the unit variant `kind` to
+// externally tagged ones.
+struct TypeOnly<'a>(&'a str);
+
+impl<'de> de::Deserializer<'de> for TypeOnly<'_> {
+    type Error = ProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(MapDeserializer::new(iter::once(("type", self.0))))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let variant: StrDeserializer<'_, ProbeError> = self.0.into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Backpressure;
+
+struct Entry<T> {
+    item: T,
+    bounded: bool,
+}
+
+struct State<T> {
+    entries: VecDeque<Entry<T>>,
+    // Bounded items that are handed out before anything in `entries`.
+    urgent: VecDeque<T>,
+    bounded: usize,
+    capacity: Option<usize>,
+    backpressure: Backpressure,
+    closed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+// A multi-producer queue where only part of the items count towards the capacity, so control
+// items like shutdown notifications are never lost or blocked.
+pub(crate) struct Queue<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Queue<T> {
+    pub(crate) fn new(capacity: Option<usize>, backpressure: Backpressure) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    entries: VecDeque::new(),
+                    urgent: VecDeque::new(),
+                    bounded: 0,
+                    capacity,
+                    backpressure,
+                    closed: false,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State<T>> {
+        self.shared.state.lock().expect("failed to lock queue")
+    }
+
+    // The item is handed back once the queue is closed.
+    pub(crate) fn push(&self, item: T) -> Result<(), T> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(item);
+        }
+        state.entries.push_back(Entry {
+            item,
+            bounded: false,
+        });
+        drop(state);
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    // Pushes an item that counts towards the capacity. The item is handed back when it is
+    // rejected or the queue is closed, and items dropped to make room are returned as well.
+    // Urgent items skip ahead of everything that is not urgent.
+    pub(crate) fn push_bounded(&self, item: T, urgent: bool) -> Result<Option<T>, T> {
+        let mut state = self.lock();
+        let mut dropped = None;
+        if let Some(capacity) = state.capacity {
+            match state.backpressure {
+                Backpressure::Block => {
+                    while !state.closed && state.bounded >= capacity {
+                        state = self
+                            .shared
+                            .not_full
+                            .wait(state)
+                            .expect("failed to lock queue");
+                    }
+                }
+                Backpressure::DropOldest if state.bounded >= capacity => {
+                    // Urgent items are only dropped when nothing else is left.
+                    dropped = match state.entries.iter().position(|entry| entry.bounded) {
+                        Some(oldest) => state.entries.remove(oldest).map(|entry| entry.item),
+                        None => state.urgent.pop_front(),
+                    };
+                    state.bounded -= 1;
+                }
+                Backpressure::Reject if state.bounded >= capacity => return Err(item),
+                Backpressure::DropOldest | Backpressure::Reject => {}
+            }
+        }
+        if state.closed {
+            return Err(item);
+        }
+        if urgent {
+            state.urgent.push_back(item);
+        } else {
+            state.entries.push_back(Entry {
+                item,
+                bounded: true,
+            });
+        }
+        state.bounded += 1;
+        drop(state);
+        self.shared.not_empty.notify_one();
+        Ok(dropped)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        let state = self.lock();
+        state.entries.len() + state.urgent.len()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    // Stops accepting items and throws away the queued ones, producers waiting for room are
+    // woken up.
+    pub(crate) fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        state.entries.clear();
+        state.urgent.clear();
+        state.bounded = 0;
+        drop(state);
+        self.shared.not_full.notify_all();
+    }
+
+    // Closes the queue when dropped, so it also happens when the consumer returns early.
+    pub(crate) fn close_on_drop(&self) -> CloseOnDrop<T> {
+        CloseOnDrop(self.clone())
+    }
+
+    pub(crate) fn recv(&self) -> T {
+        let mut state = self.lock();
+        loop {
+            if let Some(item) = self.pop(&mut state) {
+                return item;
+            }
+            state = self
+                .shared
+                .not_empty
+                .wait(state)
+                .expect("failed to lock queue");
+        }
+    }
+
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(item) = self.pop(&mut state) {
+                return Some(item);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            state = self
+                .shared
+                .not_empty
+                .wait_timeout(state, remaining)
+                .expect("failed to lock queue")
+                .0;
+        }
+    }
+
+    fn pop(&self, state: &mut State<T>) -> Option<T> {
+        if let Some(item) = state.urgent.pop_front() {
+            state.bounded -= 1;
+            self.shared.not_full.notify_one();
+            return Some(item);
+        }
+        let entry = state.entries.pop_front()?;
+        if entry.bounded {
+            state.bounded -= 1;
+            self.shared.not_full.notify_one();
+        }
+        Some(entry.item)
+    }
+}
+
+pub(crate) struct CloseOnDrop<T>(Queue<T>);
+
+impl<T> Drop for CloseOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn drop_oldest_makes_room_for_new_items() {
+        let queue = Queue::new(Some(2), Backpressure::DropOldest);
+        assert_eq!(queue.push_bounded(1, false), Ok(None));
+        assert_eq!(queue.push_bounded(2, false), Ok(None));
+        assert_eq!(queue.push_bounded(3, false), Ok(Some(1)));
+        // Items that do not count towards the capacity are never dropped.
+        queue.push(4).unwrap();
+        assert_eq!(queue.push_bounded(5, true), Ok(Some(2)));
+        let received: Vec<_> = (0..3).map(|_| queue.recv()).collect();
+        assert_eq!(received, [5, 3, 4]);
+    }
+
+    #[test]
+    fn reject_hands_back_items_over_capacity() {
+        let queue = Queue::new(Some(1), Backpressure::Reject);
+        assert_eq!(queue.push_bounded(1, false), Ok(None));
+        assert_eq!(queue.push_bounded(2, false), Err(2));
+        queue.push(3).unwrap();
+        assert_eq!(queue.recv(), 1);
+        assert_eq!(queue.push_bounded(4, false), Ok(None));
+        assert_eq!(queue.recv(), 3);
+        assert_eq!(queue.recv(), 4);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let queue = Queue::new(Some(1), Backpressure::Block);
+        queue.push_bounded(1, false).unwrap();
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.push_bounded(2, false))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.recv(), 1);
+        assert_eq!(producer.join().unwrap(), Ok(None));
+        assert_eq!(queue.recv(), 2);
+
+        // Closing wakes up producers that wait for room, which get their item back.
+        queue.push_bounded(3, false).unwrap();
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.push_bounded(4, false))
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert_eq!(producer.join().unwrap(), Err(4));
+    }
+}
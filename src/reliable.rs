@@ -0,0 +1,346 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::backoff::Backoff;
+use crate::layer::{self, RawMessage};
+use crate::wheel::TimerWheel;
+use crate::{ID_GENERATOR, Node, NodeContext, NodeId, Socket};
+
+struct Unacknowledged {
+    message: RawMessage,
+    delay: Duration,
+    // Retries that come up before this are stale, the request was sent again since.
+    retry_at: Instant,
+}
+
+#[derive(Default)]
+struct Destination {
+    in_flight: BTreeSet<u64>,
+    // Waiting for room in flight, in the order they were sent.
+    held: VecDeque<u64>,
+    // Whether a request was sent again, which makes the destination seem unreachable until it
+    // answers one.
+    retried: bool,
+}
+
+// Requests that are sent again until a reply for them arrives, waiting longer between attempts
+// according to the backoff. With `max_in_flight` only that many requests per destination are
+// sent, the others wait for replies to make room. Once an unreachable destination answers, all
+// its requests in flight are sent again together instead of each on their own retry.
+pub struct ReliableSender {
+    unacknowledged: HashMap<u64, Unacknowledged>,
+    destinations: HashMap<NodeId, Destination>,
+    // Destinations that answered, with held requests to send or requests to send again.
+    ready: BTreeSet<NodeId>,
+    retries: TimerWheel<u64>,
+    backoff: Arc<dyn Backoff>,
+    max_in_flight: Option<usize>,
+}
+
+impl ReliableSender {
+    pub(crate) fn new(backoff: Arc<dyn Backoff>, max_in_flight: Option<usize>) -> Self {
+        Self {
+            unacknowledged: HashMap::new(),
+            destinations: HashMap::new(),
+            ready: BTreeSet::new(),
+            retries: TimerWheel::new(),
+            backoff,
+            max_in_flight: max_in_flight.map(|limit| limit.max(1)),
+        }
+    }
+
+    // Held requests included.
+    pub fn len(&self) -> usize {
+        self.unacknowledged.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unacknowledged.is_empty()
+    }
+
+    pub fn is_acknowledged(&self, message_id: u64) -> bool {
+        !self.unacknowledged.contains_key(&message_id)
+    }
+
+    // Requests to the destination that were sent and wait for a reply.
+    pub fn in_flight(&self, dest: &str) -> usize {
+        self.destinations
+            .get(dest)
+            .map_or(0, |destination| destination.in_flight.len())
+    }
+
+    // Returns whether the request is to be sent now, or held until there is room in flight.
+    fn track(&mut self, message_id: u64, message: RawMessage) -> bool {
+        let destination = self.destinations.entry(message.dest().clone()).or_default();
+        let delay = self.backoff.delay(None);
+        let retry_at = Instant::now() + delay;
+        self.unacknowledged.insert(
+            message_id,
+            Unacknowledged {
+                message,
+                delay,
+                retry_at,
+            },
+        );
+        if self
+            .max_in_flight
+            .is_some_and(|limit| destination.in_flight.len() >= limit)
+        {
+            destination.held.push_back(message_id);
+            return false;
+        }
+        destination.in_flight.insert(message_id);
+        self.retries.insert(retry_at, message_id);
+        true
+    }
+
+    pub(crate) fn acknowledge(&mut self, in_reply_to: u64) {
+        let Some(acknowledged) = self.unacknowledged.remove(&in_reply_to) else {
+            return;
+        };
+        let dest = acknowledged.message.dest();
+        let Some(destination) = self.destinations.get_mut(dest) else {
+            return;
+        };
+        destination.in_flight.remove(&in_reply_to);
+        destination
+            .held
+            .retain(|message_id| *message_id != in_reply_to);
+        if destination.retried || !destination.held.is_empty() {
+            self.ready.insert(dest.clone());
+        } else if destination.in_flight.is_empty() {
+            self.destinations.remove(dest);
+        }
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        if !self.ready.is_empty() {
+            return Some(Instant::now());
+        }
+        self.retries.next_deadline()
+    }
+
+    // Sends every request again that has not been acknowledged in time, and the requests of
+    // destinations that answered since, all in one write.
+    pub(crate) fn resend_due<I, O>(&mut self, socket: &mut Socket<I, O>) -> Result<()>
+    where
+        O: Write,
+    {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for dest in std::mem::take(&mut self.ready) {
+            let Some(destination) = self.destinations.get_mut(&dest) else {
+                continue;
+            };
+            let mut sent = Vec::new();
+            if std::mem::take(&mut destination.retried) {
+                sent.extend(destination.in_flight.iter().copied());
+            }
+            while self
+                .max_in_flight
+                .is_none_or(|limit| destination.in_flight.len() < limit)
+                && let Some(message_id) = destination.held.pop_front()
+            {
+                destination.in_flight.insert(message_id);
+                sent.push(message_id);
+            }
+            // Sent again from the start of the backoff, the destination is back.
+            for message_id in &sent {
+                if let Some(unacknowledged) = self.unacknowledged.get_mut(message_id) {
+                    unacknowledged.delay = self.backoff.delay(None);
+                    unacknowledged.retry_at = now + unacknowledged.delay;
+                    self.retries.insert(unacknowledged.retry_at, *message_id);
+                }
+            }
+            due.extend(sent);
+        }
+        for message_id in self.retries.expire(now) {
+            let Some(unacknowledged) = self.unacknowledged.get_mut(&message_id) else {
+                continue;
+            };
+            if unacknowledged.retry_at > now {
+                continue;
+            }
+            unacknowledged.delay = self.backoff.delay(Some(unacknowledged.delay));
+            unacknowledged.retry_at = now + unacknowledged.delay;
+            self.retries.insert(unacknowledged.retry_at, message_id);
+            if let Some(destination) = self.destinations.get_mut(unacknowledged.message.dest()) {
+                destination.retried = true;
+            }
+            due.push(message_id);
+        }
+        if due.is_empty() {
+            return Ok(());
+        }
+        due.sort_unstable();
+        due.dedup();
+        socket
+            .send_many(
+                due.iter()
+                    .filter_map(|message_id| self.unacknowledged.get(message_id))
+                    .map(|unacknowledged| &unacknowledged.message),
+            )
+            .context("resending unacknowledged requests")
+    }
+}
+
+impl<N, I, O> NodeContext<'_, N, I, O>
+where
+    N: Node,
+    O: Write,
+{
+    // Sends the request until a reply for it arrives, which is still handed to `handle_response`.
+    pub fn send_reliable<B>(&mut self, dest: impl Into<NodeId>, body: B) -> Result<u64>
+    where
+        B: Serialize,
+    {
+        let message_id = ID_GENERATOR.next_id();
+        let message = layer::into_raw(&self.message(dest, body).with_id(message_id))?;
+        let send = message.clone();
+        if self.reliable.track(message_id, message) {
+            self.socket
+                .send(&send)
+                .context("sending reliable request")?;
+        }
+        Ok(message_id)
+    }
+
+    pub fn reliable(&self) -> &ReliableSender {
+        self.reliable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::{Value, json};
+
+    use crate::backoff::Fixed;
+    use crate::{
+        EventIncjector, Init, Loopback, MaelstromError, Never, Node, NodeContext, Reply,
+        RequestInfo, RunConfig, Socket, Transport,
+    };
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Send,
+        Unacknowledged,
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Response {
+        SendOk,
+        UnacknowledgedOk { count: usize },
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum PeerRequest {
+        Ping,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum PeerResponse {
+        PingOk,
+    }
+
+    // Pings n2 reliably for every `send`.
+    struct Pinger;
+
+    impl Node for Pinger {
+        type Request = Request;
+        type Response = Response;
+        type PeerRequest = Never;
+        type PeerResponse = Never;
+        type InboundResponse = PeerResponse;
+        type Event = ();
+
+        type InitState = ();
+
+        fn from_init(_: Init, _: (), _: EventIncjector<Self>) -> Self {
+            Self
+        }
+
+        fn handle_request(
+            &mut self,
+            request: Request,
+            _: RequestInfo,
+            ctx: &mut NodeContext<Self, impl Read, impl Write>,
+        ) -> Result<Reply<Response>, MaelstromError> {
+            Ok(match request {
+                Request::Send => {
+                    ctx.send_reliable("n2", PeerRequest::Ping)?;
+                    Response::SendOk
+                }
+                Request::Unacknowledged => Response::UnacknowledgedOk {
+                    count: ctx.reliable().len(),
+                },
+            }
+            .into())
+        }
+    }
+
+    fn receive(transport: &impl Transport) -> Value {
+        let frame = transport
+            .recv()
+            .unwrap()
+            .expect("the transport is connected");
+        serde_json::from_str(&frame).unwrap()
+    }
+
+    // The ack of the first ping is lost, nothing reaches the node after that until the resend.
+    #[test]
+    fn resends_without_new_traffic_until_acknowledged() {
+        let network = Loopback::new();
+        let node = {
+            let socket = Socket::from_transport(network.transport("n1"));
+            let config = RunConfig::default().with_retry_backoff(Fixed(Duration::from_millis(20)));
+            thread::spawn(move || Pinger::run_with_config((), socket, config))
+        };
+        let client = network.transport("c1");
+        let peer = network.transport("n2");
+        let request = |msg_id: u64, body: Value| {
+            let mut body = body;
+            body["msg_id"] = json!(msg_id);
+            let frame = json!({ "src": "c1", "dest": "n1", "body": body });
+            client.send(&frame.to_string()).unwrap();
+            let reply = receive(&client);
+            assert_eq!(reply["body"]["in_reply_to"], msg_id);
+            reply["body"].clone()
+        };
+
+        request(
+            1,
+            json!({ "type": "init", "node_id": "n1", "node_ids": ["n1", "n2"] }),
+        );
+        assert_eq!(request(2, json!({ "type": "send" }))["type"], "send_ok");
+
+        let ping = receive(&peer);
+        assert_eq!(ping["body"]["type"], "ping");
+        let resent = receive(&peer);
+        assert_eq!(resent["body"], ping["body"]);
+        let ack = json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": { "type": "ping_ok", "in_reply_to": ping["body"]["msg_id"] },
+        });
+        peer.send(&ack.to_string()).unwrap();
+        // Handled after the ack, which was sent first.
+        assert_eq!(request(3, json!({ "type": "unacknowledged" }))["count"], 0);
+
+        network.disconnect("n1");
+        node.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::mem;
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+
+use crate::{Message, Node, NodeContext, NodeId};
+
+pub enum Reply<R> {
+    Respond(R),
+    // The request is answered with several messages carrying the same `in_reply_to`, they are sent
+    // in order.
+    Stream(Vec<R>),
+    // The request is answered later on through the `Responder` taken with `NodeContext::defer`.
+    Deferred,
+    // The request is never answered, e.g. fire-and-forget messages between nodes.
+    None,
+}
+
+impl<R> Reply<R> {
+    // Answers with as few responses as possible, each carrying part of `items` and serializing to
+    // at most `max_bytes`. An item that does not fit on its own still gets a response of its own.
+    // The limit is for the body, leave room for the envelope when picking it.
+    pub fn chunked<T>(
+        items: impl IntoIterator<Item = T>,
+        max_bytes: usize,
+        mut make: impl FnMut(Vec<T>) -> R,
+    ) -> Result<Self>
+    where
+        T: Serialize,
+        R: Serialize,
+    {
+        let overhead = serialized_len(&make(Vec::new()))?;
+        let mut responses = Vec::new();
+        let mut chunk = Vec::new();
+        let mut len = overhead;
+        for item in items {
+            // Counts the separator as well.
+            let item_len = serialized_len(&item)? + 1;
+            if !chunk.is_empty() && len + item_len > max_bytes {
+                responses.push(make(mem::take(&mut chunk)));
+                len = overhead;
+            }
+            len += item_len;
+            chunk.push(item);
+        }
+        if !chunk.is_empty() || responses.is_empty() {
+            responses.push(make(chunk));
+        }
+        Ok(Self::Stream(responses))
+    }
+}
+
+fn serialized_len(value: &impl Serialize) -> Result<usize> {
+    serde_json::to_vec(value)
+        .map(|serialized| serialized.len())
+        .context("serializing chunk")
+}
+
+impl<R> From<R> for Reply<R> {
+    fn from(response: R) -> Self {
+        Self::Respond(response)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Responders {
+    next_token: u64,
+    outstanding: HashSet<u64>,
+}
+
+impl Responders {
+    fn register(&mut self) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        self.outstanding.insert(token);
+        token
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+}
+
+#[must_use = "the request is never answered if the responder is dropped"]
+pub struct Responder {
+    token: u64,
+    dest: NodeId,
+    in_reply_to: Option<u64>,
+}
+
+impl Responder {
+    pub fn dest(&self) -> &str {
+        &self.dest
+    }
+
+    // Sends part of the answer, the request stays outstanding until `respond` is called.
+    pub fn send_part<N, I, O, R>(&self, ctx: &mut NodeContext<N, I, O>, part: R) -> Result<()>
+    where
+        N: Node,
+        O: Write,
+        R: Serialize,
+    {
+        ctx.socket
+            .send(&Message::reply(
+                ctx.node_id.clone(),
+                self.dest.clone(),
+                self.in_reply_to,
+                part,
+            ))
+            .context("sending partial response")
+    }
+
+    pub fn respond<N, I, O, R>(self, ctx: &mut NodeContext<N, I, O>, response: R) -> Result<()>
+    where
+        N: Node,
+        O: Write,
+        R: Serialize,
+    {
+        ctx.responders.outstanding.remove(&self.token);
+        ctx.socket
+            .send(&Message::reply(
+                ctx.node_id.clone(),
+                self.dest,
+                self.in_reply_to,
+                response,
+            ))
+            .context("sending deferred response")
+    }
+}
+
+impl<N: Node, I, O> NodeContext<'_, N, I, O> {
+    pub fn defer(&mut self) -> Result<Responder> {
+        let Some(src) = self.src else {
+            bail!("only requests can be answered later on");
+        };
+        self.deferred = true;
+        Ok(Responder {
+            token: self.responders.register(),
+            dest: src.clone(),
+            in_reply_to: self.msg_id,
+        })
+    }
+
+    pub fn outstanding_responders(&self) -> usize {
+        self.responders.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{Loopback, Message, Socket, Transport};
+
+    #[derive(Debug, Serialize)]
+    struct ReadOk {
+        messages: Vec<u64>,
+    }
+
+    fn chunks(items: impl IntoIterator<Item = u64>, max_bytes: usize) -> Vec<ReadOk> {
+        match Reply::chunked(items, max_bytes, |messages| ReadOk { messages }).unwrap() {
+            Reply::Stream(responses) => responses,
+            _ => panic!("chunks are streamed"),
+        }
+    }
+
+    #[test]
+    fn chunks_stay_under_the_limit_and_keep_every_item() {
+        let responses = chunks(0..1000, 200);
+        assert!(responses.len() > 1);
+        for response in &responses {
+            assert!(serialized_len(response).unwrap() <= 200);
+        }
+        let items: Vec<u64> = responses
+            .into_iter()
+            .flat_map(|response| response.messages)
+            .collect();
+        assert_eq!(items, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn items_too_large_for_a_chunk_get_one_of_their_own() {
+        let responses = chunks([1, 12_345_678_901, 2], 20);
+        let messages: Vec<&[u64]> = responses.iter().map(|r| r.messages.as_slice()).collect();
+        assert_eq!(messages, [&[1][..], &[12_345_678_901], &[2]]);
+        // Nothing to send is still answered.
+        assert_eq!(chunks([], 20).len(), 1);
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_not_sent() {
+        let network = Loopback::new();
+        let client = network.transport("c1");
+        let mut socket = Socket::from_transport(network.transport("n1")).with_max_frame_size(100);
+
+        let body = |len: usize| json!({ "type": "read_ok", "text": "x".repeat(len) });
+        assert!(socket.send(&Message::new("n1", "c1", body(200))).is_err());
+        socket.send(&Message::new("n1", "c1", body(10))).unwrap();
+        let frame: Value = serde_json::from_str(&client.recv().unwrap().unwrap()).unwrap();
+        assert_eq!(frame["body"]["text"], "x".repeat(10));
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::wheel::TimerWheel;
+use crate::{Node, NodeContext};
+
+pub type RpcCallback<N, I, O> =
+    Box<dyn FnOnce(&mut N, <N as Node>::InboundResponse, &mut NodeContext<N, I, O>) -> Result<()>>;
+
+// Gets the reply as JSON, for rpcs to services whose replies the node does not know about.
+pub type RawRpcCallback<N, I, O> =
+    Box<dyn FnOnce(&mut N, Value, &mut NodeContext<N, I, O>) -> Result<()>>;
+
+pub type ScheduledCallback<N, I, O> =
+    Box<dyn FnOnce(&mut N, &mut NodeContext<N, I, O>) -> Result<()>>;
+
+// Ids of raw rpcs that are still waiting for a reply, shared with the parser so it leaves those
+// replies as JSON.
+#[derive(Clone, Default)]
+pub(crate) struct Awaited(Arc<Mutex<HashSet<u64>>>);
+
+impl Awaited {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<u64>> {
+        self.0.lock().expect("failed to lock awaited replies")
+    }
+
+    pub(crate) fn contains(&self, message_id: u64) -> bool {
+        self.lock().contains(&message_id)
+    }
+}
+
+pub struct PendingRpcs<N: Node, I, O> {
+    callbacks: HashMap<u64, RpcCallback<N, I, O>>,
+    raw: HashMap<u64, RawRpcCallback<N, I, O>>,
+    awaited: Awaited,
+    scheduled: HashMap<u64, ScheduledCallback<N, I, O>>,
+    deadlines: TimerWheel<u64>,
+    next_key: u64,
+}
+
+impl<N: Node, I, O> Default for PendingRpcs<N, I, O> {
+    fn default() -> Self {
+        Self {
+            callbacks: HashMap::new(),
+            raw: HashMap::new(),
+            awaited: Awaited::default(),
+            scheduled: HashMap::new(),
+            deadlines: TimerWheel::new(),
+            next_key: 0,
+        }
+    }
+}
+
+impl<N: Node, I, O> PendingRpcs<N, I, O> {
+    pub fn insert(&mut self, message_id: u64, callback: RpcCallback<N, I, O>) {
+        self.callbacks.insert(message_id, callback);
+    }
+
+    pub fn take(&mut self, in_reply_to: u64) -> Option<RpcCallback<N, I, O>> {
+        self.callbacks.remove(&in_reply_to)
+    }
+
+    // Has to happen before the request is sent, the reply may be parsed right after.
+    pub fn insert_raw(&mut self, message_id: u64, callback: RawRpcCallback<N, I, O>) {
+        self.awaited.lock().insert(message_id);
+        self.raw.insert(message_id, callback);
+    }
+
+    // Also gives up on the reply when it did not arrive. Should it arrive late it is parsed like
+    // any other response, which belongs to no rpc anymore.
+    pub fn take_raw(&mut self, in_reply_to: u64) -> Option<RawRpcCallback<N, I, O>> {
+        self.awaited.lock().remove(&in_reply_to);
+        self.raw.remove(&in_reply_to)
+    }
+
+    pub(crate) fn awaited(&self) -> Awaited {
+        self.awaited.clone()
+    }
+
+    // Runs the callback on the main loop once the deadline passed.
+    pub fn schedule(&mut self, deadline: Instant, callback: ScheduledCallback<N, I, O>) {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.scheduled.insert(key, callback);
+        self.deadlines.insert(deadline, key);
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.next_deadline()
+    }
+
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<ScheduledCallback<N, I, O>> {
+        self.deadlines
+            .expire(now)
+            .into_iter()
+            .filter_map(|key| self.scheduled.remove(&key))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.callbacks.len() + self.raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty() && self.raw.is_empty()
+    }
+}
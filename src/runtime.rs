@@ -0,0 +1,589 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::ops::ControlFlow;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use signal_hook::iterator::Signals;
+
+use crate::config::{GossipThrottle, ReaderSupervision};
+use crate::diagnostics::{self, Subject, Warning};
+use crate::layer::{self, Middleware};
+use crate::outbox::Outbox;
+use crate::queue::{CloseOnDrop, Queue};
+use crate::reliable::ReliableSender;
+use crate::reply::Responders;
+use crate::rpc::{Awaited, PendingRpcs};
+use crate::thread;
+use crate::timer::Timer;
+use crate::{
+    EventIncjector, Incoming, IncomingMessage, Init, InitOk, Line, MaelstromError, Message, Node,
+    NodeContext, NodeId, Protocol, RequestInfo, RequestResponse, ResponseInfo, RunConfig, Socket,
+    TopologyMessage, TopologyOk,
+};
+
+// How many lines the reader gets ahead of the parser.
+const LINE_CAPACITY: usize = 64;
+
+// Drives a node, either one incoming item at a time through `step` or until it stops through
+// `run`.
+pub struct Runtime<N: Node, I, O> {
+    node: N,
+    node_id: NodeId,
+    node_ids: HashSet<NodeId>,
+    socket: Socket<I, O>,
+    queue: Queue<Incoming<N>>,
+    rpcs: PendingRpcs<N, I, O>,
+    responders: Responders,
+    reliable: ReliableSender,
+    outbox: Outbox,
+    gossip_throttle: GossipThrottle,
+    middleware: Vec<Box<dyn Middleware<N>>>,
+    // Holds the received termination signal, or zero while running.
+    signal: Arc<AtomicI32>,
+    // Lines read but not parsed yet.
+    lines: Arc<AtomicUsize>,
+    next_tick: Option<Instant>,
+    stopped: bool,
+    // Lets event injectors and background threads know once the runtime is gone.
+    _close: CloseOnDrop<Incoming<N>>,
+}
+
+impl<N, I, O> Runtime<N, I, O>
+where
+    N: Node + 'static,
+    I: Read,
+    O: Write,
+    Socket<I, O>: Send + 'static,
+{
+    // Answers the init message and starts reading from the socket, nothing is handled before the
+    // first step.
+    pub fn new(
+        init_state: N::InitState,
+        mut socket: Socket<I, O>,
+        config: RunConfig,
+    ) -> Result<Self> {
+        let queue = Queue::new(config.channel_capacity, config.backpressure);
+        let close = queue.close_on_drop();
+
+        let init = socket
+            .receive::<Init>()
+            .expect("first message to node should be init");
+        socket
+            .send(&Message::reply(
+                init.dest,
+                init.src,
+                init.body.id,
+                InitOk {},
+            ))
+            .context("sending init ok")?;
+        let node_id = NodeId::from(&init.body.kind.node_id);
+        let node_ids = init.body.kind.node_ids.iter().map(NodeId::from).collect();
+        let timer = {
+            let queue = queue.clone();
+            let on_panic = panic_handler(&queue);
+            Timer::spawn(config.panic_policy, on_panic, move |event| {
+                queue.push(Incoming::Event(event)).is_ok()
+            })
+        };
+        let node = N::from_init(
+            init.body.kind,
+            init_state,
+            EventIncjector {
+                queue: queue.clone(),
+                timer,
+            },
+        );
+
+        let rpcs = PendingRpcs::default();
+        // Lines are parsed on a thread of their own, so parsing one message overlaps with reading
+        // the next.
+        let (sender, receiver) = mpsc::sync_channel(LINE_CAPACITY);
+        let lines = Arc::new(AtomicUsize::new(0));
+        {
+            let mut socket = socket.clone();
+            let supervision = config.reader_supervision;
+            let lines = lines.clone();
+            let on_panic = panic_handler(&queue);
+            thread::spawn_supervised("reader", config.panic_policy, on_panic, move || {
+                read_lines(&mut socket, &supervision, &sender, &lines);
+            });
+        }
+        {
+            let queue = queue.clone();
+            let mut socket = socket.clone();
+            let lines = lines.clone();
+            let awaited = rpcs.awaited();
+            let on_panic = panic_handler(&queue);
+            thread::spawn_supervised("parser", config.panic_policy, on_panic, move || {
+                if let Some(incoming) =
+                    parse_messages::<N, I, O>(&queue, &mut socket, &receiver, &lines, &awaited)
+                {
+                    let _ = queue.push(incoming);
+                }
+            });
+        }
+
+        let signal = Arc::new(AtomicI32::new(0));
+        {
+            use signal_hook::consts::{SIGINT, SIGTERM};
+
+            let mut signals = Signals::new([SIGTERM, SIGINT]).context("registering signals")?;
+            let queue = queue.clone();
+            let signal = signal.clone();
+            let on_panic = panic_handler(&queue);
+            thread::spawn_supervised("signal", config.panic_policy, on_panic, move || {
+                if let Some(received) = signals.forever().next() {
+                    signal.store(received, Ordering::Release);
+                    // Wakes up the loop in case it is waiting for messages.
+                    let _ = queue.push(Incoming::Signal);
+                }
+            });
+        }
+
+        Ok(Self {
+            node,
+            node_id,
+            node_ids,
+            socket,
+            queue,
+            rpcs,
+            responders: Responders::default(),
+            reliable: ReliableSender::new(config.retry_backoff, config.max_in_flight),
+            outbox: Outbox::default(),
+            gossip_throttle: config.gossip_throttle,
+            middleware: Vec::new(),
+            signal,
+            lines,
+            next_tick: N::TICK_INTERVAL.map(|interval| Instant::now() + interval),
+            stopped: false,
+            _close: close,
+        })
+    }
+
+    // Wraps the middleware that was added before, so the last added one is the outermost one.
+    pub fn with_middleware(mut self, middleware: impl Middleware<N> + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    pub fn run(mut self) -> Result<()> {
+        while self.step()?.is_continue() {}
+        Ok(())
+    }
+
+    // Keeps stepping until the duration elapsed or the node stopped. The runtime can be stepped
+    // further afterwards, `shutdown` stops it for good.
+    pub fn run_for(&mut self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline && self.step_before(Some(deadline))?.is_continue() {}
+        Ok(())
+    }
+
+    // Keeps stepping until the condition holds for the node or the node stopped. The condition is
+    // checked before every step.
+    pub fn run_until(&mut self, mut done: impl FnMut(&N) -> bool) -> Result<()> {
+        while !done(&self.node) && self.step()?.is_continue() {}
+        Ok(())
+    }
+
+    // Waits for the next incoming item or tick and handles it. Breaks once the node stopped,
+    // stepping a stopped runtime does nothing.
+    pub fn step(&mut self) -> Result<ControlFlow<()>> {
+        self.step_before(None)
+    }
+
+    // Calls `on_shutdown` unless the node already stopped on its own.
+    pub fn shutdown(mut self) -> Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.stopped = true;
+        let mut ctx = NodeContext::new(
+            &self.node_id,
+            &self.node_ids,
+            &mut self.socket,
+            &mut self.rpcs,
+            &mut self.responders,
+            &mut self.reliable,
+            &mut self.outbox,
+            self.gossip_throttle,
+        );
+        self.node.on_shutdown(&mut ctx).context("shutting down")?;
+        self.outbox.flush(&self.node_id, &mut self.socket)?;
+        self.socket.flush().context("flushing pending messages")
+    }
+
+    // Like `step`, but stops waiting at the deadline without handling anything.
+    fn step_before(&mut self, deadline: Option<Instant>) -> Result<ControlFlow<()>> {
+        let flow = self.handle_next(deadline)?;
+        // Messages queued by the handlers leave once they returned.
+        self.outbox.flush(&self.node_id, &mut self.socket)?;
+        if flow.is_break() {
+            self.socket.flush().context("flushing pending messages")?;
+        }
+        Ok(flow)
+    }
+
+    fn handle_next(&mut self, deadline: Option<Instant>) -> Result<ControlFlow<()>> {
+        if self.stopped {
+            return Ok(ControlFlow::Break(()));
+        }
+
+        let wait_until = [
+            self.next_tick,
+            self.reliable.next_deadline(),
+            self.rpcs.next_deadline(),
+            deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let incoming = match wait_until {
+            Some(deadline) => self
+                .queue
+                .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => Some(self.queue.recv()),
+        };
+        self.reliable.resend_due(&mut self.socket)?;
+        let node = &mut self.node;
+        let middleware = &mut self.middleware;
+        let mut ctx = NodeContext::new(
+            &self.node_id,
+            &self.node_ids,
+            &mut self.socket,
+            &mut self.rpcs,
+            &mut self.responders,
+            &mut self.reliable,
+            &mut self.outbox,
+            self.gossip_throttle,
+        );
+
+        // A busy channel must not starve the ticks.
+        if let (Some(deadline), Some(interval)) = (self.next_tick, N::TICK_INTERVAL)
+            && Instant::now() >= deadline
+        {
+            self.next_tick = Some(Instant::now() + interval);
+            node.on_tick(&mut ctx).context("handling tick")?;
+        }
+        for callback in ctx.rpcs.expire(Instant::now()) {
+            callback(node, &mut ctx).context("running scheduled callback")?;
+        }
+        // Messages that are still queued are not handled anymore once a signal arrived.
+        let received = self.signal.load(Ordering::Acquire);
+        if received != 0 {
+            eprintln!("{} received signal {received}, shutting down", self.node_id);
+            self.stopped = true;
+            node.on_shutdown(&mut ctx).context("shutting down")?;
+            return Ok(ControlFlow::Break(()));
+        }
+        let Some(incoming) = incoming else {
+            return Ok(ControlFlow::Continue(()));
+        };
+
+        match incoming {
+            Incoming::Message(message) => {
+                ctx.src = Some(&message.src);
+                ctx.msg_id = message.body.id;
+                match message.body.kind {
+                    RequestResponse::Request(Protocol::Topology(TopologyMessage { topology })) => {
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            node.on_topology(topology);
+                        }))
+                        .map(|()| TopologyOk {}.into())
+                        .map_err(MaelstromError::from_panic);
+                        ctx.answer(result)?;
+                    }
+                    RequestResponse::Request(Protocol::Client(req)) => {
+                        let info = RequestInfo { src: &message.src };
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            layer::handle_request(middleware, req, &info, &mut |req| {
+                                node.handle_request(req, RequestInfo { src: info.src }, &mut ctx)
+                            })
+                        }))
+                        .unwrap_or_else(|panic| Err(MaelstromError::from_panic(panic)));
+                        ctx.answer(result)?;
+                    }
+                    RequestResponse::Request(Protocol::Peer(req)) => {
+                        let info = RequestInfo { src: &message.src };
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            layer::handle_peer_request(middleware, req, &info, &mut |req| {
+                                node.handle_peer_request(
+                                    req,
+                                    RequestInfo { src: info.src },
+                                    &mut ctx,
+                                )
+                            })
+                        }))
+                        .unwrap_or_else(|panic| Err(MaelstromError::from_panic(panic)));
+                        ctx.answer(result)?;
+                    }
+                    RequestResponse::Response(res) => {
+                        if let Some(in_reply_to) = res.in_reply_to {
+                            ctx.reliable.acknowledge(in_reply_to);
+                        }
+                        node.handle_response(
+                            res.inner,
+                            ResponseInfo {
+                                in_reply_to: res.in_reply_to,
+                            },
+                            &mut ctx,
+                        )
+                        .context("handling a response")?;
+                    }
+                    RequestResponse::Raw(res) => {
+                        // Like in `handle_response`, there is no request to answer.
+                        ctx.src = None;
+                        ctx.msg_id = None;
+                        let callback = res.in_reply_to.and_then(|in_reply_to| {
+                            ctx.reliable.acknowledge(in_reply_to);
+                            ctx.rpcs.take_raw(in_reply_to)
+                        });
+                        if let Some(callback) = callback {
+                            callback(node, res.inner, &mut ctx).context("handling an rpc reply")?;
+                        }
+                    }
+                }
+            }
+            Incoming::Event(event) => node
+                .handle_event(event, &mut ctx)
+                .context("handling event")?,
+            Incoming::Closed => {
+                self.stopped = true;
+                node.on_shutdown(&mut ctx).context("shutting down")?;
+                return Ok(ControlFlow::Break(()));
+            }
+            Incoming::ReaderFailed(error) => {
+                eprintln!("{} stopped receiving messages: {error:#}", self.node_id);
+                node.on_reader_error(error, &mut ctx)
+                    .context("handling reader failure")?;
+            }
+            Incoming::Panicked(thread) => {
+                eprintln!("{} lost its {thread} thread, shutting down", self.node_id);
+                self.stopped = true;
+                node.on_shutdown(&mut ctx).context("shutting down")?;
+                return Ok(ControlFlow::Break(()));
+            }
+            Incoming::Signal => unreachable!("signals stop the loop before dispatching"),
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+impl<N: Node, I, O> Runtime<N, I, O> {
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+
+    pub fn node_mut(&mut self) -> &mut N {
+        &mut self.node
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn queue_depths(&self) -> QueueDepths {
+        QueueDepths {
+            lines: self.lines.load(Ordering::Relaxed),
+            incoming: self.queue.len(),
+        }
+    }
+}
+
+// How many items wait between the stages of the runtime. Lines piling up means parsing does not
+// keep up with reading, incoming items piling up means the node does not keep up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueDepths {
+    pub lines: usize,
+    pub incoming: usize,
+}
+
+// Hands lines to the parser until reading stops, restarting after transient failures.
+fn read_lines<I, O>(
+    socket: &mut Socket<I, O>,
+    supervision: &ReaderSupervision,
+    lines: &mpsc::SyncSender<Result<Option<Line>>>,
+    depth: &AtomicUsize,
+) where
+    I: Read,
+    O: Write,
+{
+    let mut restarts = 0;
+    let mut delay = None;
+    loop {
+        let line = match socket.next_frame() {
+            Err(error) if restarts < supervision.max_restarts && is_transient(&error) => {
+                restarts += 1;
+                let next = supervision.backoff.delay(delay);
+                delay = Some(next);
+                eprintln!(
+                    "reader failed: {error:#}, restarting in {next:?} ({restarts}/{})",
+                    supervision.max_restarts
+                );
+                std::thread::sleep(next);
+                continue;
+            }
+            line => line.context("receiving message from socket"),
+        };
+        let last = !matches!(line, Ok(Some(_)));
+        depth.fetch_add(1, Ordering::Relaxed);
+        // The parser is gone once the runtime is.
+        if lines.send(line).is_err() || last {
+            return;
+        }
+    }
+}
+
+// Hands messages to the node until reading stops, `None` means the runtime is gone.
+fn parse_messages<N, I, O>(
+    queue: &Queue<Incoming<N>>,
+    socket: &mut Socket<I, O>,
+    lines: &mpsc::Receiver<Result<Option<Line>>>,
+    depth: &AtomicUsize,
+    awaited: &Awaited,
+) -> Option<Incoming<N>>
+where
+    N: Node,
+    O: Write,
+{
+    loop {
+        let line = lines.recv().ok()?;
+        depth.fetch_sub(1, Ordering::Relaxed);
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => return Some(Incoming::Closed),
+            Err(error) => return Some(Incoming::ReaderFailed(error)),
+        };
+        match crate::parse_frame(line, &|in_reply_to| awaited.contains(in_reply_to)) {
+            Ok(Some(message)) => {
+                // Replies to our own rpcs go first, waiting behind a backlog of requests only
+                // leads to more retries.
+                let urgent = matches!(
+                    message.body.kind,
+                    RequestResponse::Response(_) | RequestResponse::Raw(_)
+                );
+                match queue.push_bounded(Incoming::Message(message), urgent) {
+                    Ok(None) => {}
+                    Ok(Some(Incoming::Message(dropped))) => diagnostics::warn(
+                        Warning::Dropped,
+                        format_args!(
+                            "incoming queue is full, dropped the oldest message {}",
+                            subject::<N>(&dropped)
+                        ),
+                    ),
+                    Ok(Some(_)) => unreachable!("only messages are bounded"),
+                    Err(_) if queue.is_closed() => return None,
+                    Err(Incoming::Message(message)) => {
+                        if let Err(error) = reject::<N, I, O>(socket, message) {
+                            return Some(Incoming::ReaderFailed(error));
+                        }
+                    }
+                    Err(_) => unreachable!("only messages are bounded"),
+                }
+            }
+            Ok(None) => {}
+            Err(rejected) => {
+                if let Err(error) = socket.answer_rejected(&rejected) {
+                    return Some(Incoming::ReaderFailed(error));
+                }
+            }
+        }
+    }
+}
+
+fn panic_handler<N: Node>(queue: &Queue<Incoming<N>>) -> impl FnOnce(&'static str) + use<N> {
+    let queue = queue.clone();
+    move |thread| {
+        let _ = queue.push(Incoming::Panicked(thread));
+    }
+}
+
+// Reading from the socket failed, as opposed to a message that could not be handled.
+fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<io::Error>())
+}
+
+// Answers a request that did not fit in the incoming queue, responses are dropped.
+fn reject<N, I, O>(socket: &mut Socket<I, O>, message: IncomingMessage<N>) -> Result<()>
+where
+    N: Node,
+    O: Write,
+{
+    let RequestResponse::Request(_) = message.body.kind else {
+        diagnostics::warn(
+            Warning::Dropped,
+            format_args!(
+                "incoming queue is full, dropped a response {}",
+                subject::<N>(&message)
+            ),
+        );
+        return Ok(());
+    };
+    socket
+        .send(&Message::reply(
+            message.dest,
+            message.src,
+            message.body.id,
+            MaelstromError::TemporarilyUnavailable("node is overloaded".to_string()),
+        ))
+        .context("rejecting request")
+}
+
+// The type of a parsed message is not known anymore.
+fn subject<N: Node>(message: &IncomingMessage<N>) -> Subject<'_> {
+    Subject {
+        src: &message.src,
+        dest: &message.dest,
+        kind: None,
+        id: message.body.id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::Loopback;
+    use crate::testing::{Client, Echo};
+
+    // Everything that arrives is parsed and queued before the node handles any of it.
+    #[test]
+    fn parses_ahead_of_the_node() {
+        let network = Loopback::new();
+        let socket = Socket::from_transport(network.transport("n1"));
+        let mut client = Client::new(&network, "c1");
+        let init = json!({ "type": "init", "node_id": "n1", "node_ids": ["n1"] });
+        client.send("n1", init);
+        let mut runtime = Runtime::<Echo, _, _>::new((), socket, RunConfig::default()).unwrap();
+        assert_eq!(client.recv()["body"]["type"], "init_ok");
+
+        for echo in 0..3 {
+            client.send("n1", json!({ "type": "echo", "echo": echo }));
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while runtime.queue_depths().incoming < 3 {
+            assert!(Instant::now() < deadline, "{:?}", runtime.queue_depths());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            runtime.queue_depths(),
+            QueueDepths {
+                lines: 0,
+                incoming: 3
+            }
+        );
+
+        for echo in 0..3 {
+            assert!(runtime.step().unwrap().is_continue());
+            assert_eq!(client.recv()["body"]["echo"], echo);
+        }
+        assert_eq!(runtime.queue_depths().incoming, 0);
+        network.disconnect("n1");
+        runtime.run().unwrap();
+    }
+}
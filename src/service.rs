@@ -0,0 +1,375 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::backoff::Exponential;
+use crate::stats::ServiceStats;
+use crate::{Backoff, MaelstromError, Node, NodeContext, NodeId};
+
+// How long a request waits for a reply before it is sent again, and how often.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff: Arc<dyn Backoff>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            retries: 2,
+            backoff: Arc::new(Exponential::new(
+                Duration::from_millis(50),
+                Duration::from_millis(500),
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ServiceError {
+    // No reply arrived in time, even after retrying. The request may still have been handled.
+    Timeout,
+    // The service answered with an error.
+    Service(MaelstromError),
+    // The request could not be sent, or the reply made no sense.
+    Client(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ServiceError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::Client(error)
+    }
+}
+
+impl From<ServiceError> for MaelstromError {
+    fn from(error: ServiceError) -> Self {
+        match error {
+            ServiceError::Timeout => Self::Timeout("timed out waiting for a service".into()),
+            ServiceError::Service(error) => error,
+            ServiceError::Client(error) => error.into(),
+        }
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timed out waiting for a service"),
+            Self::Service(error) => write!(f, "service failed: {error}"),
+            Self::Client(error) => write!(f, "{error:#}"),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+// Sends requests to a service like seq-kv or lin-tso and waits for the replies, sending a
+// request again when no reply came in time. Replies are handed to the callback on the main
+// loop, parsed as the response type or as an error.
+//
+// Clones share their stats.
+#[derive(Debug, Clone)]
+pub struct ServiceClient {
+    service: NodeId,
+    retry: RetryPolicy,
+    stats: ServiceStats,
+}
+
+impl ServiceClient {
+    pub fn new(service: impl Into<NodeId>) -> Self {
+        Self {
+            service: service.into(),
+            retry: RetryPolicy::default(),
+            stats: ServiceStats::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // Records into stats that other clients record into as well, to see them all at once.
+    pub fn with_stats(mut self, stats: ServiceStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn service(&self) -> &NodeId {
+        &self.service
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn stats(&self) -> &ServiceStats {
+        &self.stats
+    }
+
+    pub fn call<B, R, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        request: &B,
+        callback: impl FnOnce(&mut N, Result<R, ServiceError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), ServiceError>
+    where
+        B: Serialize,
+        R: DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+    {
+        self.start(ctx, request, self.retry.clone(), callback)
+    }
+
+    // Like `call`, but the request is never sent again, for requests that must not be handled
+    // twice like a compare-and-set. It fails with `ServiceError::Timeout` once the first timeout
+    // is up, whether the service handled it or not.
+    pub fn call_once<B, R, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        request: &B,
+        callback: impl FnOnce(&mut N, Result<R, ServiceError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), ServiceError>
+    where
+        B: Serialize,
+        R: DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+    {
+        let retry = RetryPolicy {
+            retries: 0,
+            ..self.retry.clone()
+        };
+        self.start(ctx, request, retry, callback)
+    }
+
+    fn start<B, R, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        request: &B,
+        retry: RetryPolicy,
+        callback: impl FnOnce(&mut N, Result<R, ServiceError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), ServiceError>
+    where
+        B: Serialize,
+        R: DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+    {
+        let body = serde_json::to_value(request).context("serializing request")?;
+        let operation = body
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown")
+            .to_string();
+        self.stats.called(&operation);
+        let attempt = Attempt {
+            service: self.service.clone(),
+            body,
+            retry,
+            stats: self.stats.clone(),
+            operation: Rc::from(operation),
+            started: Instant::now(),
+            retries: 0,
+            delay: None,
+            sent: Rc::default(),
+            callback: Rc::new(RefCell::new(Some(callback))),
+        };
+        attempt.send(ctx)
+    }
+}
+
+fn parse_reply<R: DeserializeOwned>(service: &NodeId, reply: Value) -> Result<R, ServiceError> {
+    if reply.get("type").and_then(Value::as_str) == Some("error") {
+        let error =
+            serde_json::from_value(reply).with_context(|| format!("parsing error of {service}"))?;
+        return Err(ServiceError::Service(error));
+    }
+    let reply =
+        serde_json::from_value(reply).with_context(|| format!("parsing reply of {service}"))?;
+    Ok(reply)
+}
+
+// One attempt at getting a reply from the service. The callback is shared with the attempts
+// after it and whoever takes it first has the final say, a late reply to an earlier attempt
+// still counts.
+struct Attempt<F> {
+    service: NodeId,
+    body: Value,
+    retry: RetryPolicy,
+    stats: ServiceStats,
+    // The type of the request, which the stats are kept by.
+    operation: Rc<str>,
+    started: Instant,
+    retries: u32,
+    delay: Option<Duration>,
+    // Requests of this and the earlier attempts that still wait for a reply.
+    sent: Rc<RefCell<Vec<u64>>>,
+    callback: Rc<RefCell<Option<F>>>,
+}
+
+impl<F> Attempt<F> {
+    fn send<R, N, I, O>(self, ctx: &mut NodeContext<N, I, O>) -> Result<(), ServiceError>
+    where
+        R: DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+        F: FnOnce(&mut N, Result<R, ServiceError>, &mut NodeContext<N, I, O>) -> Result<()>
+            + 'static,
+    {
+        let callback = self.callback.clone();
+        let service = self.service.clone();
+        let stats = self.stats.clone();
+        let operation = self.operation.clone();
+        let started = self.started;
+        let sent = self.sent.clone();
+        let message_id = ctx
+            .raw_rpc(self.service.clone(), &self.body, move |node, reply, ctx| {
+                let reply = parse_reply(&service, reply);
+                let error = match &reply {
+                    Err(ServiceError::Service(error)) => Some(error.code()),
+                    _ => None,
+                };
+                stats.replied(&operation, started.elapsed(), error);
+                finish(&callback, &sent, node, reply, ctx)
+            })
+            .with_context(|| format!("sending request to {}", self.service))?;
+        self.sent.borrow_mut().push(message_id);
+        ctx.after(self.retry.timeout, move |node, ctx| {
+            self.timed_out(node, ctx)
+        });
+        Ok(())
+    }
+
+    fn timed_out<R, N, I, O>(mut self, node: &mut N, ctx: &mut NodeContext<N, I, O>) -> Result<()>
+    where
+        R: DeserializeOwned + 'static,
+        N: Node,
+        O: Write,
+        F: FnOnce(&mut N, Result<R, ServiceError>, &mut NodeContext<N, I, O>) -> Result<()>
+            + 'static,
+    {
+        // A reply made it in time.
+        if self.callback.borrow().is_none() {
+            return Ok(());
+        }
+        if self.retries < self.retry.retries {
+            self.retries += 1;
+            self.stats.retried(&self.operation);
+            let wait = self.retry.backoff.delay(self.delay);
+            self.delay = Some(wait);
+            ctx.after(wait, move |node, ctx| {
+                // A late reply came in while waiting.
+                if self.callback.borrow().is_none() {
+                    return Ok(());
+                }
+                let callback = self.callback.clone();
+                let sent = self.sent.clone();
+                match self.send(ctx) {
+                    Ok(()) => Ok(()),
+                    Err(error) => finish(&callback, &sent, node, Err(error), ctx),
+                }
+            });
+            return Ok(());
+        }
+        self.stats.timed_out(&self.operation);
+        finish(
+            &self.callback,
+            &self.sent,
+            node,
+            Err(ServiceError::Timeout),
+            ctx,
+        )
+    }
+}
+
+// Hands the response to the callback unless it had it already, the requests that still wait for
+// a reply have nothing to add anymore.
+fn finish<F, R, N, I, O>(
+    callback: &RefCell<Option<F>>,
+    sent: &RefCell<Vec<u64>>,
+    node: &mut N,
+    response: Result<R, ServiceError>,
+    ctx: &mut NodeContext<N, I, O>,
+) -> Result<()>
+where
+    N: Node,
+    F: FnOnce(&mut N, Result<R, ServiceError>, &mut NodeContext<N, I, O>) -> Result<()>,
+{
+    for message_id in sent.borrow_mut().drain(..) {
+        ctx.rpcs.take_raw(message_id);
+    }
+    let Some(callback) = callback.borrow_mut().take() else {
+        return Ok(());
+    };
+    callback(node, response, ctx)
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "ts")]
+struct Ts {}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename = "ts_ok")]
+struct TsOk {
+    ts: u64,
+}
+
+// Hands out timestamps that grow with every request of any node.
+#[derive(Debug, Clone)]
+pub struct LinTso {
+    service: ServiceClient,
+}
+
+impl Default for LinTso {
+    fn default() -> Self {
+        Self {
+            service: ServiceClient::new("lin-tso"),
+        }
+    }
+}
+
+impl LinTso {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retry(retry: RetryPolicy) -> Self {
+        Self {
+            service: ServiceClient::new("lin-tso").with_retry(retry),
+        }
+    }
+
+    pub fn timestamp<N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        callback: impl FnOnce(
+            &mut N,
+            Result<u64, ServiceError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), ServiceError>
+    where
+        N: Node,
+        O: Write,
+    {
+        self.service
+            .call(ctx, &Ts {}, move |node, reply: Result<TsOk, _>, ctx| {
+                callback(node, reply.map(|reply| reply.ts), ctx)
+            })
+    }
+}
@@ -0,0 +1,375 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::kv::{CasResponse, KvClient, KvError, parse};
+use crate::service::ServiceClient;
+use crate::{Node, NodeContext};
+
+#[derive(Debug, Default)]
+struct Written {
+    // What this node wrote last, as long as nobody is known to have written after it.
+    values: HashMap<String, Value>,
+    refreshes: u64,
+}
+
+// Makes sure a node reads its own writes, which seq-kv does not promise: a read that does not
+// return what this node last wrote may be stale. Such a read costs a refresh round trip, a
+// compare-and-set of the own write onto itself. When it goes through, the store still holds the
+// own write and the read was stale; when it loses, someone else wrote since and the read stands.
+//
+// Clones share what was written, so a node keeps one session around.
+#[derive(Debug, Clone)]
+pub struct SessionKv<K> {
+    inner: K,
+    written: Rc<RefCell<Written>>,
+}
+
+impl<K: KvClient> SessionKv<K> {
+    pub fn new(inner: K) -> Self {
+        Self {
+            inner,
+            written: Rc::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &K {
+        &self.inner
+    }
+
+    // How many reads needed a refresh round trip so far.
+    pub fn refreshes(&self) -> u64 {
+        self.written.borrow().refreshes
+    }
+
+    // Forgets the own write of the key, for example once another node is known to own it.
+    pub fn forget(&self, key: &str) {
+        self.written.borrow_mut().values.remove(key);
+    }
+
+    // Keeps the value as the own write if the write succeeded, and forgets the key otherwise.
+    fn record(&self, key: String, value: Value) -> impl FnOnce(bool) + 'static {
+        let written = self.written.clone();
+        move |succeeded| {
+            let mut written = written.borrow_mut();
+            if succeeded {
+                written.values.insert(key, value);
+            } else {
+                written.values.remove(&key);
+            }
+        }
+    }
+
+    // Settles whether a read that missed the own write was stale.
+    fn refresh<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: String,
+        read: Option<Value>,
+        own: Value,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<()>
+    where
+        T: DeserializeOwned,
+        N: Node,
+        O: Write,
+    {
+        self.written.borrow_mut().refreshes += 1;
+        let record = self.record(key.clone(), own.clone());
+        let refreshed = own.clone();
+        let sent =
+            self.inner
+                .compare_and_set(ctx, &key, &own, &own, false, move |node, result, ctx| {
+                    // Otherwise somebody else wrote since, and the own write tells nothing.
+                    record(matches!(result, Ok(CasResponse::Ok)));
+                    let value = match result {
+                        Ok(CasResponse::Ok) => Ok(Some(refreshed)),
+                        Ok(CasResponse::Retry) | Err(KvError::KeyDoesNotExist(_)) => Ok(read),
+                        Err(error) => Err(error),
+                    };
+                    callback(node, value.and_then(parse), ctx)
+                });
+        Ok(sent?)
+    }
+}
+
+impl<K: KvClient> KvClient for SessionKv<K> {
+    fn service(&self) -> &ServiceClient {
+        self.inner.service()
+    }
+
+    fn read<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        callback: impl FnOnce(
+            &mut N,
+            Result<Option<T>, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: DeserializeOwned,
+        N: Node,
+        O: Write,
+    {
+        let session = self.clone();
+        let read = key.to_string();
+        self.inner.read(
+            ctx,
+            key,
+            move |node, value: Result<Option<Value>, KvError>, ctx| {
+                let own = session.written.borrow().values.get(&read).cloned();
+                let value = match (value, own) {
+                    (Ok(value), Some(own)) if value.as_ref() != Some(&own) => {
+                        return session.refresh(ctx, read, value, own, callback);
+                    }
+                    (value, _) => value,
+                };
+                callback(node, value.and_then(parse), ctx)
+            },
+        )
+    }
+
+    fn write<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        value: &T,
+        callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let value = serde_json::to_value(value).context("serializing value")?;
+        let record = self.record(key.to_string(), value.clone());
+        self.inner
+            .write(ctx, key, &value, move |node, result, ctx| {
+                // A write that may or may not have happened says nothing about what is stored.
+                record(result.is_ok());
+                callback(node, result, ctx)
+            })
+    }
+
+    fn compare_and_set<T, N, I, O>(
+        &self,
+        ctx: &mut NodeContext<N, I, O>,
+        key: &str,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+        callback: impl FnOnce(
+            &mut N,
+            Result<CasResponse, KvError>,
+            &mut NodeContext<N, I, O>,
+        ) -> Result<()>
+        + 'static,
+    ) -> Result<(), KvError>
+    where
+        T: Serialize,
+        N: Node,
+        O: Write,
+    {
+        let from = serde_json::to_value(from).context("serializing value")?;
+        let to = serde_json::to_value(to).context("serializing value")?;
+        let record = self.record(key.to_string(), to.clone());
+        self.inner.compare_and_set(
+            ctx,
+            key,
+            &from,
+            &to,
+            create_if_not_exists,
+            move |node, result, ctx| {
+                record(matches!(result, Ok(CasResponse::Ok)));
+                callback(node, result, ctx)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::Read;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::MockKv;
+    use crate::testing::{Cluster, Harness, Scenario};
+
+    // Reads from a replica that only catches up when told to, like seq-kv may, and writes to
+    // the primary.
+    #[derive(Debug, Clone)]
+    struct Lagging {
+        primary: MockKv,
+        replica: MockKv,
+    }
+
+    impl KvClient for Lagging {
+        fn service(&self) -> &ServiceClient {
+            self.primary.service()
+        }
+
+        fn read<T, N, I, O>(
+            &self,
+            ctx: &mut NodeContext<N, I, O>,
+            key: &str,
+            callback: impl FnOnce(
+                &mut N,
+                Result<Option<T>, KvError>,
+                &mut NodeContext<N, I, O>,
+            ) -> Result<()>
+            + 'static,
+        ) -> Result<(), KvError>
+        where
+            T: DeserializeOwned,
+            N: Node,
+            O: Write,
+        {
+            self.replica.read(ctx, key, callback)
+        }
+
+        fn write<T, N, I, O>(
+            &self,
+            ctx: &mut NodeContext<N, I, O>,
+            key: &str,
+            value: &T,
+            callback: impl FnOnce(&mut N, Result<(), KvError>, &mut NodeContext<N, I, O>) -> Result<()>
+            + 'static,
+        ) -> Result<(), KvError>
+        where
+            T: Serialize,
+            N: Node,
+            O: Write,
+        {
+            self.primary.write(ctx, key, value, callback)
+        }
+
+        fn compare_and_set<T, N, I, O>(
+            &self,
+            ctx: &mut NodeContext<N, I, O>,
+            key: &str,
+            from: &T,
+            to: &T,
+            create_if_not_exists: bool,
+            callback: impl FnOnce(
+                &mut N,
+                Result<CasResponse, KvError>,
+                &mut NodeContext<N, I, O>,
+            ) -> Result<()>
+            + 'static,
+        ) -> Result<(), KvError>
+        where
+            T: Serialize,
+            N: Node,
+            O: Write,
+        {
+            self.primary
+                .compare_and_set(ctx, key, from, to, create_if_not_exists, callback)
+        }
+    }
+
+    enum Op {
+        Read,
+        Write(u64),
+        // Another node writes to the primary.
+        Behind(u64),
+    }
+
+    // Goes through the operations on one key one at a time, keeping what the reads returned.
+    struct Session {
+        kv: SessionKv<Lagging>,
+        ops: VecDeque<Op>,
+        busy: bool,
+        reads: Vec<(Option<u64>, u64)>,
+    }
+
+    impl Scenario for Session {
+        type Event = ();
+
+        fn start(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            self.tick(ctx)
+        }
+
+        fn tick(
+            &mut self,
+            ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+        ) -> Result<()> {
+            if self.busy {
+                return Ok(());
+            }
+            let Some(op) = self.ops.pop_front() else {
+                return Ok(());
+            };
+            match op {
+                Op::Read => {
+                    self.busy = true;
+                    self.kv
+                        .read(ctx, "k", |node: &mut Harness<Self>, read, _| {
+                            let session = &mut node.scenario;
+                            session.busy = false;
+                            session.reads.push((read?, session.kv.refreshes()));
+                            Ok(())
+                        })?;
+                }
+                Op::Write(value) => {
+                    self.busy = true;
+                    self.kv
+                        .write(ctx, "k", &value, |node: &mut Harness<Self>, written, _| {
+                            node.scenario.busy = false;
+                            Ok(written?)
+                        })?;
+                }
+                Op::Behind(value) => self.kv.inner().primary.insert("k", json!(value)),
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reads_the_own_writes_of_the_node() {
+        let kv = Lagging {
+            primary: MockKv::new(),
+            replica: MockKv::new(),
+        };
+        kv.primary.insert("k", json!(1));
+        kv.replica.insert("k", json!(1));
+        let ops = [Op::Write(2), Op::Read, Op::Behind(3), Op::Read, Op::Read];
+        let mut cluster = Cluster::new([Session {
+            kv: SessionKv::new(kv.clone()),
+            ops: ops.into(),
+            busy: false,
+            reads: Vec::new(),
+        }]);
+        cluster.run_until(|cluster| {
+            let session = cluster.scenario(0);
+            session.ops.is_empty() && !session.busy
+        });
+
+        // The first read is refreshed to the own write. Once another node wrote after it, the
+        // stale read stands and the own write is forgotten.
+        assert_eq!(
+            cluster.scenario(0).reads,
+            [(Some(2), 1), (Some(1), 2), (Some(1), 2)]
+        );
+        assert_eq!(kv.primary.get("k"), Some(json!(3)));
+    }
+}
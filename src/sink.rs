@@ -0,0 +1,54 @@
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{Message, Node, NodeContext, WorkerContext};
+
+// Sends messages on behalf of a node without naming the stream types of its socket, so helpers
+// can take `&mut dyn MessageSink` instead of being generic over them. Both `NodeContext` and
+// `WorkerContext` are sinks.
+pub trait MessageSink {
+    fn node_id(&self) -> &str;
+
+    fn send_value(&mut self, dest: &str, body: Value) -> Result<()>;
+}
+
+impl dyn MessageSink + '_ {
+    pub fn send<B>(&mut self, dest: &str, body: B) -> Result<()>
+    where
+        B: Serialize,
+    {
+        let body = serde_json::to_value(body).context("serializing message body")?;
+        self.send_value(dest, body)
+    }
+}
+
+impl<N, I, O> MessageSink for NodeContext<'_, N, I, O>
+where
+    N: Node,
+    O: Write,
+{
+    fn node_id(&self) -> &str {
+        self.node_id
+    }
+
+    fn send_value(&mut self, dest: &str, body: Value) -> Result<()> {
+        self.socket
+            .send(&Message::new(self.node_id.clone(), dest, body))
+    }
+}
+
+impl<I, O> MessageSink for WorkerContext<'_, I, O>
+where
+    O: Write,
+{
+    fn node_id(&self) -> &str {
+        WorkerContext::node_id(self)
+    }
+
+    fn send_value(&mut self, dest: &str, body: Value) -> Result<()> {
+        self.send(dest, body)
+    }
+}
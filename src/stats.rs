@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct Counters {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    flushes: AtomicU64,
+    flush_nanos: AtomicU64,
+    // Batches handed to the writer thread that are not written yet.
+    queued: AtomicUsize,
+}
+
+// What a socket wrote so far, shared by all clones of it. Rates come from comparing two
+// snapshots, for example one per tick.
+#[derive(Clone, Default)]
+pub struct WriterStats(Arc<Counters>);
+
+impl WriterStats {
+    pub fn snapshot(&self) -> WriterSnapshot {
+        let counters = &self.0;
+        WriterSnapshot {
+            at: Instant::now(),
+            messages: counters.messages.load(Ordering::Relaxed),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            flushes: counters.flushes.load(Ordering::Relaxed),
+            flush_time: Duration::from_nanos(counters.flush_nanos.load(Ordering::Relaxed)),
+            queue_depth: counters.queued.load(Ordering::Relaxed),
+        }
+    }
+
+    // A batch of frames was written and flushed, which took `elapsed`.
+    pub(crate) fn record(&self, frames: &[u8], elapsed: Duration) {
+        let counters = &self.0;
+        let messages = frames.iter().filter(|byte| **byte == b'\n').count();
+        counters
+            .messages
+            .fetch_add(messages as u64, Ordering::Relaxed);
+        counters
+            .bytes
+            .fetch_add(frames.len() as u64, Ordering::Relaxed);
+        counters.flushes.fetch_add(1, Ordering::Relaxed);
+        counters
+            .flush_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn queued(&self) {
+        self.0.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dequeued(&self, batches: usize) {
+        self.0.queued.fetch_sub(batches, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriterSnapshot {
+    pub at: Instant,
+    pub messages: u64,
+    pub bytes: u64,
+    pub flushes: u64,
+    // Spent writing and flushing, summed over all flushes.
+    pub flush_time: Duration,
+    // Batches waiting for the writer thread, always zero without one.
+    pub queue_depth: usize,
+}
+
+impl WriterSnapshot {
+    pub fn messages_per_sec(&self, earlier: &Self) -> f64 {
+        self.messages.saturating_sub(earlier.messages) as f64 / self.seconds_since(earlier)
+    }
+
+    pub fn bytes_per_sec(&self, earlier: &Self) -> f64 {
+        self.bytes.saturating_sub(earlier.bytes) as f64 / self.seconds_since(earlier)
+    }
+
+    // How long a flush took on average between the snapshots.
+    pub fn flush_latency(&self, earlier: &Self) -> Duration {
+        let flushes = self.flushes.saturating_sub(earlier.flushes);
+        if flushes == 0 {
+            return Duration::ZERO;
+        }
+        self.flush_time
+            .saturating_sub(earlier.flush_time)
+            .div_f64(flushes as f64)
+    }
+
+    fn seconds_since(&self, earlier: &Self) -> f64 {
+        self.at
+            .saturating_duration_since(earlier.at)
+            .as_secs_f64()
+            .max(f64::EPSILON)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Operation {
+    calls: u64,
+    retries: u64,
+    timeouts: u64,
+    replies: u64,
+    reply_nanos: u64,
+    precondition_failed: u64,
+}
+
+// What the requests of a service client came to so far, by the type of request like `read` or
+// `cas`. Clients that share it add up.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceStats(Arc<Mutex<BTreeMap<String, Operation>>>);
+
+impl ServiceStats {
+    pub fn snapshot(&self) -> ServiceSnapshot {
+        let operations = self.0.lock().expect("service stats poisoned");
+        ServiceSnapshot {
+            at: Instant::now(),
+            operations: operations
+                .iter()
+                .map(|(name, operation)| (name.clone(), OperationSnapshot::from(*operation)))
+                .collect(),
+        }
+    }
+
+    fn with(&self, operation: &str, record: impl FnOnce(&mut Operation)) {
+        let mut operations = self.0.lock().expect("service stats poisoned");
+        record(operations.entry(operation.to_string()).or_default());
+    }
+
+    pub(crate) fn called(&self, operation: &str) {
+        self.with(operation, |operation| operation.calls += 1);
+    }
+
+    pub(crate) fn retried(&self, operation: &str) {
+        self.with(operation, |operation| operation.retries += 1);
+    }
+
+    pub(crate) fn timed_out(&self, operation: &str) {
+        self.with(operation, |operation| operation.timeouts += 1);
+    }
+
+    // A reply arrived `elapsed` after the request was first sent, with the code of the error it
+    // carried if any.
+    pub(crate) fn replied(&self, operation: &str, elapsed: Duration, error: Option<u32>) {
+        self.with(operation, |operation| {
+            operation.replies += 1;
+            operation.reply_nanos += elapsed.as_nanos() as u64;
+            if error == Some(PRECONDITION_FAILED) {
+                operation.precondition_failed += 1;
+            }
+        });
+    }
+}
+
+const PRECONDITION_FAILED: u32 = 22;
+
+#[derive(Debug, Clone)]
+pub struct ServiceSnapshot {
+    pub at: Instant,
+    pub operations: BTreeMap<String, OperationSnapshot>,
+}
+
+impl ServiceSnapshot {
+    // All zero for operations that were never requested.
+    pub fn operation(&self, operation: &str) -> OperationSnapshot {
+        self.operations.get(operation).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationSnapshot {
+    pub calls: u64,
+    // Requests sent again because no reply came in time.
+    pub retries: u64,
+    // Calls that gave up waiting after their last retry.
+    pub timeouts: u64,
+    pub replies: u64,
+    // From the first request to the reply, summed over all replies.
+    pub reply_time: Duration,
+    // Replies that a compare-and-set lost to another write.
+    pub precondition_failed: u64,
+}
+
+impl From<Operation> for OperationSnapshot {
+    fn from(operation: Operation) -> Self {
+        Self {
+            calls: operation.calls,
+            retries: operation.retries,
+            timeouts: operation.timeouts,
+            replies: operation.replies,
+            reply_time: Duration::from_nanos(operation.reply_nanos),
+            precondition_failed: operation.precondition_failed,
+        }
+    }
+}
+
+impl OperationSnapshot {
+    // How long a reply took on average between the snapshots.
+    pub fn latency(&self, earlier: &Self) -> Duration {
+        let replies = self.replies.saturating_sub(earlier.replies);
+        if replies == 0 {
+            return Duration::ZERO;
+        }
+        self.reply_time
+            .saturating_sub(earlier.reply_time)
+            .div_f64(replies as f64)
+    }
+
+    // The share of replies between the snapshots that were conflicts, between 0 and 1.
+    pub fn conflict_rate(&self, earlier: &Self) -> f64 {
+        let replies = self.replies.saturating_sub(earlier.replies);
+        if replies == 0 {
+            return 0.0;
+        }
+        self.precondition_failed
+            .saturating_sub(earlier.precondition_failed) as f64
+            / replies as f64
+    }
+}
@@ -0,0 +1,251 @@
+// Helpers for tests that run nodes on a loopback network.
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::runtime::Runtime;
+use crate::{
+    EventIncjector, Init, Loopback, LoopbackTransport, MaelstromError, Never, Node, NodeContext,
+    Reply, RequestInfo, RunConfig, Socket, Transport,
+};
+
+// Talks to nodes on the network the way Maelstrom does.
+pub(crate) struct Client {
+    id: String,
+    transport: LoopbackTransport,
+    msg_id: u64,
+}
+
+impl Client {
+    pub(crate) fn new(network: &Loopback, id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            transport: network.transport(id),
+            msg_id: 0,
+        }
+    }
+
+    // Sends the body with the next message id, which is returned.
+    pub(crate) fn send(&mut self, dest: &str, mut body: Value) -> u64 {
+        self.msg_id += 1;
+        body["msg_id"] = json!(self.msg_id);
+        self.send_raw(dest, body);
+        self.msg_id
+    }
+
+    // Sends the body as it is, like a reply or a request with a message id of its own.
+    pub(crate) fn send_raw(&self, dest: &str, body: Value) {
+        let frame = json!({ "src": self.id, "dest": dest, "body": body });
+        self.transport.send(&frame.to_string()).unwrap();
+    }
+
+    // The next message to this client, whole.
+    pub(crate) fn recv(&self) -> Value {
+        let frame = self
+            .transport
+            .recv()
+            .unwrap()
+            .expect("the client is connected");
+        serde_json::from_str(&frame).unwrap()
+    }
+
+    // Sends the request and returns the body of its reply, skipping other messages.
+    pub(crate) fn request(&mut self, dest: &str, body: Value) -> Value {
+        let msg_id = self.send(dest, body);
+        loop {
+            let reply = self.recv();
+            if reply["body"]["in_reply_to"] == msg_id {
+                return reply["body"].clone();
+            }
+        }
+    }
+
+    pub(crate) fn init(&mut self, dest: &str, node_ids: &[&str]) {
+        let init = json!({ "type": "init", "node_id": dest, "node_ids": node_ids });
+        assert_eq!(self.request(dest, init)["type"], "init_ok");
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Request {
+    Echo { echo: Value },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Response {
+    EchoOk { echo: Value },
+}
+
+// Answers every `echo` with what it carries.
+pub(crate) struct Echo;
+
+impl Node for Echo {
+    type Request = Request;
+    type Response = Response;
+    type PeerRequest = Never;
+    type PeerResponse = Never;
+    type InboundResponse = Never;
+    type Event = ();
+
+    type InitState = ();
+
+    fn from_init(_: Init, _: (), _: EventIncjector<Self>) -> Self {
+        Self
+    }
+
+    fn handle_request(
+        &mut self,
+        request: Request,
+        _: RequestInfo,
+        _: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Response>, MaelstromError> {
+        let Request::Echo { echo } = request;
+        Ok(Response::EchoOk { echo }.into())
+    }
+}
+
+// What a node of a `Cluster` does, for helpers that only work through the context of a node.
+pub(crate) trait Scenario: Sized + 'static {
+    type Event: Send + 'static;
+
+    // Called on the first tick.
+    fn start(&mut self, ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>) -> Result<()>;
+
+    // Called on every tick after the first.
+    fn tick(&mut self, ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>) -> Result<()> {
+        let _ = ctx;
+        Ok(())
+    }
+
+    fn event(
+        &mut self,
+        event: Self::Event,
+        ctx: &mut NodeContext<Harness<Self>, impl Read, impl Write>,
+    ) -> Result<()> {
+        let _ = (event, ctx);
+        Ok(())
+    }
+}
+
+// Runs a scenario, which callbacks reach through `scenario`.
+pub(crate) struct Harness<S> {
+    pub(crate) scenario: S,
+    started: bool,
+}
+
+impl<S: Scenario> Node for Harness<S> {
+    type Request = Never;
+    type Response = Never;
+    type PeerRequest = Never;
+    type PeerResponse = Never;
+    type InboundResponse = Never;
+    type Event = S::Event;
+
+    type InitState = S;
+
+    const TICK_INTERVAL: Option<Duration> = Some(Duration::from_millis(1));
+
+    fn from_init(_: Init, scenario: S, _: EventIncjector<Self>) -> Self {
+        Self {
+            scenario,
+            started: false,
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        request: Never,
+        _: RequestInfo,
+        _: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<Reply<Never>, MaelstromError> {
+        match request {}
+    }
+
+    fn handle_event(
+        &mut self,
+        event: S::Event,
+        ctx: &mut NodeContext<Self, impl Read, impl Write>,
+    ) -> Result<()> {
+        self.scenario.event(event, ctx)
+    }
+
+    fn on_tick(&mut self, ctx: &mut NodeContext<Self, impl Read, impl Write>) -> Result<()> {
+        if self.started {
+            self.scenario.tick(ctx)
+        } else {
+            self.started = true;
+            self.scenario.start(ctx)
+        }
+    }
+}
+
+// Runs scenarios as the nodes `n1`, `n2`, ... on the calling thread, taking turns, so they can
+// share a `MockKv`.
+pub(crate) struct Cluster<S: Scenario> {
+    // Keeps the transports of the nodes connected.
+    _network: Loopback,
+    runtimes: Vec<Runtime<Harness<S>, io::Empty, io::Sink>>,
+}
+
+impl<S: Scenario> Cluster<S> {
+    pub(crate) fn new(scenarios: impl IntoIterator<Item = S>) -> Self {
+        let network = Loopback::new();
+        let scenarios: Vec<S> = scenarios.into_iter().collect();
+        let node_ids: Vec<String> = (1..=scenarios.len()).map(|n| format!("n{n}")).collect();
+        let maelstrom = network.transport("c1");
+        let runtimes = scenarios
+            .into_iter()
+            .zip(&node_ids)
+            .map(|(scenario, node_id)| {
+                let socket = Socket::from_transport(network.transport(node_id));
+                let init = json!({
+                    "src": "c1",
+                    "dest": node_id,
+                    "body": { "type": "init", "msg_id": 1, "node_id": node_id, "node_ids": node_ids },
+                });
+                maelstrom.send(&init.to_string()).unwrap();
+                Runtime::new(scenario, socket, RunConfig::default()).unwrap()
+            })
+            .collect();
+        Self {
+            _network: network,
+            runtimes,
+        }
+    }
+
+    pub(crate) fn scenario(&self, node: usize) -> &S {
+        &self.runtimes[node].node().scenario
+    }
+
+    pub(crate) fn scenario_mut(&mut self, node: usize) -> &mut S {
+        &mut self.runtimes[node].node_mut().scenario
+    }
+
+    // Lets every node run for a millisecond at a time until the condition holds, for at most five
+    // seconds.
+    pub(crate) fn run_until(&mut self, mut done: impl FnMut(&Self) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(self) {
+            assert!(Instant::now() < deadline, "the cluster did not get there");
+            self.step();
+        }
+    }
+
+    pub(crate) fn run_for(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        for runtime in &mut self.runtimes {
+            runtime.run_for(Duration::from_millis(1)).unwrap();
+        }
+    }
+}
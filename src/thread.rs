@@ -0,0 +1,47 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+use std::thread;
+
+use crate::config::PanicPolicy;
+
+const PREFIX: &str = "mael-";
+
+// Starts a background thread under a `mael-` name, its panics are logged by the hook.
+pub(crate) fn spawn(name: &str, f: impl FnOnce() + Send + 'static) {
+    install_panic_hook();
+    thread::Builder::new()
+        .name(format!("{PREFIX}{name}"))
+        .spawn(f)
+        .unwrap_or_else(|error| panic!("failed to spawn thread {PREFIX}{name}: {error}"));
+}
+
+// Like `spawn`, but a panic aborts the process or is handed to `on_panic` depending on the policy.
+pub(crate) fn spawn_supervised(
+    name: &'static str,
+    policy: PanicPolicy,
+    on_panic: impl FnOnce(&'static str) + Send + 'static,
+    f: impl FnOnce() + Send + 'static,
+) {
+    spawn(name, move || {
+        if panic::catch_unwind(AssertUnwindSafe(f)).is_ok() {
+            return;
+        }
+        match policy {
+            PanicPolicy::Abort => std::process::abort(),
+            PanicPolicy::Shutdown => on_panic(name),
+        }
+    });
+}
+
+// Logs panics of our own threads, those of other threads go to the hook that was installed
+// before.
+fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| match thread::current().name() {
+            Some(name) if name.starts_with(PREFIX) => eprintln!("thread {name} {info}"),
+            _ => previous(info),
+        }));
+    });
+}
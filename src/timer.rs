@@ -0,0 +1,149 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::config::PanicPolicy;
+use crate::thread;
+
+struct Timed<E> {
+    deadline: Instant,
+    period: Option<Duration>,
+    make: Box<dyn FnMut() -> E + Send>,
+}
+
+pub(crate) struct Timer<E> {
+    sender: mpsc::Sender<Timed<E>>,
+}
+
+impl<E> Clone for Timer<E> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<E> Timer<E>
+where
+    E: Send + 'static,
+{
+    // The sink returns false once nobody is interested in timer events anymore, which stops the
+    // scheduler thread.
+    pub(crate) fn spawn(
+        policy: PanicPolicy,
+        on_panic: impl FnOnce(&'static str) + Send + 'static,
+        mut sink: impl FnMut(E) -> bool + Send + 'static,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel::<Timed<E>>();
+
+        thread::spawn_supervised("timer", policy, on_panic, move || {
+            let mut next_key = 0u64;
+            let mut deadlines: BinaryHeap<Reverse<(Instant, u64)>> = BinaryHeap::new();
+            let mut timers: HashMap<u64, Timed<E>> = HashMap::new();
+
+            let mut connected = true;
+
+            loop {
+                let next_deadline = deadlines.peek().map(|&Reverse((deadline, _))| deadline);
+                let command = match (connected, next_deadline) {
+                    (true, Some(deadline)) => {
+                        match receiver
+                            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                        {
+                            Ok(timed) => Some(timed),
+                            Err(mpsc::RecvTimeoutError::Timeout) => None,
+                            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                                connected = false;
+                                None
+                            }
+                        }
+                    }
+                    (true, None) => match receiver.recv() {
+                        Ok(timed) => Some(timed),
+                        Err(_) => return,
+                    },
+                    // All injectors are gone, but scheduled timers still have to fire.
+                    (false, Some(deadline)) => {
+                        std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                        None
+                    }
+                    (false, None) => return,
+                };
+                if let Some(timed) = command {
+                    deadlines.push(Reverse((timed.deadline, next_key)));
+                    timers.insert(next_key, timed);
+                    next_key += 1;
+                    continue;
+                }
+
+                let now = Instant::now();
+                while let Some(&Reverse((deadline, key))) = deadlines.peek() {
+                    if deadline > now {
+                        break;
+                    }
+                    deadlines.pop();
+                    let mut timed = timers.remove(&key).expect("timer should be known");
+                    if !sink((timed.make)()) {
+                        return;
+                    }
+                    if let Some(period) = timed.period {
+                        timed.deadline = deadline + period;
+                        deadlines.push(Reverse((timed.deadline, key)));
+                        timers.insert(key, timed);
+                    }
+                }
+            }
+        });
+
+        Self { sender }
+    }
+}
+
+impl<E> Timer<E> {
+    pub(crate) fn after(&self, delay: Duration, event: E)
+    where
+        E: Send + 'static,
+    {
+        let mut event = Some(event);
+        self.schedule(Timed {
+            deadline: Instant::now() + delay,
+            period: None,
+            make: Box::new(move || event.take().expect("one-shot timer fires once")),
+        })
+    }
+
+    pub(crate) fn interval(&self, period: Duration, make: impl Fn() -> E + Send + 'static) {
+        self.schedule(Timed {
+            deadline: Instant::now() + period,
+            period: Some(period),
+            make: Box::new(make),
+        })
+    }
+
+    fn schedule(&self, timed: Timed<E>) {
+        // The scheduler only stops once the node shut down, the event would be dropped anyway.
+        let _ = self.sender.send(timed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_by_deadline_and_repeats_intervals() {
+        let (sender, receiver) = mpsc::channel();
+        let timer = Timer::spawn(
+            PanicPolicy::default(),
+            |_| {},
+            move |event| sender.send(event).is_ok(),
+        );
+        timer.after(Duration::from_millis(120), "late");
+        timer.interval(Duration::from_millis(50), || "tick");
+        timer.after(Duration::from_millis(20), "early");
+
+        let fired: Vec<&str> = receiver.iter().take(5).collect();
+        assert_eq!(fired, ["early", "tick", "tick", "late", "tick"]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde::Deserialize;
+
+use crate::NodeId;
+
+// The neighbours Maelstrom hands every node in the `topology` message. The runtime answers the
+// message itself and passes it on to `Node::on_topology`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Topology(HashMap<NodeId, HashSet<NodeId>>);
+
+impl Topology {
+    pub fn neighbours(&self, node: &str) -> impl Iterator<Item = &NodeId> {
+        self.0.get(node).into_iter().flatten()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.0.keys()
+    }
+
+    pub fn graph(&self) -> &HashMap<NodeId, HashSet<NodeId>> {
+        &self.0
+    }
+
+    // A tree along the edges of the topology, see `SpanningTree::from_graph`.
+    pub fn spanning_tree(&self, degree: usize) -> Option<SpanningTree> {
+        SpanningTree::from_graph(self.nodes(), &self.0, degree)
+    }
+}
+
+// A tree over the nodes of the cluster, for sending things down from the root or up to it with
+// every node hearing from its parent and its children only. Every node computes the same tree
+// from the same ids, so they agree on it without talking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanningTree {
+    root: NodeId,
+    parents: HashMap<NodeId, NodeId>,
+    children: HashMap<NodeId, Vec<NodeId>>,
+    depth: usize,
+}
+
+impl SpanningTree {
+    // Fills the tree level by level with up to `degree` children per node, so it is about
+    // `log_degree(n)` deep. Returns `None` without nodes.
+    pub fn balanced<'a>(
+        nodes: impl IntoIterator<Item = &'a NodeId>,
+        degree: usize,
+    ) -> Option<Self> {
+        let nodes = ordered(nodes);
+        let degree = degree.max(1);
+        let mut tree = Self::rooted(nodes.first()?.clone());
+        for (index, node) in nodes.iter().enumerate().skip(1) {
+            tree.attach(&nodes[(index - 1) / degree], node.clone());
+        }
+        Some(tree)
+    }
+
+    // Follows the edges of the graph breadth-first from the lowest id, like the topology that
+    // Maelstrom hands out, with up to `degree` children per node. Nodes the edges do not reach
+    // in time are added under the least deep nodes that have room left.
+    pub fn from_graph<'a>(
+        nodes: impl IntoIterator<Item = &'a NodeId>,
+        graph: &HashMap<NodeId, HashSet<NodeId>>,
+        degree: usize,
+    ) -> Option<Self> {
+        let nodes = ordered(nodes);
+        let degree = degree.max(1);
+        let mut tree = Self::rooted(nodes.first()?.clone());
+        let known: HashSet<&NodeId> = nodes.iter().collect();
+        // Breadth-first, so also from the least deep node to the deepest.
+        let mut visited = vec![tree.root.clone()];
+        let mut queue = VecDeque::from([tree.root.clone()]);
+        while let Some(node) = queue.pop_front() {
+            let neighbours = ordered(graph.get(&node).into_iter().flatten());
+            for neighbour in neighbours {
+                if tree.children(&node).len() >= degree {
+                    break;
+                }
+                if !known.contains(&neighbour) || tree.contains(&neighbour) {
+                    continue;
+                }
+                tree.attach(&node, neighbour.clone());
+                visited.push(neighbour.clone());
+                queue.push_back(neighbour);
+            }
+        }
+        let mut parent = 0;
+        for node in nodes {
+            if tree.contains(&node) {
+                continue;
+            }
+            while tree.children(&visited[parent]).len() >= degree {
+                parent += 1;
+            }
+            tree.attach(&visited[parent], node.clone());
+            visited.push(node);
+        }
+        Some(tree)
+    }
+
+    pub fn root(&self) -> &NodeId {
+        &self.root
+    }
+
+    // `None` for the root and for nodes outside the tree.
+    pub fn parent(&self, node: &str) -> Option<&NodeId> {
+        self.parents.get(node)
+    }
+
+    pub fn children(&self, node: &str) -> &[NodeId] {
+        self.children.get(node).map_or(&[], Vec::as_slice)
+    }
+
+    // The parent and the children, everyone the node talks to.
+    pub fn neighbours(&self, node: &str) -> impl Iterator<Item = &NodeId> {
+        self.parent(node).into_iter().chain(self.children(node))
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        *self.root == *node || self.parents.contains_key(node)
+    }
+
+    // Edges from the root to the deepest node.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn rooted(root: NodeId) -> Self {
+        Self {
+            root,
+            parents: HashMap::new(),
+            children: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn attach(&mut self, parent: &NodeId, node: NodeId) {
+        let depth = self.level(parent) + 1;
+        self.depth = self.depth.max(depth);
+        self.children
+            .entry(parent.clone())
+            .or_default()
+            .push(node.clone());
+        self.parents.insert(node, parent.clone());
+    }
+
+    fn level(&self, node: &str) -> usize {
+        let mut level = 0;
+        let mut node = node;
+        while let Some(parent) = self.parents.get(node) {
+            level += 1;
+            node = parent;
+        }
+        level
+    }
+}
+
+// By length first, so `n2` comes before `n10`, which is the same on every node.
+fn ordered<'a>(nodes: impl IntoIterator<Item = &'a NodeId>) -> Vec<NodeId> {
+    let mut nodes: Vec<NodeId> = nodes.into_iter().cloned().collect();
+    nodes.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    nodes.dedup();
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn ids(nodes: impl IntoIterator<Item = usize>) -> Vec<NodeId> {
+        nodes
+            .into_iter()
+            .map(|node| NodeId::new(&format!("n{node}")))
+            .collect()
+    }
+
+    // Every node but the root hangs under a parent that lists it, with no more than `degree`
+    // children per node.
+    fn assert_tree(tree: &SpanningTree, nodes: &[NodeId], degree: usize) {
+        for node in nodes {
+            assert!(tree.contains(node));
+            assert!(tree.children(node).len() <= degree);
+            match tree.parent(node) {
+                Some(parent) => assert!(tree.children(parent).contains(node)),
+                None => assert_eq!(node, tree.root()),
+            }
+        }
+    }
+
+    #[test]
+    fn balanced_trees_are_shallow_and_the_same_everywhere() {
+        let nodes = ids(1..=25);
+        let tree = SpanningTree::balanced(&nodes, 4).unwrap();
+        assert_tree(&tree, &nodes, 4);
+        assert_eq!(tree.root().as_str(), "n1");
+        // 1 + 4 + 16 nodes fill the first three levels.
+        assert_eq!(tree.depth(), 3);
+        assert_eq!(tree.children("n1"), ids(2..=5));
+        assert_eq!(tree.parent("n10").unwrap().as_str(), "n3");
+
+        let mut shuffled = nodes.clone();
+        shuffled.reverse();
+        assert_eq!(SpanningTree::balanced(&shuffled, 4), Some(tree));
+        assert_eq!(SpanningTree::balanced(&[], 4), None);
+    }
+
+    #[test]
+    fn trees_follow_the_edges_of_the_topology() {
+        // A line from n1 to n4, and n5 without any edges.
+        let topology: Topology = serde_json::from_value(json!({
+            "n1": ["n2"],
+            "n2": ["n1", "n3"],
+            "n3": ["n2", "n4"],
+            "n4": ["n3"],
+            "n5": [],
+        }))
+        .unwrap();
+        let tree = topology.spanning_tree(2).unwrap();
+        let nodes = ids(1..=5);
+        assert_tree(&tree, &nodes, 2);
+        assert_eq!(tree.parent("n2").unwrap().as_str(), "n1");
+        assert_eq!(tree.parent("n3").unwrap().as_str(), "n2");
+        assert_eq!(tree.parent("n4").unwrap().as_str(), "n3");
+        // Added under the least deep node with room.
+        assert_eq!(tree.parent("n5").unwrap().as_str(), "n1");
+        assert_eq!(tree.depth(), 3);
+        assert_eq!(
+            tree.neighbours("n2")
+                .map(NodeId::as_str)
+                .collect::<Vec<_>>(),
+            ["n1", "n3"]
+        );
+    }
+}
@@ -0,0 +1,760 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Stdin, Stdout, Write};
+use std::iter;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::buffers::Buffers;
+use crate::diagnostics::{self, Subject, Warning};
+use crate::encoding::{Encoding, Json};
+use crate::stats::WriterStats;
+use crate::{Input, Layers, Message, NodeId, Output, Socket, Source, write_frame};
+
+// Carries frames, one serialized message each without the newline, between the node and the
+// outside world. Receiving happens on the reader thread while other threads send, so
+// implementations take care of their own locking.
+pub trait Transport: Send + Sync {
+    fn send(&self, frame: &str) -> Result<()>;
+
+    // Called once after a batch of frames was sent.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // Blocks until the next frame arrives, `None` once no more frames will arrive.
+    fn recv(&self) -> Result<Option<String>>;
+}
+
+// Newline delimited frames over stdin and stdout, like `Socket::new` with the standard streams.
+pub struct StdioTransport {
+    stdin: Mutex<BufReader<Stdin>>,
+    stdout: Mutex<Stdout>,
+}
+
+impl StdioTransport {
+    pub fn new() -> Self {
+        Self {
+            stdin: Mutex::new(BufReader::new(io::stdin())),
+            stdout: Mutex::new(io::stdout()),
+        }
+    }
+}
+
+impl Default for StdioTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for StdioTransport {
+    fn send(&self, frame: &str) -> Result<()> {
+        let mut stdout = self.stdout.lock().expect("failed to lock stdout");
+        stdout
+            .write_all(frame.as_bytes())
+            .and_then(|()| stdout.write_all(b"\n"))
+            .context("writing frame to stdout")
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut stdout = self.stdout.lock().expect("failed to lock stdout");
+        stdout.flush().context("flushing stdout")
+    }
+
+    fn recv(&self) -> Result<Option<String>> {
+        let mut stdin = self.stdin.lock().expect("failed to lock stdin");
+        let mut line = String::new();
+        if stdin
+            .read_line(&mut line)
+            .context("reading frame from stdin")?
+            == 0
+        {
+            return Ok(None);
+        }
+        line.truncate(line.trim_end().len());
+        Ok(Some(line))
+    }
+}
+
+// A connection oriented stream that frames can be exchanged over.
+trait Connection: Read + Write + Send + Sized + 'static {
+    type Address: fmt::Debug + Send + Sync;
+
+    fn connect(address: &Self::Address) -> io::Result<Self>;
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Connection for TcpStream {
+    type Address = SocketAddr;
+
+    fn connect(address: &SocketAddr) -> io::Result<Self> {
+        TcpStream::connect(address)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+impl Connection for UnixStream {
+    type Address = PathBuf;
+
+    fn connect(address: &PathBuf) -> io::Result<Self> {
+        UnixStream::connect(address)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+type Routes<S> = Arc<Mutex<HashMap<String, S>>>;
+
+#[derive(Deserialize)]
+struct Addressed {
+    src: String,
+    dest: String,
+}
+
+// Encoded frames over connections. Frames are sent to the peer named by their `dest`,
+// connecting to its address on first use. Everybody that connects to the listener is answered
+// over their own connection once they sent a frame.
+struct Streams<S: Connection> {
+    frames: Mutex<mpsc::Receiver<String>>,
+    sender: mpsc::Sender<String>,
+    routes: Routes<S>,
+    peers: HashMap<String, S::Address>,
+    encoding: Arc<dyn Encoding>,
+}
+
+impl<S: Connection> Streams<S> {
+    fn listen<L>(
+        incoming: L,
+        peers: HashMap<String, S::Address>,
+        encoding: Arc<dyn Encoding>,
+    ) -> Self
+    where
+        L: Iterator<Item = io::Result<S>> + Send + 'static,
+    {
+        let (sender, frames) = mpsc::channel();
+        let routes = Routes::default();
+        {
+            let sender = sender.clone();
+            let routes = routes.clone();
+            let encoding = encoding.clone();
+            crate::thread::spawn("accept", move || {
+                for stream in incoming {
+                    match stream {
+                        Ok(stream) => {
+                            read_frames(stream, sender.clone(), routes.clone(), encoding.clone())
+                        }
+                        Err(error) => eprintln!("accepting connection failed: {error}"),
+                    }
+                }
+            });
+        }
+        Self {
+            frames: Mutex::new(frames),
+            sender,
+            routes,
+            peers,
+            encoding,
+        }
+    }
+
+    fn connect(&self, dest: &str) -> Result<S> {
+        let address = self
+            .peers
+            .get(dest)
+            .ok_or_else(|| anyhow!("no address known for {dest}"))?;
+        let stream =
+            S::connect(address).with_context(|| format!("connecting to {dest} at {address:?}"))?;
+        // Peers answer over the connection they were reached on.
+        read_frames(
+            stream.try_clone().context("cloning stream")?,
+            self.sender.clone(),
+            self.routes.clone(),
+            self.encoding.clone(),
+        );
+        Ok(stream)
+    }
+
+    fn send(&self, frame: &str) -> Result<()> {
+        let dest = serde_json::from_str::<Addressed>(frame)
+            .context("reading destination of frame")?
+            .dest;
+        let mut routes = self.routes.lock().expect("failed to lock routes");
+        if !routes.contains_key(&dest) {
+            let stream = self.connect(&dest)?;
+            routes.insert(dest.clone(), stream);
+        }
+        let stream = routes.get_mut(&dest).expect("route was just added");
+        let mut encoded = Vec::with_capacity(frame.len() + 1);
+        self.encoding.write_frame(frame, &mut encoded)?;
+        if let Err(error) = stream.write_all(&encoded) {
+            // Connects again on the next frame.
+            routes.remove(&dest);
+            return Err(error).with_context(|| format!("sending frame to {dest}"));
+        }
+        Ok(())
+    }
+
+    fn recv(&self) -> Result<Option<String>> {
+        Ok(self
+            .frames
+            .lock()
+            .expect("failed to lock frames")
+            .recv()
+            .ok())
+    }
+}
+
+// Hands every frame of the connection to the transport, the connection becomes the route to
+// whoever sent the frames.
+fn read_frames<S: Connection>(
+    stream: S,
+    sender: mpsc::Sender<String>,
+    routes: Routes<S>,
+    encoding: Arc<dyn Encoding>,
+) {
+    crate::thread::spawn("connection", move || {
+        let Ok(route) = stream.try_clone() else {
+            return;
+        };
+        let mut route = Some(route);
+        let mut stream = BufReader::new(stream);
+        loop {
+            let line = match encoding.read_frame(&mut stream) {
+                Ok(Some(line)) => line,
+                Ok(None) => return,
+                Err(error) => {
+                    eprintln!("dropping connection: {error:#}");
+                    return;
+                }
+            };
+            if let Ok(addressed) = serde_json::from_str::<Addressed>(&line)
+                && let Some(route) = route.take()
+            {
+                routes
+                    .lock()
+                    .expect("failed to lock routes")
+                    .entry(addressed.src)
+                    .or_insert(route);
+            }
+            if sender.send(line).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+// Frames over TCP, for running nodes outside of Maelstrom.
+pub struct TcpTransport {
+    streams: Streams<TcpStream>,
+    local_addr: SocketAddr,
+}
+
+impl TcpTransport {
+    pub fn bind(address: impl ToSocketAddrs, peers: HashMap<String, SocketAddr>) -> Result<Self> {
+        Self::bind_with_encoding(address, peers, Json)
+    }
+
+    // Every peer has to use the same encoding.
+    pub fn bind_with_encoding(
+        address: impl ToSocketAddrs,
+        peers: HashMap<String, SocketAddr>,
+        encoding: impl Encoding + 'static,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(address).context("binding tcp listener")?;
+        let local_addr = listener
+            .local_addr()
+            .context("reading tcp listener address")?;
+        let incoming = iter::repeat_with(move || listener.accept().map(|(stream, _)| stream));
+        Ok(Self {
+            streams: Streams::listen(incoming, peers, Arc::new(encoding)),
+            local_addr,
+        })
+    }
+
+    // Where peers reach this transport, with the port picked when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, frame: &str) -> Result<()> {
+        self.streams.send(frame)
+    }
+
+    fn recv(&self) -> Result<Option<String>> {
+        self.streams.recv()
+    }
+}
+
+// Frames over unix sockets, for nodes running as local processes. Peers are
+// reached through the socket paths they listen on.
+pub struct UnixTransport {
+    streams: Streams<UnixStream>,
+}
+
+impl UnixTransport {
+    pub fn bind(path: impl AsRef<Path>, peers: HashMap<String, PathBuf>) -> Result<Self> {
+        Self::bind_with_encoding(path, peers, Json)
+    }
+
+    // Every peer has to use the same encoding.
+    pub fn bind_with_encoding(
+        path: impl AsRef<Path>,
+        peers: HashMap<String, PathBuf>,
+        encoding: impl Encoding + 'static,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("binding unix listener at {}", path.display()))?;
+        let incoming = iter::repeat_with(move || listener.accept().map(|(stream, _)| stream));
+        Ok(Self {
+            streams: Streams::listen(incoming, peers, Arc::new(encoding)),
+        })
+    }
+}
+
+impl Transport for UnixTransport {
+    fn send(&self, frame: &str) -> Result<()> {
+        self.streams.send(frame)
+    }
+
+    fn recv(&self) -> Result<Option<String>> {
+        self.streams.recv()
+    }
+}
+
+// Connects transports within one process, e.g. a cluster of nodes in a test together with the
+// clients that talk to them. Frames go straight to the transport named by their `dest`, frames
+// to a disconnected one are dropped like the network drops them for a node that is down.
+#[derive(Clone, Default)]
+pub struct Loopback {
+    // `None` once disconnected.
+    inboxes: Arc<Mutex<HashMap<String, Option<mpsc::Sender<String>>>>>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces an earlier transport under the same id.
+    pub fn transport(&self, id: impl Into<String>) -> LoopbackTransport {
+        let (sender, frames) = mpsc::channel();
+        self.lock_inboxes().insert(id.into(), Some(sender));
+        LoopbackTransport {
+            network: self.clone(),
+            frames: Mutex::new(frames),
+        }
+    }
+
+    // The transport stops receiving once the frames sent to it so far are received, which ends
+    // the input of a node running on it.
+    pub fn disconnect(&self, id: &str) {
+        if let Some(inbox) = self.lock_inboxes().get_mut(id) {
+            *inbox = None;
+        }
+    }
+
+    fn lock_inboxes(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<String, Option<mpsc::Sender<String>>>> {
+        self.inboxes
+            .lock()
+            .expect("failed to lock loopback inboxes")
+    }
+}
+
+pub struct LoopbackTransport {
+    network: Loopback,
+    frames: Mutex<mpsc::Receiver<String>>,
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&self, frame: &str) -> Result<()> {
+        let dest = serde_json::from_str::<Addressed>(frame)
+            .context("reading destination of frame")?
+            .dest;
+        let inboxes = self.network.lock_inboxes();
+        let Some(inbox) = inboxes
+            .get(&dest)
+            .ok_or_else(|| anyhow!("no loopback transport for {dest}"))?
+        else {
+            return Ok(());
+        };
+        inbox
+            .send(frame.to_string())
+            .map_err(|_| anyhow!("loopback transport for {dest} is gone"))
+    }
+
+    fn recv(&self) -> Result<Option<String>> {
+        Ok(self
+            .frames
+            .lock()
+            .expect("failed to lock loopback frames")
+            .recv()
+            .ok())
+    }
+}
+
+// The stream types only fill in the parameters of the socket, all frames go through the
+// transport.
+impl Socket<io::Empty, io::Sink> {
+    pub fn from_transport(transport: impl Transport + 'static) -> Self {
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        Self {
+            stdin: Arc::new(Mutex::new(Input {
+                source: Source::Transport(transport.clone()),
+                offset: 0,
+            })),
+            stdout: Output::Transport(transport),
+            layers: Layers::default(),
+            sides: SideChannels::default(),
+            max_frame_size: None,
+            buffers: Buffers::default(),
+            stats: WriterStats::default(),
+        }
+    }
+}
+
+// Frames that are read ahead of the socket.
+const MERGED_CAPACITY: usize = 64;
+
+struct SideChannel {
+    transport: Arc<dyn Transport>,
+    dests: HashSet<NodeId>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct SideChannels(Arc<Mutex<Vec<SideChannel>>>);
+
+impl SideChannels {
+    pub(crate) fn route(&self, max_frame_size: Option<usize>) -> Routed<'_> {
+        Routed {
+            sides: self.0.lock().expect("failed to lock side channels"),
+            sent: Vec::new(),
+            max_frame_size,
+        }
+    }
+}
+
+// Sends frames for the destinations of side channels right away, the others are left for the
+// primary output.
+pub(crate) struct Routed<'a> {
+    sides: MutexGuard<'a, Vec<SideChannel>>,
+    // Side channels that have to be flushed.
+    sent: Vec<usize>,
+    max_frame_size: Option<usize>,
+}
+
+impl Routed<'_> {
+    pub(crate) fn frame<R>(&mut self, frames: &mut Vec<u8>, message: &Message<R>) -> Result<()>
+    where
+        R: Serialize,
+    {
+        self.route(frames, message).inspect_err(|error| {
+            diagnostics::warn(
+                Warning::SendFailed,
+                format_args!("{}: {error:#}", Subject::of(message)),
+            );
+        })
+    }
+
+    fn route<R>(&mut self, frames: &mut Vec<u8>, message: &Message<R>) -> Result<()>
+    where
+        R: Serialize,
+    {
+        let Some(index) = self
+            .sides
+            .iter()
+            .position(|side| side.dests.contains(message.dest()))
+        else {
+            let start = frames.len();
+            write_frame(frames, message)?;
+            // Without the newline.
+            return self.check_size(frames.len() - start - 1, message);
+        };
+        let frame = serde_json::to_string(message).context("serializing message")?;
+        self.check_size(frame.len(), message)?;
+        self.sides[index]
+            .transport
+            .send(&frame)
+            .with_context(|| format!("sending message to side channel of {}", message.dest()))?;
+        if !self.sent.contains(&index) {
+            self.sent.push(index);
+        }
+        Ok(())
+    }
+
+    fn check_size<R>(&self, len: usize, message: &Message<R>) -> Result<()> {
+        match self.max_frame_size {
+            Some(max) if len > max => bail!(
+                "message to {} is {len} bytes, more than the limit of {max} bytes",
+                message.dest()
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn flush(self) -> Result<()> {
+        for index in self.sent {
+            self.sides[index]
+                .transport
+                .flush()
+                .context("flushing side channel")?;
+        }
+        Ok(())
+    }
+}
+
+impl<I, O> Socket<I, O>
+where
+    I: Read + Send + 'static,
+{
+    // Adds a transport next to the primary input and output of the socket. Messages to any of
+    // `dests` are sent over it and the frames it receives are handled like those of the primary
+    // input, which still decides when the input ends.
+    pub fn with_side_channel(
+        self,
+        transport: impl Transport + 'static,
+        dests: impl IntoIterator<Item = impl Into<NodeId>>,
+    ) -> Self {
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let sender = {
+            let mut input = self.stdin.lock().expect("failed to lock stdin");
+            if let Source::Merged { sender, .. } = &input.source {
+                sender.clone()
+            } else {
+                let (sender, frames) = mpsc::sync_channel(MERGED_CAPACITY);
+                let primary = mem::replace(
+                    &mut input.source,
+                    Source::Merged {
+                        frames,
+                        sender: sender.clone(),
+                    },
+                );
+                forward_primary(primary, sender.clone());
+                sender
+            }
+        };
+        {
+            let transport = transport.clone();
+            crate::thread::spawn("side-channel", move || {
+                loop {
+                    match transport.recv() {
+                        Ok(Some(frame)) => {
+                            if sender.send(Ok(Some(frame))).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => return,
+                        Err(error) => {
+                            eprintln!("side channel stopped receiving: {error:#}");
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+        self.sides
+            .0
+            .lock()
+            .expect("failed to lock side channels")
+            .push(SideChannel {
+                transport,
+                dests: dests.into_iter().map(Into::into).collect(),
+            });
+        self
+    }
+}
+
+// Read errors are passed on and reading goes on once they were received, so the reader of the
+// socket decides whether to keep going.
+fn forward_primary<I>(mut primary: Source<I>, sender: mpsc::SyncSender<Result<Option<String>>>)
+where
+    I: Read + Send + 'static,
+{
+    crate::thread::spawn("primary", move || {
+        let mut line = String::new();
+        loop {
+            let frame = match primary.read_line(&mut line) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(line.trim_end().to_string())),
+                Err(error) => Err(error),
+            };
+            let end = matches!(frame, Ok(None));
+            if sender.send(frame).is_err() || end {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::Node;
+    use crate::testing::Echo;
+
+    fn frame(src: &str, dest: &str, body: Value) -> String {
+        json!({ "src": src, "dest": dest, "body": body }).to_string()
+    }
+
+    fn init(dest: &str) -> String {
+        let body = json!({ "type": "init", "msg_id": 1, "node_id": dest, "node_ids": [dest] });
+        frame("c1", dest, body)
+    }
+
+    fn echo(dest: &str, msg_id: u64) -> String {
+        let body = json!({ "type": "echo", "msg_id": msg_id, "echo": msg_id });
+        frame("c1", dest, body)
+    }
+
+    // Hands out the frames it was given, after which the input ends, and keeps what is sent.
+    struct Scripted {
+        frames: Mutex<VecDeque<String>>,
+        sent: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl Transport for Scripted {
+        fn send(&self, frame: &str) -> Result<()> {
+            let message = serde_json::from_str(frame).context("parsing sent frame")?;
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        fn recv(&self) -> Result<Option<String>> {
+            Ok(self.frames.lock().unwrap().pop_front())
+        }
+    }
+
+    #[test]
+    fn runs_a_node_over_any_transport() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let transport = Scripted {
+            frames: Mutex::new([init("n1"), echo("n1", 2), echo("n1", 3)].into()),
+            sent: sent.clone(),
+        };
+        Echo::run((), Socket::from_transport(transport)).unwrap();
+
+        let sent = sent.lock().unwrap();
+        let replies: Vec<(&Value, &Value)> = sent
+            .iter()
+            .map(|message| (&message["body"]["type"], &message["body"]["in_reply_to"]))
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                (&json!("init_ok"), &json!(1)),
+                (&json!("echo_ok"), &json!(2)),
+                (&json!("echo_ok"), &json!(3)),
+            ]
+        );
+        assert!(sent.iter().all(|message| message["dest"] == "c1"));
+    }
+
+    #[test]
+    fn exchanges_frames_over_tcp() {
+        let node = TcpTransport::bind("127.0.0.1:0", HashMap::new()).unwrap();
+        let peers = HashMap::from([("n1".to_owned(), node.local_addr())]);
+        let client = TcpTransport::bind("127.0.0.1:0", peers).unwrap();
+        // Runs until the test exits, nothing closes the listener.
+        std::thread::spawn(move || Echo::run((), Socket::from_transport(node)));
+
+        // The node answers over the connection the client reached it on.
+        client.send(&init("n1")).unwrap();
+        let init_ok: Value = serde_json::from_str(&client.recv().unwrap().unwrap()).unwrap();
+        assert_eq!(init_ok["body"]["type"], "init_ok");
+        client.send(&echo("n1", 2)).unwrap();
+        let echo_ok: Value = serde_json::from_str(&client.recv().unwrap().unwrap()).unwrap();
+        assert_eq!(echo_ok["src"], "n1");
+        assert_eq!(echo_ok["body"]["echo"], 2);
+    }
+
+    #[test]
+    fn exchanges_frames_over_unix_sockets() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(format!("mael-{}-{name}.sock", std::process::id()));
+        let (node_path, client_path) = (path("n1"), path("c1"));
+        for path in [&node_path, &client_path] {
+            let _ = std::fs::remove_file(path);
+        }
+        let node = UnixTransport::bind(&node_path, HashMap::new()).unwrap();
+        let peers = HashMap::from([("n1".to_owned(), node_path.clone())]);
+        let client = UnixTransport::bind(&client_path, peers).unwrap();
+        // Runs until the test exits, nothing closes the listener.
+        std::thread::spawn(move || Echo::run((), Socket::from_transport(node)));
+
+        client.send(&init("n1")).unwrap();
+        let init_ok: Value = serde_json::from_str(&client.recv().unwrap().unwrap()).unwrap();
+        assert_eq!(init_ok["body"]["type"], "init_ok");
+        client.send(&echo("n1", 2)).unwrap();
+        let echo_ok: Value = serde_json::from_str(&client.recv().unwrap().unwrap()).unwrap();
+        assert_eq!(echo_ok["src"], "n1");
+        assert_eq!(echo_ok["body"]["echo"], 2);
+
+        for path in [&node_path, &client_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn loopback_routes_frames_by_destination() {
+        let network = Loopback::new();
+        let (n1, n2) = (network.transport("n1"), network.transport("n2"));
+        n1.send(&echo("n2", 1)).unwrap();
+        n2.send(&echo("n1", 2)).unwrap();
+        assert_eq!(n2.recv().unwrap(), Some(echo("n2", 1)));
+        assert_eq!(n1.recv().unwrap(), Some(echo("n1", 2)));
+        assert!(n1.send(&echo("n3", 3)).is_err());
+
+        // Frames sent before the disconnect are still received, later ones are dropped.
+        n1.send(&echo("n2", 4)).unwrap();
+        network.disconnect("n2");
+        n1.send(&echo("n2", 5)).unwrap();
+        assert_eq!(n2.recv().unwrap(), Some(echo("n2", 4)));
+        assert_eq!(n2.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn side_channels_carry_the_frames_of_their_destinations() {
+        let (primary, side) = (Loopback::new(), Loopback::new());
+        let client = primary.transport("c1");
+        let service = side.transport("svc");
+        let node = {
+            let socket = Socket::from_transport(primary.transport("n1"))
+                .with_side_channel(side.transport("n1"), ["svc"]);
+            std::thread::spawn(move || Echo::run((), socket))
+        };
+        let recv = |transport: &LoopbackTransport| -> Value {
+            serde_json::from_str(&transport.recv().unwrap().unwrap()).unwrap()
+        };
+
+        client.send(&init("n1")).unwrap();
+        assert_eq!(recv(&client)["body"]["type"], "init_ok");
+        let request = json!({ "type": "echo", "msg_id": 2, "echo": "side" });
+        service.send(&frame("svc", "n1", request)).unwrap();
+        client.send(&echo("n1", 3)).unwrap();
+        assert_eq!(recv(&service)["body"]["echo"], "side");
+        assert_eq!(recv(&client)["body"]["echo"], 3);
+
+        // The primary input still decides when the node stops.
+        primary.disconnect("n1");
+        node.join().unwrap().unwrap();
+    }
+}
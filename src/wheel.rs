@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+const SLOTS: usize = 1024;
+
+// A hashed timer wheel, deadlines are rounded up to the next millisecond and hashed into a slot by
+// their tick. Entries further away than one turn of the wheel share a slot with earlier ones and
+// are skipped until their turn comes.
+pub(crate) struct TimerWheel<K> {
+    start: Instant,
+    // The first tick that has not expired yet.
+    current: u64,
+    slots: Vec<Vec<(u64, K)>>,
+    // How many entries are due at each tick, the first one is the next deadline without walking
+    // the slots.
+    due: BTreeMap<u64, usize>,
+}
+
+impl<K> TimerWheel<K> {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            current: 0,
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            due: BTreeMap::new(),
+        }
+    }
+
+    fn tick_at(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.start).as_millis() as u64
+    }
+
+    fn instant_of(&self, tick: u64) -> Instant {
+        self.start + Duration::from_millis(tick)
+    }
+
+    pub(crate) fn insert(&mut self, deadline: Instant, key: K) {
+        let mut tick = self.tick_at(deadline);
+        // Rounds up, a deadline never fires early.
+        if self.instant_of(tick) < deadline {
+            tick += 1;
+        }
+        let tick = tick.max(self.current);
+        self.slots[tick as usize % SLOTS].push((tick, key));
+        *self.due.entry(tick).or_default() += 1;
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        let (tick, _) = self.due.first_key_value()?;
+        Some(self.instant_of(*tick))
+    }
+
+    // Takes out every entry whose deadline has passed.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<K> {
+        let now_tick = self.tick_at(now);
+        let mut expired = Vec::new();
+        if now_tick < self.current {
+            return expired;
+        }
+        // Nothing is due, which is most of the time.
+        if self
+            .due
+            .first_key_value()
+            .is_none_or(|(tick, _)| *tick > now_tick)
+        {
+            self.current = now_tick + 1;
+            return expired;
+        }
+        let turns = (now_tick - self.current + 1).min(SLOTS as u64);
+        for tick in self.current..self.current + turns {
+            let slot = &mut self.slots[tick as usize % SLOTS];
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].0 <= now_tick {
+                    let (tick, key) = slot.swap_remove(index);
+                    expired.push(key);
+                    if let Some(count) = self.due.get_mut(&tick) {
+                        *count -= 1;
+                        if *count == 0 {
+                            self.due.remove(&tick);
+                        }
+                    }
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.current = now_tick + 1;
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_deadline_follows_inserts_and_expiry() {
+        let mut wheel = TimerWheel::new();
+        let start = wheel.start;
+        let at = |millis| start + Duration::from_millis(millis);
+        assert_eq!(wheel.next_deadline(), None);
+
+        // One beyond a turn of the wheel, sharing the slot of the first.
+        wheel.insert(at(5 + SLOTS as u64), "late");
+        wheel.insert(at(20), "second");
+        wheel.insert(at(5), "first");
+        assert_eq!(wheel.next_deadline(), Some(at(5)));
+
+        assert!(wheel.expire(at(4)).is_empty());
+        assert_eq!(wheel.expire(at(5)), ["first"]);
+        assert_eq!(wheel.next_deadline(), Some(at(20)));
+        assert_eq!(wheel.expire(at(SLOTS as u64)), ["second"]);
+        assert_eq!(wheel.next_deadline(), Some(at(5 + SLOTS as u64)));
+        assert_eq!(wheel.expire(at(5 + SLOTS as u64)), ["late"]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn deadlines_round_up_to_the_next_tick() {
+        let mut wheel = TimerWheel::new();
+        let deadline = wheel.start + Duration::from_micros(2500);
+        wheel.insert(deadline, ());
+        assert_eq!(
+            wheel.next_deadline(),
+            Some(wheel.start + Duration::from_millis(3))
+        );
+        assert!(
+            wheel
+                .expire(wheel.start + Duration::from_millis(2))
+                .is_empty()
+        );
+        assert_eq!(wheel.expire(deadline + Duration::from_millis(1)).len(), 1);
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Instant;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::buffers::Buffers;
+use crate::stats::WriterStats;
+
+enum Command {
+    Write(Vec<u8>),
+    // Answered once everything sent before it is written and flushed.
+    Flush(mpsc::Sender<()>),
+}
+
+// Writes frames on a thread of its own. Frames that queue up while the thread is busy are written
+// together, followed by a single flush.
+#[derive(Clone)]
+pub(crate) struct Writer {
+    sender: mpsc::Sender<Command>,
+    stats: WriterStats,
+    // The first write error, after which the thread stops.
+    error: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl Writer {
+    pub(crate) fn spawn<O>(mut output: O, buffers: Buffers, stats: WriterStats) -> Self
+    where
+        O: Write + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let error = Arc::new(Mutex::new(None));
+
+        {
+            let error = error.clone();
+            let stats = stats.clone();
+            crate::thread::spawn("writer", move || {
+                let mut buffer = Vec::new();
+                let mut flushed = Vec::new();
+                while let Ok(command) = receiver.recv() {
+                    let mut batches = 0;
+                    for command in std::iter::once(command).chain(receiver.try_iter()) {
+                        match command {
+                            Command::Write(frames) => {
+                                buffer.extend_from_slice(&frames);
+                                buffers.give(frames);
+                                batches += 1;
+                            }
+                            Command::Flush(done) => flushed.push(done),
+                        }
+                    }
+                    let started = Instant::now();
+                    let written = output.write_all(&buffer).and_then(|()| output.flush());
+                    stats.record(&buffer, started.elapsed());
+                    stats.dequeued(batches);
+                    buffer.clear();
+                    if let Err(write_error) = written {
+                        *error.lock().expect("failed to lock writer error") = Some(write_error);
+                        return;
+                    }
+                    for done in flushed.drain(..) {
+                        let _ = done.send(());
+                    }
+                }
+            });
+        }
+
+        Self {
+            sender,
+            stats,
+            error,
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match &*self.error.lock().expect("failed to lock writer error") {
+            Some(error) => Err(anyhow!("{error}")).context("writer thread failed"),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn write(&self, frames: Vec<u8>) -> Result<()> {
+        self.check()?;
+        self.stats.queued();
+        self.sender
+            .send(Command::Write(frames))
+            .map_err(|_| anyhow!("writer thread stopped"))
+    }
+
+    // Waits until everything that was written before is flushed.
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.check()?;
+        let (done, flushed) = mpsc::channel();
+        self.sender
+            .send(Command::Flush(done))
+            .map_err(|_| anyhow!("writer thread stopped"))?;
+        if flushed.recv().is_err() {
+            self.check()?;
+            return Err(anyhow!("writer thread stopped"));
+        }
+        Ok(())
+    }
+}